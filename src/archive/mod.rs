@@ -0,0 +1,569 @@
+mod stream;
+mod tar;
+mod zip;
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::protocol::*;
+use crate::reply::DirBuf;
+use crate::{Errno, Request};
+use stream::{Codec, Cursors, Source, Stream};
+
+type Result<T> = std::result::Result<T, Errno>;
+
+const BLOCK_SIZE: u64 = 4096;
+/// Offsets of the `.` and `..` entries, the other entries come after.
+const DOT_OFFSET: u64 = 1;
+const DOTDOT_OFFSET: u64 = 2;
+
+/// A read-only file system exposing the members of a tar or zip archive.
+///
+/// Tar archives can be compressed with gzip, or with zstd when the `zstd`
+/// feature is enabled. Opening an archive reads its index, the headers of a
+/// tar archive or the central directory of a zip archive, while the data is
+/// only read and decompressed when the files are read. Reading a compressed
+/// tar archive sequentially continues the decompression where it stopped,
+/// random reads decompress it again from the start.
+///
+/// The inode numbers follow the order of the members in the archive, so they
+/// are the same each time the archive is mounted. The archive must not
+/// change while it is mounted, the kernel keeps the data of the files in its
+/// cache between opens. The file system should be mounted with
+/// [`MountBuilder::rdonly`](crate::MountBuilder::rdonly).
+pub struct ArchiveFs {
+    file: Arc<File>,
+    nodes: Vec<Node>,
+    cursors: Arc<Mutex<Cursors>>,
+    timeout: Duration,
+}
+
+#[derive(Debug)]
+struct Node {
+    parent: u64,
+    kind: Kind,
+    /// File type and permissions.
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+    nlink: u32,
+    rdev: u32,
+    size: u64,
+}
+
+#[derive(Debug)]
+enum Kind {
+    Dir(BTreeMap<OsString, u64>),
+    File(Data),
+    Symlink(Vec<u8>),
+    /// Fifos and devices, which are handled by the kernel.
+    Special,
+}
+
+/// Where the data of a file is.
+#[derive(Debug, Clone, Copy)]
+enum Data {
+    /// Stored as is at an offset of the archive.
+    Stored { offset: u64 },
+    /// At an offset of the decompressed data of a source.
+    Compressed { source: Source, offset: u64 },
+    /// Encrypted or compressed with an unsupported method.
+    Unsupported,
+}
+
+impl ArchiveFs {
+    /// Opens an archive and reads its index, which reads the whole archive if
+    /// it is a compressed tar archive.
+    ///
+    /// The format is detected from the contents of the file.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = Arc::new(File::open(path)?);
+        let metadata = file.metadata()?;
+        let root = Node {
+            parent: FUSE_ROOT_ID,
+            kind: Kind::Dir(BTreeMap::new()),
+            mode: libc::S_IFDIR | 0o755,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mtime: metadata.mtime(),
+            nlink: 2,
+            rdev: 0,
+            size: BLOCK_SIZE,
+        };
+        let mut tree = Tree { nodes: vec![root] };
+
+        let start = stream::read_at(&file, 0, 512)?;
+        let compressed = |codec| Source { codec, offset: 0, len: metadata.len() };
+        if start.starts_with(&[0x1f, 0x8b]) {
+            let source = compressed(Codec::Gzip);
+            tree.add_tar(&mut Stream::open(&file, source)?, Some(source))?;
+        } else if start.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            #[cfg(feature = "zstd")]
+            {
+                let source = compressed(Codec::Zstd);
+                tree.add_tar(&mut Stream::open(&file, source)?, Some(source))?;
+            }
+            #[cfg(not(feature = "zstd"))]
+            io_error!(io::ErrorKind::Unsupported, "zstd archives need the `zstd` feature");
+        } else if zip::is_zip(&start) {
+            tree.add_zip(&file)?;
+        } else if tar::is_tar(&start) {
+            tree.add_tar(&mut tar::Plain { file: &file, offset: 0, len: metadata.len() }, None)?;
+        } else {
+            io_error!(io::ErrorKind::InvalidData, "unknown archive format");
+        }
+
+        tree.count_links();
+        Ok(Self {
+            file,
+            nodes: tree.nodes,
+            cursors: Arc::default(),
+            timeout: Duration::from_secs(3600),
+        })
+    }
+
+    /// How long the kernel caches the entries and the attributes, defaults
+    /// to an hour since they don't change.
+    #[inline]
+    #[must_use = "An ArchiveFs does nothing unless passed to a Session"]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn node(&self, ino: u64) -> Result<&Node> {
+        ino.checked_sub(1)
+            .and_then(|index| self.nodes.get(index as usize))
+            .ok_or(Errno::ESTALE)
+    }
+
+    /// Identifies the archive file and its tree, to check that a snapshot
+    /// was taken with the same archive.
+    fn identity(&self) -> Result<Vec<u8>> {
+        let metadata = self.file.metadata()?;
+        let mtime = metadata.mtime() as u64;
+        let identity = [metadata.dev(), metadata.ino(), metadata.len(), mtime];
+        Ok(identity.into_iter()
+            .chain([self.nodes.len() as u64])
+            .flat_map(u64::to_le_bytes)
+            .collect())
+    }
+
+    fn entry(&self, ino: u64, node: &Node) -> fuse_entry_out {
+        fuse_entry_out {
+            nodeid: ino,
+            generation: 0,
+            entry_valid: self.timeout.as_secs(),
+            attr_valid: self.timeout.as_secs(),
+            entry_valid_nsec: self.timeout.subsec_nanos(),
+            attr_valid_nsec: self.timeout.subsec_nanos(),
+            attr: node.attr(ino),
+        }
+    }
+}
+
+impl std::fmt::Debug for ArchiveFs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ArchiveFs")
+            .field("file", &self.file)
+            .field("nodes", &self.nodes.len())
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The nodes of an archive while it is indexed.
+struct Tree {
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    fn node(&self, ino: u64) -> &Node {
+        &self.nodes[ino as usize - 1]
+    }
+
+    fn node_mut(&mut self, ino: u64) -> &mut Node {
+        &mut self.nodes[ino as usize - 1]
+    }
+
+    fn child(&self, dir: u64, name: &OsStr) -> Option<u64> {
+        match &self.node(dir).kind {
+            Kind::Dir(children) => children.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    /// A node with the owner and time of the root, for the directories that
+    /// are implied by the paths of the members.
+    fn implicit(&self, mode: u32, kind: Kind) -> Node {
+        let root = self.node(FUSE_ROOT_ID);
+        Node {
+            parent: FUSE_ROOT_ID,
+            kind,
+            mode,
+            uid: root.uid,
+            gid: root.gid,
+            mtime: root.mtime,
+            nlink: 1,
+            rdev: 0,
+            size: 0,
+        }
+    }
+
+    /// The directory of the member at `path` and its name, making the
+    /// missing directories. `None` for the root and for paths going up with
+    /// `..`, which are skipped.
+    fn parent_of<'a>(&mut self, path: &'a [u8]) -> Option<(u64, &'a OsStr)> {
+        let mut components: Vec<_> = path.split(|&byte| byte == b'/')
+            .filter(|&component| !component.is_empty() && component != b".")
+            .collect();
+        if components.contains(&&b".."[..]) {
+            return None;
+        }
+        let name = OsStr::from_bytes(components.pop()?);
+
+        let mut dir = FUSE_ROOT_ID;
+        for component in components {
+            let component = OsStr::from_bytes(component);
+            dir = match self.child(dir, component) {
+                Some(ino) if self.node(ino).is_dir() => ino,
+                _ => {
+                    let node = self.implicit(libc::S_IFDIR | 0o755, Kind::Dir(BTreeMap::new()));
+                    self.insert(dir, component, node)
+                },
+            };
+        }
+        Some((dir, name))
+    }
+
+    /// Adds a node to a directory, replacing the entry of the same name, as
+    /// the later members of an archive override the earlier ones. A
+    /// directory replacing a directory only updates its attributes.
+    fn insert(&mut self, parent: u64, name: &OsStr, mut node: Node) -> u64 {
+        if let Some(old) = self.child(parent, name) {
+            let existing = self.node_mut(old);
+            if existing.is_dir() && node.is_dir() {
+                existing.update(&node);
+                return old;
+            }
+            existing.nlink = existing.nlink.saturating_sub(1);
+        }
+        node.parent = parent;
+        self.nodes.push(node);
+        let ino = self.nodes.len() as u64;
+        self.link(parent, name, ino);
+        ino
+    }
+
+    fn link(&mut self, parent: u64, name: &OsStr, ino: u64) {
+        if let Kind::Dir(children) = &mut self.node_mut(parent).kind {
+            children.insert(name.to_owned(), ino);
+        }
+    }
+
+    fn add(&mut self, path: &[u8], node: Node) {
+        match self.parent_of(path) {
+            Some((parent, name)) => {
+                self.insert(parent, name, node);
+            },
+            None if node.is_dir() && !path.split(|&byte| byte == b'/').any(|c| c == b"..") => {
+                self.node_mut(FUSE_ROOT_ID).update(&node);
+            },
+            None => {},
+        }
+    }
+
+    /// Adds a hard link to the file at `target`, which must come before.
+    fn add_link(&mut self, path: &[u8], target: &[u8]) {
+        let Some(target) = self.resolve(target).filter(|&ino| !self.node(ino).is_dir()) else {
+            return;
+        };
+        let Some((parent, name)) = self.parent_of(path) else { return };
+        if let Some(old) = self.child(parent, name) {
+            if old == target || self.node(old).is_dir() {
+                return;
+            }
+            self.node_mut(old).nlink -= 1;
+        }
+        self.link(parent, name, target);
+        self.node_mut(target).nlink += 1;
+    }
+
+    fn resolve(&self, path: &[u8]) -> Option<u64> {
+        path.split(|&byte| byte == b'/')
+            .filter(|&component| !component.is_empty() && component != b".")
+            .try_fold(FUSE_ROOT_ID, |dir, name| self.child(dir, OsStr::from_bytes(name)))
+    }
+
+    fn add_tar(&mut self, input: &mut impl tar::Input, source: Option<Source>) -> io::Result<()> {
+        tar::index(input, |entry| {
+            let (file_type, kind) = match entry.kind {
+                tar::Kind::Link => return self.add_link(&entry.path, &entry.link),
+                tar::Kind::File => {
+                    let data = match source {
+                        Some(source) => Data::Compressed { source, offset: entry.offset },
+                        None => Data::Stored { offset: entry.offset },
+                    };
+                    (libc::S_IFREG, Kind::File(data))
+                },
+                tar::Kind::Dir => (libc::S_IFDIR, Kind::Dir(BTreeMap::new())),
+                tar::Kind::Symlink => (libc::S_IFLNK, Kind::Symlink(entry.link.clone())),
+                tar::Kind::Char => (libc::S_IFCHR, Kind::Special),
+                tar::Kind::Block => (libc::S_IFBLK, Kind::Special),
+                tar::Kind::Fifo => (libc::S_IFIFO, Kind::Special),
+            };
+            let node = Node {
+                parent: FUSE_ROOT_ID,
+                kind,
+                mode: file_type | entry.mode,
+                uid: entry.uid,
+                gid: entry.gid,
+                mtime: entry.mtime,
+                nlink: 1,
+                rdev: entry.rdev,
+                size: match entry.kind {
+                    tar::Kind::Symlink => entry.link.len() as u64,
+                    _ => entry.size,
+                },
+            };
+            self.add(&entry.path, node);
+        })
+    }
+
+    fn add_zip(&mut self, file: &Arc<File>) -> io::Result<()> {
+        let mut entries = Vec::new();
+        zip::index(file, |entry| entries.push(entry))?;
+
+        let cursors = Mutex::default();
+        for entry in entries {
+            let data = match entry.method {
+                zip::Method::Stored { offset } => Data::Stored { offset },
+                zip::Method::Compressed(source) => Data::Compressed { source, offset: 0 },
+                zip::Method::Unsupported => Data::Unsupported,
+            };
+            let default = match entry.is_dir {
+                true => libc::S_IFDIR | 0o755,
+                false => libc::S_IFREG | 0o644,
+            };
+            let mode = entry.mode.filter(|&mode| mode & libc::S_IFMT != 0).unwrap_or(default);
+            let kind = match mode & libc::S_IFMT {
+                libc::S_IFDIR => Kind::Dir(BTreeMap::new()),
+                libc::S_IFREG => Kind::File(data),
+                libc::S_IFLNK => {
+                    let len = entry.size.min(libc::PATH_MAX as u64) as usize;
+                    Kind::Symlink(read_data(file, &cursors, data, 0, len)?)
+                },
+                _ => Kind::Special,
+            };
+            let mut node = self.implicit(mode, kind);
+            node.mtime = entry.mtime;
+            node.size = entry.size;
+            self.add(&entry.path, node);
+        }
+        Ok(())
+    }
+
+    /// Sets the link count of the directories, from their subdirectories.
+    fn count_links(&mut self) {
+        let mut links = vec![0; self.nodes.len()];
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.is_dir() && index > 0 {
+                links[node.parent as usize - 1] += 1;
+            }
+        }
+        for (node, subdirs) in self.nodes.iter_mut().zip(links) {
+            if node.is_dir() {
+                node.nlink = 2 + subdirs;
+                node.size = BLOCK_SIZE;
+            }
+        }
+    }
+}
+
+impl Node {
+    #[inline]
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, Kind::Dir(_))
+    }
+
+    /// Takes the attributes of a later member at the same path.
+    fn update(&mut self, other: &Node) {
+        self.mode = other.mode;
+        self.uid = other.uid;
+        self.gid = other.gid;
+        self.mtime = other.mtime;
+    }
+
+    fn attr(&self, ino: u64) -> fuse_attr {
+        let mtime = self.mtime.max(0) as u64;
+        fuse_attr {
+            ino,
+            size: self.size,
+            blocks: self.size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            atimensec: 0,
+            mtimensec: 0,
+            ctimensec: 0,
+            mode: self.mode,
+            nlink: self.nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: self.rdev,
+            blksize: BLOCK_SIZE as u32,
+            flags: AttrFlags::empty(),
+        }
+    }
+}
+
+/// Reads up to `len` bytes at `offset` of the data of a file.
+fn read_data(
+    file: &Arc<File>,
+    cursors: &Mutex<Cursors>,
+    data: Data,
+    offset: u64,
+    len: usize
+) -> io::Result<Vec<u8>> {
+    match data {
+        Data::Stored { offset: start } => stream::read_at(file, start + offset, len),
+        Data::Compressed { source, offset: start } => {
+            cursors.lock().unwrap().read(file, source, start + offset, len)
+        },
+        Data::Unsupported => Err(io::ErrorKind::Unsupported.into()),
+    }
+}
+
+impl crate::Filesystem for ArchiveFs {
+    /// The node ids only depend on the archive, so it is enough to check
+    /// that the next process serves the same one.
+    async fn snapshot(&self) -> Result<Vec<u8>> {
+        self.identity()
+    }
+
+    async fn restore(&self, snapshot: &[u8]) -> Result<()> {
+        if snapshot != self.identity()? {
+            return Err(Errno::ESTALE);
+        }
+        Ok(())
+    }
+
+    async fn lookup(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<fuse_entry_out> {
+        let Kind::Dir(children) = &self.node(parent)?.kind else {
+            return Err(Errno::ENOTDIR);
+        };
+        let ino = *children.get(name).ok_or(Errno::ENOENT)?;
+        Ok(self.entry(ino, self.node(ino)?))
+    }
+
+    async fn getattr(
+        &self,
+        _req: &Request,
+        ino: u64,
+        _arg: &fuse_getattr_in
+    ) -> Result<fuse_attr_out> {
+        Ok(fuse_attr_out {
+            attr_valid: self.timeout.as_secs(),
+            attr_valid_nsec: self.timeout.subsec_nanos(),
+            dummy: Padding::new(),
+            attr: self.node(ino)?.attr(ino),
+        })
+    }
+
+    async fn readlink(&self, _req: &Request, ino: u64) -> Result<Vec<u8>> {
+        match &self.node(ino)?.kind {
+            Kind::Symlink(target) => Ok(target.clone()),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    async fn open(&self, _req: &Request, ino: u64, arg: &fuse_open_in) -> Result<fuse_open_out> {
+        match self.node(ino)?.kind {
+            Kind::File(Data::Unsupported) => return Err(Errno::EOPNOTSUPP),
+            Kind::File(_) => {},
+            Kind::Dir(_) => return Err(Errno::EISDIR),
+            _ => return Err(Errno::ENXIO),
+        }
+        if arg.flags as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(Errno::EROFS);
+        }
+        Ok(fuse_open_out { fh: 0, open_flags: OpenOutFlags::FOPEN_KEEP_CACHE, backing_id: 0 })
+    }
+
+    async fn read(&self, _req: &Request, ino: u64, arg: &fuse_read_in) -> Result<Vec<u8>> {
+        let node = self.node(ino)?;
+        let Kind::File(data) = node.kind else { return Err(Errno::EISDIR) };
+        if arg.offset >= node.size {
+            return Ok(Vec::new());
+        }
+        let len = (node.size - arg.offset).min(arg.size as u64) as usize;
+
+        let (file, cursors, offset) = (self.file.clone(), self.cursors.clone(), arg.offset);
+        tokio::task::spawn_blocking(move || read_data(&file, &cursors, data, offset, len))
+            .await
+            .map_err(|_| Errno::EIO)?
+            .map_err(Errno::from)
+    }
+
+    async fn statfs(&self, _req: &Request, _ino: u64) -> Result<fuse_kstatfs> {
+        let size: u64 = self.nodes.iter().map(|node| node.size).sum();
+        Ok(fuse_kstatfs {
+            blocks: size.div_ceil(BLOCK_SIZE),
+            bfree: 0,
+            bavail: 0,
+            files: self.nodes.len() as u64,
+            ffree: 0,
+            bsize: BLOCK_SIZE as u32,
+            namelen: 255,
+            frsize: BLOCK_SIZE as u32,
+            padding: Padding::new(),
+            spare: Padding::new(),
+        })
+    }
+
+    async fn opendir(
+        &self,
+        _req: &Request,
+        ino: u64,
+        _arg: &fuse_open_in
+    ) -> Result<fuse_open_out> {
+        if !self.node(ino)?.is_dir() {
+            return Err(Errno::ENOTDIR);
+        }
+        let open_flags = OpenOutFlags::FOPEN_KEEP_CACHE | OpenOutFlags::FOPEN_CACHE_DIR;
+        Ok(fuse_open_out { fh: 0, open_flags, backing_id: 0 })
+    }
+
+    async fn readdir(
+        &self,
+        _req: &Request,
+        ino: u64,
+        arg: &fuse_read_in,
+        buf: &mut DirBuf
+    ) -> Result<()> {
+        let node = self.node(ino)?;
+        let Kind::Dir(children) = &node.kind else { return Err(Errno::ENOTDIR) };
+        let dots = [
+            (ino, OsStr::new("."), DOT_OFFSET),
+            (node.parent, OsStr::new(".."), DOTDOT_OFFSET),
+        ];
+        let children = children.iter().enumerate().map(|(index, (name, &ino))| {
+            (ino, name.as_os_str(), DOTDOT_OFFSET + 1 + index as u64)
+        });
+        for (ino, name, next) in dots.into_iter().chain(children).skip(arg.offset as usize) {
+            let file_type = self.node(ino)?.mode & libc::S_IFMT;
+            if !buf.push(ino, next, file_type, name) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+#[cfg(feature = "zstd")]
+use std::io::BufRead;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use flate2::bufread::{DeflateDecoder, MultiGzDecoder};
+#[cfg(feature = "zstd")]
+use ruzstd::decoding::{FrameDecoder, StreamingDecoder};
+
+/// Streams kept open to continue reading compressed data, the most recently
+/// used last.
+const MAX_CURSORS: usize = 4;
+
+/// Compression of a part of an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Codec {
+    Gzip,
+    /// Raw deflate, as in zip archives.
+    Deflate,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// A compressed part of an archive, which can only be read from its start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Source {
+    pub codec: Codec,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Decompressed stream of a [`Source`], with its position.
+pub(super) struct Stream {
+    source: Source,
+    reader: Box<dyn Read + Send>,
+    position: u64,
+}
+
+impl Stream {
+    pub fn open(file: &Arc<File>, source: Source) -> io::Result<Self> {
+        let raw = BufReader::new(ReadAt {
+            file: file.clone(),
+            offset: source.offset,
+            end: source.offset.saturating_add(source.len),
+        });
+        let reader: Box<dyn Read + Send> = match source.codec {
+            Codec::Gzip => Box::new(MultiGzDecoder::new(raw)),
+            Codec::Deflate => Box::new(DeflateDecoder::new(raw)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Box::new(ZstdDecoder::new(raw)?),
+        };
+        Ok(Self { source, reader, position: 0 })
+    }
+
+    #[inline]
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Skips `len` bytes, failing if the stream ends before.
+    pub fn skip(&mut self, len: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut self.by_ref().take(len), &mut io::sink())?;
+        if skipped < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    /// Reads up to `len` bytes, less only at the end of the stream.
+    pub fn read_up_to(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len);
+        self.by_ref().take(len as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+/// Open streams of the compressed parts of an archive, so that reading a
+/// file sequentially doesn't decompress it again from the start.
+#[derive(Default)]
+pub(super) struct Cursors {
+    streams: Vec<Stream>,
+}
+
+impl Cursors {
+    /// Reads up to `len` bytes at `offset` of the decompressed data of a
+    /// source, continuing from the closest open stream before it.
+    pub fn read(
+        &mut self,
+        file: &Arc<File>,
+        source: Source,
+        offset: u64,
+        len: usize
+    ) -> io::Result<Vec<u8>> {
+        let closest = self.streams.iter()
+            .enumerate()
+            .filter(|(_, stream)| stream.source == source && stream.position <= offset)
+            .max_by_key(|(_, stream)| stream.position)
+            .map(|(index, _)| index);
+        let mut stream = match closest {
+            Some(index) => self.streams.remove(index),
+            None => Stream::open(file, source)?,
+        };
+
+        stream.skip(offset - stream.position)?;
+        let data = stream.read_up_to(len)?;
+        if self.streams.len() == MAX_CURSORS {
+            self.streams.remove(0);
+        }
+        self.streams.push(stream);
+        Ok(data)
+    }
+}
+
+/// Reads a range of a file at its own position, so that the streams can
+/// share the file.
+struct ReadAt {
+    file: Arc<File>,
+    offset: u64,
+    end: u64,
+}
+
+impl Read for ReadAt {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min((self.end - self.offset).try_into().unwrap_or(usize::MAX));
+        let read = self.file.read_at(&mut buf[..len], self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+/// Reads exactly `len` bytes at `offset` of a file, less at the end of the
+/// file.
+pub(super) fn read_at(file: &File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; len];
+    let mut read = 0;
+    while read < len {
+        match file.read_at(&mut data[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    data.truncate(read);
+    Ok(data)
+}
+
+/// Decoder of zstd streams made of several frames, as written by parallel
+/// compressors.
+#[cfg(feature = "zstd")]
+struct ZstdDecoder<R: BufRead> {
+    /// Decoder of the current frame, missing if the next one is invalid.
+    frame: Option<StreamingDecoder<R, FrameDecoder>>,
+}
+
+#[cfg(feature = "zstd")]
+impl<R: BufRead> ZstdDecoder<R> {
+    fn new(reader: R) -> io::Result<Self> {
+        let frame = StreamingDecoder::new(reader).map_err(io::Error::other)?;
+        Ok(Self { frame: Some(frame) })
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<R: BufRead> Read for ZstdDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let Some(frame) = &mut self.frame else {
+                return Err(io::ErrorKind::InvalidData.into());
+            };
+            let read = frame.read(buf)?;
+            if read > 0 || buf.is_empty() || frame.get_mut().fill_buf()?.is_empty() {
+                return Ok(read);
+            }
+            // The frame ended and another one follows
+            let (reader, decoder) = self.frame.take().unwrap().into_parts();
+            let frame = StreamingDecoder::new_with_decoder(reader, decoder);
+            self.frame = Some(frame.map_err(io::Error::other)?);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+
+use super::stream::Stream;
+
+const BLOCK_SIZE: u64 = 512;
+
+/// An archive read from the start, either as is or decompressed.
+pub(super) trait Input: Read {
+    fn position(&self) -> u64;
+
+    fn skip(&mut self, len: u64) -> io::Result<()>;
+}
+
+/// A tar archive which isn't compressed, so its data can be skipped.
+pub(super) struct Plain<'a> {
+    pub file: &'a File,
+    pub offset: u64,
+    /// Size of the file.
+    pub len: u64,
+}
+
+impl Read for Plain<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+impl Input for Plain<'_> {
+    fn position(&self) -> u64 {
+        self.offset
+    }
+
+    /// Skips `len` bytes, failing if the file ends before.
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.offset = self.offset.checked_add(len)
+            .filter(|&offset| offset <= self.len)
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        Ok(())
+    }
+}
+
+impl Input for Stream {
+    fn position(&self) -> u64 {
+        Stream::position(self)
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        Stream::skip(self, len)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Kind {
+    File,
+    /// A hard link to the file at `link`.
+    Link,
+    Symlink,
+    Char,
+    Block,
+    Dir,
+    Fifo,
+}
+
+/// A member of a tar archive.
+#[derive(Debug)]
+pub(super) struct Entry {
+    pub path: Vec<u8>,
+    pub kind: Kind,
+    /// Permission bits of the mode.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub size: u64,
+    /// Position of the data in the archive.
+    pub offset: u64,
+    /// Target of a symbolic or hard link.
+    pub link: Vec<u8>,
+    pub rdev: u32,
+}
+
+/// Attributes of the next entry set by the pax and GNU extensions.
+#[derive(Debug, Default)]
+struct Extensions {
+    path: Option<Vec<u8>>,
+    link: Option<Vec<u8>>,
+    size: Option<u64>,
+    mtime: Option<i64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+/// Reads the headers of a tar archive, calling `add` with each member.
+///
+/// The data of the members is skipped, it is read later from the offsets of
+/// the entries.
+pub(super) fn index(input: &mut impl Input, mut add: impl FnMut(Entry)) -> io::Result<()> {
+    let mut ext = Extensions::default();
+    let mut header = [0u8; BLOCK_SIZE as usize];
+    loop {
+        if !read_block(input, &mut header)? || header.iter().all(|&byte| byte == 0) {
+            return Ok(());
+        }
+        if checksum(&header) != octal(&header[148..156])? {
+            return Err(invalid("bad header checksum"));
+        }
+
+        let size = match ext.size.take() {
+            Some(size) => size,
+            None => octal(&header[124..136])?,
+        };
+        let padded = size.div_ceil(BLOCK_SIZE)
+            .checked_mul(BLOCK_SIZE)
+            .ok_or_else(|| invalid("bad size"))?;
+        let kind = match header[156] {
+            b'0' | b'\0' | b'7' => Kind::File,
+            b'1' => Kind::Link,
+            b'2' => Kind::Symlink,
+            b'3' => Kind::Char,
+            b'4' => Kind::Block,
+            b'5' => Kind::Dir,
+            b'6' => Kind::Fifo,
+            // GNU long names, the data is the name of the next entry
+            b'L' => {
+                ext.path = Some(read_string(input, size, padded)?);
+                continue;
+            },
+            b'K' => {
+                ext.link = Some(read_string(input, size, padded)?);
+                continue;
+            },
+            b'x' => {
+                let records = read_data(input, size, padded)?;
+                parse_pax(&records, &mut ext)?;
+                continue;
+            },
+            // Global pax headers, volume labels and the like
+            _ => {
+                input.skip(padded)?;
+                ext = Extensions::default();
+                continue;
+            },
+        };
+
+        let mut path = ext.path.take().unwrap_or_else(|| {
+            let name = cstr(&header[0..100]);
+            let prefix = cstr(&header[345..500]);
+            if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                [prefix, b"/", name].concat()
+            } else {
+                name.to_vec()
+            }
+        });
+        // Old archives mark directories with a trailing slash
+        let kind = match kind {
+            Kind::File if path.ends_with(b"/") => Kind::Dir,
+            kind => kind,
+        };
+        if path.ends_with(b"/") {
+            path.pop();
+        }
+
+        let uid = match ext.uid.take() {
+            Some(uid) => uid,
+            None => octal(&header[108..116])? as u32,
+        };
+        let gid = match ext.gid.take() {
+            Some(gid) => gid,
+            None => octal(&header[116..124])? as u32,
+        };
+        let mtime = match ext.mtime.take() {
+            Some(mtime) => mtime,
+            None => octal(&header[136..148])? as i64,
+        };
+        let major = octal(&header[329..337]).unwrap_or(0) as u32;
+        let minor = octal(&header[337..345]).unwrap_or(0) as u32;
+        let entry = Entry {
+            path,
+            kind,
+            mode: octal(&header[100..108])? as u32 & 0o7777,
+            uid,
+            gid,
+            mtime,
+            // Hard links and devices have no data, even with a size
+            size: if kind == Kind::File { size } else { 0 },
+            offset: input.position(),
+            link: ext.link.take().unwrap_or_else(|| cstr(&header[157..257]).to_vec()),
+            rdev: libc::makedev(major, minor) as u32,
+        };
+        add(entry);
+        input.skip(padded)?;
+    }
+}
+
+/// Reads a block, returns `false` at the end of the archive.
+fn read_block(input: &mut impl Input, block: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < block.len() {
+        match input.read(&mut block[read..])? {
+            0 if read == 0 => return Ok(false),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+    Ok(true)
+}
+
+fn read_data(input: &mut impl Input, size: u64, padded: u64) -> io::Result<Vec<u8>> {
+    // The extensions are small, a huge one is a corrupted archive
+    if size > 1 << 20 {
+        return Err(invalid("extended header too large"));
+    }
+    let mut data = vec![0; size as usize];
+    input.read_exact(&mut data)?;
+    input.skip(padded - size)?;
+    Ok(data)
+}
+
+fn read_string(input: &mut impl Input, size: u64, padded: u64) -> io::Result<Vec<u8>> {
+    let data = read_data(input, size, padded)?;
+    Ok(cstr(&data).to_vec())
+}
+
+/// Parses the `<length> <key>=<value>\n` records of a pax header.
+fn parse_pax(mut records: &[u8], ext: &mut Extensions) -> io::Result<()> {
+    while !records.is_empty() {
+        let space = records.iter().position(|&byte| byte == b' ')
+            .ok_or_else(|| invalid("bad pax record"))?;
+        let len: usize = std::str::from_utf8(&records[..space]).ok()
+            .and_then(|len| len.parse().ok())
+            .filter(|&len| len > space + 1 && len <= records.len())
+            .ok_or_else(|| invalid("bad pax record"))?;
+        let record = &records[space + 1..len - 1];
+        records = &records[len..];
+
+        let Some(equal) = record.iter().position(|&byte| byte == b'=') else { continue };
+        let (key, value) = (&record[..equal], &record[equal + 1..]);
+        let number = || std::str::from_utf8(value).ok();
+        match key {
+            b"path" => ext.path = Some(value.to_vec()),
+            b"linkpath" => ext.link = Some(value.to_vec()),
+            b"size" => ext.size = number().and_then(|size| size.parse().ok()),
+            // Times may have a fraction of a second
+            b"mtime" => ext.mtime = number()
+                .and_then(|time| time.split('.').next()?.parse().ok()),
+            b"uid" => ext.uid = number().and_then(|id| id.parse().ok()),
+            b"gid" => ext.gid = number().and_then(|id| id.parse().ok()),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+/// Sum of the bytes of a header, counting the checksum field as spaces.
+fn checksum(header: &[u8]) -> u64 {
+    header.iter()
+        .enumerate()
+        .map(|(index, &byte)| if (148..156).contains(&index) { b' ' } else { byte })
+        .map(u64::from)
+        .sum()
+}
+
+/// Parses a numeric field, in octal or in the base-256 encoding of GNU tar
+/// for large values.
+fn octal(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        let value = field[1..].iter().fold(u64::from(field[0] & 0x7f), |value, &byte| {
+            value << 8 | u64::from(byte)
+        });
+        return Ok(value);
+    }
+    let digits = cstr(field).trim_ascii();
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    digits.iter().try_fold(0u64, |value, &digit| match digit {
+        b'0'..=b'7' => Ok(value << 3 | u64::from(digit - b'0')),
+        _ => Err(invalid("bad numeric field")),
+    })
+}
+
+/// A field up to its first NUL byte.
+fn cstr(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid tar archive: {msg}"))
+}
+
+/// Whether a block looks like the first header of a tar archive.
+pub(super) fn is_tar(header: &[u8]) -> bool {
+    header.len() >= BLOCK_SIZE as usize
+        && octal(&header[148..156]).is_ok_and(|sum| sum == checksum(&header[..512]))
+}
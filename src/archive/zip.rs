@@ -0,0 +1,218 @@
+use std::fs::File;
+use std::io;
+
+use super::stream::{Codec, Source, read_at};
+
+const END_SIGNATURE: u32 = 0x0605_4b50;
+const END64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const END64_SIGNATURE: u32 = 0x0606_4b50;
+const CENTRAL_SIGNATURE: u32 = 0x0201_4b50;
+const LOCAL_SIGNATURE: u32 = 0x0403_4b50;
+/// Size of the end of central directory record, without its comment.
+const END_SIZE: usize = 22;
+const END64_LOCATOR_SIZE: u64 = 20;
+const CENTRAL_SIZE: usize = 46;
+const LOCAL_SIZE: usize = 30;
+
+/// Extra field with the 64-bit sizes and offset.
+const ZIP64_EXTRA: u16 = 0x0001;
+/// Extra field with the Unix modification time.
+const TIMESTAMP_EXTRA: u16 = 0x5455;
+/// Systems of the `version made by` field whose attributes have a Unix mode.
+const UNIX_HOSTS: [u8; 2] = [3, 19];
+
+/// How the data of a member is stored.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Method {
+    Stored { offset: u64 },
+    Compressed(Source),
+    /// Encrypted or compressed with an unsupported method.
+    Unsupported,
+}
+
+/// A member of a zip archive.
+#[derive(Debug)]
+pub(super) struct Entry {
+    pub path: Vec<u8>,
+    /// Mode of the member, when it was made on Unix.
+    pub mode: Option<u32>,
+    pub is_dir: bool,
+    pub mtime: i64,
+    pub size: u64,
+    pub method: Method,
+}
+
+/// Reads the central directory of a zip archive, calling `add` with each
+/// member.
+pub(super) fn index(file: &File, mut add: impl FnMut(Entry)) -> io::Result<()> {
+    let len = file.metadata()?.len();
+    let (count, offset, size) = find_central(file, len)?;
+    if offset.checked_add(size).is_none_or(|end| end > len) {
+        return Err(invalid("central directory out of bounds"));
+    }
+    let size = usize::try_from(size).map_err(|_| invalid("central directory too large"))?;
+    let central = read_at(file, offset, size)?;
+
+    let mut rest = &central[..];
+    for _ in 0..count {
+        if rest.len() < CENTRAL_SIZE || u32_at(rest, 0) != CENTRAL_SIGNATURE {
+            return Err(invalid("bad central directory entry"));
+        }
+        let host = rest[5];
+        let flags = u16_at(rest, 8);
+        let method = u16_at(rest, 10);
+        let (time, date) = (u16_at(rest, 12), u16_at(rest, 14));
+        let mut compressed = u64::from(u32_at(rest, 20));
+        let mut size = u64::from(u32_at(rest, 24));
+        let name_len = usize::from(u16_at(rest, 28));
+        let extra_len = usize::from(u16_at(rest, 30));
+        let comment_len = usize::from(u16_at(rest, 32));
+        let attributes = u32_at(rest, 38);
+        let mut local = u64::from(u32_at(rest, 42));
+
+        let end = CENTRAL_SIZE + name_len + extra_len + comment_len;
+        if rest.len() < end {
+            return Err(invalid("bad central directory entry"));
+        }
+        let path = rest[CENTRAL_SIZE..CENTRAL_SIZE + name_len].to_vec();
+        let extra = &rest[CENTRAL_SIZE + name_len..CENTRAL_SIZE + name_len + extra_len];
+        rest = &rest[end..];
+
+        let mut mtime = dos_time(date, time);
+        for (id, data) in extra_fields(extra) {
+            match id {
+                // Only the fields saturated in the entry are present
+                ZIP64_EXTRA => {
+                    let mut values = data.chunks_exact(8).map(u64_at_start);
+                    for field in [&mut size, &mut compressed, &mut local] {
+                        if *field == u64::from(u32::MAX) {
+                            *field = values.next().ok_or_else(|| invalid("bad zip64 field"))?;
+                        }
+                    }
+                },
+                TIMESTAMP_EXTRA if data.len() >= 5 && data[0] & 1 != 0 => {
+                    mtime = i64::from(u32_at(data, 1) as i32);
+                },
+                _ => {},
+            }
+        }
+
+        let offset = data_offset(file, local)?;
+        let method = match method {
+            _ if flags & 1 != 0 => Method::Unsupported,
+            0 => Method::Stored { offset },
+            8 => Method::Compressed(Source { codec: Codec::Deflate, offset, len: compressed }),
+            #[cfg(feature = "zstd")]
+            93 => Method::Compressed(Source { codec: Codec::Zstd, offset, len: compressed }),
+            _ => Method::Unsupported,
+        };
+        let mode = UNIX_HOSTS.contains(&host)
+            .then_some(attributes >> 16)
+            .filter(|&mode| mode != 0);
+        // MS-DOS directory attribute
+        let is_dir = path.ends_with(b"/") || attributes & 0x10 != 0 && mode.is_none();
+        add(Entry { path, mode, is_dir, mtime, size, method });
+    }
+    Ok(())
+}
+
+/// Finds the central directory, returns its number of entries, offset and
+/// size.
+fn find_central(file: &File, len: u64) -> io::Result<(u64, u64, u64)> {
+    // The record is at the end, followed by a comment of up to 64 KiB
+    let tail_len = len.min((END_SIZE + usize::from(u16::MAX)) as u64);
+    let tail = read_at(file, len - tail_len, tail_len as usize)?;
+    if tail.len() < END_SIZE {
+        return Err(invalid("too short"));
+    }
+    let start = (0..=tail.len() - END_SIZE)
+        .rev()
+        .find(|&start| u32_at(&tail[start..], 0) == END_SIGNATURE)
+        .ok_or_else(|| invalid("end of central directory not found"))?;
+    let end = &tail[start..];
+    let count = u64::from(u16_at(end, 10));
+    let size = u64::from(u32_at(end, 12));
+    let offset = u64::from(u32_at(end, 16));
+
+    // Zip64 archives have another record before, located by the locator
+    let position = len - tail_len + start as u64;
+    if position >= END64_LOCATOR_SIZE {
+        let locator = read_at(file, position - END64_LOCATOR_SIZE, END64_LOCATOR_SIZE as usize)?;
+        if u32_at(&locator, 0) == END64_LOCATOR_SIGNATURE {
+            let end64 = read_at(file, u64_at(&locator, 8), 56)?;
+            if end64.len() < 56 || u32_at(&end64, 0) != END64_SIGNATURE {
+                return Err(invalid("bad zip64 end of central directory"));
+            }
+            return Ok((u64_at(&end64, 32), u64_at(&end64, 48), u64_at(&end64, 40)));
+        }
+    }
+    Ok((count, offset, size))
+}
+
+/// Offset of the data of a member, after its local header.
+fn data_offset(file: &File, local: u64) -> io::Result<u64> {
+    let header = read_at(file, local, LOCAL_SIZE)?;
+    if header.len() < LOCAL_SIZE || u32_at(&header, 0) != LOCAL_SIGNATURE {
+        return Err(invalid("bad local header"));
+    }
+    let name_len = u64::from(u16_at(&header, 26));
+    let extra_len = u64::from(u16_at(&header, 28));
+    Ok(local + LOCAL_SIZE as u64 + name_len + extra_len)
+}
+
+/// The `(id, data)` pairs of an extra field.
+fn extra_fields(mut extra: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if extra.len() < 4 {
+            return None;
+        }
+        let (id, len) = (u16_at(extra, 0), usize::from(u16_at(extra, 2)));
+        let data = extra.get(4..4 + len)?;
+        extra = &extra[4 + len..];
+        Some((id, data))
+    })
+}
+
+/// Converts an MS-DOS date and time, in local time, as if it was UTC.
+fn dos_time(date: u16, time: u16) -> i64 {
+    let year = i64::from(date >> 9) + 1980;
+    let month = i64::from((date >> 5) & 0xf).clamp(1, 12);
+    let day = i64::from(date & 0x1f).max(1);
+    // Days since the epoch, from the algorithm of Howard Hinnant
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    let seconds = i64::from(time >> 11) * 3600
+        + i64::from((time >> 5) & 0x3f) * 60
+        + i64::from(time & 0x1f) * 2;
+    days * 86400 + seconds
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+fn u64_at_start(data: &[u8]) -> u64 {
+    u64_at(data, 0)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid zip archive: {msg}"))
+}
+
+/// Whether the start of a file looks like a zip archive, possibly empty.
+pub(super) fn is_zip(start: &[u8]) -> bool {
+    start.len() >= 4 && matches!(u32_at(start, 0), LOCAL_SIGNATURE | END_SIGNATURE)
+}
@@ -0,0 +1,48 @@
+//! Mounts a tar or zip archive read-only.
+//!
+//! ```text
+//! fuse-async-archive <archive> <mountpoint>
+//! ```
+//!
+//! The archive stays mounted until it is unmounted with `fusermount3 -u` or
+//! the process exits.
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use fuse_async::{ArchiveFs, Mount, Session};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let args: Vec<_> = std::env::args_os().skip(1).collect();
+    let [archive, mountpoint] = <[_; 2]>::try_from(args).unwrap_or_else(|_| {
+        eprintln!("usage: fuse-async-archive <archive> <mountpoint>");
+        std::process::exit(2);
+    });
+
+    match run(archive.into(), mountpoint.into()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("fuse-async-archive: {err}");
+            ExitCode::FAILURE
+        },
+    }
+}
+
+async fn run(archive: PathBuf, mountpoint: PathBuf) -> std::io::Result<()> {
+    let path = archive.clone();
+    let fs = tokio::task::spawn_blocking(move || ArchiveFs::open(path)).await??;
+
+    let name = archive.file_name().unwrap_or(archive.as_os_str()).to_string_lossy();
+    // The devices and set-user-ID files of the archive are listed, not used
+    let mount = Mount::builder(mountpoint, name.into_owned())
+        .subtype("archive")
+        .rdonly(true)
+        .nodev(true)
+        .nosuid(true)
+        .auto_unmount(true)
+        .build()
+        .await?;
+    Session::new(mount, fs).run().await?;
+    Ok(())
+}
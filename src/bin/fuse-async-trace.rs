@@ -0,0 +1,140 @@
+//! Mirrors a directory with `PassthroughFs`, printing every request and its
+//! reply, or prints a recording made earlier.
+//!
+//! ```text
+//! fuse-async-trace [--opcode OPCODE]... [--nodeid NODEID]... [--record FILE]
+//!                  <directory> <mountpoint>
+//! fuse-async-trace [--opcode OPCODE]... [--nodeid NODEID]... --replay FILE
+//! ```
+//!
+//! The opcodes are named like `FUSE_LOOKUP` or `lookup`, and the messages are
+//! printed to the standard error. With `--record` every message, including
+//! the filtered ones, is also written to `FILE`.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use zerocopy::TryFromBytes;
+
+use fuse_async::protocol::fuse_opcode;
+use fuse_async::transport::{Recording, Tracer};
+use fuse_async::{Mount, PassthroughFs, Session, SessionConfig};
+
+const USAGE: &str = "\
+usage: fuse-async-trace [--opcode OPCODE]... [--nodeid NODEID]... [--record FILE] \
+<directory> <mountpoint>
+       fuse-async-trace [--opcode OPCODE]... [--nodeid NODEID]... --replay FILE";
+
+enum Command {
+    Mirror { directory: PathBuf, mountpoint: PathBuf, record: Option<PathBuf> },
+    Replay { recording: PathBuf },
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let (command, tracer) = parse_args(std::env::args_os().skip(1)).unwrap_or_else(|err| {
+        eprintln!("fuse-async-trace: {err}\n{USAGE}");
+        std::process::exit(2);
+    });
+
+    let result = match command {
+        Command::Mirror { directory, mountpoint, record } => {
+            mirror(directory, mountpoint, record, tracer).await
+        },
+        Command::Replay { recording } => replay(recording, tracer),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("fuse-async-trace: {err}");
+            ExitCode::FAILURE
+        },
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = OsString>) -> Result<(Command, Tracer), String> {
+    let mut tracer = Tracer::new();
+    let mut record = None;
+    let mut replay = None;
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("missing value of {name}"));
+        match arg.to_str() {
+            Some("--opcode") => {
+                let name = value("--opcode")?;
+                let name = name.to_string_lossy();
+                let opcode = parse_opcode(&name).ok_or(format!("unknown opcode {name}"))?;
+                tracer = tracer.opcode(opcode);
+            },
+            Some("--nodeid") => {
+                let nodeid = value("--nodeid")?;
+                let nodeid = nodeid.to_str()
+                    .and_then(|nodeid| nodeid.parse().ok())
+                    .ok_or(format!("bad node ID {}", nodeid.to_string_lossy()))?;
+                tracer = tracer.nodeid(nodeid);
+            },
+            Some("--record") => record = Some(PathBuf::from(value("--record")?)),
+            Some("--replay") => replay = Some(PathBuf::from(value("--replay")?)),
+            Some(option) if option.starts_with("--") => {
+                return Err(format!("unknown option {option}"));
+            },
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+
+    if record.is_some() && replay.is_some() {
+        return Err("--record and --replay can't be used together".into());
+    }
+    let command = match (replay, <[_; 2]>::try_from(paths)) {
+        (Some(recording), Err(paths)) if paths.is_empty() => Command::Replay { recording },
+        (None, Ok([directory, mountpoint])) => Command::Mirror { directory, mountpoint, record },
+        _ => return Err("wrong number of arguments".into()),
+    };
+    Ok((command, tracer))
+}
+
+/// Finds an opcode by its name, with or without the `FUSE_` prefix.
+fn parse_opcode(name: &str) -> Option<fuse_opcode> {
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("FUSE_").unwrap_or(&name);
+    (0..=u8::MAX.into())
+        .filter_map(|value: u32| fuse_opcode::try_read_from_bytes(&value.to_ne_bytes()).ok())
+        .find(|opcode| format!("{opcode:?}").strip_prefix("FUSE_") == Some(name))
+}
+
+async fn mirror(
+    directory: PathBuf,
+    mountpoint: PathBuf,
+    record: Option<PathBuf>,
+    mut tracer: Tracer
+) -> io::Result<()> {
+    if let Some(path) = record {
+        let file = File::create(&path).map_err(|err| {
+            io::Error::new(err.kind(), format!("Failed to create {}: {err}", path.display()))
+        })?;
+        tracer = tracer.record(BufWriter::new(file))?;
+    }
+    let fs = PassthroughFs::new(&directory)?;
+
+    let name = directory.to_string_lossy().into_owned();
+    let mount = Mount::builder(mountpoint, name)
+        .subtype("trace")
+        .auto_unmount(true)
+        .build()
+        .await?;
+    Session::with_tracer(mount, fs, SessionConfig::new(), tracer)?.run().await?;
+    Ok(())
+}
+
+fn replay(path: PathBuf, mut tracer: Tracer) -> io::Result<()> {
+    let file = File::open(&path).map_err(|err| {
+        io::Error::new(err.kind(), format!("Failed to open {}: {err}", path.display()))
+    })?;
+    for record in Recording::new(BufReader::new(file))? {
+        tracer.trace(&record?);
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::num::NonZeroI32;
+
+macro_rules! errno_consts {
+    ($($name:ident),* $(,)?) => {
+        impl Errno {
+            $(
+                #[doc = concat!("The `", stringify!($name), "` error code.")]
+                pub const $name: Self = Self::from_const(libc::$name);
+            )*
+
+            /// Name of the error constant, if known.
+            pub const fn name(self) -> Option<&'static str> {
+                match self.0.get() {
+                    $(libc::$name => Some(stringify!($name)),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+/// An error code returned to the kernel in reply to a request.
+///
+/// The value is always a positive errno, it is negated only when it gets
+/// written in [`fuse_out_header`](crate::protocol::fuse_out_header), so a
+/// reply can never carry a positive error.
+///
+/// Handlers can use `?` on [`io::Result`]s, since the error code of the
+/// [`io::Error`] is preserved, errors without an OS code are mapped from their
+/// [`ErrorKind`], falling back to [`Errno::EIO`].
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Errno(NonZeroI32);
+
+errno_consts! {
+    EPERM, ENOENT, ESRCH, EINTR, EIO, ENXIO, E2BIG, ENOEXEC, EBADF, ECHILD,
+    EAGAIN, ENOMEM, EACCES, EFAULT, EBUSY, EEXIST, EXDEV, ENODEV, ENOTDIR,
+    EISDIR, EINVAL, ENFILE, EMFILE, ENOTTY, ETXTBSY, EFBIG, ENOSPC, ESPIPE,
+    EROFS, EMLINK, EPIPE, ERANGE, EDEADLK, ENAMETOOLONG, ENOLCK, ENOSYS,
+    ENOTEMPTY, ELOOP, ENODATA, EPROTO, EOVERFLOW, EOPNOTSUPP, ENOTCONN,
+    ETIMEDOUT, ESTALE, EDQUOT, ECANCELED,
+}
+
+impl Errno {
+    /// Constructs an error code from a positive errno value.
+    ///
+    /// Returns `None` if the value is zero or negative.
+    #[inline]
+    pub const fn new(code: i32) -> Option<Self> {
+        if code > 0 {
+            // SAFETY: code is positive
+            Some(Self(unsafe { NonZeroI32::new_unchecked(code) }))
+        } else {
+            None
+        }
+    }
+
+    const fn from_const(code: i32) -> Self {
+        match Self::new(code) {
+            Some(errno) => errno,
+            None => panic!("errno values must be positive"),
+        }
+    }
+
+    /// The positive errno value.
+    #[inline]
+    pub const fn code(self) -> i32 {
+        self.0.get()
+    }
+
+    /// Maps the errors without an OS code.
+    fn from_kind(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::NotFound => Self::ENOENT,
+            ErrorKind::PermissionDenied => Self::EACCES,
+            ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected => Self::ENOTCONN,
+            ErrorKind::AlreadyExists => Self::EEXIST,
+            ErrorKind::WouldBlock => Self::EAGAIN,
+            ErrorKind::NotADirectory => Self::ENOTDIR,
+            ErrorKind::IsADirectory => Self::EISDIR,
+            ErrorKind::DirectoryNotEmpty => Self::ENOTEMPTY,
+            ErrorKind::ReadOnlyFilesystem => Self::EROFS,
+            ErrorKind::StaleNetworkFileHandle => Self::ESTALE,
+            ErrorKind::InvalidInput => Self::EINVAL,
+            ErrorKind::TimedOut => Self::ETIMEDOUT,
+            ErrorKind::StorageFull => Self::ENOSPC,
+            ErrorKind::NotSeekable => Self::ESPIPE,
+            ErrorKind::QuotaExceeded => Self::EDQUOT,
+            ErrorKind::FileTooLarge => Self::EFBIG,
+            ErrorKind::ResourceBusy => Self::EBUSY,
+            ErrorKind::ExecutableFileBusy => Self::ETXTBSY,
+            ErrorKind::Deadlock => Self::EDEADLK,
+            ErrorKind::CrossesDevices => Self::EXDEV,
+            ErrorKind::TooManyLinks => Self::EMLINK,
+            ErrorKind::InvalidFilename => Self::ENAMETOOLONG,
+            ErrorKind::ArgumentListTooLong => Self::E2BIG,
+            ErrorKind::Interrupted => Self::EINTR,
+            ErrorKind::Unsupported => Self::EOPNOTSUPP,
+            ErrorKind::OutOfMemory => Self::ENOMEM,
+            _ => Self::EIO,
+        }
+    }
+}
+
+/// Uses the OS code of the error, or maps its kind.
+///
+/// [`ErrorKind::Unsupported`] becomes [`Errno::EOPNOTSUPP`] rather than
+/// [`Errno::ENOSYS`]: the kernel takes `ENOSYS` as the whole operation being
+/// unimplemented and stops sending it, so it must be returned explicitly.
+impl From<io::Error> for Errno {
+    fn from(err: io::Error) -> Self {
+        Self::from(&err)
+    }
+}
+
+impl From<&io::Error> for Errno {
+    fn from(err: &io::Error) -> Self {
+        err.raw_os_error()
+            .and_then(Self::new)
+            .unwrap_or_else(|| Self::from_kind(err.kind()))
+    }
+}
+
+impl From<ErrorKind> for Errno {
+    #[inline]
+    fn from(kind: ErrorKind) -> Self {
+        Self::from_kind(kind)
+    }
+}
+
+impl From<Errno> for io::Error {
+    #[inline]
+    fn from(errno: Errno) -> Self {
+        io::Error::from_raw_os_error(errno.code())
+    }
+}
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => f.debug_tuple("Errno").field(&self.code()).finish(),
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let err = io::Error::from_raw_os_error(self.code());
+        match self.name() {
+            Some(name) => write!(f, "{name}: {err}"),
+            None => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Errno {}
@@ -0,0 +1,543 @@
+use std::ffi::OsStr;
+use std::future::Future;
+
+use crate::protocol::*;
+use crate::reply::DirBuf;
+use crate::transport::DaxWindow;
+use crate::{Errno, Request, SetattrRequest};
+
+/// A filesystem served by a [`Session`](crate::Session).
+///
+/// Each method handles one kind of request, the requests are handled
+/// concurrently so the methods take `&self`. The default implementations reply
+/// with [`Errno::ENOSYS`], except for the requests that the kernel expects to
+/// always succeed.
+///
+/// Methods can be implemented with `async fn`, the returned future must be
+/// [`Send`] since each request runs in its own task.
+pub trait Filesystem: Send + Sync + 'static {
+    /// Called after the `FUSE_INIT` handshake, before any other request.
+    fn init(&self, req: &Request) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = req;
+        async { Ok(()) }
+    }
+
+    /// Called once when the session ends, either because the kernel sent
+    /// `FUSE_DESTROY` or because the filesystem was unmounted.
+    fn destroy(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Saves what the next server process needs to keep serving the inodes
+    /// known by the kernel, sent by
+    /// [`ShutdownHandle::handover`](crate::ShutdownHandle::handover) once the
+    /// requests in flight are answered.
+    ///
+    /// File systems built on an [`InodeTable`](crate::InodeTable) save it
+    /// with [`InodeTable::snapshot`](crate::InodeTable::snapshot). Nothing is
+    /// saved by default, so the kernel gets [`Errno::ESTALE`] for the inodes
+    /// it knew after the handover.
+    fn snapshot(&self) -> impl Future<Output = Result<Vec<u8>, Errno>> + Send {
+        async { Ok(Vec::new()) }
+    }
+
+    /// Restores the inodes saved by [`Filesystem::snapshot`] in the previous
+    /// server process, called before any request by a session resumed with
+    /// [`Session::resume_from`](crate::Session::resume_from).
+    ///
+    /// Not called if the previous process saved nothing, the session fails
+    /// if the snapshot can't be restored.
+    fn restore(&self, snapshot: &[u8]) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = snapshot;
+        async { Ok(()) }
+    }
+
+    /// Looks up `name` in the directory `parent`.
+    ///
+    /// A successful lookup increments the lookup count of the inode, which is
+    /// decremented by [`Filesystem::forget`].
+    fn lookup(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr
+    ) -> impl Future<Output = Result<fuse_entry_out, Errno>> + Send {
+        let _ = (req, parent, name);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Decrements the lookup count of an inode by `nlookup`.
+    ///
+    /// The kernel does not expect a reply, this is also called for each inode
+    /// of a `FUSE_BATCH_FORGET` request.
+    fn forget(
+        &self,
+        req: &Request,
+        ino: u64,
+        nlookup: u64
+    ) -> impl Future<Output = ()> + Send {
+        let _ = (req, ino, nlookup);
+        async {}
+    }
+
+    fn getattr(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_getattr_in
+    ) -> impl Future<Output = Result<fuse_attr_out, Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn setattr(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: SetattrRequest
+    ) -> impl Future<Output = Result<fuse_attr_out, Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Reads the target of a symbolic link.
+    fn readlink(
+        &self,
+        req: &Request,
+        ino: u64
+    ) -> impl Future<Output = Result<Vec<u8>, Errno>> + Send {
+        let _ = (req, ino);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn symlink(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        target: &OsStr
+    ) -> impl Future<Output = Result<fuse_entry_out, Errno>> + Send {
+        let _ = (req, parent, name, target);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn mknod(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_mknod_in,
+        name: &OsStr
+    ) -> impl Future<Output = Result<fuse_entry_out, Errno>> + Send {
+        let _ = (req, parent, arg, name);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn mkdir(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_mkdir_in,
+        name: &OsStr
+    ) -> impl Future<Output = Result<fuse_entry_out, Errno>> + Send {
+        let _ = (req, parent, arg, name);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn unlink(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, parent, name);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn rmdir(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, parent, name);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Handles both `FUSE_RENAME` and `FUSE_RENAME2`, `flags` contains the
+    /// `RENAME_*` flags of `renameat2(2)` and is zero for `FUSE_RENAME`.
+    fn rename(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, parent, name, newparent, newname, flags);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Creates a hard link to `ino` named `newname` in `newparent`.
+    fn link(
+        &self,
+        req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr
+    ) -> impl Future<Output = Result<fuse_entry_out, Errno>> + Send {
+        let _ = (req, ino, newparent, newname);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Opens a file, the default implementation succeeds with a zero file
+    /// handle.
+    fn open(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_open_in
+    ) -> impl Future<Output = Result<fuse_open_out, Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Ok(empty_open_out()) }
+    }
+
+    /// Reads up to `arg.size` bytes, returning less bytes signals the end of
+    /// the file unless `FOPEN_DIRECT_IO` was set on open.
+    fn read(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_read_in
+    ) -> impl Future<Output = Result<Vec<u8>, Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Writes `data`, returning the number of bytes written.
+    fn write(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_write_in,
+        data: &[u8]
+    ) -> impl Future<Output = Result<u32, Errno>> + Send {
+        let _ = (req, ino, arg, data);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// The default implementation reports an empty filesystem.
+    fn statfs(
+        &self,
+        req: &Request,
+        ino: u64
+    ) -> impl Future<Output = Result<fuse_kstatfs, Errno>> + Send {
+        let _ = (req, ino);
+        async {
+            Ok(fuse_kstatfs {
+                blocks: 0,
+                bfree: 0,
+                bavail: 0,
+                files: 0,
+                ffree: 0,
+                bsize: 512,
+                namelen: 255,
+                frsize: 0,
+                padding: Padding::new(),
+                spare: Padding::new(),
+            })
+        }
+    }
+
+    /// Called when the last reference to an open file is closed, errors are
+    /// ignored by the kernel.
+    fn release(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_release_in
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Ok(()) }
+    }
+
+    fn fsync(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_fsync_in
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn setxattr(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_setxattr_in,
+        name: &OsStr,
+        value: &[u8]
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, arg, name, value);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Returns the value of an extended attribute.
+    ///
+    /// The size requested by the kernel is handled by the session, which
+    /// replies with the size of the value or [`Errno::ERANGE`] as needed.
+    fn getxattr(
+        &self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr
+    ) -> impl Future<Output = Result<Vec<u8>, Errno>> + Send {
+        let _ = (req, ino, name);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Returns the names of the extended attributes, each terminated by a NUL.
+    ///
+    /// The requested size is handled like in [`Filesystem::getxattr`].
+    fn listxattr(
+        &self,
+        req: &Request,
+        ino: u64
+    ) -> impl Future<Output = Result<Vec<u8>, Errno>> + Send {
+        let _ = (req, ino);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn removexattr(
+        &self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, name);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Called on each `close(2)` of an open file.
+    fn flush(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_flush_in
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Opens a directory, the default implementation succeeds with a zero
+    /// file handle.
+    fn opendir(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_open_in
+    ) -> impl Future<Output = Result<fuse_open_out, Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Ok(empty_open_out()) }
+    }
+
+    /// Fills `buf` with the entries starting at `arg.offset`.
+    ///
+    /// Leaving the buffer empty signals the end of the directory.
+    fn readdir(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_read_in,
+        buf: &mut DirBuf
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, arg, buf);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Like [`Filesystem::readdir`] but with the attributes of the entries,
+    /// see [`DirBuf::push_plus`].
+    fn readdirplus(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_read_in,
+        buf: &mut DirBuf
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, arg, buf);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn releasedir(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_release_in
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Ok(()) }
+    }
+
+    fn fsyncdir(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_fsync_in
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn getlk(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_lk_in
+    ) -> impl Future<Output = Result<fuse_file_lock, Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Handles both `FUSE_SETLK` and `FUSE_SETLKW`, `sleep` is set for the
+    /// latter, in that case the call should wait until the lock is acquired.
+    fn setlk(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_lk_in,
+        sleep: bool
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, arg, sleep);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn access(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_access_in
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Creates and opens a file, if this returns [`Errno::ENOSYS`] the kernel
+    /// will use [`Filesystem::mknod`] and [`Filesystem::open`] instead.
+    fn create(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_create_in,
+        name: &OsStr
+    ) -> impl Future<Output = Result<(fuse_entry_out, fuse_open_out), Errno>> + Send {
+        let _ = (req, parent, arg, name);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Creates and opens an unnamed file in the directory `parent`, for
+    /// `O_TMPFILE`.
+    ///
+    /// The file has no links until it is given a name with
+    /// [`Filesystem::link`], if this returns [`Errno::ENOSYS`] the kernel
+    /// fails `O_TMPFILE` with `EOPNOTSUPP`.
+    fn tmpfile(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_create_in
+    ) -> impl Future<Output = Result<(fuse_entry_out, fuse_open_out), Errno>> + Send {
+        let _ = (req, parent, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Maps a block of the file to a block of the device, only used by
+    /// `fuseblk` filesystems.
+    fn bmap(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_bmap_in
+    ) -> impl Future<Output = Result<u64, Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn fallocate(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_fallocate_in
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Returns the new offset, only called for `SEEK_DATA` and `SEEK_HOLE`.
+    fn lseek(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_lseek_in
+    ) -> impl Future<Output = Result<u64, Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Returns the number of bytes copied.
+    fn copy_file_range(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_copy_file_range_in
+    ) -> impl Future<Output = Result<u32, Errno>> + Send {
+        let _ = (req, ino, arg);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    fn syncfs(
+        &self,
+        req: &Request,
+        ino: u64
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Maps part of the open file `arg.fh` in the DAX window of a virtio-fs
+    /// device, with [`DaxWindow::map`].
+    ///
+    /// Only called when the transport has a DAX window.
+    fn setupmapping(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_setupmapping_in,
+        window: &dyn DaxWindow
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = (req, ino, arg, window);
+        async { Err(Errno::ENOSYS) }
+    }
+
+    /// Removes mappings created by [`Filesystem::setupmapping`].
+    ///
+    /// The default implementation unmaps them from the window, file systems
+    /// that track their mappings can override it.
+    fn removemapping(
+        &self,
+        req: &Request,
+        mappings: &[fuse_removemapping_one],
+        window: &dyn DaxWindow
+    ) -> impl Future<Output = Result<(), Errno>> + Send {
+        let _ = req;
+        async move { window.unmap(mappings).await.map_err(Errno::from) }
+    }
+}
+
+fn empty_open_out() -> fuse_open_out {
+    fuse_open_out {
+        fh: 0,
+        open_flags: OpenOutFlags::empty(),
+        backing_id: 0,
+    }
+}
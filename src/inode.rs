@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use crate::Errno;
+use crate::protocol::FUSE_ROOT_ID;
+
+/// Inodes known by the kernel, with their lookup count.
+///
+/// Each inode is identified by a key, like the device and inode number of a
+/// file, so that looking up the same file twice, through hard links for
+/// example, gives the same node id. Node ids are never reused, so the
+/// generation of the entries can always be zero.
+///
+/// The root has the node id [`FUSE_ROOT_ID`] and is never forgotten.
+#[derive(Debug)]
+pub struct InodeTable<K, V> {
+    inner: Mutex<Inner<K, V>>,
+}
+
+#[derive(Debug)]
+struct Inner<K, V> {
+    inodes: HashMap<u64, Entry<K, V>>,
+    by_key: HashMap<K, u64>,
+    next: u64,
+}
+
+/// The inodes of an [`InodeTable`] with their lookup count, saved by
+/// [`InodeTable::snapshot`] to carry them to the next server process of a
+/// [`Handover`](crate::Handover).
+///
+/// Each inode holds the data saved by the file system to make it again, of
+/// type `S`. Snapshots of bytes are encoded with
+/// [`to_bytes`](InodeSnapshot::to_bytes).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InodeSnapshot<S> {
+    next: u64,
+    inodes: Vec<(u64, u64, S)>,
+}
+
+#[derive(Debug)]
+struct Entry<K, V> {
+    key: K,
+    value: Arc<V>,
+    lookups: u64,
+}
+
+impl<K: Eq + Hash + Clone, V> InodeTable<K, V> {
+    pub fn new(root_key: K, root: V) -> Self {
+        let mut inner = Inner {
+            inodes: HashMap::new(),
+            by_key: HashMap::new(),
+            next: FUSE_ROOT_ID + 1,
+        };
+        inner.by_key.insert(root_key.clone(), FUSE_ROOT_ID);
+        inner.inodes.insert(FUSE_ROOT_ID, Entry {
+            key: root_key,
+            value: Arc::new(root),
+            lookups: 1,
+        });
+        Self { inner: Mutex::new(inner) }
+    }
+
+    pub fn get(&self, ino: u64) -> Option<Arc<V>> {
+        let inner = self.inner.lock().unwrap();
+        inner.inodes.get(&ino).map(|entry| entry.value.clone())
+    }
+
+    /// The node id of the inode identified by `key`, without counting a
+    /// lookup.
+    pub fn find(&self, key: &K) -> Option<(u64, Arc<V>)> {
+        let inner = self.inner.lock().unwrap();
+        let ino = *inner.by_key.get(key)?;
+        Some((ino, inner.inodes[&ino].value.clone()))
+    }
+
+    /// Counts a lookup of the inode identified by `key`, adding it with the
+    /// value made by `make` if it is not known yet.
+    pub fn lookup<E>(
+        &self,
+        key: K,
+        make: impl FnOnce() -> Result<V, E>
+    ) -> Result<(u64, Arc<V>), E> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(&ino) = inner.by_key.get(&key) {
+            let entry = inner.inodes.get_mut(&ino).unwrap();
+            entry.lookups += 1;
+            return Ok((ino, entry.value.clone()));
+        }
+
+        let value = Arc::new(make()?);
+        let ino = inner.next;
+        inner.next += 1;
+        inner.by_key.insert(key.clone(), ino);
+        inner.inodes.insert(ino, Entry { key, value: value.clone(), lookups: 1 });
+        Ok((ino, value))
+    }
+
+    /// Counts one more lookup of a known inode, returns `false` if it is not
+    /// known.
+    pub fn acquire(&self, ino: u64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.inodes.get_mut(&ino) {
+            Some(entry) => {
+                entry.lookups += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Changes the key of a known inode, when the file it stands for is
+    /// replaced by another one, returns `false` if it is not known.
+    pub fn rekey(&self, ino: u64, key: K) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entry) = inner.inodes.get_mut(&ino) else { return false };
+        let old = std::mem::replace(&mut entry.key, key.clone());
+        if inner.by_key.get(&old) == Some(&ino) {
+            inner.by_key.remove(&old);
+        }
+        inner.by_key.insert(key, ino);
+        true
+    }
+
+    /// Decrements the lookup count by `nlookup`, the inode is removed when it
+    /// reaches zero and its value is returned.
+    pub fn forget(&self, ino: u64, nlookup: u64) -> Option<Arc<V>> {
+        if ino == FUSE_ROOT_ID {
+            return None;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.inodes.get_mut(&ino)?;
+        entry.lookups = entry.lookups.saturating_sub(nlookup);
+        if entry.lookups > 0 {
+            return None;
+        }
+        let entry = inner.inodes.remove(&ino)?;
+        if inner.by_key.get(&entry.key) == Some(&ino) {
+            inner.by_key.remove(&entry.key);
+        }
+        Some(entry.value)
+    }
+
+    /// Number of inodes, including the root.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().inodes.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Saves the inodes known by the kernel, `save` returns the data needed
+    /// to make each inode again, or `None` to leave it out.
+    ///
+    /// The inodes left out are answered with [`Errno::ESTALE`] after the
+    /// snapshot is restored.
+    pub fn snapshot<S>(&self, mut save: impl FnMut(&K, &V) -> Option<S>) -> InodeSnapshot<S> {
+        let inner = self.inner.lock().unwrap();
+        let mut inodes: Vec<_> = inner.inodes.iter()
+            .filter_map(|(&ino, entry)| {
+                Some((ino, entry.lookups, save(&entry.key, &entry.value)?))
+            })
+            .collect();
+        inodes.sort_unstable_by_key(|&(ino, ..)| ino);
+        InodeSnapshot { next: inner.next, inodes }
+    }
+
+    /// Replaces the inodes by those of `snapshot`, keeping their node ids and
+    /// lookup counts. `load` makes the key and the value of an inode from its
+    /// saved data, or returns `None` if its file is gone.
+    ///
+    /// The current root is kept if it can't be loaded, and node ids keep
+    /// increasing from those of the snapshot.
+    pub fn restore<S>(
+        &self,
+        snapshot: InodeSnapshot<S>,
+        mut load: impl FnMut(u64, S) -> Option<(K, V)>
+    ) {
+        let mut saved = snapshot.inodes;
+        saved.sort_unstable_by_key(|&(ino, ..)| ino);
+        let mut saved = saved.into_iter().peekable();
+
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { inodes, by_key, next } = &mut *inner;
+        let old_root = inodes.remove(&FUSE_ROOT_ID).unwrap();
+        let root = saved.next_if(|&(ino, ..)| ino == FUSE_ROOT_ID)
+            .and_then(|(ino, _, data)| load(ino, data))
+            .map(|(key, value)| Entry { key, value: Arc::new(value), lookups: 1 })
+            .unwrap_or(old_root);
+        inodes.clear();
+        by_key.clear();
+        by_key.insert(root.key.clone(), FUSE_ROOT_ID);
+        inodes.insert(FUSE_ROOT_ID, root);
+
+        for (ino, lookups, data) in saved {
+            let Some((key, value)) = load(ino, data) else { continue };
+            // Two inodes can't stand for the same file, the first one is kept
+            if by_key.contains_key(&key) || ino == FUSE_ROOT_ID {
+                continue;
+            }
+            by_key.insert(key.clone(), ino);
+            inodes.insert(ino, Entry { key, value: Arc::new(value), lookups });
+        }
+        *next = (*next).max(snapshot.next);
+    }
+
+    /// Forgets every inode but the root, when the session ends.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.inodes.retain(|&ino, _| ino == FUSE_ROOT_ID);
+        let root = inner.inodes[&FUSE_ROOT_ID].key.clone();
+        inner.by_key.clear();
+        inner.by_key.insert(root, FUSE_ROOT_ID);
+    }
+}
+
+impl InodeSnapshot<Vec<u8>> {
+    /// Encodes the snapshot, to send it along with a handover.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.next.to_le_bytes());
+        bytes.extend_from_slice(&(self.inodes.len() as u64).to_le_bytes());
+        for (ino, lookups, data) in &self.inodes {
+            bytes.extend_from_slice(&ino.to_le_bytes());
+            bytes.extend_from_slice(&lookups.to_le_bytes());
+            put_bytes(&mut bytes, data);
+        }
+        bytes
+    }
+
+    /// Decodes a snapshot encoded by [`to_bytes`](Self::to_bytes), fails
+    /// with [`Errno::EINVAL`] if it is malformed.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Errno> {
+        let mut reader = Reader(bytes);
+        let next = reader.u64()?;
+        let count = reader.u64()?;
+        let mut inodes = Vec::new();
+        for _ in 0..count {
+            inodes.push((reader.u64()?, reader.u64()?, reader.bytes()?.to_vec()));
+        }
+        reader.finish()?;
+        Ok(Self { next, inodes })
+    }
+}
+
+/// Appends `data` prefixed with its length, read back by [`Reader::bytes`].
+pub(crate) fn put_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(data);
+}
+
+/// Reads the little-endian encoding of the snapshots, every read fails with
+/// [`Errno::EINVAL`] past the end.
+pub(crate) struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], Errno> {
+        if self.0.len() < len {
+            return Err(Errno::EINVAL);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Errno> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Errno> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], Errno> {
+        let len = usize::try_from(self.u64()?).map_err(|_| Errno::EINVAL)?;
+        self.take(len)
+    }
+
+    /// Checks that everything was read.
+    pub fn finish(self) -> Result<(), Errno> {
+        if self.0.is_empty() { Ok(()) } else { Err(Errno::EINVAL) }
+    }
+}
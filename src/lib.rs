@@ -1,4 +1,53 @@
+macro_rules! io_error {
+    ($kind:expr, $msg:literal $($args:tt)*) => {{
+        return Err(std::io::Error::new(
+            $kind,
+            format!($msg $($args)*)
+        ));
+    }};
+}
+
 mod mount;
-pub use mount::{Mount, MountBuilder};
+pub use mount::{DetachedMount, Mount, MountBuilder, PreflightError};
+pub use mount::{BackingFiles, BackingId, FuseCtl, MountInfo, unshare_user_namespace};
+use mount::MountState;
+
+mod errno;
+pub use errno::Errno;
+
+mod request;
+pub use request::{Request, SetattrRequest, TimeOrNow};
+
+mod filesystem;
+pub use filesystem::Filesystem;
+
+mod inode;
+pub use inode::{InodeSnapshot, InodeTable};
+
+#[cfg(feature = "passthrough")]
+mod passthrough;
+#[cfg(feature = "passthrough")]
+pub use passthrough::PassthroughFs;
+
+mod memfs;
+pub use memfs::MemFs;
+
+mod overlay;
+pub use overlay::OverlayFs;
+
+#[cfg(feature = "archive")]
+mod archive;
+#[cfg(feature = "archive")]
+pub use archive::ArchiveFs;
+
+pub mod reply;
+
+mod session;
+pub use session::{Handover, Session, SessionConfig, SessionSummary, ShutdownHandle};
+pub use session::{Histogram, Metrics, MetricsSnapshot, OpcodeStats};
 
 pub mod protocol;
+
+pub mod transport;
+
+pub mod testing;
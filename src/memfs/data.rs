@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+use crate::Errno;
+use crate::inode::Reader;
+
+/// Size of the blocks of the files, the holes don't take memory.
+pub(super) const BLOCK_SIZE: u64 = 4096;
+
+/// Contents of a regular file, stored as blocks so the files can be sparse.
+///
+/// The bytes of the blocks past the end of the file are always zero, so the
+/// file can grow without clearing them.
+#[derive(Debug, Default)]
+pub(super) struct Data {
+    size: u64,
+    blocks: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl Data {
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Number of allocated blocks.
+    #[inline]
+    pub fn blocks(&self) -> u64 {
+        self.blocks.len() as u64
+    }
+
+    pub fn read(&self, offset: u64, size: u32) -> Vec<u8> {
+        let end = offset.saturating_add(size as u64).min(self.size);
+        if offset >= end {
+            return Vec::new();
+        }
+
+        let mut buf = vec![0u8; (end - offset) as usize];
+        for (&index, block) in self.blocks.range(block_range(offset, end - offset)) {
+            let start = index * BLOCK_SIZE;
+            let (from, to) = (start.max(offset), (start + BLOCK_SIZE).min(end));
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&block[(from - start) as usize..(to - start) as usize]);
+        }
+        buf
+    }
+
+    /// Number of blocks that writing `len` bytes at `offset` would allocate.
+    pub fn missing(&self, offset: u64, len: u64) -> u64 {
+        if len == 0 {
+            return 0;
+        }
+        let range = block_range(offset, len);
+        let total = range.end() - range.start() + 1;
+        total - self.blocks.range(range).count() as u64
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = offset + data.len() as u64;
+        self.for_each_block(offset, data.len() as u64, |block, start, from, to| {
+            block[(from - start) as usize..(to - start) as usize]
+                .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+        });
+        self.size = self.size.max(end);
+    }
+
+    /// Allocates the blocks of a range, without changing the size.
+    pub fn allocate(&mut self, offset: u64, len: u64) {
+        self.for_each_block(offset, len, |_, _, _, _| {});
+    }
+
+    /// Frees the blocks of a range, and clears the blocks it partially
+    /// covers.
+    pub fn punch_hole(&mut self, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let end = offset.saturating_add(len);
+        let indices: Vec<u64> = self.blocks.range(block_range(offset, len))
+            .map(|(&index, _)| index)
+            .collect();
+        for index in indices {
+            let start = index * BLOCK_SIZE;
+            let (from, to) = (start.max(offset), (start + BLOCK_SIZE).min(end));
+            if to - from == BLOCK_SIZE {
+                self.blocks.remove(&index);
+            } else if let Some(block) = self.blocks.get_mut(&index) {
+                block[(from - start) as usize..(to - start) as usize].fill(0);
+            }
+        }
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        if size < self.size {
+            self.punch_hole(size, self.size - size);
+        }
+        self.size = size;
+    }
+
+    /// Offset of the first data at or after `offset`, `None` if there is no
+    /// data until the end of the file.
+    pub fn next_data(&self, offset: u64) -> Option<u64> {
+        let (&index, _) = self.blocks.range(offset / BLOCK_SIZE..).next()?;
+        Some((index * BLOCK_SIZE).max(offset)).filter(|&data| data < self.size)
+    }
+
+    /// Offset of the first hole at or after `offset`, the end of the file
+    /// counts as a hole.
+    pub fn next_hole(&self, offset: u64) -> u64 {
+        let mut index = offset / BLOCK_SIZE;
+        while self.blocks.contains_key(&index) {
+            index += 1;
+        }
+        (index * BLOCK_SIZE).max(offset).min(self.size)
+    }
+
+    /// Appends the size and the allocated blocks, read back by
+    /// [`Data::load`].
+    pub fn save(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        bytes.extend_from_slice(&self.blocks().to_le_bytes());
+        for (index, block) in &self.blocks {
+            bytes.extend_from_slice(&index.to_le_bytes());
+            bytes.extend_from_slice(block);
+        }
+    }
+
+    pub fn load(reader: &mut Reader) -> Result<Self, Errno> {
+        let size = reader.u64()?;
+        let mut blocks = BTreeMap::new();
+        for _ in 0..reader.u64()? {
+            let index = reader.u64()?;
+            if index >= size.div_ceil(BLOCK_SIZE) {
+                return Err(Errno::EINVAL);
+            }
+            blocks.insert(index, reader.take(BLOCK_SIZE as usize)?.into());
+        }
+        Ok(Self { size, blocks })
+    }
+
+    /// Calls `f` with each block of a range, allocating them as needed, along
+    /// with the offsets of the block and of the part of the range it covers.
+    fn for_each_block(
+        &mut self,
+        offset: u64,
+        len: u64,
+        mut f: impl FnMut(&mut [u8], u64, u64, u64)
+    ) {
+        if len == 0 {
+            return;
+        }
+        let end = offset + len;
+        for index in block_range(offset, len) {
+            let block = self.blocks.entry(index)
+                .or_insert_with(|| vec![0; BLOCK_SIZE as usize].into_boxed_slice());
+            let start = index * BLOCK_SIZE;
+            f(block, start, start.max(offset), (start + BLOCK_SIZE).min(end));
+        }
+    }
+}
+
+/// Indices of the blocks covered by a range, which must not be empty.
+fn block_range(offset: u64, len: u64) -> RangeInclusive<u64> {
+    offset / BLOCK_SIZE..=(offset.saturating_add(len) - 1) / BLOCK_SIZE
+}
@@ -0,0 +1,979 @@
+mod data;
+mod snapshot;
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::protocol::*;
+use crate::reply::DirBuf;
+use crate::{Errno, Request, SetattrRequest, TimeOrNow};
+use data::{BLOCK_SIZE, Data};
+
+type Result<T> = std::result::Result<T, Errno>;
+
+/// Longest name of a directory entry.
+const NAME_MAX: usize = 255;
+/// Longest target of a symbolic link.
+const PATH_MAX: usize = 4096;
+/// Most bytes copied by one `FUSE_COPY_FILE_RANGE`, the callers continue
+/// with the rest.
+const MAX_COPY: u64 = 1 << 20;
+/// Offsets of the `.` and `..` entries, the other entries come after.
+const DOT_OFFSET: u64 = 1;
+const DOTDOT_OFFSET: u64 = 2;
+
+/// A file system stored in memory, like `tmpfs`.
+///
+/// It supports every file type, hard links, extended attributes and sparse
+/// files, which makes it useful as scratch space and as a fixture to test
+/// the session, either mounted or through
+/// [`MockKernel`](crate::testing::MockKernel). The files are lost when the
+/// file system is dropped, unless the session is handed over to another
+/// process, which gets the whole tree in the snapshot.
+///
+/// The permissions are not checked by the file system, it must be mounted
+/// with [`MountBuilder::default_permissions`](crate::MountBuilder::default_permissions)
+/// for the kernel to check them.
+#[derive(Debug)]
+pub struct MemFs {
+    inner: Mutex<Inner>,
+    timeout: Duration,
+}
+
+#[derive(Debug)]
+struct Inner {
+    nodes: HashMap<u64, Node>,
+    next_ino: u64,
+    /// Blocks allocated by all the files.
+    blocks: u64,
+    max_blocks: u64,
+    max_inodes: u64,
+}
+
+#[derive(Debug)]
+struct Node {
+    kind: Kind,
+    /// File type and permissions.
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    rdev: u32,
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    xattrs: BTreeMap<OsString, Vec<u8>>,
+    /// Lookups by the kernel, a node is removed once it has neither links
+    /// nor lookups.
+    lookups: u64,
+}
+
+#[derive(Debug)]
+enum Kind {
+    File(Data),
+    Dir(Dir),
+    Symlink(Vec<u8>),
+    /// Fifos, sockets and devices, which are handled by the kernel.
+    Special,
+}
+
+#[derive(Debug)]
+struct Dir {
+    parent: u64,
+    /// Entries by offset, the offsets don't change when other entries are
+    /// added or removed so the directory can be read while it changes.
+    entries: BTreeMap<u64, (OsString, u64)>,
+    offsets: HashMap<OsString, u64>,
+    next_offset: u64,
+}
+
+impl MemFs {
+    /// Creates an empty file system, whose root belongs to the current user.
+    ///
+    /// Like `tmpfs`, the size and the number of inodes are limited to half of
+    /// the memory by default.
+    pub fn new() -> Self {
+        let memory = unsafe {
+            libc::sysconf(libc::_SC_PHYS_PAGES) as u64 * libc::sysconf(libc::_SC_PAGESIZE) as u64
+        };
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let root_dir = Kind::Dir(Dir::new(FUSE_ROOT_ID));
+        let mut root = Node::new(uid, gid, libc::S_IFDIR | 0o755, root_dir);
+        root.nlink = 2;
+
+        Self {
+            inner: Mutex::new(Inner {
+                nodes: HashMap::from([(FUSE_ROOT_ID, root)]),
+                next_ino: FUSE_ROOT_ID + 1,
+                blocks: 0,
+                max_blocks: memory / 2 / BLOCK_SIZE,
+                max_inodes: memory / 2 / BLOCK_SIZE,
+            }),
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// Maximum size of the data of all files, rounded down to a block,
+    /// writing more fails with [`Errno::ENOSPC`].
+    #[inline]
+    #[must_use = "A MemFs does nothing unless passed to a Session"]
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.inner.get_mut().unwrap().max_blocks = bytes / BLOCK_SIZE;
+        self
+    }
+
+    /// Maximum number of inodes, including the root.
+    #[inline]
+    #[must_use = "A MemFs does nothing unless passed to a Session"]
+    pub fn max_inodes(mut self, inodes: u64) -> Self {
+        self.inner.get_mut().unwrap().max_inodes = inodes;
+        self
+    }
+
+    /// How long the kernel caches the entries and the attributes, defaults
+    /// to one second.
+    #[inline]
+    #[must_use = "A MemFs does nothing unless passed to a Session"]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+
+    fn entry(&self, ino: u64, node: &Node) -> fuse_entry_out {
+        fuse_entry_out {
+            nodeid: ino,
+            generation: 0,
+            entry_valid: self.timeout.as_secs(),
+            attr_valid: self.timeout.as_secs(),
+            entry_valid_nsec: self.timeout.subsec_nanos(),
+            attr_valid_nsec: self.timeout.subsec_nanos(),
+            attr: node.attr(ino),
+        }
+    }
+
+    fn attr_out(&self, ino: u64, node: &Node) -> fuse_attr_out {
+        fuse_attr_out {
+            attr_valid: self.timeout.as_secs(),
+            attr_valid_nsec: self.timeout.subsec_nanos(),
+            dummy: Padding::new(),
+            attr: node.attr(ino),
+        }
+    }
+
+    /// Creates a node and replies with its entry.
+    fn create_entry(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: Option<&OsStr>,
+        mode: u32,
+        rdev: u32,
+        kind: Kind
+    ) -> Result<fuse_entry_out> {
+        let mut inner = self.lock();
+        let ino = inner.create(req, parent, name, mode, rdev, kind)?;
+        Ok(self.entry(ino, inner.node(ino)?))
+    }
+}
+
+impl Default for MemFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Inner {
+    fn node(&self, ino: u64) -> Result<&Node> {
+        self.nodes.get(&ino).ok_or(Errno::ESTALE)
+    }
+
+    fn node_mut(&mut self, ino: u64) -> Result<&mut Node> {
+        self.nodes.get_mut(&ino).ok_or(Errno::ESTALE)
+    }
+
+    fn dir_mut(&mut self, ino: u64) -> Result<&mut Dir> {
+        match &mut self.node_mut(ino)?.kind {
+            Kind::Dir(dir) => Ok(dir),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn child(&self, parent: u64, name: &OsStr) -> Result<u64> {
+        self.node(parent)?.dir()?.get(name).ok_or(Errno::ENOENT)
+    }
+
+    /// Adds a node with one lookup, linked as `name` in `parent` or unlinked
+    /// if `name` is `None`.
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: Option<&OsStr>,
+        mode: u32,
+        rdev: u32,
+        kind: Kind
+    ) -> Result<u64> {
+        let dir = self.node(parent)?;
+        if let Some(name) = name {
+            check_name(name)?;
+            if dir.dir()?.get(name).is_some() {
+                return Err(Errno::EEXIST);
+            }
+        }
+        if self.nodes.len() as u64 >= self.max_inodes {
+            return Err(Errno::ENOSPC);
+        }
+
+        let mut node = Node::new(req.uid(), req.gid(), mode, kind);
+        node.rdev = rdev;
+        node.lookups = 1;
+        // The files created in a set-group-ID directory belong to its group
+        if dir.mode & libc::S_ISGID != 0 {
+            node.gid = dir.gid;
+            if node.is_dir() {
+                node.mode |= libc::S_ISGID;
+            }
+        }
+
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        match name {
+            Some(name) => {
+                let is_dir = node.is_dir();
+                self.dir_mut(parent)?.insert(name, ino);
+                let dir = self.node_mut(parent)?;
+                if is_dir {
+                    node.nlink = 2;
+                    dir.nlink += 1;
+                }
+                dir.modified();
+            },
+            None => node.nlink = 0,
+        }
+        self.nodes.insert(ino, node);
+        Ok(ino)
+    }
+
+    /// Removes a node once it has neither links nor lookups.
+    fn release(&mut self, ino: u64) {
+        let unused = self.nodes.get(&ino)
+            .is_some_and(|node| node.nlink == 0 && node.lookups == 0);
+        if !unused || ino == FUSE_ROOT_ID {
+            return;
+        }
+        if let Some(Node { kind: Kind::File(data), .. }) = self.nodes.remove(&ino) {
+            self.blocks -= data.blocks();
+        }
+    }
+
+    /// Changes the data of a file, `change` gets the number of free blocks
+    /// and must fail with [`Errno::ENOSPC`] if it needs more.
+    fn change_data<T>(
+        &mut self,
+        ino: u64,
+        change: impl FnOnce(&mut Data, u64) -> Result<T>
+    ) -> Result<T> {
+        let free = self.max_blocks.saturating_sub(self.blocks);
+        let node = self.nodes.get_mut(&ino).ok_or(Errno::ESTALE)?;
+        let data = node.data_mut()?;
+        let before = data.blocks();
+        let result = change(data, free)?;
+        let after = data.blocks();
+        node.modified();
+        self.blocks = self.blocks + after - before;
+        Ok(result)
+    }
+
+    /// Moves the directory `ino` from `from` to `to`, for the links of `..`.
+    fn reparent(&mut self, ino: u64, from: u64, to: u64) -> Result<()> {
+        if from == to || !self.node(ino)?.is_dir() {
+            return Ok(());
+        }
+        self.dir_mut(ino)?.parent = to;
+        self.node_mut(from)?.nlink -= 1;
+        self.node_mut(to)?.nlink += 1;
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32
+    ) -> Result<()> {
+        let known = libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE | libc::RENAME_WHITEOUT;
+        if flags & !known != 0
+            || flags & libc::RENAME_EXCHANGE != 0 && flags != libc::RENAME_EXCHANGE
+        {
+            return Err(Errno::EINVAL);
+        }
+        check_name(newname)?;
+        let src = self.child(parent, name)?;
+        let dst = self.node(newparent)?.dir()?.get(newname);
+
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            let dst = dst.ok_or(Errno::ENOENT)?;
+            self.dir_mut(parent)?.replace(name, dst);
+            self.dir_mut(newparent)?.replace(newname, src);
+            self.reparent(src, parent, newparent)?;
+            self.reparent(dst, newparent, parent)?;
+            for ino in [src, dst] {
+                self.node_mut(ino)?.changed();
+            }
+            for ino in [parent, newparent] {
+                self.node_mut(ino)?.modified();
+            }
+            return Ok(());
+        }
+
+        if let Some(dst) = dst {
+            if flags & libc::RENAME_NOREPLACE != 0 {
+                return Err(Errno::EEXIST);
+            }
+            // Renaming a file to one of its links does nothing
+            if dst == src {
+                return Ok(());
+            }
+            let dst = self.node(dst)?;
+            match (self.node(src)?.is_dir(), dst.is_dir()) {
+                (true, false) => return Err(Errno::ENOTDIR),
+                (false, true) => return Err(Errno::EISDIR),
+                (true, true) if !dst.dir()?.is_empty() => return Err(Errno::ENOTEMPTY),
+                _ => {},
+            }
+        }
+        let whiteout = flags & libc::RENAME_WHITEOUT != 0;
+        if whiteout && self.nodes.len() as u64 >= self.max_inodes {
+            return Err(Errno::ENOSPC);
+        }
+
+        self.dir_mut(parent)?.remove(name);
+        match dst {
+            Some(dst) => {
+                self.dir_mut(newparent)?.replace(newname, src);
+                let node = self.node_mut(dst)?;
+                node.changed();
+                if node.is_dir() {
+                    node.nlink = 0;
+                    self.node_mut(newparent)?.nlink -= 1;
+                } else {
+                    node.nlink -= 1;
+                }
+                self.release(dst);
+            },
+            None => self.dir_mut(newparent)?.insert(newname, src),
+        }
+        self.reparent(src, parent, newparent)?;
+        self.node_mut(src)?.changed();
+        for ino in [parent, newparent] {
+            self.node_mut(ino)?.modified();
+        }
+
+        // A 0/0 character device, which hides the entry of the lower layers
+        // of an overlay file system
+        if whiteout {
+            let ino = self.create(req, parent, Some(name), libc::S_IFCHR, 0, Kind::Special)?;
+            self.node_mut(ino)?.lookups = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Node {
+    fn new(uid: u32, gid: u32, mode: u32, kind: Kind) -> Self {
+        let now = SystemTime::now();
+        Self {
+            kind,
+            mode,
+            uid,
+            gid,
+            nlink: 1,
+            rdev: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            xattrs: BTreeMap::new(),
+            lookups: 0,
+        }
+    }
+
+    #[inline]
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, Kind::Dir(_))
+    }
+
+    fn dir(&self) -> Result<&Dir> {
+        match &self.kind {
+            Kind::Dir(dir) => Ok(dir),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn data(&self) -> Result<&Data> {
+        match &self.kind {
+            Kind::File(data) => Ok(data),
+            Kind::Dir(_) => Err(Errno::EISDIR),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    fn data_mut(&mut self) -> Result<&mut Data> {
+        match &mut self.kind {
+            Kind::File(data) => Ok(data),
+            Kind::Dir(_) => Err(Errno::EISDIR),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    /// Updates the change time, after a change of the attributes.
+    fn changed(&mut self) {
+        self.ctime = SystemTime::now();
+    }
+
+    /// Updates the modification and change times, after a change of the
+    /// contents.
+    fn modified(&mut self) {
+        self.mtime = SystemTime::now();
+        self.ctime = self.mtime;
+    }
+
+    fn attr(&self, ino: u64) -> fuse_attr {
+        let (size, blocks) = match &self.kind {
+            Kind::File(data) => (data.size(), data.blocks() * (BLOCK_SIZE / 512)),
+            Kind::Dir(_) => (BLOCK_SIZE, 0),
+            Kind::Symlink(target) => (target.len() as u64, 0),
+            Kind::Special => (0, 0),
+        };
+        let (atime, atimensec) = timestamp(self.atime);
+        let (mtime, mtimensec) = timestamp(self.mtime);
+        let (ctime, ctimensec) = timestamp(self.ctime);
+        fuse_attr {
+            ino,
+            size,
+            blocks,
+            atime,
+            mtime,
+            ctime,
+            atimensec,
+            mtimensec,
+            ctimensec,
+            mode: self.mode,
+            nlink: self.nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: self.rdev,
+            blksize: BLOCK_SIZE as u32,
+            flags: AttrFlags::empty(),
+        }
+    }
+}
+
+impl Dir {
+    fn new(parent: u64) -> Self {
+        Self {
+            parent,
+            entries: BTreeMap::new(),
+            offsets: HashMap::new(),
+            next_offset: DOTDOT_OFFSET + 1,
+        }
+    }
+
+    fn get(&self, name: &OsStr) -> Option<u64> {
+        let offset = self.offsets.get(name)?;
+        Some(self.entries[offset].1)
+    }
+
+    fn insert(&mut self, name: &OsStr, ino: u64) {
+        let offset = self.next_offset;
+        self.next_offset += 1;
+        self.entries.insert(offset, (name.to_owned(), ino));
+        self.offsets.insert(name.to_owned(), offset);
+    }
+
+    /// Points an existing entry to another inode, keeping its offset.
+    fn replace(&mut self, name: &OsStr, ino: u64) {
+        if let Some(offset) = self.offsets.get(name) {
+            self.entries.get_mut(offset).unwrap().1 = ino;
+        }
+    }
+
+    fn remove(&mut self, name: &OsStr) -> Option<u64> {
+        let offset = self.offsets.remove(name)?;
+        self.entries.remove(&offset).map(|(_, ino)| ino)
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The entries after `offset`, with `.` and `..` first, along with the
+    /// offset of the next entry.
+    fn read(&self, ino: u64, offset: u64) -> impl Iterator<Item = (u64, u64, &OsStr)> {
+        let dots = [(DOT_OFFSET, ino, "."), (DOTDOT_OFFSET, self.parent, "..")];
+        let dots = dots.into_iter()
+            .filter(move |&(next, _, _)| next > offset)
+            .map(|(next, ino, name)| (next, ino, OsStr::new(name)));
+        let entries = self.entries.range(offset.max(DOTDOT_OFFSET) + 1..)
+            .map(|(&next, (name, ino))| (next, *ino, name.as_os_str()));
+        dots.chain(entries)
+    }
+}
+
+fn check_name(name: &OsStr) -> Result<()> {
+    match name.len() {
+        0 => Err(Errno::ENOENT),
+        1..=NAME_MAX => Ok(()),
+        _ => Err(Errno::ENAMETOOLONG),
+    }
+}
+
+/// Seconds and nanoseconds since the epoch, the seconds are negative for
+/// the times before it.
+fn timestamp(time: SystemTime) -> (u64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => (since.as_secs(), since.subsec_nanos()),
+        Err(err) => {
+            let before = err.duration();
+            let secs = (before.as_secs() as i64).wrapping_neg();
+            match before.subsec_nanos() {
+                0 => (secs as u64, 0),
+                nanos => ((secs - 1) as u64, 1_000_000_000 - nanos),
+            }
+        },
+    }
+}
+
+/// Checks that `len` bytes at `offset` fit in the largest file.
+fn check_range(offset: u64, len: u64) -> Result<()> {
+    match offset.checked_add(len) {
+        Some(end) if end <= i64::MAX as u64 => Ok(()),
+        _ => Err(Errno::EFBIG),
+    }
+}
+
+impl crate::Filesystem for MemFs {
+    async fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(snapshot::save(&self.lock()))
+    }
+
+    async fn restore(&self, snapshot: &[u8]) -> Result<()> {
+        snapshot::load(&mut self.lock(), snapshot)
+    }
+
+    async fn lookup(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<fuse_entry_out> {
+        let mut inner = self.lock();
+        let ino = inner.child(parent, name)?;
+        let node = inner.node_mut(ino)?;
+        node.lookups += 1;
+        Ok(self.entry(ino, node))
+    }
+
+    async fn forget(&self, _req: &Request, ino: u64, nlookup: u64) {
+        let mut inner = self.lock();
+        if let Ok(node) = inner.node_mut(ino) {
+            node.lookups = node.lookups.saturating_sub(nlookup);
+            inner.release(ino);
+        }
+    }
+
+    async fn getattr(
+        &self,
+        _req: &Request,
+        ino: u64,
+        _arg: &fuse_getattr_in
+    ) -> Result<fuse_attr_out> {
+        let inner = self.lock();
+        Ok(self.attr_out(ino, inner.node(ino)?))
+    }
+
+    async fn setattr(
+        &self,
+        _req: &Request,
+        ino: u64,
+        arg: SetattrRequest
+    ) -> Result<fuse_attr_out> {
+        let mut inner = self.lock();
+        if let Some(size) = arg.size {
+            check_range(size, 0)?;
+            inner.change_data(ino, |data, _| {
+                data.set_size(size);
+                Ok(())
+            })?;
+        }
+
+        let node = inner.node_mut(ino)?;
+        let now = SystemTime::now();
+        if let Some(mode) = arg.mode {
+            node.mode = node.mode & libc::S_IFMT | mode & 0o7777;
+        }
+        node.uid = arg.uid.unwrap_or(node.uid);
+        node.gid = arg.gid.unwrap_or(node.gid);
+        let time = |time| match time {
+            TimeOrNow::Time(time) => time,
+            TimeOrNow::Now => now,
+        };
+        node.atime = arg.atime.map_or(node.atime, time);
+        node.mtime = arg.mtime.map_or(node.mtime, time);
+        node.ctime = arg.ctime.unwrap_or(now);
+        Ok(self.attr_out(ino, node))
+    }
+
+    async fn readlink(&self, _req: &Request, ino: u64) -> Result<Vec<u8>> {
+        match &self.lock().node(ino)?.kind {
+            Kind::Symlink(target) => Ok(target.clone()),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    async fn symlink(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        target: &OsStr
+    ) -> Result<fuse_entry_out> {
+        if target.len() >= PATH_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        let kind = Kind::Symlink(target.as_bytes().to_vec());
+        self.create_entry(req, parent, Some(name), libc::S_IFLNK | 0o777, 0, kind)
+    }
+
+    async fn mknod(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_mknod_in,
+        name: &OsStr
+    ) -> Result<fuse_entry_out> {
+        let (kind, rdev) = match arg.mode & libc::S_IFMT {
+            libc::S_IFREG => (Kind::File(Data::default()), 0),
+            libc::S_IFIFO | libc::S_IFSOCK => (Kind::Special, 0),
+            libc::S_IFCHR | libc::S_IFBLK => (Kind::Special, arg.rdev),
+            _ => return Err(Errno::EINVAL),
+        };
+        self.create_entry(req, parent, Some(name), arg.mode, rdev, kind)
+    }
+
+    async fn mkdir(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_mkdir_in,
+        name: &OsStr
+    ) -> Result<fuse_entry_out> {
+        let mode = libc::S_IFDIR | arg.mode & 0o7777;
+        self.create_entry(req, parent, Some(name), mode, 0, Kind::Dir(Dir::new(parent)))
+    }
+
+    async fn unlink(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<()> {
+        let mut inner = self.lock();
+        let ino = inner.child(parent, name)?;
+        if inner.node(ino)?.is_dir() {
+            return Err(Errno::EISDIR);
+        }
+        inner.dir_mut(parent)?.remove(name);
+        inner.node_mut(parent)?.modified();
+        let node = inner.node_mut(ino)?;
+        node.nlink -= 1;
+        node.changed();
+        inner.release(ino);
+        Ok(())
+    }
+
+    async fn rmdir(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<()> {
+        let mut inner = self.lock();
+        let ino = inner.child(parent, name)?;
+        if !inner.node(ino)?.dir()?.is_empty() {
+            return Err(Errno::ENOTEMPTY);
+        }
+        inner.dir_mut(parent)?.remove(name);
+        let dir = inner.node_mut(parent)?;
+        dir.nlink -= 1;
+        dir.modified();
+        let node = inner.node_mut(ino)?;
+        node.nlink = 0;
+        node.changed();
+        inner.release(ino);
+        Ok(())
+    }
+
+    async fn rename(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32
+    ) -> Result<()> {
+        self.lock().rename(req, parent, name, newparent, newname, flags)
+    }
+
+    async fn link(
+        &self,
+        _req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr
+    ) -> Result<fuse_entry_out> {
+        let mut inner = self.lock();
+        if inner.node(ino)?.is_dir() {
+            return Err(Errno::EPERM);
+        }
+        check_name(newname)?;
+        if inner.node(newparent)?.dir()?.get(newname).is_some() {
+            return Err(Errno::EEXIST);
+        }
+
+        inner.dir_mut(newparent)?.insert(newname, ino);
+        inner.node_mut(newparent)?.modified();
+        let node = inner.node_mut(ino)?;
+        node.nlink += 1;
+        node.lookups += 1;
+        node.changed();
+        Ok(self.entry(ino, node))
+    }
+
+    async fn read(&self, _req: &Request, ino: u64, arg: &fuse_read_in) -> Result<Vec<u8>> {
+        Ok(self.lock().node(ino)?.data()?.read(arg.offset, arg.size))
+    }
+
+    async fn write(
+        &self,
+        _req: &Request,
+        ino: u64,
+        arg: &fuse_write_in,
+        data: &[u8]
+    ) -> Result<u32> {
+        let offset = arg.offset;
+        check_range(offset, data.len() as u64)?;
+        self.lock().change_data(ino, |file, free| {
+            if file.missing(offset, data.len() as u64) > free {
+                return Err(Errno::ENOSPC);
+            }
+            file.write(offset, data);
+            Ok(data.len() as u32)
+        })
+    }
+
+    async fn statfs(&self, _req: &Request, _ino: u64) -> Result<fuse_kstatfs> {
+        let inner = self.lock();
+        let free = inner.max_blocks.saturating_sub(inner.blocks);
+        Ok(fuse_kstatfs {
+            blocks: inner.max_blocks,
+            bfree: free,
+            bavail: free,
+            files: inner.max_inodes,
+            ffree: inner.max_inodes.saturating_sub(inner.nodes.len() as u64),
+            bsize: BLOCK_SIZE as u32,
+            namelen: NAME_MAX as u32,
+            frsize: BLOCK_SIZE as u32,
+            padding: Padding::new(),
+            spare: Padding::new(),
+        })
+    }
+
+    async fn fsync(&self, _req: &Request, _ino: u64, _arg: &fuse_fsync_in) -> Result<()> {
+        Ok(())
+    }
+
+    async fn setxattr(
+        &self,
+        _req: &Request,
+        ino: u64,
+        arg: &fuse_setxattr_in,
+        name: &OsStr,
+        value: &[u8]
+    ) -> Result<()> {
+        let mut inner = self.lock();
+        let node = inner.node_mut(ino)?;
+        // Like Linux, only regular files and directories have user attributes
+        let regular = matches!(node.kind, Kind::File(_) | Kind::Dir(_));
+        if name.as_bytes().starts_with(b"user.") && !regular {
+            return Err(Errno::EPERM);
+        }
+
+        let exists = node.xattrs.contains_key(name);
+        match arg.flags as i32 {
+            libc::XATTR_CREATE if exists => return Err(Errno::EEXIST),
+            libc::XATTR_REPLACE if !exists => return Err(Errno::ENODATA),
+            _ => {},
+        }
+        node.xattrs.insert(name.to_owned(), value.to_vec());
+        node.changed();
+        Ok(())
+    }
+
+    async fn getxattr(&self, _req: &Request, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        let inner = self.lock();
+        inner.node(ino)?.xattrs.get(name).cloned().ok_or(Errno::ENODATA)
+    }
+
+    async fn listxattr(&self, _req: &Request, ino: u64) -> Result<Vec<u8>> {
+        let inner = self.lock();
+        let mut names = Vec::new();
+        for name in inner.node(ino)?.xattrs.keys() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    async fn removexattr(&self, _req: &Request, ino: u64, name: &OsStr) -> Result<()> {
+        let mut inner = self.lock();
+        let node = inner.node_mut(ino)?;
+        node.xattrs.remove(name).ok_or(Errno::ENODATA)?;
+        node.changed();
+        Ok(())
+    }
+
+    async fn flush(&self, _req: &Request, _ino: u64, _arg: &fuse_flush_in) -> Result<()> {
+        Ok(())
+    }
+
+    async fn readdir(
+        &self,
+        _req: &Request,
+        ino: u64,
+        arg: &fuse_read_in,
+        buf: &mut DirBuf
+    ) -> Result<()> {
+        let inner = self.lock();
+        for (next, ino, name) in inner.node(ino)?.dir()?.read(ino, arg.offset) {
+            let file_type = inner.node(ino).map_or(0, |node| node.mode & libc::S_IFMT);
+            if !buf.push(ino, next, file_type, name) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn readdirplus(
+        &self,
+        _req: &Request,
+        ino: u64,
+        arg: &fuse_read_in,
+        buf: &mut DirBuf
+    ) -> Result<()> {
+        let mut inner = self.lock();
+        let mut found = Vec::new();
+        for (next, ino, name) in inner.node(ino)?.dir()?.read(ino, arg.offset) {
+            let mut entry = self.entry(ino, inner.node(ino)?);
+            let dots = next <= DOTDOT_OFFSET;
+            // The kernel doesn't count lookups for `.` and `..`
+            if dots {
+                entry.nodeid = 0;
+            }
+            if !buf.push_plus(entry, next, name) {
+                break;
+            }
+            if !dots {
+                found.push(ino);
+            }
+        }
+        for ino in found {
+            inner.node_mut(ino)?.lookups += 1;
+        }
+        Ok(())
+    }
+
+    async fn fsyncdir(&self, _req: &Request, _ino: u64, _arg: &fuse_fsync_in) -> Result<()> {
+        Ok(())
+    }
+
+    async fn create(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_create_in,
+        name: &OsStr
+    ) -> Result<(fuse_entry_out, fuse_open_out)> {
+        let mode = libc::S_IFREG | arg.mode & 0o7777;
+        let file = Kind::File(Data::default());
+        let entry = self.create_entry(req, parent, Some(name), mode, 0, file)?;
+        Ok((entry, fuse_open_out { fh: 0, open_flags: OpenOutFlags::empty(), backing_id: 0 }))
+    }
+
+    async fn tmpfile(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_create_in
+    ) -> Result<(fuse_entry_out, fuse_open_out)> {
+        let mode = libc::S_IFREG | arg.mode & 0o7777;
+        let entry = self.create_entry(req, parent, None, mode, 0, Kind::File(Data::default()))?;
+        Ok((entry, fuse_open_out { fh: 0, open_flags: OpenOutFlags::empty(), backing_id: 0 }))
+    }
+
+    async fn fallocate(&self, _req: &Request, ino: u64, arg: &fuse_fallocate_in) -> Result<()> {
+        let (offset, len) = (arg.offset, arg.length);
+        check_range(offset, len)?;
+        let keep_size = arg.mode as i32 & libc::FALLOC_FL_KEEP_SIZE != 0;
+        self.lock().change_data(ino, |data, free| {
+            let end = offset + len;
+            match arg.mode as i32 & !libc::FALLOC_FL_KEEP_SIZE {
+                0 => {
+                    if data.missing(offset, len) > free {
+                        return Err(Errno::ENOSPC);
+                    }
+                    data.allocate(offset, len);
+                },
+                libc::FALLOC_FL_PUNCH_HOLE if keep_size => data.punch_hole(offset, len),
+                libc::FALLOC_FL_ZERO_RANGE => data.punch_hole(offset, len),
+                _ => return Err(Errno::EOPNOTSUPP),
+            }
+            if !keep_size && end > data.size() {
+                data.set_size(end);
+            }
+            Ok(())
+        })
+    }
+
+    async fn lseek(&self, _req: &Request, ino: u64, arg: &fuse_lseek_in) -> Result<u64> {
+        let inner = self.lock();
+        let data = inner.node(ino)?.data()?;
+        if arg.offset >= data.size() {
+            return Err(Errno::ENXIO);
+        }
+        match arg.whence as i32 {
+            libc::SEEK_DATA => data.next_data(arg.offset).ok_or(Errno::ENXIO),
+            libc::SEEK_HOLE => Ok(data.next_hole(arg.offset)),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    async fn copy_file_range(
+        &self,
+        _req: &Request,
+        ino: u64,
+        arg: &fuse_copy_file_range_in
+    ) -> Result<u32> {
+        let mut inner = self.lock();
+        let data = inner.node(ino)?.data()?.read(arg.off_in, arg.len.min(MAX_COPY) as u32);
+        let offset = arg.off_out;
+        check_range(offset, data.len() as u64)?;
+        inner.change_data(arg.nodeid_out, |file, free| {
+            if file.missing(offset, data.len() as u64) > free {
+                return Err(Errno::ENOSPC);
+            }
+            file.write(offset, &data);
+            Ok(data.len() as u32)
+        })
+    }
+
+    async fn syncfs(&self, _req: &Request, _ino: u64) -> Result<()> {
+        Ok(())
+    }
+}
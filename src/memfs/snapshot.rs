@@ -0,0 +1,180 @@
+//! Encoding of the whole tree of a [`MemFs`](super::MemFs), carried to the
+//! next server process of a handover since the files only live in memory.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::Errno;
+use crate::inode::{Reader, put_bytes};
+use crate::protocol::FUSE_ROOT_ID;
+use super::data::Data;
+use super::{Dir, Inner, Kind, Node, Result};
+
+const FILE: u32 = 0;
+const DIR: u32 = 1;
+const SYMLINK: u32 = 2;
+const SPECIAL: u32 = 3;
+
+pub(super) fn save(inner: &Inner) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&inner.next_ino.to_le_bytes());
+    bytes.extend_from_slice(&(inner.nodes.len() as u64).to_le_bytes());
+    for (ino, node) in &inner.nodes {
+        bytes.extend_from_slice(&ino.to_le_bytes());
+        save_node(&mut bytes, node);
+    }
+    bytes
+}
+
+/// Decodes the nodes saved by [`save`] into `inner`, keeping its limits.
+///
+/// Fails with [`Errno::EINVAL`] if the snapshot is malformed, including
+/// entries pointing to missing nodes.
+pub(super) fn load(inner: &mut Inner, bytes: &[u8]) -> Result<()> {
+    let mut reader = Reader(bytes);
+    let next_ino = reader.u64()?;
+    let mut nodes = HashMap::new();
+    for _ in 0..reader.u64()? {
+        let ino = reader.u64()?;
+        if ino >= next_ino || nodes.insert(ino, load_node(&mut reader)?).is_some() {
+            return Err(Errno::EINVAL);
+        }
+    }
+    reader.finish()?;
+
+    let linked = nodes.values().all(|node| match &node.kind {
+        Kind::Dir(dir) => {
+            nodes.contains_key(&dir.parent)
+                && dir.entries.values().all(|(_, ino)| nodes.contains_key(ino))
+        },
+        _ => true,
+    });
+    if !linked || !nodes.get(&FUSE_ROOT_ID).is_some_and(Node::is_dir) {
+        return Err(Errno::EINVAL);
+    }
+
+    inner.blocks = nodes.values()
+        .map(|node| match &node.kind {
+            Kind::File(data) => data.blocks(),
+            _ => 0,
+        })
+        .sum();
+    inner.nodes = nodes;
+    inner.next_ino = next_ino;
+    Ok(())
+}
+
+fn save_node(bytes: &mut Vec<u8>, node: &Node) {
+    for value in [node.mode, node.uid, node.gid, node.nlink, node.rdev] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    for time in [node.atime, node.mtime, node.ctime] {
+        save_time(bytes, time);
+    }
+    bytes.extend_from_slice(&node.lookups.to_le_bytes());
+    bytes.extend_from_slice(&(node.xattrs.len() as u64).to_le_bytes());
+    for (name, value) in &node.xattrs {
+        put_bytes(bytes, name.as_bytes());
+        put_bytes(bytes, value);
+    }
+
+    match &node.kind {
+        Kind::File(data) => {
+            bytes.extend_from_slice(&FILE.to_le_bytes());
+            data.save(bytes);
+        },
+        Kind::Dir(dir) => {
+            bytes.extend_from_slice(&DIR.to_le_bytes());
+            bytes.extend_from_slice(&dir.parent.to_le_bytes());
+            bytes.extend_from_slice(&dir.next_offset.to_le_bytes());
+            bytes.extend_from_slice(&(dir.entries.len() as u64).to_le_bytes());
+            for (offset, (name, ino)) in &dir.entries {
+                bytes.extend_from_slice(&offset.to_le_bytes());
+                bytes.extend_from_slice(&ino.to_le_bytes());
+                put_bytes(bytes, name.as_bytes());
+            }
+        },
+        Kind::Symlink(target) => {
+            bytes.extend_from_slice(&SYMLINK.to_le_bytes());
+            put_bytes(bytes, target);
+        },
+        Kind::Special => bytes.extend_from_slice(&SPECIAL.to_le_bytes()),
+    }
+}
+
+fn load_node(reader: &mut Reader) -> Result<Node> {
+    let [mode, uid, gid, nlink, rdev] = [(); 5].map(|()| reader.u32());
+    let [atime, mtime, ctime] = [(); 3].map(|()| load_time(reader));
+    let lookups = reader.u64()?;
+    let mut xattrs = BTreeMap::new();
+    for _ in 0..reader.u64()? {
+        let name = OsStr::from_bytes(reader.bytes()?).to_owned();
+        xattrs.insert(name, reader.bytes()?.to_vec());
+    }
+
+    let kind = match reader.u32()? {
+        FILE => Kind::File(Data::load(reader)?),
+        DIR => {
+            let mut dir = Dir::new(reader.u64()?);
+            let next_offset = reader.u64()?;
+            for _ in 0..reader.u64()? {
+                let (offset, ino) = (reader.u64()?, reader.u64()?);
+                let name = OsStr::from_bytes(reader.bytes()?);
+                if offset >= next_offset || dir.offsets.insert(name.to_owned(), offset).is_some() {
+                    return Err(Errno::EINVAL);
+                }
+                dir.entries.insert(offset, (name.to_owned(), ino));
+            }
+            dir.next_offset = next_offset;
+            Kind::Dir(dir)
+        },
+        SYMLINK => Kind::Symlink(reader.bytes()?.to_vec()),
+        SPECIAL => Kind::Special,
+        _ => return Err(Errno::EINVAL),
+    };
+
+    Ok(Node {
+        kind,
+        mode: mode?,
+        uid: uid?,
+        gid: gid?,
+        nlink: nlink?,
+        rdev: rdev?,
+        atime: atime?,
+        mtime: mtime?,
+        ctime: ctime?,
+        xattrs,
+        lookups,
+    })
+}
+
+/// Appends a time as signed seconds and nanoseconds since the epoch.
+fn save_time(bytes: &mut Vec<u8>, time: SystemTime) {
+    let (secs, nsecs) = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+        Err(err) => {
+            let before = err.duration();
+            match before.subsec_nanos() {
+                0 => (-(before.as_secs() as i64), 0),
+                nsecs => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nsecs),
+            }
+        },
+    };
+    bytes.extend_from_slice(&secs.to_le_bytes());
+    bytes.extend_from_slice(&nsecs.to_le_bytes());
+}
+
+fn load_time(reader: &mut Reader) -> Result<SystemTime> {
+    let (secs, nsecs) = (reader.u64()? as i64, reader.u32()?);
+    if nsecs >= 1_000_000_000 {
+        return Err(Errno::EINVAL);
+    }
+    let time = match u64::try_from(secs) {
+        Ok(secs) => UNIX_EPOCH.checked_add(Duration::new(secs, nsecs)),
+        Err(_) => UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+            .and_then(|time| time.checked_add(Duration::from_nanos(nsecs.into()))),
+    };
+    time.ok_or(Errno::EINVAL)
+}
@@ -0,0 +1,106 @@
+use std::io::{self, ErrorKind};
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+use std::sync::Arc;
+
+use crate::protocol::{
+    FUSE_DEV_IOC_BACKING_CLOSE, FUSE_DEV_IOC_BACKING_OPEN, fuse_backing_map
+};
+
+/// Id of a backing file registered with [`BackingFiles::open`].
+///
+/// The id is sent in the `backing_id` field of a [`fuse_open_out`] with the
+/// `FOPEN_PASSTHROUGH` flag, then the kernel forwards the reads and writes of
+/// the open file to the backing file, without involving the server.
+///
+/// [`fuse_open_out`]: crate::protocol::fuse_open_out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BackingId(i32);
+
+impl BackingId {
+    #[inline]
+    pub fn get(self) -> i32 {
+        self.0
+    }
+}
+
+/// Registers the backing files of passthrough open files.
+///
+/// Passthrough must be negotiated by setting
+/// [`SessionConfig::max_stack_depth`](crate::SessionConfig::max_stack_depth),
+/// and registering files requires `CAP_SYS_ADMIN`. The handle can be cloned
+/// and given to the file system before the session starts.
+#[derive(Debug, Clone)]
+pub struct BackingFiles {
+    fuse_dev: Arc<OwnedFd>,
+}
+
+impl BackingFiles {
+    pub(super) fn new(fuse_dev: Arc<OwnedFd>) -> Self {
+        Self { fuse_dev }
+    }
+
+    /// Registers `file` as a backing file.
+    ///
+    /// The kernel limits how many stacked file systems can be below a file,
+    /// the file system of `file` must be less deep than the `max_stack_depth`
+    /// of the session. For example a file on overlayfs needs a depth of 2.
+    pub fn open(&self, file: BorrowedFd) -> io::Result<BackingId> {
+        let map = fuse_backing_map { fd: file.as_raw_fd(), flags: 0, padding: 0 };
+        let ret = unsafe {
+            libc::ioctl(self.fuse_dev.as_raw_fd(), FUSE_DEV_IOC_BACKING_OPEN as _, &map)
+        };
+        if ret >= 0 {
+            return Ok(BackingId(ret));
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::ELOOP) => io_error!(
+                ErrorKind::InvalidInput,
+                "The backing file is on a stacked file system ({}) deeper than the \
+                max_stack_depth of the session", fs_name(file)
+            ),
+            Some(libc::EPERM) => io_error!(
+                ErrorKind::PermissionDenied,
+                "Backing files require passthrough to be negotiated, with \
+                `SessionConfig::max_stack_depth`, and CAP_SYS_ADMIN"
+            ),
+            Some(libc::EOPNOTSUPP) => io_error!(
+                ErrorKind::Unsupported,
+                "The file system of the backing file ({}) does not support \
+                passthrough", fs_name(file)
+            ),
+            _ => io_error!(err.kind(), "Failed to register the backing file: {err}"),
+        }
+    }
+
+    /// Unregisters a backing file, the files already opened with it keep
+    /// using it.
+    pub fn close(&self, id: BackingId) -> io::Result<()> {
+        let id = id.0 as u32;
+        let ret = unsafe {
+            libc::ioctl(self.fuse_dev.as_raw_fd(), FUSE_DEV_IOC_BACKING_CLOSE as _, &id)
+        };
+        if ret == -1 {
+            let err = io::Error::last_os_error();
+            io_error!(err.kind(), "Failed to unregister the backing file: {err}");
+        }
+        Ok(())
+    }
+}
+
+/// Name of the file system of `file`, for the error messages.
+fn fs_name(file: BorrowedFd) -> &'static str {
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::fstatfs(file.as_raw_fd(), stat.as_mut_ptr()) } == -1 {
+        return "unknown";
+    }
+    // SAFETY: fstatfs succeeded
+    match unsafe { stat.assume_init() }.f_type {
+        libc::OVERLAYFS_SUPER_MAGIC => "overlayfs",
+        libc::FUSE_SUPER_MAGIC => "fuse",
+        libc::ECRYPTFS_SUPER_MAGIC => "ecryptfs",
+        _ => "unknown",
+    }
+}
@@ -1,14 +1,21 @@
 use std::io;
-use std::ffi::CString;
-use std::os::fd::AsRawFd;
+use std::ffi::{CStr, CString};
+use std::fmt::Display;
+use std::fs::Metadata;
+use std::os::fd::{AsRawFd, OwnedFd};
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
 use std::borrow::Cow;
 
 use tokio::fs;
 
-use super::Mount;
+use super::{DetachedMount, Mount};
+use super::namespace::Namespace;
+use super::preflight;
+
+const FS_TYPE: &CStr = c"fuse";
+const FS_TYPE_BLK: &CStr = c"fuseblk";
 
 macro_rules! flag_setters {
     ($(pub fn $name:ident($flag:expr);)*) => {$(
@@ -27,12 +34,20 @@ pub struct MountBuilder {
     mountpoint: PathBuf,
 
     // Mount options
-    fsname: CString,
-    subtype: Option<CString>,
+    fsname: String,
+    subtype: Option<String>,
     rootmode: Option<u16>,
     default_permissions: bool,
     allow_other: bool,
     max_read: Option<usize>,
+    block_device: Option<(PathBuf, u32)>,
+    user_id: Option<u32>,
+    group_id: Option<u32>,
+    allow_idmap: bool,
+    namespace: Option<Namespace>,
+    auto_unmount: bool,
+    preflight: bool,
+    unmount_stale: bool,
 
     // Mount flags
     flags: u64,
@@ -42,8 +57,17 @@ pub(super) struct MountOptions {
     pub(super) mountpoint: PathBuf,
     pub(super) source: CString,
     pub(super) target: CString,
+    pub(super) fs_type: &'static CStr,
     pub(super) flags: u64,
-    pub(super) options: CString,
+    pub(super) options: Vec<MountOption>,
+    pub(super) blksize: Option<u32>,
+    pub(super) allow_idmap: bool,
+    pub(super) auto_unmount: bool,
+}
+
+pub(super) struct MountOption {
+    pub(super) key: &'static CStr,
+    pub(super) value: Option<CString>,
 }
 
 impl MountBuilder {
@@ -52,19 +76,22 @@ impl MountBuilder {
         mountpoint: impl Into<PathBuf>,
         fsname: impl Into<String>,
     ) -> Self {
-        // TODO: check that fsname is valid (no commas for example)
-        // TODO: remove the expect
-        let fsname = CString::new(fsname.into().into_bytes())
-            .expect("The filesystem name cannot contain NUL bytes");
-
         Self {
             mountpoint: mountpoint.into(),
-            fsname,
+            fsname: fsname.into(),
             subtype: None,
             rootmode: None,
             default_permissions: false,
             allow_other: false,
             max_read: None,
+            block_device: None,
+            user_id: None,
+            group_id: None,
+            allow_idmap: false,
+            namespace: None,
+            auto_unmount: false,
+            preflight: false,
+            unmount_stale: false,
             flags: 0,
         }
     }
@@ -104,17 +131,137 @@ impl MountBuilder {
         self
     }
 
+    /// Sets the subtype, the mount table shows the type as `fuse.<subtype>`.
+    ///
+    /// Like the file system name, it must not contain commas nor NUL bytes.
     #[inline]
     #[must_use = "A MountBuilder will do noting unless you call `.build()`"]
     pub fn subtype<'b>(
         mut self,
         subtype: impl Into<Cow<'b, str>>
     ) -> Self {
-        let subtype = subtype.into().into_owned();
-        // TODO: remove the expect
-        let subtype = CString::new(subtype.into_bytes())
-            .expect("The subtype cannot contain NUL bytes");
-        self.subtype = Some(subtype);
+        self.subtype = Some(subtype.into().into_owned());
+        self
+    }
+
+    /// Mounts the file system as `fuseblk`, backed by a block device.
+    ///
+    /// The device is used as the mount source instead of the file system
+    /// name given to [`MountBuilder::new`], and is validated when the file
+    /// system is mounted. The kernel sends `FUSE_BMAP` requests, answered by
+    /// [`Filesystem::bmap`](crate::Filesystem::bmap), using `blksize` as the
+    /// block size, this must be a power of two between 512 and the page size.
+    ///
+    /// Mounting a `fuseblk` file system requires `CAP_SYS_ADMIN`.
+    #[inline]
+    #[must_use = "A MountBuilder will do noting unless you call `.build()`"]
+    pub fn block_device(
+        mut self,
+        device: impl Into<PathBuf>,
+        blksize: u32
+    ) -> Self {
+        self.block_device = Some((device.into(), blksize));
+        self
+    }
+
+    /// Sets the user id of the mount owner.
+    ///
+    /// Only the owner can access the file system unless `allow_other` is set,
+    /// defaults to the real user id of the process.
+    #[inline]
+    #[must_use = "A MountBuilder will do noting unless you call `.build()`"]
+    pub fn user_id(mut self, uid: u32) -> Self {
+        self.user_id = Some(uid);
+        self
+    }
+
+    /// Sets the group id of the mount owner.
+    ///
+    /// Defaults to the real group id of the process.
+    #[inline]
+    #[must_use = "A MountBuilder will do noting unless you call `.build()`"]
+    pub fn group_id(mut self, gid: u32) -> Self {
+        self.group_id = Some(gid);
+        self
+    }
+
+    /// Allows creating id-mapped clones of the mount, with
+    /// [`Mount::idmapped_clone`].
+    ///
+    /// The session negotiates `FUSE_ALLOW_IDMAP` with the kernel, which only
+    /// accepts it together with `default_permissions`.
+    #[inline]
+    #[must_use = "A MountBuilder will do noting unless you call `.build()`"]
+    pub fn allow_idmap(mut self, allow: bool) -> Self {
+        self.allow_idmap = allow;
+        self
+    }
+
+    /// Mounts the file system in the mount namespace `ns`.
+    ///
+    /// The mount is performed by a helper thread that joins the namespace,
+    /// the mountpoint and the block device paths are resolved there.
+    ///
+    /// Joining a mount namespace requires `CAP_SYS_ADMIN` both in the current
+    /// user namespace and in the one that owns the mount namespace. If the
+    /// owner is another user namespace, like the one created by
+    /// [`unshare_user_namespace`](crate::unshare_user_namespace), the helper
+    /// is a child process that joins both namespaces instead.
+    #[inline]
+    #[must_use = "A MountBuilder will do noting unless you call `.build()`"]
+    pub fn mount_namespace(mut self, ns: impl Into<OwnedFd>) -> Self {
+        self.namespace = Some(Namespace::Fd(ns.into()));
+        self
+    }
+
+    /// Mounts the file system in the mount namespace of the process `pid`.
+    ///
+    /// See [`MountBuilder::mount_namespace`].
+    #[inline]
+    #[must_use = "A MountBuilder will do noting unless you call `.build()`"]
+    pub fn mount_namespace_of(mut self, pid: u32) -> Self {
+        self.namespace = Some(Namespace::Pid(pid));
+        self
+    }
+
+    /// Unmounts the file system when the [`Mount`] is dropped or the process
+    /// exits, even if it crashes.
+    ///
+    /// A small watchdog process is forked after mounting, it lazily unmounts
+    /// the mountpoint once the server's end of a pipe is closed. Unmounting
+    /// requires `CAP_SYS_ADMIN` in the user namespace owning the mount.
+    #[inline]
+    #[must_use = "A MountBuilder will do noting unless you call `.build()`"]
+    pub fn auto_unmount(mut self, enable: bool) -> Self {
+        self.auto_unmount = enable;
+        self
+    }
+
+    /// Checks the mountpoint before mounting.
+    ///
+    /// The checks detect stale FUSE mounts on the mountpoint, verify that the
+    /// mountpoint has the same type of the root (a directory, or a file for
+    /// file mounts) and for unprivileged users that it is owned and writable,
+    /// like `fusermount` does.
+    ///
+    /// Failures are reported as a [`PreflightError`] wrapped in the returned
+    /// [`io::Error`].
+    #[inline]
+    #[must_use = "A MountBuilder will do noting unless you call `.build()`"]
+    pub fn preflight_checks(mut self, enable: bool) -> Self {
+        self.preflight = enable;
+        self
+    }
+
+    /// Lazily unmounts stale FUSE mounts found on the mountpoint, instead of
+    /// failing. This enables the preflight checks.
+    ///
+    /// See [`MountBuilder::preflight_checks`].
+    #[inline]
+    #[must_use = "A MountBuilder will do noting unless you call `.build()`"]
+    pub fn unmount_stale(mut self, enable: bool) -> Self {
+        self.unmount_stale = enable;
+        self.preflight |= enable;
         self
     }
 
@@ -133,61 +280,244 @@ impl MountBuilder {
         Mount::from_builder(self).await
     }
 
+    /// Creates the mount without attaching it to the mountpoint.
+    ///
+    /// The returned [`DetachedMount`] can be attached later, the mount file
+    /// descriptor can also be moved into another mount namespace.
+    ///
+    /// Detached mounts need the new mount API (Linux 5.2), there is no
+    /// fallback to `mount(2)`.
+    pub async fn build_detached(self) -> io::Result<DetachedMount> {
+        DetachedMount::from_builder(self).await
+    }
+
+    /// Opens the target mount namespace, if one was set.
+    pub(super) fn take_namespace(&mut self) -> io::Result<Option<OwnedFd>> {
+        self.namespace.take().map(Namespace::open).transpose()
+    }
+
+    /// Runs the preflight checks on the mountpoint, if enabled.
+    ///
+    /// The checks run on a blocking thread, since the mountpoint may belong
+    /// to a server that stopped answering.
+    pub(super) async fn preflight(&self) -> io::Result<()> {
+        if !self.preflight {
+            return Ok(());
+        }
+        let mountpoint = self.mountpoint.clone();
+        let (rootmode, unmount_stale) = (self.rootmode, self.unmount_stale);
+        let check = move || preflight::check(&mountpoint, rootmode, unmount_stale);
+        match tokio::task::spawn_blocking(check).await {
+            Ok(result) => Ok(result?),
+            Err(err) => Err(io::Error::other(err)),
+        }
+    }
+
+    /// Like [`MountBuilder::preflight`], for the helper running in another
+    /// namespace.
+    pub(super) fn preflight_blocking(&self) -> io::Result<()> {
+        if self.preflight {
+            preflight::check(&self.mountpoint, self.rootmode, self.unmount_stale)?;
+        }
+        Ok(())
+    }
+
     pub(super) async fn into_options(
-        self,
+        mut self,
         fuse_dev: &impl AsRawFd
+    ) -> io::Result<MountOptions> {
+        // The mountpoint is resolved like the kernel does, to find the mount
+        // in the mount table later
+        self.mountpoint = fs::canonicalize(&self.mountpoint).await?;
+        let rootmode = match self.rootmode {
+            Some(mode) => mode,
+            None => fs::metadata(&self.mountpoint).await?
+                .mode() as u16
+        };
+
+        if let Some((device, blksize)) = &self.block_device {
+            check_block_device(device, fs::metadata(device).await, *blksize)?;
+        }
+
+        self.into_options_with(fuse_dev, rootmode)
+    }
+
+    /// Resolves the mountpoint, finds the mode of the root and checks the
+    /// block device like [`MountBuilder::into_options`], using blocking calls.
+    /// This is used when the paths must be resolved by a helper in another
+    /// namespace, the options are then built with
+    /// [`MountBuilder::into_options_with`].
+    pub(super) fn rootmode_blocking(&mut self) -> io::Result<u16> {
+        self.mountpoint = std::fs::canonicalize(&self.mountpoint)?;
+        let rootmode = match self.rootmode {
+            Some(mode) => mode,
+            None => std::fs::metadata(&self.mountpoint)?
+                .mode() as u16
+        };
+
+        if let Some((device, blksize)) = &self.block_device {
+            check_block_device(device, std::fs::metadata(device), *blksize)?;
+        }
+
+        Ok(rootmode)
+    }
+
+    pub(super) fn into_options_with(
+        self,
+        fuse_dev: &impl AsRawFd,
+        rootmode: u16
     ) -> io::Result<MountOptions> {
         let target = self.mountpoint
             .as_os_str()
             .as_bytes()
             .to_vec();
 
-        // TODO: remove the expect
         let target = CString::new(target)
-            .expect("The mountpoint cannot contain NUL bytes");
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
 
-        let rootmode = match self.rootmode {
-            Some(mode) => mode,
-            None => fs::metadata(&self.mountpoint).await?
-                .mode() as u16
+        let fsname = option_value("file system name", self.fsname)?;
+        let source = match &self.block_device {
+            Some((device, _)) => CString::new(device.as_os_str().as_bytes())
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?,
+            None => fsname,
         };
-        let uid = unsafe { libc::getuid() };
-        let gid = unsafe { libc::getgid() };
-        let fd = fuse_dev.as_raw_fd();
 
-        let mut options = format!("fd={fd},rootmode={rootmode:o},user_id={uid},group_id={gid},fsname=")
-            .into_bytes();
+        if self.allow_idmap && !self.default_permissions {
+            io_error!(
+                io::ErrorKind::InvalidInput,
+                "Id-mapped mounts require `default_permissions`"
+            );
+        }
+
+        let uid = self.user_id.unwrap_or_else(|| unsafe { libc::getuid() });
+        let gid = self.group_id.unwrap_or_else(|| unsafe { libc::getgid() });
+        let fd = fuse_dev.as_raw_fd();
 
-        options.extend_from_slice(self.fsname.as_bytes());
+        // The file system name is passed as the mount source, the kernel
+        // doesn't accept an `fsname` option
+        let mut options = vec![
+            MountOption::number(c"fd", fd),
+            MountOption::value(c"rootmode", format!("{rootmode:o}")),
+            MountOption::number(c"user_id", uid),
+            MountOption::number(c"group_id", gid),
+        ];
 
         if let Some(subtype) = self.subtype {
-            options.extend_from_slice(b",subtype=");
-            options.extend_from_slice(subtype.as_bytes());
+            let subtype = option_value("subtype", subtype)?;
+            options.push(MountOption { key: c"subtype", value: Some(subtype) });
         }
 
         if self.default_permissions {
-            options.extend_from_slice(b",default_permissions");
+            options.push(MountOption::flag(c"default_permissions"));
         }
 
         if self.allow_other {
-            options.extend_from_slice(b",allow_other");
+            options.push(MountOption::flag(c"allow_other"));
+        }
+
+        if let Some((_, blksize)) = self.block_device {
+            options.push(MountOption::number(c"blksize", blksize));
         }
 
         if let Some(size) = self.max_read {
-            options.extend_from_slice(format!("max_read={size}").as_bytes());
+            options.push(MountOption::number(c"max_read", size));
         }
-        
-        // SAFETY: The genereted options string cannot contain NUL bytes, 
-        //         because it is built only from static byte strings, CStrings,
-        //         and stringified numbers
-        let options = unsafe { CString::from_vec_unchecked(options) };
+
+        let fs_type = match self.block_device {
+            Some(_) => FS_TYPE_BLK,
+            None => FS_TYPE,
+        };
 
         Ok(MountOptions {
             mountpoint: self.mountpoint,
-            source: self.fsname,
+            source,
             target,
+            fs_type,
             flags: self.flags,
             options,
+            blksize: self.block_device.map(|(_, blksize)| blksize),
+            allow_idmap: self.allow_idmap,
+            auto_unmount: self.auto_unmount,
         })
     }
 }
+
+impl MountOption {
+    fn flag(key: &'static CStr) -> Self {
+        Self { key, value: None }
+    }
+
+    fn value(key: &'static CStr, value: String) -> Self {
+        // SAFETY: the values are only built from stringified numbers
+        let value = unsafe { CString::from_vec_unchecked(value.into_bytes()) };
+        Self { key, value: Some(value) }
+    }
+
+    fn number(key: &'static CStr, value: impl Display) -> Self {
+        Self::value(key, value.to_string())
+    }
+}
+
+impl MountOptions {
+    /// Comma separated options, used by `mount(2)`
+    pub(super) fn joined_options(&self) -> CString {
+        let mut joined = Vec::new();
+        for option in &self.options {
+            if !joined.is_empty() {
+                joined.push(b',');
+            }
+            joined.extend_from_slice(option.key.to_bytes());
+            if let Some(value) = &option.value {
+                joined.push(b'=');
+                joined.extend_from_slice(value.as_bytes());
+            }
+        }
+
+        // SAFETY: The genereted options string cannot contain NUL bytes,
+        //         because it is built only from CStrings
+        unsafe { CString::from_vec_unchecked(joined) }
+    }
+}
+
+/// Checks a value that is shown in the options of the mount table, or passed
+/// in the options of `mount(2)`, where a comma would start another option.
+fn option_value(what: &str, value: String) -> io::Result<CString> {
+    if value.contains(',') {
+        io_error!(io::ErrorKind::InvalidInput, "The {what} `{value}` contains a comma");
+    }
+    match CString::new(value) {
+        Ok(value) => Ok(value),
+        Err(_) => io_error!(io::ErrorKind::InvalidInput, "The {what} contains a NUL byte"),
+    }
+}
+
+fn check_block_device(
+    device: &Path,
+    metadata: io::Result<Metadata>,
+    blksize: u32
+) -> io::Result<()> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+    if !blksize.is_power_of_two() || !(512..=page_size).contains(&blksize) {
+        io_error!(
+            io::ErrorKind::InvalidInput,
+            "Invalid block size {blksize}, it must be a power of two between 512 and {page_size}"
+        );
+    }
+
+    let metadata = match metadata {
+        Ok(metadata) => metadata,
+        Err(err) => io_error!(
+            err.kind(),
+            "Failed to access block device `{}`: {err}", device.display()
+        ),
+    };
+
+    if !metadata.file_type().is_block_device() {
+        io_error!(
+            io::ErrorKind::InvalidInput,
+            "`{}` is not a block device", device.display()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use tokio::fs;
+
+const FUSECTL_DIR: &str = "/sys/fs/fuse/connections";
+
+/// Control interface of a FUSE connection, exposed by the fusectl file system
+/// in `/sys/fs/fuse/connections/<id>/`.
+///
+/// Changing the values and aborting the connection requires write permission
+/// on the fusectl files, usually only root has it.
+#[derive(Debug, Clone)]
+pub struct FuseCtl {
+    dir: PathBuf,
+}
+
+impl FuseCtl {
+    /// Control interface of the connection with id `connection_id`.
+    ///
+    /// See [`Mount::connection_id`](crate::Mount::connection_id).
+    pub fn new(connection_id: u32) -> Self {
+        Self { dir: Path::new(FUSECTL_DIR).join(connection_id.to_string()) }
+    }
+
+    /// Directory of the connection in the fusectl file system.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Number of requests waiting to be answered by the server.
+    pub async fn waiting(&self) -> io::Result<u32> {
+        self.read("waiting").await
+    }
+
+    /// Aborts the connection, every pending and future request fails with
+    /// `ENOTCONN`.
+    pub async fn abort(&self) -> io::Result<()> {
+        self.write("abort", "1").await
+    }
+
+    /// Maximum number of background requests the kernel queues.
+    pub async fn max_background(&self) -> io::Result<u32> {
+        self.read("max_background").await
+    }
+
+    pub async fn set_max_background(&self, value: u32) -> io::Result<()> {
+        self.write("max_background", &value.to_string()).await
+    }
+
+    /// Number of background requests after which the kernel considers the
+    /// connection congested.
+    pub async fn congestion_threshold(&self) -> io::Result<u32> {
+        self.read("congestion_threshold").await
+    }
+
+    pub async fn set_congestion_threshold(&self, value: u32) -> io::Result<()> {
+        self.write("congestion_threshold", &value.to_string()).await
+    }
+
+    async fn read<T: FromStr>(&self, file: &str) -> io::Result<T> {
+        let path = self.dir.join(file);
+        let content = match fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(err) => return Err(self.error(&path, err)),
+        };
+        match content.trim().parse() {
+            Ok(value) => Ok(value),
+            Err(_) => io_error!(
+                ErrorKind::InvalidData,
+                "Invalid value in `{}`: {content:?}", path.display()
+            ),
+        }
+    }
+
+    async fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        let path = self.dir.join(file);
+        fs::write(&path, value).await
+            .map_err(|err| self.error(&path, err))
+    }
+
+    fn error(&self, path: &Path, err: io::Error) -> io::Error {
+        // The connection directory is also missing after the file system is
+        // unmounted, but the missing fusectl mount is the common mistake
+        let hint = if err.kind() == ErrorKind::NotFound {
+            format!(
+                ". Is fusectl mounted? Try `mount -t fusectl none {FUSECTL_DIR}`"
+            )
+        } else {
+            String::new()
+        };
+        io::Error::new(
+            err.kind(),
+            format!("Failed to access `{}`: {err}{hint}", path.display())
+        )
+    }
+}
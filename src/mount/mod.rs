@@ -1,5 +1,25 @@
 mod builder;
 pub use builder::MountBuilder;
 
+#[allow(clippy::module_inception)]
 mod mount;
-pub use mount::Mount;
+pub use mount::{DetachedMount, Mount};
+pub(crate) use mount::MountState;
+
+mod namespace;
+pub use namespace::unshare_user_namespace;
+
+mod mountinfo;
+pub use mountinfo::MountInfo;
+
+mod fusectl;
+pub use fusectl::FuseCtl;
+
+mod backing;
+pub use backing::{BackingFiles, BackingId};
+
+mod preflight;
+pub use preflight::PreflightError;
+
+mod sys;
+mod watchdog;
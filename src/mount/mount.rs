@@ -1,27 +1,56 @@
-use std::ffi::CStr;
 use std::io::{self, ErrorKind};
-use std::path::PathBuf;
-use tokio::fs::{File, OpenOptions};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use tokio::fs::OpenOptions;
 
-use super::MountBuilder;
+use crate::transport::FuseDevice;
+use super::{BackingFiles, FuseCtl, MountBuilder, MountInfo};
+use super::mountinfo;
+use super::builder::MountOptions;
+use super::namespace::run_in_namespace;
+use super::preflight::lazy_unmount;
+use super::sys;
+use super::watchdog::Watchdog;
 
-const FS_TYPE: &CStr = unsafe {
-    CStr::from_bytes_with_nul_unchecked(b"fuse\0")
-};
+/// An handle to a mounted FUSE file system.
+pub struct Mount {
+    fuse_dev: Arc<OwnedFd>,
+    mountpoint: PathBuf,
+    namespace: Option<OwnedFd>,
+    blksize: Option<u32>,
+    allow_idmap: bool,
+    connection_id: u32,
+    watchdog: Option<Watchdog>,
+}
 
-macro_rules! io_error {
-    ($kind:expr, $msg:literal $($args:tt)*) => {{
-        return Err(std::io::Error::new(
-            $kind,
-            format!($msg $($args)*)
-        ));
-    }};
+/// Duplicated file descriptors and settings of a [`Mount`], used to move the
+/// mount to another process.
+pub(crate) struct MountState {
+    pub(crate) fuse_dev: OwnedFd,
+    pub(crate) namespace: Option<OwnedFd>,
+    pub(crate) watchdog: Option<OwnedFd>,
+    pub(crate) mountpoint: PathBuf,
+    pub(crate) blksize: Option<u32>,
+    pub(crate) allow_idmap: bool,
+    pub(crate) connection_id: u32,
 }
 
-/// An handle to a mounted FUSE file system.
-pub struct Mount {
-    fuse_dev: File,
+/// A FUSE file system that was created but is not attached to a mountpoint.
+///
+/// The mount can be attached with [`DetachedMount::attach`], or the mount file
+/// descriptor can be passed to `move_mount(2)` by another process.
+pub struct DetachedMount {
+    fuse_dev: OwnedFd,
+    mount_fd: OwnedFd,
+    namespace: Option<OwnedFd>,
+    connection_id: u32,
     mountpoint: PathBuf,
+    blksize: Option<u32>,
+    allow_idmap: bool,
+    auto_unmount: bool,
 }
 
 impl Mount {
@@ -32,44 +61,519 @@ impl Mount {
         MountBuilder::new(mountpoint, fs_name)
     }
 
-    pub(super) async fn from_builder(builder: MountBuilder) -> io::Result<Self> {
-        const FUSE_DEVICE: &str = "/dev/fuse";
+    /// Path where the file system is mounted, canonicalized when mounting.
+    #[inline]
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Block size of a `fuseblk` mount, `None` for regular mounts.
+    ///
+    /// Only `fuseblk` mounts receive `FUSE_BMAP` requests.
+    #[inline]
+    pub fn block_size(&self) -> Option<u32> {
+        self.blksize
+    }
+
+    /// Id of the kernel connection of the file system.
+    ///
+    /// This is derived from the device number of the mount, and it's the name
+    /// of the connection directory in the fusectl file system.
+    #[inline]
+    pub fn connection_id(&self) -> u32 {
+        self.connection_id
+    }
+
+    /// Control interface of the kernel connection.
+    #[inline]
+    pub fn fusectl(&self) -> FuseCtl {
+        FuseCtl::new(self.connection_id)
+    }
+
+    /// Handle to register the backing files of passthrough open files.
+    pub fn backing_files(&self) -> BackingFiles {
+        BackingFiles::new(self.fuse_dev.clone())
+    }
+
+    /// The FUSE device as a [`Transport`](crate::transport::Transport), this
+    /// is what [`Session::new`](crate::Session::new) uses.
+    pub fn device(&self) -> FuseDevice {
+        FuseDevice::new(self.fuse_dev.clone())
+    }
 
-        let fuse_dev = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(FUSE_DEVICE)
-            .await;
+    /// Finds the entry of the mount in `/proc/self/mountinfo`.
+    ///
+    /// Mounts made in another mount namespace are not visible from the
+    /// current one, so they are not found.
+    pub fn mount_info(&self) -> io::Result<MountInfo> {
+        let mount = MountInfo::read_all()?
+            .into_iter()
+            .rfind(|mount| {
+                mount.connection_id() == self.connection_id
+                    && mount.mountpoint == self.mountpoint
+            });
 
-        let fuse_dev = match fuse_dev {
-            Ok(dev) => dev,
-            Err(e) if e.kind() == ErrorKind::NotFound => io_error!(
+        match mount {
+            Some(mount) => Ok(mount),
+            None => io_error!(
                 ErrorKind::NotFound,
-                "FUSE device file not fount `{FUSE_DEVICE}`. Try `modprobe fuse`"
+                "The mount `{}` is not in the mount table", self.mountpoint.display()
             ),
-            Err(e) => return Err(e)
+        }
+    }
+
+    /// Whether the session must negotiate `FUSE_ALLOW_IDMAP`.
+    #[inline]
+    pub fn allows_idmap(&self) -> bool {
+        self.allow_idmap
+    }
+
+    /// Creates a detached clone of the mount, with ids mapped through the
+    /// user namespace `userns`.
+    ///
+    /// The returned mount file descriptor can be attached with
+    /// `move_mount(2)`, even in another mount namespace, or with
+    /// [`Mount::attach_idmapped`].
+    ///
+    /// The mount must be built with [`MountBuilder::allow_idmap`] and the
+    /// session must have completed the `FUSE_INIT` handshake, otherwise the
+    /// kernel rejects the mapping.
+    pub fn idmapped_clone(&self, userns: BorrowedFd) -> io::Result<OwnedFd> {
+        if !self.allow_idmap {
+            io_error!(
+                ErrorKind::InvalidInput,
+                "The mount was not built with `allow_idmap`"
+            );
+        }
+
+        let path = CString::new(self.mountpoint.as_os_str().as_bytes())
+            .map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
+
+        let clone = match sys::open_tree_clone(&path) {
+            Ok(clone) => clone,
+            Err(err) => io_error!(err.kind(), "Failed to call open_tree(): {err}"),
+        };
+
+        if let Err(err) = sys::mount_setattr_idmap(clone.as_fd(), userns) {
+            io_error!(err.kind(), "Failed to call mount_setattr(): {err}");
+        }
+
+        Ok(clone)
+    }
+
+    /// Attaches an id-mapped clone of the mount to `target`.
+    ///
+    /// See [`Mount::idmapped_clone`].
+    pub fn attach_idmapped(
+        &self,
+        userns: BorrowedFd,
+        target: impl AsRef<Path>
+    ) -> io::Result<()> {
+        let clone = self.idmapped_clone(userns)?;
+        let target = CString::new(target.as_ref().as_os_str().as_bytes())
+            .map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
+        attach(&clone, &target)
+    }
+
+    /// Lazily unmounts the file system.
+    ///
+    /// The mountpoint is detached immediately, the kernel ends the session
+    /// once the file system is no longer in use, then the session receives
+    /// `ENODEV` and returns. Fails with [`ErrorKind::NotFound`] if the
+    /// mountpoint no longer refers to this file system.
+    pub fn unmount(&self) -> io::Result<()> {
+        let target = CString::new(self.mountpoint.as_os_str().as_bytes())
+            .map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
+
+        let unmount = || {
+            match connection_id(None, &target) {
+                Ok(id) if id == self.connection_id => (),
+                _ => io_error!(
+                    ErrorKind::NotFound,
+                    "The file system is not mounted on `{}`", self.mountpoint.display()
+                ),
+            }
+            match lazy_unmount(&self.mountpoint) {
+                Ok(()) => Ok(()),
+                Err(err) => io_error!(err.kind(), "Failed to call umount2(): {err}"),
+            }
         };
 
-        let mount_options = builder.into_options(&fuse_dev).await?;
+        match &self.namespace {
+            Some(ns) => run_in_namespace(ns.as_fd(), unmount),
+            None => unmount(),
+        }
+    }
 
-        let result = unsafe {
-            libc::mount(
-                mount_options.source.as_ptr(),
-                mount_options.target.as_ptr(),
-                FS_TYPE.as_ptr(),
-                mount_options.flags,
-                mount_options.options.as_ptr().cast()
-            )
+    /// Duplicates the state of the mount, to hand it over to another process.
+    pub(crate) fn export_state(&self) -> io::Result<MountState> {
+        let dup = |fd: BorrowedFd| match fd.try_clone_to_owned() {
+            Ok(fd) => Ok(fd),
+            Err(err) => io_error!(err.kind(), "Failed to duplicate the mount fds: {err}"),
         };
 
-        if result == -1 {
-            let err = io::Error::last_os_error();
-            io_error!(err.kind(), "Failed to call mount(): {err}");
+        Ok(MountState {
+            fuse_dev: dup(self.fuse_dev.as_fd())?,
+            namespace: self.namespace.as_ref().map(|ns| dup(ns.as_fd())).transpose()?,
+            watchdog: self.watchdog.as_ref().map(|w| dup(w.pipe())).transpose()?,
+            mountpoint: self.mountpoint.clone(),
+            blksize: self.blksize,
+            allow_idmap: self.allow_idmap,
+            connection_id: self.connection_id,
+        })
+    }
+
+    /// Takes over a mount exported by another process.
+    pub(crate) fn from_state(state: MountState) -> Self {
+        Self {
+            fuse_dev: Arc::new(state.fuse_dev),
+            mountpoint: state.mountpoint,
+            namespace: state.namespace,
+            blksize: state.blksize,
+            allow_idmap: state.allow_idmap,
+            connection_id: state.connection_id,
+            watchdog: state.watchdog.map(Watchdog::from_pipe),
         }
+    }
+
+    pub(super) async fn from_builder(mut builder: MountBuilder) -> io::Result<Self> {
+        let namespace = builder.take_namespace()?;
+        let fuse_dev = open_fuse_dev(namespace.as_ref()).await?;
+
+        let (mount_options, connection_id) = match &namespace {
+            Some(ns) => {
+                let (ns, dev) = (ns.try_clone()?, fuse_dev.try_clone()?);
+                blocking(move || {
+                    let rootmode = run_in_namespace(ns.as_fd(), || {
+                        builder.preflight_blocking()?;
+                        builder.rootmode_blocking()
+                    })?;
+                    let mount_options = builder.into_options_with(&dev, rootmode)?;
+                    let connection_id = run_in_namespace(ns.as_fd(), || mount(&mount_options))?;
+                    Ok((mount_options, connection_id))
+                }).await?
+            },
+            None => {
+                builder.preflight().await?;
+                let mount_options = builder.into_options(&fuse_dev).await?;
+                let connection_id = mount(&mount_options)?;
+                (mount_options, connection_id)
+            }
+        };
+
+        let watchdog = if mount_options.auto_unmount {
+            let ns = namespace.as_ref().map(|ns| ns.as_fd());
+            Some(Watchdog::spawn(&mount_options.target, ns, connection_id)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            fuse_dev: Arc::new(fuse_dev),
+            mountpoint: mount_options.mountpoint,
+            namespace,
+            blksize: mount_options.blksize,
+            allow_idmap: mount_options.allow_idmap,
+            connection_id,
+            watchdog,
+        })
+    }
+}
+
+impl DetachedMount {
+    pub(super) async fn from_builder(mut builder: MountBuilder) -> io::Result<Self> {
+        let namespace = builder.take_namespace()?;
+        let fuse_dev = open_fuse_dev(namespace.as_ref()).await?;
+
+        let (mount_options, mount_fd) = match &namespace {
+            Some(ns) => {
+                let (ns, dev) = (ns.try_clone()?, fuse_dev.try_clone()?);
+                blocking(move || {
+                    let rootmode = run_in_namespace(ns.as_fd(), || {
+                        builder.preflight_blocking()?;
+                        builder.rootmode_blocking()
+                    })?;
+                    let mount_options = builder.into_options_with(&dev, rootmode)?;
+                    let mount_fd = run_in_namespace(ns.as_fd(), || create(&mount_options))?;
+                    Ok((mount_options, mount_fd))
+                }).await?
+            },
+            None => {
+                builder.preflight().await?;
+                let mount_options = builder.into_options(&fuse_dev).await?;
+                let mount_fd = create(&mount_options)?;
+                (mount_options, mount_fd)
+            }
+        };
+
+        let connection_id = connection_id(Some(mount_fd.as_fd()), c"")?;
 
         Ok(Self {
             fuse_dev,
+            mount_fd,
+            namespace,
+            connection_id,
             mountpoint: mount_options.mountpoint,
+            blksize: mount_options.blksize,
+            allow_idmap: mount_options.allow_idmap,
+            auto_unmount: mount_options.auto_unmount,
+        })
+    }
+
+    /// Attaches the file system to the mountpoint set in the builder.
+    ///
+    /// If the builder had a mount namespace, the file system is attached in
+    /// that namespace.
+    pub fn attach(self) -> io::Result<Mount> {
+        let mountpoint = self.mountpoint.clone();
+        self.attach_to(mountpoint)
+    }
+
+    /// Attaches the file system to a different mountpoint.
+    pub fn attach_to(self, mountpoint: impl Into<PathBuf>) -> io::Result<Mount> {
+        let mountpoint = mountpoint.into();
+        let attach_at = || {
+            let mountpoint = std::fs::canonicalize(&mountpoint)?;
+            let target = CString::new(mountpoint.as_os_str().as_bytes())
+                .map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
+            attach(&self.mount_fd, &target)?;
+            Ok((mountpoint, target))
+        };
+
+        let ns = self.namespace.as_ref().map(|ns| ns.as_fd());
+        let (mountpoint, target) = match ns {
+            Some(ns) => run_in_namespace(ns, attach_at)?,
+            None => attach_at()?,
+        };
+
+        let watchdog = if self.auto_unmount {
+            Some(Watchdog::spawn(&target, ns, self.connection_id)?)
+        } else {
+            None
+        };
+
+        Ok(Mount {
+            fuse_dev: Arc::new(self.fuse_dev),
+            mountpoint,
+            namespace: self.namespace,
+            blksize: self.blksize,
+            allow_idmap: self.allow_idmap,
+            connection_id: self.connection_id,
+            watchdog,
         })
     }
+
+    /// Id of the kernel connection of the file system.
+    ///
+    /// See [`Mount::connection_id`].
+    #[inline]
+    pub fn connection_id(&self) -> u32 {
+        self.connection_id
+    }
+
+    /// The detached mount file descriptor.
+    #[inline]
+    pub fn mount_fd(&self) -> BorrowedFd<'_> {
+        self.mount_fd.as_fd()
+    }
+
+    /// The FUSE device file descriptor.
+    #[inline]
+    pub fn fuse_dev(&self) -> BorrowedFd<'_> {
+        self.fuse_dev.as_fd()
+    }
+}
+
+/// Opens the FUSE device, in the mount namespace `ns` if set.
+///
+/// The kernel only accepts a device opened in the user namespace that owns
+/// the mount, so it is opened by the helper that joins `ns`.
+async fn open_fuse_dev(ns: Option<&OwnedFd>) -> io::Result<OwnedFd> {
+    const FUSE_DEVICE: &str = "/dev/fuse";
+
+    let fuse_dev = match ns {
+        Some(ns) => {
+            let ns = ns.try_clone()?;
+            blocking(move || run_in_namespace(ns.as_fd(), || {
+                let dev = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(FUSE_DEVICE)?;
+                Ok(dev.into())
+            })).await
+        },
+        None => match OpenOptions::new().read(true).write(true).open(FUSE_DEVICE).await {
+            Ok(dev) => Ok(dev.into_std().await.into()),
+            Err(err) => Err(err),
+        },
+    };
+
+    match fuse_dev {
+        Ok(dev) => Ok(dev),
+        Err(e) if e.kind() == ErrorKind::NotFound => io_error!(
+            ErrorKind::NotFound,
+            "FUSE device file not fount `{FUSE_DEVICE}`. Try `modprobe fuse`"
+        ),
+        Err(e) => Err(e)
+    }
+}
+
+/// Runs blocking work, like waiting for the namespace helper, on the blocking
+/// threads of tokio.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static
+) -> io::Result<T> {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(err) => Err(io::Error::other(err)),
+    }
+}
+
+/// Mounts the file system, returns its connection id.
+fn mount(mount_options: &MountOptions) -> io::Result<u32> {
+    match create_mount(mount_options)? {
+        Some(mount_fd) => attach(&mount_fd, &mount_options.target)?,
+        // The new mount API is not available, use the legacy one
+        None => legacy_mount(mount_options)?,
+    }
+    connection_id(None, &mount_options.target)
+}
+
+/// Creates a detached mount of the file system.
+fn create(mount_options: &MountOptions) -> io::Result<OwnedFd> {
+    match create_mount(mount_options)? {
+        Some(mount_fd) => Ok(mount_fd),
+        None => io_error!(
+            ErrorKind::Unsupported,
+            "Detached mounts require the new mount API"
+        ),
+    }
+}
+
+fn legacy_mount(mount_options: &MountOptions) -> io::Result<()> {
+    let options = mount_options.joined_options();
+    let result = unsafe {
+        libc::mount(
+            mount_options.source.as_ptr(),
+            mount_options.target.as_ptr(),
+            mount_options.fs_type.as_ptr(),
+            mount_options.flags,
+            options.as_ptr().cast()
+        )
+    };
+
+    if result == -1 {
+        let err = io::Error::last_os_error();
+        io_error!(err.kind(), "Failed to call mount(): {err}");
+    }
+
+    Ok(())
+}
+
+/// Creates a detached mount using `fsopen(2)` and `fsmount(2)`.
+///
+/// Returns `None` if the new mount API is not available.
+fn create_mount(mount_options: &MountOptions) -> io::Result<Option<OwnedFd>> {
+    let fs = match sys::fsopen(mount_options.fs_type) {
+        Ok(fs) => fs,
+        // ENOSYS on kernels before 5.2, seccomp filters can also use EPERM
+        Err(err) if matches!(err.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) => {
+            return Ok(None)
+        },
+        Err(err) => io_error!(err.kind(), "Failed to call fsopen(): {err}"),
+    };
+
+    let fs_error = |call: &str, err: io::Error| {
+        let log = sys::read_fs_log(fs.as_fd());
+        let mut msg = format!("Failed to call {call}: {err}");
+        for line in log {
+            msg.push_str("\n  ");
+            msg.push_str(&line);
+        }
+        io::Error::new(err.kind(), msg)
+    };
+
+    sys::fsconfig_set_string(fs.as_fd(), c"source", &mount_options.source)
+        .map_err(|err| fs_error("fsconfig() for `source`", err))?;
+
+    for option in &mount_options.options {
+        let result = match &option.value {
+            Some(value) => sys::fsconfig_set_string(fs.as_fd(), option.key, value),
+            None => sys::fsconfig_set_flag(fs.as_fd(), option.key),
+        };
+        result.map_err(|err| {
+            let key = option.key.to_string_lossy();
+            fs_error(&format!("fsconfig() for `{key}`"), err)
+        })?;
+    }
+
+    let (sb_flags, attr_flags) = split_flags(mount_options.flags);
+    for flag in sb_flags {
+        sys::fsconfig_set_flag(fs.as_fd(), flag)
+            .map_err(|err| fs_error("fsconfig()", err))?;
+    }
+
+    sys::fsconfig_create(fs.as_fd())
+        .map_err(|err| fs_error("fsconfig() to create the superblock", err))?;
+
+    let mount_fd = sys::fsmount(fs.as_fd(), attr_flags)
+        .map_err(|err| fs_error("fsmount()", err))?;
+
+    Ok(Some(mount_fd))
+}
+
+fn connection_id(dir: Option<BorrowedFd>, path: &CStr) -> io::Result<u32> {
+    match sys::device_number(dir, path) {
+        Ok((major, minor)) => Ok(mountinfo::connection_id(major, minor)),
+        Err(err) => io_error!(err.kind(), "Failed to call statx() on the mount: {err}"),
+    }
+}
+
+fn attach(mount_fd: &OwnedFd, target: &CStr) -> io::Result<()> {
+    if let Err(err) = sys::move_mount(mount_fd.as_fd(), target) {
+        io_error!(err.kind(), "Failed to call move_mount(): {err}");
+    }
+    Ok(())
+}
+
+/// Splits the `mount(2)` flags into superblock flags, set with `fsconfig(2)`,
+/// and mount attributes, set with `fsmount(2)`.
+fn split_flags(flags: u64) -> (Vec<&'static CStr>, libc::c_uint) {
+    const SB_FLAGS: &[(u64, &CStr)] = &[
+        (libc::MS_RDONLY, c"ro"),
+        (libc::MS_SYNCHRONOUS, c"sync"),
+        (libc::MS_DIRSYNC, c"dirsync"),
+    ];
+    const ATTR_FLAGS: &[(u64, libc::c_uint)] = &[
+        (libc::MS_RDONLY, sys::MOUNT_ATTR_RDONLY),
+        (libc::MS_NOSUID, sys::MOUNT_ATTR_NOSUID),
+        (libc::MS_NODEV, sys::MOUNT_ATTR_NODEV),
+        (libc::MS_NOEXEC, sys::MOUNT_ATTR_NOEXEC),
+        (libc::MS_NOATIME, sys::MOUNT_ATTR_NOATIME),
+        (libc::MS_NODIRATIME, sys::MOUNT_ATTR_NODIRATIME),
+    ];
+
+    let sb_flags = SB_FLAGS.iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|&(_, name)| name)
+        .collect();
+    let attr_flags = ATTR_FLAGS.iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .fold(0, |attrs, (_, attr)| attrs | attr);
+
+    (sb_flags, attr_flags)
+}
+
+impl AsFd for Mount {
+    #[inline]
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fuse_dev.as_fd()
+    }
+}
+
+impl AsRawFd for Mount {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.fuse_dev.as_raw_fd()
+    }
 }
@@ -0,0 +1,144 @@
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+
+/// An entry of `/proc/self/mountinfo`.
+///
+/// The format of the entries is described in `proc_pid_mountinfo(5)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    /// Unique id of the mount.
+    pub mount_id: u32,
+    /// Id of the parent mount.
+    pub parent_id: u32,
+    /// Major number of the device of the file system.
+    pub major: u32,
+    /// Minor number of the device of the file system.
+    pub minor: u32,
+    /// Path of the file system that is the root of the mount.
+    pub root: PathBuf,
+    /// Path of the mountpoint, relative to the root of the process.
+    pub mountpoint: PathBuf,
+    /// Per mount options.
+    pub mount_options: String,
+    /// Optional fields, like the propagation of the mount.
+    pub optional_fields: Vec<String>,
+    /// Type of the file system, `fuse.<subtype>` for FUSE mounts with a
+    /// subtype.
+    pub fs_type: String,
+    /// Mount source, for FUSE mounts this is the file system name.
+    pub source: String,
+    /// Per superblock options.
+    pub super_options: String,
+}
+
+impl MountInfo {
+    /// Reads the mounts of the mount namespace of the calling thread.
+    pub fn read_all() -> io::Result<Vec<Self>> {
+        // thread-self is used since the thread may have changed namespace
+        let mountinfo = std::fs::read("/proc/thread-self/mountinfo")?;
+        mountinfo
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+
+    /// Parses a line of a mountinfo file, without the newline.
+    pub fn parse(line: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid mountinfo line: {}", String::from_utf8_lossy(line))
+        );
+
+        let mut fields = line.split(|&b| b == b' ');
+        let mut next = || fields.next().ok_or_else(invalid);
+        let number = |field: &[u8]| std::str::from_utf8(field).ok()
+            .and_then(|field| field.parse().ok())
+            .ok_or_else(invalid);
+        let string = |field: &[u8]| {
+            String::from_utf8_lossy(&unescape(field)).into_owned()
+        };
+        let path = |field: &[u8]| PathBuf::from(OsString::from_vec(unescape(field)));
+
+        let mount_id = number(next()?)?;
+        let parent_id = number(next()?)?;
+        let device = next()?;
+        let colon = device.iter().position(|&b| b == b':').ok_or_else(invalid)?;
+        let major = number(&device[..colon])?;
+        let minor = number(&device[colon + 1..])?;
+        let root = path(next()?);
+        let mountpoint = path(next()?);
+        let mount_options = string(next()?);
+
+        let mut optional_fields = Vec::new();
+        loop {
+            match next()? {
+                b"-" => break,
+                field => optional_fields.push(string(field)),
+            }
+        }
+
+        let fs_type = string(next()?);
+        let source = string(next()?);
+        let super_options = string(next()?);
+
+        Ok(Self {
+            mount_id,
+            parent_id,
+            major,
+            minor,
+            root,
+            mountpoint,
+            mount_options,
+            optional_fields,
+            fs_type,
+            source,
+            super_options,
+        })
+    }
+
+    /// Whether this is a FUSE file system.
+    pub fn is_fuse(&self) -> bool {
+        self.fs_type == "fuse"
+            || self.fs_type == "fuseblk"
+            || self.fs_type.starts_with("fuse.")
+    }
+
+    /// Id of the FUSE connection of the file system, the name of its directory
+    /// in the fusectl file system.
+    pub fn connection_id(&self) -> u32 {
+        connection_id(self.major, self.minor)
+    }
+}
+
+/// Encodes a device number like the kernel does internally, this is also the
+/// id of FUSE connections.
+pub(super) fn connection_id(major: u32, minor: u32) -> u32 {
+    (major << 20) | minor
+}
+
+/// Decodes the octal escapes (`\040`) used for spaces, tabs, newlines and
+/// backslashes.
+fn unescape(field: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let escape = field.get(i + 1..i + 4)
+            .filter(|_| field[i] == b'\\')
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match escape {
+            Some(byte) => {
+                out.push(byte);
+                i += 4;
+            },
+            None => {
+                out.push(field[i]);
+                i += 1;
+            },
+        }
+    }
+    out
+}
@@ -0,0 +1,264 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::MetadataExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+/// `NS_GET_USERNS` from `linux/nsfs.h`
+const NS_GET_USERNS: u32 = 0xb701;
+
+/// Mount namespace the file system is mounted into.
+#[derive(Debug)]
+pub(super) enum Namespace {
+    Fd(OwnedFd),
+    Pid(u32),
+}
+
+impl Namespace {
+    pub(super) fn open(self) -> io::Result<OwnedFd> {
+        match self {
+            Self::Fd(fd) => Ok(fd),
+            Self::Pid(pid) => {
+                let path = format!("/proc/{pid}/ns/mnt");
+                match File::open(&path) {
+                    Ok(file) => Ok(file.into()),
+                    Err(err) => io_error!(
+                        err.kind(),
+                        "Failed to open mount namespace `{path}`: {err}"
+                    ),
+                }
+            }
+        }
+    }
+}
+
+/// Runs `f` in the mount namespace `ns`.
+///
+/// If `ns` belongs to the current user namespace, `f` runs on a helper thread
+/// that joins it, the thread is discarded once `f` returns. Otherwise the
+/// owner of `ns` must be joined first, which a multithreaded process cannot
+/// do, and `f` runs in a helper process instead, see [`run_cloned`].
+pub(super) fn run_in_namespace<T, F>(ns: BorrowedFd, f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send,
+    T: Send,
+{
+    if let Some(userns) = foreign_owner(ns) {
+        return run_cloned(Some((userns.as_fd(), ns)), f);
+    }
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            // Threads sharing the filesystem information with other threads
+            // cannot change mount namespace
+            if unsafe { libc::unshare(libc::CLONE_FS) } == -1 {
+                let err = io::Error::last_os_error();
+                io_error!(err.kind(), "Failed to call unshare(): {err}");
+            }
+
+            if unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNS) } == -1 {
+                let err = io::Error::last_os_error();
+                io_error!(err.kind(), "Failed to join the mount namespace: {err}");
+            }
+
+            f()
+        }).join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// Opens the user namespace owning the namespace `ns`, if it is not the one
+/// of the calling thread.
+///
+/// Returns `None` if the owner cannot be opened either, joining `ns` alone
+/// then reports the error.
+pub(super) fn foreign_owner(ns: BorrowedFd) -> Option<OwnedFd> {
+    let fd = unsafe { libc::ioctl(ns.as_raw_fd(), NS_GET_USERNS as _) };
+    if fd == -1 {
+        return None;
+    }
+    // SAFETY: the ioctl returned a new file descriptor
+    let owner = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    let owner_ns = owner.metadata().ok()?;
+    let current = fs::metadata("/proc/thread-self/ns/user").ok()?;
+    let same = owner_ns.dev() == current.dev() && owner_ns.ino() == current.ino();
+    (!same).then(|| owner.into())
+}
+
+/// Runs `f` in a child process, after joining the user namespace and then the
+/// mount namespace in `join`, and returns its result.
+///
+/// The child is its own thread group, so unlike a thread it can join or
+/// create another user namespace. It is cloned with `CLONE_VM` and
+/// `CLONE_FILES`, it shares the memory and the file descriptors of the
+/// calling process instead of getting a copy of them, like `fork()` would.
+/// The locks held by the other threads are then still released, and `f` can
+/// allocate and take locks as it would on a thread. `CLONE_VFORK` suspends
+/// the calling thread until the child exits, the child runs on its own stack
+/// but uses the thread-local storage of the calling thread meanwhile.
+pub(super) fn run_cloned<T, F>(join: Option<(BorrowedFd, BorrowedFd)>, f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T>,
+{
+    let stack = Stack::new()?;
+    let mut call = Call { join, f: Some(f), result: None };
+
+    let flags = libc::CLONE_VM | libc::CLONE_VFORK | libc::CLONE_FILES | libc::SIGCHLD;
+    // SAFETY: the call outlives the child, the calling thread is suspended
+    //         until the child exits
+    let pid = unsafe {
+        libc::clone(helper::<T, F>, stack.top(), flags, (&raw mut call).cast())
+    };
+    if pid == -1 {
+        let err = io::Error::last_os_error();
+        io_error!(err.kind(), "Failed to clone the namespace helper: {err}");
+    }
+
+    // The result is in the shared memory, the status is only reaped
+    let mut status = 0;
+    while unsafe { libc::waitpid(pid, &mut status, 0) } == -1
+        && io::Error::last_os_error().kind() == ErrorKind::Interrupted
+    {}
+
+    match call.result {
+        Some(Ok(result)) => result,
+        Some(Err(panic)) => panic::resume_unwind(panic),
+        None => io_error!(ErrorKind::Other, "The namespace helper exited without a result"),
+    }
+}
+
+/// Closure run by a helper process and its result, shared with the parent.
+struct Call<'a, T, F> {
+    join: Option<(BorrowedFd<'a>, BorrowedFd<'a>)>,
+    f: Option<F>,
+    result: Option<thread::Result<io::Result<T>>>,
+}
+
+/// Entry point of the helper process, `arg` points to the [`Call`].
+extern "C" fn helper<T, F>(arg: *mut libc::c_void) -> libc::c_int
+where
+    F: FnOnce() -> io::Result<T>,
+{
+    // SAFETY: the parent is suspended until the helper exits, so the call is
+    //         not used by anyone else
+    let call = unsafe { &mut *arg.cast::<Call<T, F>>() };
+    let Some(f) = call.f.take() else { return 1 };
+    let join = call.join;
+    call.result = Some(panic::catch_unwind(AssertUnwindSafe(move || {
+        if let Some((userns, ns)) = join {
+            join_namespaces(userns, ns)?;
+        }
+        f()
+    })));
+    0
+}
+
+/// Stack of a helper process, with a guard page below it.
+struct Stack {
+    base: *mut libc::c_void,
+}
+
+impl Stack {
+    /// Same size as the stacks of the threads spawned by std.
+    const SIZE: usize = 2 << 20;
+
+    fn new() -> io::Result<Self> {
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK;
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let base = unsafe { libc::mmap(std::ptr::null_mut(), Self::SIZE, prot, flags, -1, 0) };
+        if base == libc::MAP_FAILED {
+            let err = io::Error::last_os_error();
+            io_error!(err.kind(), "Failed to allocate the helper stack: {err}");
+        }
+        let stack = Self { base };
+
+        // An overflow hits the guard page and kills the helper
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        if unsafe { libc::mprotect(base, page, libc::PROT_NONE) } == -1 {
+            let err = io::Error::last_os_error();
+            io_error!(err.kind(), "Failed to protect the helper stack: {err}");
+        }
+        Ok(stack)
+    }
+
+    /// The stack grows down from its end.
+    fn top(&self) -> *mut libc::c_void {
+        self.base.wrapping_byte_add(Self::SIZE)
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base, Self::SIZE) };
+    }
+}
+
+fn join_namespaces(userns: BorrowedFd, ns: BorrowedFd) -> io::Result<()> {
+    if unsafe { libc::setns(userns.as_raw_fd(), libc::CLONE_NEWUSER) } == -1 {
+        let err = io::Error::last_os_error();
+        io_error!(err.kind(), "Failed to join the user namespace: {err}");
+    }
+    if unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNS) } == -1 {
+        let err = io::Error::last_os_error();
+        io_error!(err.kind(), "Failed to join the mount namespace: {err}");
+    }
+    Ok(())
+}
+
+/// Creates new user and mount namespaces, where the current user is mapped
+/// to root, and returns the mount namespace.
+///
+/// The namespaces are created by a helper process, the calling process
+/// stays where it is and may be multithreaded. Passing the namespace to
+/// [`MountBuilder::mount_namespace`](crate::MountBuilder::mount_namespace)
+/// mounts FUSE file systems without privileges, the mounts are only visible
+/// inside the new mount namespace, to the processes that join it together
+/// with its user namespace (`NS_GET_USERNS`).
+///
+/// This is intended for tests and sandboxes, it requires unprivileged user
+/// namespaces to be enabled.
+pub fn unshare_user_namespace() -> io::Result<OwnedFd> {
+    let uid = unsafe { libc::geteuid() };
+    let gid = unsafe { libc::getegid() };
+
+    run_cloned(None, || {
+        if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) } == -1 {
+            let err = io::Error::last_os_error();
+            io_error!(err.kind(), "Failed to call unshare(): {err}");
+        }
+
+        // setgroups must be denied before an unprivileged process writes
+        // gid_map
+        let maps = [
+            ("/proc/self/setgroups", "deny".to_owned()),
+            ("/proc/self/uid_map", format!("0 {uid} 1")),
+            ("/proc/self/gid_map", format!("0 {gid} 1")),
+        ];
+        for (path, content) in maps {
+            if let Err(err) = fs::write(path, content) {
+                io_error!(err.kind(), "Failed to write `{path}`: {err}");
+            }
+        }
+
+        // Keep the mounts from propagating back to the parent namespace
+        let result = unsafe {
+            libc::mount(
+                std::ptr::null(),
+                c"/".as_ptr(),
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                std::ptr::null()
+            )
+        };
+        if result == -1 {
+            let err = io::Error::last_os_error();
+            io_error!(err.kind(), "Failed to make the mounts private: {err}");
+        }
+
+        match File::open("/proc/self/ns/mnt") {
+            Ok(file) => Ok(file.into()),
+            Err(err) => io_error!(err.kind(), "Failed to open the mount namespace: {err}"),
+        }
+    })
+}
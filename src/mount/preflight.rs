@@ -0,0 +1,218 @@
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use super::mountinfo::MountInfo;
+
+/// Error found by the preflight checks of the mountpoint.
+///
+/// This error is returned wrapped in an [`io::Error`], it can be extracted
+/// with [`io::Error::get_ref`] and [`Error::downcast_ref`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PreflightError {
+    /// The mountpoint is a FUSE file system whose server is gone.
+    StaleMount { mountpoint: PathBuf, fs_type: String },
+    /// The stale mount could not be unmounted.
+    UnmountFailed { mountpoint: PathBuf, source: io::Error },
+    /// The mountpoint cannot be accessed.
+    Inaccessible { mountpoint: PathBuf, source: io::Error },
+    /// The type of the mountpoint doesn't match the root of the file system.
+    WrongType {
+        mountpoint: PathBuf,
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// The mountpoint must be owned by the user mounting the file system.
+    NotOwner { mountpoint: PathBuf, owner: u32, uid: u32 },
+    /// The mountpoint must be writable by the user mounting the file system.
+    NotWritable { mountpoint: PathBuf },
+}
+
+impl PreflightError {
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::StaleMount { .. } => io::ErrorKind::NotConnected,
+            Self::UnmountFailed { source, .. }
+            | Self::Inaccessible { source, .. } => source.kind(),
+            Self::WrongType { .. } => io::ErrorKind::InvalidInput,
+            Self::NotOwner { .. }
+            | Self::NotWritable { .. } => io::ErrorKind::PermissionDenied,
+        }
+    }
+}
+
+impl fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StaleMount { mountpoint, fs_type } => write!(
+                f,
+                "`{}` is a stale `{fs_type}` mount, its server is not running",
+                mountpoint.display()
+            ),
+            Self::UnmountFailed { mountpoint, source } => write!(
+                f,
+                "Failed to unmount the stale mount `{}`: {source}",
+                mountpoint.display()
+            ),
+            Self::Inaccessible { mountpoint, source } => write!(
+                f,
+                "Cannot access the mountpoint `{}`: {source}",
+                mountpoint.display()
+            ),
+            Self::WrongType { mountpoint, expected, found } => write!(
+                f,
+                "The mountpoint `{}` is a {found}, expected a {expected}",
+                mountpoint.display()
+            ),
+            Self::NotOwner { mountpoint, owner, uid } => write!(
+                f,
+                "The mountpoint `{}` is owned by {owner}, not by {uid}",
+                mountpoint.display()
+            ),
+            Self::NotWritable { mountpoint } => write!(
+                f,
+                "The mountpoint `{}` is not writable",
+                mountpoint.display()
+            ),
+        }
+    }
+}
+
+impl Error for PreflightError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::UnmountFailed { source, .. }
+            | Self::Inaccessible { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<PreflightError> for io::Error {
+    fn from(err: PreflightError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// Checks that `mountpoint` can be used to mount a file system whose root has
+/// the type in `rootmode`.
+///
+/// Stale FUSE mounts on the mountpoint are lazily unmounted if `unmount_stale`
+/// is set. This uses blocking calls, since it may run in a helper thread in
+/// another mount namespace.
+pub(super) fn check(
+    mountpoint: &Path,
+    rootmode: Option<u16>,
+    unmount_stale: bool
+) -> Result<(), PreflightError> {
+    let mountpoint = mountpoint.to_path_buf();
+
+    if let Some(stale) = find_stale_mount(&mountpoint) {
+        if !unmount_stale {
+            return Err(PreflightError::StaleMount {
+                mountpoint,
+                fs_type: stale.fs_type,
+            });
+        }
+        if let Err(source) = lazy_unmount(&mountpoint) {
+            return Err(PreflightError::UnmountFailed { mountpoint, source });
+        }
+    }
+
+    let metadata = match fs::metadata(&mountpoint) {
+        Ok(metadata) => metadata,
+        Err(source) => {
+            return Err(PreflightError::Inaccessible { mountpoint, source });
+        }
+    };
+
+    let found = metadata.mode() & libc::S_IFMT;
+    let expected = match rootmode {
+        Some(mode) => u32::from(mode) & libc::S_IFMT,
+        None if found == libc::S_IFREG => libc::S_IFREG,
+        None => libc::S_IFDIR,
+    };
+    if found != expected {
+        return Err(PreflightError::WrongType {
+            mountpoint,
+            expected: type_name(expected),
+            found: type_name(found),
+        });
+    }
+
+    let uid = unsafe { libc::geteuid() };
+    if uid != 0 {
+        // Same rules used by fusermount, the user must be able to write the
+        // mountpoint, and own it if it is a file or a sticky directory
+        let sticky = metadata.mode() & libc::S_ISVTX != 0;
+        if (metadata.is_file() || sticky) && metadata.uid() != uid {
+            return Err(PreflightError::NotOwner {
+                mountpoint,
+                owner: metadata.uid(),
+                uid,
+            });
+        }
+
+        let path = match CString::new(mountpoint.as_os_str().as_bytes()) {
+            Ok(path) => path,
+            Err(_) => {
+                let source = io::Error::from(io::ErrorKind::InvalidInput);
+                return Err(PreflightError::Inaccessible { mountpoint, source });
+            }
+        };
+        if unsafe { libc::access(path.as_ptr(), libc::W_OK) } == -1 {
+            return Err(PreflightError::NotWritable { mountpoint });
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds a FUSE mount on `mountpoint` whose server is gone.
+fn find_stale_mount(mountpoint: &Path) -> Option<MountInfo> {
+    // A stale mount cannot be canonicalized, since accessing it fails
+    let absolute = std::path::absolute(mountpoint).ok()?;
+    let path = match (absolute.parent(), absolute.file_name()) {
+        (Some(parent), Some(name)) => parent.canonicalize().ok()?.join(name),
+        _ => absolute,
+    };
+
+    // The last entry is the one on top of the mount stack
+    let mount = MountInfo::read_all().ok()?
+        .into_iter()
+        .rfind(|mount| mount.mountpoint == path)?;
+
+    let stale = mount.is_fuse() && matches!(
+        fs::metadata(&path),
+        Err(err) if err.raw_os_error() == Some(libc::ENOTCONN)
+    );
+    stale.then_some(mount)
+}
+
+pub(super) fn lazy_unmount(mountpoint: &Path) -> io::Result<()> {
+    let path = CString::new(mountpoint.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn type_name(mode: u32) -> &'static str {
+    match mode {
+        libc::S_IFDIR => "directory",
+        libc::S_IFREG => "regular file",
+        libc::S_IFLNK => "symbolic link",
+        libc::S_IFCHR => "character device",
+        libc::S_IFBLK => "block device",
+        libc::S_IFIFO => "fifo",
+        libc::S_IFSOCK => "socket",
+        _ => "unknown file type",
+    }
+}
@@ -0,0 +1,201 @@
+//! Wrappers for the syscalls of the new mount API.
+//!
+//! The constants are copied from `linux/mount.h`, since they are not
+//! available in every version of libc.
+
+use std::ffi::CStr;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, BorrowedFd};
+use std::ptr;
+
+const FSOPEN_CLOEXEC: libc::c_uint = 0x1;
+const FSMOUNT_CLOEXEC: libc::c_uint = 0x1;
+
+const FSCONFIG_SET_FLAG: libc::c_uint = 0;
+const FSCONFIG_SET_STRING: libc::c_uint = 1;
+const FSCONFIG_CMD_CREATE: libc::c_uint = 6;
+
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x4;
+
+const OPEN_TREE_CLONE: libc::c_uint = 0x1;
+const OPEN_TREE_CLOEXEC: libc::c_uint = libc::O_CLOEXEC as libc::c_uint;
+
+pub(super) const MOUNT_ATTR_RDONLY: libc::c_uint = 0x1;
+pub(super) const MOUNT_ATTR_NOSUID: libc::c_uint = 0x2;
+pub(super) const MOUNT_ATTR_NODEV: libc::c_uint = 0x4;
+pub(super) const MOUNT_ATTR_NOEXEC: libc::c_uint = 0x8;
+pub(super) const MOUNT_ATTR_NOATIME: libc::c_uint = 0x10;
+pub(super) const MOUNT_ATTR_NODIRATIME: libc::c_uint = 0x80;
+const MOUNT_ATTR_IDMAP: u64 = 0x100000;
+
+#[repr(C)]
+struct mount_attr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+fn check(result: libc::c_long) -> io::Result<libc::c_long> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+pub(super) fn fsopen(fs_type: &CStr) -> io::Result<OwnedFd> {
+    let fd = check(unsafe {
+        libc::syscall(libc::SYS_fsopen, fs_type.as_ptr(), FSOPEN_CLOEXEC)
+    })?;
+    // SAFETY: the syscall returned a new file descriptor
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as _) })
+}
+
+pub(super) fn fsconfig_set_flag(fs: BorrowedFd, key: &CStr) -> io::Result<()> {
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_fsconfig,
+            fs.as_raw_fd(),
+            FSCONFIG_SET_FLAG,
+            key.as_ptr(),
+            ptr::null::<libc::c_char>(),
+            0
+        )
+    })?;
+    Ok(())
+}
+
+pub(super) fn fsconfig_set_string(
+    fs: BorrowedFd,
+    key: &CStr,
+    value: &CStr
+) -> io::Result<()> {
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_fsconfig,
+            fs.as_raw_fd(),
+            FSCONFIG_SET_STRING,
+            key.as_ptr(),
+            value.as_ptr(),
+            0
+        )
+    })?;
+    Ok(())
+}
+
+pub(super) fn fsconfig_create(fs: BorrowedFd) -> io::Result<()> {
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_fsconfig,
+            fs.as_raw_fd(),
+            FSCONFIG_CMD_CREATE,
+            ptr::null::<libc::c_char>(),
+            ptr::null::<libc::c_char>(),
+            0
+        )
+    })?;
+    Ok(())
+}
+
+pub(super) fn fsmount(fs: BorrowedFd, attr_flags: libc::c_uint) -> io::Result<OwnedFd> {
+    let fd = check(unsafe {
+        libc::syscall(
+            libc::SYS_fsmount,
+            fs.as_raw_fd(),
+            FSMOUNT_CLOEXEC,
+            attr_flags
+        )
+    })?;
+    // SAFETY: the syscall returned a new file descriptor
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as _) })
+}
+
+pub(super) fn move_mount(mount: BorrowedFd, target: &CStr) -> io::Result<()> {
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            mount.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_FDCWD,
+            target.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH
+        )
+    })?;
+    Ok(())
+}
+
+pub(super) fn open_tree_clone(path: &CStr) -> io::Result<OwnedFd> {
+    let fd = check(unsafe {
+        libc::syscall(
+            libc::SYS_open_tree,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            OPEN_TREE_CLONE | OPEN_TREE_CLOEXEC
+        )
+    })?;
+    // SAFETY: the syscall returned a new file descriptor
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as _) })
+}
+
+pub(super) fn mount_setattr_idmap(
+    mount: BorrowedFd,
+    userns: BorrowedFd
+) -> io::Result<()> {
+    let attr = mount_attr {
+        attr_set: MOUNT_ATTR_IDMAP,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: userns.as_raw_fd() as u64,
+    };
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            mount.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH,
+            &attr as *const mount_attr,
+            std::mem::size_of::<mount_attr>()
+        )
+    })?;
+    Ok(())
+}
+
+/// Device number of the file `path`, relative to `dir`.
+///
+/// The attributes are not refreshed, so that a FUSE file system doesn't
+/// receive a request that could block while the session is not running.
+pub(super) fn device_number(
+    dir: Option<BorrowedFd>,
+    path: &CStr
+) -> io::Result<(u32, u32)> {
+    let mut flags = libc::AT_STATX_DONT_SYNC;
+    if path.is_empty() {
+        flags |= libc::AT_EMPTY_PATH;
+    }
+    let dir = dir.map_or(libc::AT_FDCWD, |dir| dir.as_raw_fd());
+    let mut stat = std::mem::MaybeUninit::<libc::statx>::uninit();
+    check(unsafe {
+        libc::statx(dir, path.as_ptr(), flags, 0, stat.as_mut_ptr()).into()
+    })?;
+    // SAFETY: statx filled the structure
+    let stat = unsafe { stat.assume_init() };
+    Ok((stat.stx_dev_major, stat.stx_dev_minor))
+}
+
+/// Reads the messages logged by the kernel in the file system context.
+pub(super) fn read_fs_log(fs: BorrowedFd) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let len = unsafe {
+            libc::read(fs.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len())
+        };
+        // The log is empty once read fails with ENODATA
+        if len <= 0 {
+            break messages;
+        }
+        let message = String::from_utf8_lossy(&buf[..len as usize]);
+        messages.push(message.trim_end().to_owned());
+    }
+}
@@ -0,0 +1,214 @@
+use std::ffi::CStr;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+
+use super::namespace::foreign_owner;
+use super::{mountinfo, sys};
+
+/// Handle to a process that unmounts the file system once the handle is
+/// dropped, or when the server process exits.
+///
+/// The watchdog waits for the end of file on a pipe, whose write end is only
+/// held by this handle.
+pub(super) struct Watchdog {
+    pipe: OwnedFd,
+}
+
+impl Watchdog {
+    /// Takes over the watchdog of another process, from a duplicate of the
+    /// write end of its pipe.
+    pub(super) fn from_pipe(pipe: OwnedFd) -> Self {
+        Self { pipe }
+    }
+
+    /// The write end of the pipe, the watchdog fires once every copy is
+    /// closed.
+    pub(super) fn pipe(&self) -> BorrowedFd<'_> {
+        self.pipe.as_fd()
+    }
+
+    /// Spawns a watchdog for the mountpoint `target`, in the mount namespace
+    /// `ns` or in the current one. The watchdog also joins the user namespace
+    /// owning `ns`, if it isn't the current one.
+    ///
+    /// The watchdog only unmounts `target` if it is still the FUSE
+    /// connection `connection_id`, it may have been unmounted and replaced
+    /// by another file system meanwhile.
+    pub(super) fn spawn(
+        target: &CStr,
+        ns: Option<BorrowedFd>,
+        connection_id: u32
+    ) -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+            let err = io::Error::last_os_error();
+            io_error!(err.kind(), "Failed to create the watchdog pipe: {err}");
+        }
+        // SAFETY: pipe2 returned two new file descriptors
+        let (read_end, write_end) = unsafe {
+            (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1]))
+        };
+
+        let userns = ns.and_then(foreign_owner);
+        let mut keep = [
+            read_end.as_raw_fd(),
+            ns.map_or(-1, |ns| ns.as_raw_fd()),
+            userns.as_ref().map_or(-1, |userns| userns.as_raw_fd()),
+        ];
+        keep.sort_unstable();
+
+        // The process is forked twice, so that the watchdog is reparented to
+        // init and doesn't become a zombie of the server
+        let pid = unsafe { libc::fork() };
+        if pid == -1 {
+            let err = io::Error::last_os_error();
+            io_error!(err.kind(), "Failed to fork the watchdog: {err}");
+        }
+
+        if pid == 0 {
+            // SAFETY: only async-signal-safe functions are called in the child
+            unsafe {
+                if libc::fork() == 0 {
+                    let ns = ns.map(|ns| ns.as_raw_fd());
+                    let userns = userns.as_ref().map(|userns| userns.as_raw_fd());
+                    watchdog(read_end.as_raw_fd(), ns, userns, &keep, target, connection_id);
+                }
+                libc::_exit(0);
+            }
+        }
+
+        let mut status = 0;
+        while unsafe { libc::waitpid(pid, &mut status, 0) } == -1
+            && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+        {}
+
+        Ok(Self { pipe: write_end })
+    }
+}
+
+/// Body of the watchdog process.
+///
+/// This runs in a process forked from a multithreaded one, so only
+/// async-signal-safe functions can be used.
+unsafe fn watchdog(
+    pipe: i32,
+    ns: Option<i32>,
+    userns: Option<i32>,
+    keep: &[i32; 3],
+    target: &CStr,
+    connection_id: u32
+) -> ! {
+    unsafe {
+        // Detach from the terminal, so the watchdog survives signals sent to
+        // the server's process group
+        libc::setsid();
+
+        // Close every file descriptor except the pipe and the namespaces, the
+        // FUSE device must be closed for the connection to be aborted
+        let mut first = 0;
+        let mut closed = true;
+        for &fd in keep.iter().filter(|&&fd| fd >= 0) {
+            if fd > first {
+                closed &= libc::syscall(libc::SYS_close_range, first, fd - 1, 0) == 0;
+            }
+            first = fd + 1;
+        }
+        closed &= libc::syscall(libc::SYS_close_range, first, libc::c_uint::MAX, 0) == 0;
+        // close_range() is only available since Linux 5.9
+        if !closed {
+            close_except(keep);
+        }
+
+        // The owner of the mount namespace first, to have the capabilities
+        // needed to join it
+        if let Some(userns) = userns {
+            if libc::setns(userns, libc::CLONE_NEWUSER) == -1 {
+                libc::_exit(1);
+            }
+            libc::close(userns);
+        }
+        if let Some(ns) = ns {
+            if libc::setns(ns, libc::CLONE_NEWNS) == -1 {
+                libc::_exit(1);
+            }
+            libc::close(ns);
+        }
+
+        let mut buf = 0u8;
+        loop {
+            let result = libc::read(pipe, (&raw mut buf).cast(), 1);
+            if result == -1 && *libc::__errno_location() == libc::EINTR {
+                continue;
+            }
+            // The pipe is never written, any other result means it was closed
+            break;
+        }
+
+        // The mountpoint may hold another file system by now. Compare its
+        // device number with the connection, like the mount table would,
+        // statx() doesn't allocate nor wait for the dead server
+        let mounted = sys::device_number(None, target)
+            .is_ok_and(|(major, minor)| mountinfo::connection_id(major, minor) == connection_id);
+        if mounted {
+            libc::umount2(target.as_ptr(), libc::MNT_DETACH);
+        }
+        libc::_exit(0)
+    }
+}
+
+/// Closes the file descriptors not in `keep` without `close_range()`, from
+/// the entries of `/proc/self/fd`, or up to the limit of open files if it
+/// cannot be read.
+///
+/// This runs in the watchdog process, so only async-signal-safe functions
+/// can be used.
+unsafe fn close_except(keep: &[i32; 3]) {
+    unsafe {
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+        let dir = libc::open(c"/proc/self/fd".as_ptr(), flags);
+        if dir == -1 {
+            let mut limit: libc::rlimit = std::mem::zeroed();
+            if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0 {
+                let max = limit.rlim_cur.min(libc::c_int::MAX as libc::rlim_t) as i32;
+                for fd in (0..max).filter(|fd| !keep.contains(fd)) {
+                    libc::close(fd);
+                }
+            }
+            return;
+        }
+
+        let mut buf = [0u64; 512];
+        loop {
+            let len = libc::syscall(libc::SYS_getdents64, dir, buf.as_mut_ptr(), size_of_val(&buf));
+            if len <= 0 {
+                break;
+            }
+            let entries = std::slice::from_raw_parts(buf.as_ptr().cast::<u8>(), len as usize);
+
+            // Each entry is a `linux_dirent64`, the name of a descriptor is
+            // its number
+            let mut closed = false;
+            let mut offset = 0;
+            while offset + 19 < entries.len() {
+                let len = u16::from_ne_bytes([entries[offset + 16], entries[offset + 17]]);
+                let name = &entries[offset + 19..offset + usize::from(len)];
+                let name = &name[..name.iter().position(|&byte| byte == 0).unwrap_or(name.len())];
+                let fd = name.iter().try_fold(0i32, |fd, &digit| match digit {
+                    b'0'..=b'9' => fd.checked_mul(10)?.checked_add(i32::from(digit - b'0')),
+                    _ => None,
+                });
+                if let Some(fd) = fd.filter(|&fd| fd != dir && !keep.contains(&fd)) {
+                    libc::close(fd);
+                    closed = true;
+                }
+                offset += usize::from(len);
+            }
+            // The entries change as the descriptors are closed, the directory
+            // is read again until nothing is left to close
+            if closed {
+                libc::lseek(dir, 0, libc::SEEK_SET);
+            }
+        }
+        libc::close(dir);
+    }
+}
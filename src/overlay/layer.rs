@@ -0,0 +1,108 @@
+use std::ffi::OsStr;
+use std::future::Future;
+use std::pin::Pin;
+
+use crate::protocol::*;
+use crate::reply::DirBuf;
+use crate::{Errno, Filesystem, Request, SetattrRequest};
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+type Result<T> = std::result::Result<T, Errno>;
+
+/// Declares [`Layer`] with the given methods of [`Filesystem`], and
+/// implements it for every file system by boxing the futures.
+macro_rules! layer {
+    ($lt:lifetime; $(fn $name:ident($($arg:ident: $ty:ty),*) -> $out:ty;)*) => {
+        /// The methods of [`Filesystem`] used by the overlay, in a form that
+        /// can be made into an object, so that the layers can be of different
+        /// types.
+        pub(super) trait Layer: Send + Sync + 'static {
+            fn destroy(&self) -> BoxFuture<'_, ()>;
+            fn snapshot(&self) -> BoxFuture<'_, Result<Vec<u8>>>;
+            fn restore<'b>(&'b self, snapshot: &'b [u8]) -> BoxFuture<'b, Result<()>>;
+
+            $(fn $name<$lt>(
+                &$lt self,
+                req: &$lt Request
+                $(, $arg: $ty)*
+            ) -> BoxFuture<$lt, $out>;)*
+        }
+
+        impl<F: Filesystem> Layer for F {
+            fn destroy(&self) -> BoxFuture<'_, ()> {
+                Box::pin(Filesystem::destroy(self))
+            }
+
+            fn snapshot(&self) -> BoxFuture<'_, Result<Vec<u8>>> {
+                Box::pin(Filesystem::snapshot(self))
+            }
+
+            fn restore<'b>(&'b self, snapshot: &'b [u8]) -> BoxFuture<'b, Result<()>> {
+                Box::pin(Filesystem::restore(self, snapshot))
+            }
+
+            $(fn $name<$lt>(
+                &$lt self,
+                req: &$lt Request
+                $(, $arg: $ty)*
+            ) -> BoxFuture<$lt, $out> {
+                Box::pin(Filesystem::$name(self, req $(, $arg)*))
+            })*
+        }
+    };
+}
+
+layer! { 'a;
+    fn init() -> Result<()>;
+    fn lookup(parent: u64, name: &'a OsStr) -> Result<fuse_entry_out>;
+    fn forget(ino: u64, nlookup: u64) -> ();
+    fn getattr(ino: u64, arg: &'a fuse_getattr_in) -> Result<fuse_attr_out>;
+    fn setattr(ino: u64, arg: SetattrRequest) -> Result<fuse_attr_out>;
+    fn readlink(ino: u64) -> Result<Vec<u8>>;
+    fn symlink(parent: u64, name: &'a OsStr, target: &'a OsStr) -> Result<fuse_entry_out>;
+    fn mknod(parent: u64, arg: &'a fuse_mknod_in, name: &'a OsStr) -> Result<fuse_entry_out>;
+    fn mkdir(parent: u64, arg: &'a fuse_mkdir_in, name: &'a OsStr) -> Result<fuse_entry_out>;
+    fn unlink(parent: u64, name: &'a OsStr) -> Result<()>;
+    fn rmdir(parent: u64, name: &'a OsStr) -> Result<()>;
+    fn rename(
+        parent: u64,
+        name: &'a OsStr,
+        newparent: u64,
+        newname: &'a OsStr,
+        flags: u32
+    ) -> Result<()>;
+    fn link(ino: u64, newparent: u64, newname: &'a OsStr) -> Result<fuse_entry_out>;
+    fn open(ino: u64, arg: &'a fuse_open_in) -> Result<fuse_open_out>;
+    fn read(ino: u64, arg: &'a fuse_read_in) -> Result<Vec<u8>>;
+    fn write(ino: u64, arg: &'a fuse_write_in, data: &'a [u8]) -> Result<u32>;
+    fn statfs(ino: u64) -> Result<fuse_kstatfs>;
+    fn release(ino: u64, arg: &'a fuse_release_in) -> Result<()>;
+    fn fsync(ino: u64, arg: &'a fuse_fsync_in) -> Result<()>;
+    fn setxattr(
+        ino: u64,
+        arg: &'a fuse_setxattr_in,
+        name: &'a OsStr,
+        value: &'a [u8]
+    ) -> Result<()>;
+    fn getxattr(ino: u64, name: &'a OsStr) -> Result<Vec<u8>>;
+    fn listxattr(ino: u64) -> Result<Vec<u8>>;
+    fn removexattr(ino: u64, name: &'a OsStr) -> Result<()>;
+    fn flush(ino: u64, arg: &'a fuse_flush_in) -> Result<()>;
+    fn opendir(ino: u64, arg: &'a fuse_open_in) -> Result<fuse_open_out>;
+    fn readdir(ino: u64, arg: &'a fuse_read_in, buf: &'a mut DirBuf) -> Result<()>;
+    fn releasedir(ino: u64, arg: &'a fuse_release_in) -> Result<()>;
+    fn getlk(ino: u64, arg: &'a fuse_lk_in) -> Result<fuse_file_lock>;
+    fn setlk(ino: u64, arg: &'a fuse_lk_in, sleep: bool) -> Result<()>;
+    fn access(ino: u64, arg: &'a fuse_access_in) -> Result<()>;
+    fn create(
+        parent: u64,
+        arg: &'a fuse_create_in,
+        name: &'a OsStr
+    ) -> Result<(fuse_entry_out, fuse_open_out)>;
+    fn tmpfile(parent: u64, arg: &'a fuse_create_in) -> Result<(fuse_entry_out, fuse_open_out)>;
+    fn fallocate(ino: u64, arg: &'a fuse_fallocate_in) -> Result<()>;
+    fn lseek(ino: u64, arg: &'a fuse_lseek_in) -> Result<u64>;
+    fn copy_file_range(ino: u64, arg: &'a fuse_copy_file_range_in) -> Result<u32>;
+    fn syncfs(ino: u64) -> Result<()>;
+}
@@ -0,0 +1,1172 @@
+mod layer;
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::protocol::*;
+use crate::reply::DirBuf;
+use crate::testing::{DirEntry, parse_dirents};
+use crate::inode::{Reader, put_bytes};
+use crate::{Errno, Filesystem, InodeSnapshot, InodeTable, Request, SetattrRequest, TimeOrNow};
+use layer::Layer;
+
+type Result<T> = std::result::Result<T, Errno>;
+
+/// Index of the writable layer, the layers below are only read.
+const UPPER: usize = 0;
+/// Extended attribute set to `y` on the directories that hide the
+/// directories of the same name in the layers below.
+const OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+/// Prefix of the extended attributes used by the overlay, which are hidden.
+const OVERLAY_XATTRS: &[u8] = b"trusted.overlay.";
+/// Bytes read at once from the layers, to copy files and read directories.
+const CHUNK_SIZE: u32 = 128 * 1024;
+
+/// A file system made of a stack of file systems, like the overlay file
+/// system of Linux.
+///
+/// The files of a layer hide the files of the same name in the layers below
+/// it, except for directories which are merged. Only the upper layer is
+/// written to: a file of a lower layer is copied up, along with its parent
+/// directories, before it is changed.
+///
+/// The layers use the on-disk format of the overlay file system of Linux, so
+/// they can be shared with it. Removed files are hidden by whiteouts,
+/// character devices with the device number 0/0, and a directory replacing a
+/// removed one is made opaque with the `trusted.overlay.opaque` extended
+/// attribute so that it doesn't show the directories below it. The upper
+/// layer must support both, as [`MemFs`](crate::MemFs) does.
+///
+/// Like the overlay file system of Linux without `redirect_dir`, directories
+/// with files in the lower layers can't be renamed, the requests fail with
+/// [`Errno::EXDEV`] and tools like `mv` copy them instead. The permissions are
+/// not checked, the overlay must be mounted with
+/// [`MountBuilder::default_permissions`](crate::MountBuilder::default_permissions).
+pub struct OverlayFs {
+    /// The upper layer, then the lower layers from the top.
+    layers: Vec<Box<dyn Layer>>,
+    inodes: InodeTable<Layered, Inode>,
+    handles: Mutex<HashMap<u64, Handle>>,
+    next_fh: AtomicU64,
+    /// Taken while copying up a file, so that each file is copied once.
+    copy_up: tokio::sync::Mutex<()>,
+}
+
+/// A file of one of the layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Layered {
+    layer: usize,
+    nodeid: u64,
+}
+
+#[derive(Debug)]
+struct Inode {
+    /// File type, as in the `S_IFMT` bits of the mode.
+    file_type: u32,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// The files of the inode from the top, each holding a lookup. Only the
+    /// directories have more than one file, the lower ones are merged.
+    files: Vec<Layered>,
+    /// Entry the inode was last found at, where it is copied up.
+    parent: u64,
+    name: OsString,
+}
+
+#[derive(Debug, Clone)]
+enum Handle {
+    File { file: Layered, fh: u64 },
+    /// The merged entries, read again when the directory is rewound.
+    Dir(Arc<Mutex<Vec<DirEntry>>>),
+}
+
+/// Result of looking up a name in the layers of a directory.
+enum Found {
+    /// The files of the entry, each holding a lookup, and the entry of the
+    /// topmost one.
+    Entry(Vec<Layered>, fuse_entry_out),
+    /// The name was removed, a whiteout was found in the layer.
+    Whiteout(usize),
+    Missing,
+}
+
+impl OverlayFs {
+    /// Creates an overlay with only its upper layer, which is written to.
+    pub fn new(upper: impl Filesystem) -> Self {
+        Self {
+            layers: vec![Box::new(upper)],
+            inodes: root(1),
+            handles: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(1),
+            copy_up: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Adds a read-only layer below the other ones.
+    #[inline]
+    #[must_use = "An OverlayFs does nothing unless passed to a Session"]
+    pub fn lower(mut self, layer: impl Filesystem) -> Self {
+        self.layers.push(Box::new(layer));
+        self.inodes = root(self.layers.len());
+        self
+    }
+
+    #[inline]
+    fn layer(&self, file: Layered) -> &dyn Layer {
+        &*self.layers[file.layer]
+    }
+
+    #[inline]
+    fn upper(&self) -> &dyn Layer {
+        &*self.layers[UPPER]
+    }
+
+    fn inode(&self, ino: u64) -> Result<Arc<Inode>> {
+        self.inodes.get(ino).ok_or(Errno::ESTALE)
+    }
+
+    fn add_handle(&self, handle: Handle) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(fh, handle);
+        fh
+    }
+
+    /// The open file of a layer behind a file handle.
+    fn file(&self, fh: u64) -> Result<(Layered, u64)> {
+        match self.handles.lock().unwrap().get(&fh) {
+            Some(&Handle::File { file, fh }) => Ok((file, fh)),
+            Some(Handle::Dir(_)) => Err(Errno::EISDIR),
+            None => Err(Errno::EBADF),
+        }
+    }
+
+    /// Drops the lookups of files of the layers.
+    async fn release_files(&self, req: &Request, files: &[Layered]) {
+        for &file in files {
+            self.layer(file).forget(req, file.nodeid, 1).await;
+        }
+    }
+
+    /// Looks up a name in the layers of a directory, from the top.
+    async fn find(&self, req: &Request, dirs: &[Layered], name: &OsStr) -> Result<Found> {
+        let mut files = Vec::new();
+        let mut top = None;
+        for &dir in dirs {
+            let entry = match self.layer(dir).lookup(req, dir.nodeid, name).await {
+                Ok(entry) => entry,
+                Err(Errno::ENOENT) => continue,
+                Err(errno) => {
+                    self.release_files(req, &files).await;
+                    return Err(errno);
+                },
+            };
+            let file = Layered { layer: dir.layer, nodeid: entry.nodeid };
+            let is_dir = is_dir(&entry.attr);
+            // A whiteout or a file below a directory ends the directory
+            if is_whiteout(&entry.attr) || top.is_some() && !is_dir {
+                self.layer(file).forget(req, file.nodeid, 1).await;
+                if top.is_none() {
+                    return Ok(Found::Whiteout(dir.layer));
+                }
+                break;
+            }
+            files.push(file);
+            top.get_or_insert(entry);
+            if !is_dir || self.is_opaque(req, file).await {
+                break;
+            }
+        }
+        Ok(match top {
+            Some(entry) => Found::Entry(files, entry),
+            None => Found::Missing,
+        })
+    }
+
+    /// Whether a name is in the lower layers of a directory.
+    async fn lower_has(&self, req: &Request, dir: &Inode, name: &OsStr) -> Result<bool> {
+        let lower: Vec<_> = dir.files().into_iter().filter(|file| file.layer != UPPER).collect();
+        match self.find(req, &lower, name).await? {
+            Found::Entry(files, _) => {
+                self.release_files(req, &files).await;
+                Ok(true)
+            },
+            Found::Whiteout(_) | Found::Missing => Ok(false),
+        }
+    }
+
+    /// Counts a lookup of the inode of the files found at an entry.
+    async fn add_entry(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        files: Vec<Layered>,
+        mut entry: fuse_entry_out
+    ) -> Result<fuse_entry_out> {
+        let mut added = false;
+        let (ino, _) = self.inodes.lookup(files[0], || {
+            added = true;
+            Ok::<_, Errno>(Inode::new(entry.attr.mode & libc::S_IFMT, files.clone(), parent, name))
+        })?;
+        if !added {
+            self.release_files(req, &files).await;
+        }
+        entry.nodeid = ino;
+        entry.generation = 0;
+        entry.attr.ino = ino;
+        Ok(entry)
+    }
+
+    /// Adds a file just made in the upper layer.
+    async fn add_upper(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        entry: fuse_entry_out
+    ) -> Result<fuse_entry_out> {
+        let file = Layered { layer: UPPER, nodeid: entry.nodeid };
+        self.add_entry(req, parent, name, vec![file], entry).await
+    }
+
+    async fn is_opaque(&self, req: &Request, dir: Layered) -> bool {
+        let value = self.layer(dir).getxattr(req, dir.nodeid, OsStr::new(OPAQUE_XATTR)).await;
+        value.is_ok_and(|value| value == b"y")
+    }
+
+    async fn set_opaque(&self, req: &Request, dir: u64) -> Result<()> {
+        let arg = fuse_setxattr_in { size: 1, flags: 0, setxattr_flags: 0, padding: 0 };
+        self.upper().setxattr(req, dir, &arg, OsStr::new(OPAQUE_XATTR), b"y").await
+    }
+
+    /// Hides the files of the lower layers at an entry of the upper layer.
+    async fn whiteout(&self, req: &Request, parent: u64, name: &OsStr) -> Result<()> {
+        let arg = fuse_mknod_in { mode: libc::S_IFCHR, rdev: 0, umask: 0, padding: 0 };
+        let entry = self.upper().mknod(req, parent, &arg, name).await?;
+        self.upper().forget(req, entry.nodeid, 1).await;
+        Ok(())
+    }
+
+    /// Checks that a name can be created in a directory, and returns the
+    /// directory of the upper layer to create it in, along with whether a
+    /// whiteout had to be removed.
+    async fn prepare_create(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr
+    ) -> Result<(u64, bool)> {
+        let dir = self.inode(parent)?;
+        match self.find(req, &dir.files(), name).await? {
+            Found::Entry(files, _) => {
+                self.release_files(req, &files).await;
+                Err(Errno::EEXIST)
+            },
+            Found::Whiteout(UPPER) => {
+                let upper = dir.upper().ok_or(Errno::EIO)?;
+                self.upper().unlink(req, upper, name).await?;
+                Ok((upper, true))
+            },
+            Found::Whiteout(_) | Found::Missing => Ok((self.copy_up(req, parent).await?, false)),
+        }
+    }
+
+    /// The file of the upper layer of an inode, copied up with its parents
+    /// if it is only in the lower layers.
+    async fn copy_up(&self, req: &Request, ino: u64) -> Result<u64> {
+        let inode = self.inode(ino)?;
+        if let Some(upper) = inode.upper() {
+            return Ok(upper);
+        }
+        let (parent, name) = inode.origin();
+        let upper_parent = Box::pin(self.copy_up(req, parent)).await?;
+
+        let _guard = self.copy_up.lock().await;
+        if let Some(upper) = inode.upper() {
+            return Ok(upper);
+        }
+        let entry = self.copy_file(req, inode.top(), upper_parent, &name).await?;
+        let upper = Layered { layer: UPPER, nodeid: entry.nodeid };
+        let dropped = inode.copied_up(upper);
+        self.inodes.rekey(ino, upper);
+        self.release_files(req, &dropped).await;
+        Ok(upper.nodeid)
+    }
+
+    /// Copies a file of a lower layer to a directory of the upper layer,
+    /// with its owner, times and extended attributes.
+    async fn copy_file(
+        &self,
+        req: &Request,
+        lower: Layered,
+        parent: u64,
+        name: &OsStr
+    ) -> Result<fuse_entry_out> {
+        let layer = self.layer(lower);
+        let upper = self.upper();
+        let attr = layer.getattr(req, lower.nodeid, &getattr_in()).await?.attr;
+
+        let entry = match attr.mode & libc::S_IFMT {
+            libc::S_IFDIR => {
+                let arg = fuse_mkdir_in { mode: attr.mode & 0o7777, umask: 0 };
+                upper.mkdir(req, parent, &arg, name).await?
+            },
+            libc::S_IFLNK => {
+                let target = layer.readlink(req, lower.nodeid).await?;
+                upper.symlink(req, parent, name, OsStr::from_bytes(&target)).await?
+            },
+            libc::S_IFREG => {
+                let arg = fuse_create_in {
+                    flags: libc::O_WRONLY as u32,
+                    mode: attr.mode,
+                    umask: 0,
+                    open_flags: OpenInFlags::empty(),
+                };
+                let (entry, open) = upper.create(req, parent, &arg, name).await?;
+                let copied = self.copy_data(req, lower, entry.nodeid, open.fh).await;
+                let _ = upper.release(req, entry.nodeid, &release_in(open.fh, arg.flags)).await;
+                if let Err(errno) = copied {
+                    let _ = upper.unlink(req, parent, name).await;
+                    upper.forget(req, entry.nodeid, 1).await;
+                    return Err(errno);
+                }
+                entry
+            },
+            _ => {
+                let arg = fuse_mknod_in { mode: attr.mode, rdev: attr.rdev, umask: 0, padding: 0 };
+                upper.mknod(req, parent, &arg, name).await?
+            },
+        };
+
+        // Only a privileged upper layer can keep the owner, the copy belongs
+        // to the caller otherwise
+        let time = |secs, nanos| TimeOrNow::Time(UNIX_EPOCH + Duration::new(secs, nanos));
+        let times = SetattrRequest {
+            atime: Some(time(attr.atime, attr.atimensec)),
+            mtime: Some(time(attr.mtime, attr.mtimensec)),
+            ..SetattrRequest::default()
+        };
+        let owner = SetattrRequest { uid: Some(attr.uid), gid: Some(attr.gid), ..times.clone() };
+        if upper.setattr(req, entry.nodeid, owner).await.is_err() {
+            let _ = upper.setattr(req, entry.nodeid, times).await;
+        }
+
+        if let Ok(names) = layer.listxattr(req, lower.nodeid).await {
+            for name in xattr_names(&names) {
+                let Ok(value) = layer.getxattr(req, lower.nodeid, name).await else { continue };
+                let arg = fuse_setxattr_in {
+                    size: value.len() as u32,
+                    flags: 0,
+                    setxattr_flags: 0,
+                    padding: 0,
+                };
+                let _ = upper.setxattr(req, entry.nodeid, &arg, name, &value).await;
+            }
+        }
+        Ok(entry)
+    }
+
+    async fn copy_data(&self, req: &Request, lower: Layered, nodeid: u64, fh: u64) -> Result<()> {
+        let layer = self.layer(lower);
+        let arg = fuse_open_in { flags: libc::O_RDONLY as u32, open_flags: OpenInFlags::empty() };
+        let open = layer.open(req, lower.nodeid, &arg).await?;
+
+        let copied = async {
+            let mut offset = 0;
+            loop {
+                let data = layer.read(req, lower.nodeid, &read_in(open.fh, offset)).await?;
+                if data.is_empty() {
+                    return Ok(());
+                }
+                let mut written = 0;
+                while written < data.len() {
+                    let rest = &data[written..];
+                    let arg = fuse_write_in {
+                        fh,
+                        offset: offset + written as u64,
+                        size: rest.len() as u32,
+                        write_flags: WriteFlags::empty(),
+                        lock_owner: 0,
+                        flags: libc::O_WRONLY as u32,
+                        padding: 0,
+                    };
+                    match self.upper().write(req, nodeid, &arg, rest).await? {
+                        0 => return Err(Errno::EIO),
+                        size => written += size as usize,
+                    }
+                }
+                offset += data.len() as u64;
+            }
+        }.await;
+
+        let _ = layer.release(req, lower.nodeid, &release_in(open.fh, arg.flags)).await;
+        copied
+    }
+
+    /// Reads every entry of a directory of a layer.
+    async fn read_layer_dir(&self, req: &Request, dir: Layered) -> Result<Vec<DirEntry>> {
+        let layer = self.layer(dir);
+        let flags = (libc::O_RDONLY | libc::O_DIRECTORY) as u32;
+        let arg = fuse_open_in { flags, open_flags: OpenInFlags::empty() };
+        let open = layer.opendir(req, dir.nodeid, &arg).await?;
+
+        let mut entries = Vec::new();
+        let read = async {
+            let mut arg = read_in(open.fh, 0);
+            loop {
+                let mut buf = DirBuf::new(CHUNK_SIZE as usize);
+                layer.readdir(req, dir.nodeid, &arg, &mut buf).await?;
+                let batch = parse_dirents(buf.as_bytes())?;
+                let Some(last) = batch.last() else { return Ok(()) };
+                arg.offset = last.offset;
+                entries.extend(batch);
+            }
+        }.await;
+
+        let _ = layer.releasedir(req, dir.nodeid, &release_in(open.fh, flags)).await;
+        read.map(|()| entries)
+    }
+
+    /// Reads the entries of the layers of a directory, without `.` and `..`,
+    /// the entries hidden by upper layers and the whiteouts.
+    async fn read_dir(&self, req: &Request, dirs: &[Layered]) -> Result<Vec<DirEntry>> {
+        let mut seen = HashSet::new();
+        let mut entries = Vec::new();
+        for &dir in dirs {
+            for entry in self.read_layer_dir(req, dir).await? {
+                if entry.name == "." || entry.name == ".." || !seen.insert(entry.name.clone()) {
+                    continue;
+                }
+                if entry.file_type == libc::S_IFCHR
+                    && self.is_whiteout_entry(req, dir, &entry.name).await
+                {
+                    continue;
+                }
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn is_whiteout_entry(&self, req: &Request, dir: Layered, name: &OsStr) -> bool {
+        let layer = self.layer(dir);
+        match layer.lookup(req, dir.nodeid, name).await {
+            Ok(entry) => {
+                layer.forget(req, entry.nodeid, 1).await;
+                is_whiteout(&entry.attr)
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Removes the whiteouts left in a directory of the upper layer, so that
+    /// it can be removed or replaced.
+    async fn clear_whiteouts(&self, req: &Request, dir: u64) -> Result<()> {
+        let upper = Layered { layer: UPPER, nodeid: dir };
+        for entry in self.read_layer_dir(req, upper).await? {
+            if entry.name != "." && entry.name != ".." {
+                self.upper().unlink(req, dir, &entry.name).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the entry of a directory whose files are `files`, leaving a
+    /// whiteout if the lower layers have the name.
+    async fn remove(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        files: &[Layered],
+        is_dir: bool
+    ) -> Result<()> {
+        let dir = self.inode(parent)?;
+        let top = files[0];
+        let whiteout = top.layer != UPPER || self.lower_has(req, &dir, name).await?;
+        let upper_parent = self.copy_up(req, parent).await?;
+        if top.layer == UPPER {
+            if is_dir {
+                self.clear_whiteouts(req, top.nodeid).await?;
+                self.upper().rmdir(req, upper_parent, name).await?;
+            } else {
+                self.upper().unlink(req, upper_parent, name).await?;
+            }
+        }
+        if whiteout {
+            self.whiteout(req, upper_parent, name).await?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the destination of a rename can be replaced, and returns
+    /// whether the renamed directory must be made opaque.
+    async fn prepare_replace(
+        &self,
+        req: &Request,
+        newparent: u64,
+        newname: &OsStr,
+        is_dir: bool,
+        flags: u32
+    ) -> Result<bool> {
+        let newdir = self.inode(newparent)?;
+        match self.find(req, &newdir.files(), newname).await? {
+            Found::Entry(files, entry) => {
+                let replace = async {
+                    if flags & libc::RENAME_NOREPLACE != 0 {
+                        return Err(Errno::EEXIST);
+                    }
+                    match (is_dir, self::is_dir(&entry.attr)) {
+                        (false, true) => Err(Errno::EISDIR),
+                        (true, false) => Err(Errno::ENOTDIR),
+                        (false, false) => Ok(false),
+                        (true, true) => {
+                            if !self.read_dir(req, &files).await?.is_empty() {
+                                return Err(Errno::ENOTEMPTY);
+                            }
+                            if files[0].layer == UPPER {
+                                self.clear_whiteouts(req, files[0].nodeid).await?;
+                            }
+                            Ok(files.iter().any(|file| file.layer != UPPER))
+                        },
+                    }
+                }.await;
+                self.release_files(req, &files).await;
+                replace
+            },
+            // A directory can't replace the character device of a whiteout
+            Found::Whiteout(UPPER) if is_dir => {
+                let upper = newdir.upper().ok_or(Errno::EIO)?;
+                self.upper().unlink(req, upper, newname).await?;
+                Ok(true)
+            },
+            Found::Whiteout(_) | Found::Missing => Ok(false),
+        }
+    }
+}
+
+impl fmt::Debug for OverlayFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OverlayFs")
+            .field("layers", &self.layers.len())
+            .field("inodes", &self.inodes)
+            .field("handles", &self.handles)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Inode {
+    fn new(file_type: u32, files: Vec<Layered>, parent: u64, name: &OsStr) -> Self {
+        Self {
+            file_type,
+            state: Mutex::new(State { files, parent, name: name.to_owned() }),
+        }
+    }
+
+    #[inline]
+    fn is_dir(&self) -> bool {
+        self.file_type == libc::S_IFDIR
+    }
+
+    fn files(&self) -> Vec<Layered> {
+        self.state.lock().unwrap().files.clone()
+    }
+
+    fn top(&self) -> Layered {
+        self.state.lock().unwrap().files[0]
+    }
+
+    /// The file of the upper layer, if the inode has one.
+    fn upper(&self) -> Option<u64> {
+        let top = self.top();
+        (top.layer == UPPER).then_some(top.nodeid)
+    }
+
+    fn origin(&self) -> (u64, OsString) {
+        let state = self.state.lock().unwrap();
+        (state.parent, state.name.clone())
+    }
+
+    fn moved(&self, parent: u64, name: &OsStr) {
+        let mut state = self.state.lock().unwrap();
+        state.parent = parent;
+        state.name = name.to_owned();
+    }
+
+    /// Saves the inode identified by `key`, along with its files and the
+    /// entry it was found at.
+    fn save(&self, key: &Layered) -> Vec<u8> {
+        let state = self.state.lock().unwrap();
+        let mut data = Vec::new();
+        let mut put = |value: u64| data.extend_from_slice(&value.to_le_bytes());
+        put(key.layer as u64);
+        put(key.nodeid);
+        put(self.file_type.into());
+        put(state.parent);
+        put(state.files.len() as u64);
+        for file in &state.files {
+            put(file.layer as u64);
+            put(file.nodeid);
+        }
+        data.extend_from_slice(state.name.as_bytes());
+        data
+    }
+
+    /// Makes an inode saved by [`Inode::save`] again, `None` if it is
+    /// malformed or refers to a layer beyond `layers`.
+    fn load(data: &[u8], layers: usize) -> Option<(Layered, Self)> {
+        let mut reader = Reader(data);
+        let layered = |reader: &mut Reader| -> Option<Layered> {
+            let layer = usize::try_from(reader.u64().ok()?).ok()?;
+            (layer < layers).then_some(Layered { layer, nodeid: reader.u64().ok()? })
+        };
+        let key = layered(&mut reader)?;
+        let (file_type, parent) = (reader.u64().ok()?, reader.u64().ok()?);
+        let count = reader.u64().ok()?;
+        let files = (0..count).map(|_| layered(&mut reader)).collect::<Option<Vec<_>>>()?;
+        if files.is_empty() {
+            return None;
+        }
+        let name = OsStr::from_bytes(reader.0);
+        Some((key, Self::new(u32::try_from(file_type).ok()?, files, parent, name)))
+    }
+
+    /// Puts the copy of the inode on top of its files, and returns the files
+    /// it replaces, which are the files of the lower layers for anything but
+    /// a directory.
+    fn copied_up(&self, upper: Layered) -> Vec<Layered> {
+        let mut state = self.state.lock().unwrap();
+        state.files.insert(0, upper);
+        if self.is_dir() {
+            Vec::new()
+        } else {
+            state.files.split_off(1)
+        }
+    }
+}
+
+/// The inodes of an overlay of `layers` layers, with the roots of the layers
+/// merged as its root.
+fn root(layers: usize) -> InodeTable<Layered, Inode> {
+    let files: Vec<_> = (0..layers)
+        .map(|layer| Layered { layer, nodeid: FUSE_ROOT_ID })
+        .collect();
+    let root = Inode::new(libc::S_IFDIR, files.clone(), FUSE_ROOT_ID, OsStr::new(""));
+    InodeTable::new(files[0], root)
+}
+
+#[inline]
+fn is_dir(attr: &fuse_attr) -> bool {
+    attr.mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+#[inline]
+fn is_whiteout(attr: &fuse_attr) -> bool {
+    attr.mode & libc::S_IFMT == libc::S_IFCHR && attr.rdev == 0
+}
+
+#[inline]
+fn is_overlay_xattr(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(OVERLAY_XATTRS)
+}
+
+/// The names of a `listxattr(2)` list, without the ones of the overlay.
+fn xattr_names(list: &[u8]) -> impl Iterator<Item = &OsStr> {
+    list.split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+        .map(OsStr::from_bytes)
+        .filter(|name| !is_overlay_xattr(name))
+}
+
+fn getattr_in() -> fuse_getattr_in {
+    fuse_getattr_in { getattr_flags: GetattrFlags::empty(), dummy: 0, fh: 0 }
+}
+
+fn read_in(fh: u64, offset: u64) -> fuse_read_in {
+    fuse_read_in {
+        fh,
+        offset,
+        size: CHUNK_SIZE,
+        read_flags: ReadFlags::empty(),
+        lock_owner: 0,
+        flags: libc::O_RDONLY as u32,
+        padding: 0,
+    }
+}
+
+fn release_in(fh: u64, flags: u32) -> fuse_release_in {
+    fuse_release_in { fh, flags, release_flags: 0, lock_owner: 0 }
+}
+
+impl Filesystem for OverlayFs {
+    async fn init(&self, req: &Request) -> Result<()> {
+        for layer in &self.layers {
+            layer.init(req).await?;
+        }
+        Ok(())
+    }
+
+    async fn destroy(&self) {
+        self.handles.lock().unwrap().clear();
+        self.inodes.clear();
+        for layer in &self.layers {
+            layer.destroy().await;
+        }
+    }
+
+    /// Saves the inodes of the layers, then those of the overlay which refer
+    /// to them.
+    async fn snapshot(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.layers.len() as u64).to_le_bytes());
+        for layer in &self.layers {
+            put_bytes(&mut bytes, &layer.snapshot().await?);
+        }
+        let snapshot = self.inodes.snapshot(|key, inode| Some(inode.save(key)));
+        put_bytes(&mut bytes, &snapshot.to_bytes());
+        Ok(bytes)
+    }
+
+    async fn restore(&self, snapshot: &[u8]) -> Result<()> {
+        let mut reader = Reader(snapshot);
+        if reader.u64()? != self.layers.len() as u64 {
+            return Err(Errno::EINVAL);
+        }
+        for layer in &self.layers {
+            let snapshot = reader.bytes()?;
+            if !snapshot.is_empty() {
+                layer.restore(snapshot).await?;
+            }
+        }
+        let snapshot = InodeSnapshot::from_bytes(reader.bytes()?)?;
+        reader.finish()?;
+        let layers = self.layers.len();
+        self.inodes.restore(snapshot, |_, data| Inode::load(&data, layers));
+        Ok(())
+    }
+
+    async fn lookup(&self, req: &Request, parent: u64, name: &OsStr) -> Result<fuse_entry_out> {
+        let dir = self.inode(parent)?;
+        match self.find(req, &dir.files(), name).await? {
+            Found::Entry(files, entry) => self.add_entry(req, parent, name, files, entry).await,
+            Found::Whiteout(_) | Found::Missing => Err(Errno::ENOENT),
+        }
+    }
+
+    async fn forget(&self, req: &Request, ino: u64, nlookup: u64) {
+        if let Some(inode) = self.inodes.forget(ino, nlookup) {
+            self.release_files(req, &inode.files()).await;
+        }
+    }
+
+    async fn getattr(
+        &self,
+        req: &Request,
+        ino: u64,
+        _arg: &fuse_getattr_in
+    ) -> Result<fuse_attr_out> {
+        let top = self.inode(ino)?.top();
+        let mut out = self.layer(top).getattr(req, top.nodeid, &getattr_in()).await?;
+        out.attr.ino = ino;
+        Ok(out)
+    }
+
+    async fn setattr(
+        &self,
+        req: &Request,
+        ino: u64,
+        mut arg: SetattrRequest
+    ) -> Result<fuse_attr_out> {
+        let upper = self.copy_up(req, ino).await?;
+        // Handles opened before the copy up are on the lower layer
+        arg.fh = arg.fh
+            .and_then(|fh| self.file(fh).ok())
+            .and_then(|(file, fh)| (file.layer == UPPER).then_some(fh));
+        let mut out = self.upper().setattr(req, upper, arg).await?;
+        out.attr.ino = ino;
+        Ok(out)
+    }
+
+    async fn readlink(&self, req: &Request, ino: u64) -> Result<Vec<u8>> {
+        let top = self.inode(ino)?.top();
+        self.layer(top).readlink(req, top.nodeid).await
+    }
+
+    async fn symlink(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        target: &OsStr
+    ) -> Result<fuse_entry_out> {
+        let (upper_parent, _) = self.prepare_create(req, parent, name).await?;
+        let entry = self.upper().symlink(req, upper_parent, name, target).await?;
+        self.add_upper(req, parent, name, entry).await
+    }
+
+    async fn mknod(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_mknod_in,
+        name: &OsStr
+    ) -> Result<fuse_entry_out> {
+        let (upper_parent, _) = self.prepare_create(req, parent, name).await?;
+        let entry = self.upper().mknod(req, upper_parent, arg, name).await?;
+        self.add_upper(req, parent, name, entry).await
+    }
+
+    async fn mkdir(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_mkdir_in,
+        name: &OsStr
+    ) -> Result<fuse_entry_out> {
+        let (upper_parent, whiteout) = self.prepare_create(req, parent, name).await?;
+        let entry = self.upper().mkdir(req, upper_parent, arg, name).await?;
+        // The directory replaces a removed one, whose files must stay hidden
+        if whiteout && let Err(errno) = self.set_opaque(req, entry.nodeid).await {
+            self.upper().forget(req, entry.nodeid, 1).await;
+            let _ = self.upper().rmdir(req, upper_parent, name).await;
+            return Err(errno);
+        }
+        self.add_upper(req, parent, name, entry).await
+    }
+
+    async fn unlink(&self, req: &Request, parent: u64, name: &OsStr) -> Result<()> {
+        let dir = self.inode(parent)?;
+        let Found::Entry(files, entry) = self.find(req, &dir.files(), name).await? else {
+            return Err(Errno::ENOENT);
+        };
+        let removed = match is_dir(&entry.attr) {
+            true => Err(Errno::EISDIR),
+            false => self.remove(req, parent, name, &files, false).await,
+        };
+        self.release_files(req, &files).await;
+        removed
+    }
+
+    async fn rmdir(&self, req: &Request, parent: u64, name: &OsStr) -> Result<()> {
+        let dir = self.inode(parent)?;
+        let Found::Entry(files, entry) = self.find(req, &dir.files(), name).await? else {
+            return Err(Errno::ENOENT);
+        };
+        let removed = async {
+            if !is_dir(&entry.attr) {
+                return Err(Errno::ENOTDIR);
+            }
+            if !self.read_dir(req, &files).await?.is_empty() {
+                return Err(Errno::ENOTEMPTY);
+            }
+            self.remove(req, parent, name, &files, true).await
+        }.await;
+        self.release_files(req, &files).await;
+        removed
+    }
+
+    async fn rename(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32
+    ) -> Result<()> {
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            return Err(Errno::EINVAL);
+        }
+        let dir = self.inode(parent)?;
+        let Found::Entry(files, entry) = self.find(req, &dir.files(), name).await? else {
+            return Err(Errno::ENOENT);
+        };
+        let renamed = async {
+            let is_dir = is_dir(&entry.attr);
+            if is_dir && files.iter().any(|file| file.layer != UPPER) {
+                return Err(Errno::EXDEV);
+            }
+            let whiteout = files[0].layer != UPPER || self.lower_has(req, &dir, name).await?;
+            let source = match files[0].layer {
+                UPPER => files[0].nodeid,
+                _ => {
+                    let (ino, _) = self.inodes.find(&files[0]).ok_or(Errno::EXDEV)?;
+                    self.copy_up(req, ino).await?
+                },
+            };
+            let upper_parent = self.copy_up(req, parent).await?;
+            let upper_newparent = self.copy_up(req, newparent).await?;
+            let opaque = self.prepare_replace(req, newparent, newname, is_dir, flags).await?;
+
+            self.upper().rename(req, upper_parent, name, upper_newparent, newname, 0).await?;
+            if opaque {
+                self.set_opaque(req, source).await?;
+            }
+            if whiteout {
+                self.whiteout(req, upper_parent, name).await?;
+            }
+            if let Some((_, inode)) = self.inodes.find(&Layered { layer: UPPER, nodeid: source }) {
+                inode.moved(newparent, newname);
+            }
+            Ok(())
+        }.await;
+        self.release_files(req, &files).await;
+        renamed
+    }
+
+    async fn link(
+        &self,
+        req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr
+    ) -> Result<fuse_entry_out> {
+        if self.inode(ino)?.is_dir() {
+            return Err(Errno::EPERM);
+        }
+        let upper = self.copy_up(req, ino).await?;
+        let (upper_parent, _) = self.prepare_create(req, newparent, newname).await?;
+        let entry = self.upper().link(req, upper, upper_parent, newname).await?;
+        self.add_upper(req, newparent, newname, entry).await
+    }
+
+    async fn open(&self, req: &Request, ino: u64, arg: &fuse_open_in) -> Result<fuse_open_out> {
+        let flags = arg.flags as i32;
+        let write = flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0;
+        let file = match write {
+            true => Layered { layer: UPPER, nodeid: self.copy_up(req, ino).await? },
+            false => self.inode(ino)?.top(),
+        };
+        let out = self.layer(file).open(req, file.nodeid, arg).await?;
+        let fh = self.add_handle(Handle::File { file, fh: out.fh });
+        Ok(fuse_open_out { fh, ..out })
+    }
+
+    async fn read(&self, req: &Request, _ino: u64, arg: &fuse_read_in) -> Result<Vec<u8>> {
+        let (file, fh) = self.file(arg.fh)?;
+        let arg = fuse_read_in { fh, ..arg.clone() };
+        self.layer(file).read(req, file.nodeid, &arg).await
+    }
+
+    async fn write(
+        &self,
+        req: &Request,
+        _ino: u64,
+        arg: &fuse_write_in,
+        data: &[u8]
+    ) -> Result<u32> {
+        let (file, fh) = self.file(arg.fh)?;
+        let arg = fuse_write_in { fh, ..arg.clone() };
+        self.layer(file).write(req, file.nodeid, &arg, data).await
+    }
+
+    async fn statfs(&self, req: &Request, _ino: u64) -> Result<fuse_kstatfs> {
+        self.upper().statfs(req, FUSE_ROOT_ID).await
+    }
+
+    async fn release(&self, req: &Request, _ino: u64, arg: &fuse_release_in) -> Result<()> {
+        let (file, fh) = self.file(arg.fh)?;
+        self.handles.lock().unwrap().remove(&arg.fh);
+        let arg = fuse_release_in { fh, ..arg.clone() };
+        self.layer(file).release(req, file.nodeid, &arg).await
+    }
+
+    async fn fsync(&self, req: &Request, _ino: u64, arg: &fuse_fsync_in) -> Result<()> {
+        let (file, fh) = self.file(arg.fh)?;
+        let arg = fuse_fsync_in { fh, ..arg.clone() };
+        self.layer(file).fsync(req, file.nodeid, &arg).await
+    }
+
+    async fn setxattr(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_setxattr_in,
+        name: &OsStr,
+        value: &[u8]
+    ) -> Result<()> {
+        if is_overlay_xattr(name) {
+            return Err(Errno::EPERM);
+        }
+        let upper = self.copy_up(req, ino).await?;
+        self.upper().setxattr(req, upper, arg, name, value).await
+    }
+
+    async fn getxattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        if is_overlay_xattr(name) {
+            return Err(Errno::ENODATA);
+        }
+        let top = self.inode(ino)?.top();
+        self.layer(top).getxattr(req, top.nodeid, name).await
+    }
+
+    async fn listxattr(&self, req: &Request, ino: u64) -> Result<Vec<u8>> {
+        let top = self.inode(ino)?.top();
+        let list = self.layer(top).listxattr(req, top.nodeid).await?;
+        let mut names = Vec::with_capacity(list.len());
+        for name in xattr_names(&list) {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    async fn removexattr(&self, req: &Request, ino: u64, name: &OsStr) -> Result<()> {
+        if is_overlay_xattr(name) {
+            return Err(Errno::EPERM);
+        }
+        let upper = self.copy_up(req, ino).await?;
+        self.upper().removexattr(req, upper, name).await
+    }
+
+    async fn flush(&self, req: &Request, _ino: u64, arg: &fuse_flush_in) -> Result<()> {
+        let (file, fh) = self.file(arg.fh)?;
+        let arg = fuse_flush_in { fh, ..arg.clone() };
+        self.layer(file).flush(req, file.nodeid, &arg).await
+    }
+
+    async fn opendir(
+        &self,
+        _req: &Request,
+        ino: u64,
+        _arg: &fuse_open_in
+    ) -> Result<fuse_open_out> {
+        if !self.inode(ino)?.is_dir() {
+            return Err(Errno::ENOTDIR);
+        }
+        let fh = self.add_handle(Handle::Dir(Arc::default()));
+        Ok(fuse_open_out { fh, open_flags: OpenOutFlags::empty(), backing_id: 0 })
+    }
+
+    async fn readdir(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_read_in,
+        buf: &mut DirBuf
+    ) -> Result<()> {
+        let Some(Handle::Dir(entries)) = self.handles.lock().unwrap().get(&arg.fh).cloned() else {
+            return Err(Errno::EBADF);
+        };
+        if arg.offset == 0 {
+            let inode = self.inode(ino)?;
+            let (parent, _) = inode.origin();
+            let dots = [(ino, "."), (parent, "..")].map(|(ino, name)| DirEntry {
+                ino,
+                offset: 0,
+                file_type: libc::S_IFDIR,
+                name: name.into(),
+            });
+            let merged = self.read_dir(req, &inode.files()).await?;
+            *entries.lock().unwrap() = dots.into_iter().chain(merged).collect();
+        }
+
+        let entries = entries.lock().unwrap();
+        for (index, entry) in entries.iter().enumerate().skip(arg.offset as usize) {
+            if !buf.push(entry.ino, index as u64 + 1, entry.file_type, &entry.name) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn releasedir(&self, _req: &Request, _ino: u64, arg: &fuse_release_in) -> Result<()> {
+        self.handles.lock().unwrap().remove(&arg.fh);
+        Ok(())
+    }
+
+    async fn fsyncdir(&self, _req: &Request, _ino: u64, _arg: &fuse_fsync_in) -> Result<()> {
+        Ok(())
+    }
+
+    async fn getlk(&self, req: &Request, _ino: u64, arg: &fuse_lk_in) -> Result<fuse_file_lock> {
+        let (file, fh) = self.file(arg.fh)?;
+        let arg = fuse_lk_in { fh, ..arg.clone() };
+        self.layer(file).getlk(req, file.nodeid, &arg).await
+    }
+
+    async fn setlk(&self, req: &Request, _ino: u64, arg: &fuse_lk_in, sleep: bool) -> Result<()> {
+        let (file, fh) = self.file(arg.fh)?;
+        let arg = fuse_lk_in { fh, ..arg.clone() };
+        self.layer(file).setlk(req, file.nodeid, &arg, sleep).await
+    }
+
+    async fn access(&self, req: &Request, ino: u64, arg: &fuse_access_in) -> Result<()> {
+        let top = self.inode(ino)?.top();
+        self.layer(top).access(req, top.nodeid, arg).await
+    }
+
+    async fn create(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_create_in,
+        name: &OsStr
+    ) -> Result<(fuse_entry_out, fuse_open_out)> {
+        let (upper_parent, _) = self.prepare_create(req, parent, name).await?;
+        let (entry, open) = self.upper().create(req, upper_parent, arg, name).await?;
+        let file = Layered { layer: UPPER, nodeid: entry.nodeid };
+        let fh = self.add_handle(Handle::File { file, fh: open.fh });
+        let entry = self.add_upper(req, parent, name, entry).await?;
+        Ok((entry, fuse_open_out { fh, ..open }))
+    }
+
+    async fn tmpfile(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_create_in
+    ) -> Result<(fuse_entry_out, fuse_open_out)> {
+        let upper_parent = self.copy_up(req, parent).await?;
+        let (entry, open) = self.upper().tmpfile(req, upper_parent, arg).await?;
+        let file = Layered { layer: UPPER, nodeid: entry.nodeid };
+        let fh = self.add_handle(Handle::File { file, fh: open.fh });
+        let entry = self.add_upper(req, parent, OsStr::new(""), entry).await?;
+        Ok((entry, fuse_open_out { fh, ..open }))
+    }
+
+    async fn fallocate(&self, req: &Request, _ino: u64, arg: &fuse_fallocate_in) -> Result<()> {
+        let (file, fh) = self.file(arg.fh)?;
+        let arg = fuse_fallocate_in { fh, ..arg.clone() };
+        self.layer(file).fallocate(req, file.nodeid, &arg).await
+    }
+
+    async fn lseek(&self, req: &Request, _ino: u64, arg: &fuse_lseek_in) -> Result<u64> {
+        let (file, fh) = self.file(arg.fh)?;
+        let arg = fuse_lseek_in { fh, ..arg.clone() };
+        self.layer(file).lseek(req, file.nodeid, &arg).await
+    }
+
+    async fn copy_file_range(
+        &self,
+        req: &Request,
+        _ino: u64,
+        arg: &fuse_copy_file_range_in
+    ) -> Result<u32> {
+        let (file_in, fh_in) = self.file(arg.fh_in)?;
+        let (file_out, fh_out) = self.file(arg.fh_out)?;
+        // The kernel copies the data itself across layers
+        if file_in.layer != file_out.layer {
+            return Err(Errno::EXDEV);
+        }
+        let arg = fuse_copy_file_range_in {
+            fh_in,
+            nodeid_out: file_out.nodeid,
+            fh_out,
+            ..arg.clone()
+        };
+        self.layer(file_in).copy_file_range(req, file_in.nodeid, &arg).await
+    }
+
+    async fn syncfs(&self, req: &Request, _ino: u64) -> Result<()> {
+        self.upper().syncfs(req, FUSE_ROOT_ID).await
+    }
+}
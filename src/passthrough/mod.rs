@@ -0,0 +1,839 @@
+mod sys;
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::io;
+use std::os::fd::{AsFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::protocol::*;
+use crate::reply::DirBuf;
+use crate::transport::DaxWindow;
+use crate::inode::Reader;
+use crate::{BackingFiles, BackingId, Errno, InodeSnapshot, InodeTable, Request, SetattrRequest};
+use crate::TimeOrNow;
+use sys::Result;
+
+/// Size of the buffer of `getdents64(2)`.
+const DIRENT_BUFFER: usize = 8192;
+
+/// A file system mirroring a directory of the host.
+///
+/// Each inode holds an `O_PATH` file descriptor of its file, so the files
+/// keep their identity when they are renamed outside of the mount, and the
+/// requests map to the `*at` syscalls relative to these descriptors.
+///
+/// When the server runs as root, the files are created with the user and
+/// group of the calling process, otherwise they belong to the server. POSIX
+/// locks, enabled with [`SessionConfig::locks`](crate::SessionConfig::locks),
+/// are emulated with open file description locks on the open files.
+#[derive(Debug)]
+pub struct PassthroughFs {
+    inodes: InodeTable<InodeKey, Inode>,
+    handles: Mutex<HashMap<u64, Arc<Handle>>>,
+    next_fh: AtomicU64,
+    timeout: Duration,
+    xattr: bool,
+    backing: Option<BackingFiles>,
+    /// Whether the files are created with the ids of the caller.
+    caller_ids: bool,
+}
+
+/// Identity of a file of the source directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct InodeKey {
+    dev: u64,
+    ino: u64,
+}
+
+#[derive(Debug)]
+struct Inode {
+    /// `O_PATH` file descriptor of the file.
+    fd: OwnedFd,
+    /// File type, as in the `S_IFMT` bits of the mode.
+    file_type: u32,
+}
+
+impl Inode {
+    #[inline]
+    fn is_symlink(&self) -> bool {
+        self.file_type == libc::S_IFLNK
+    }
+}
+
+/// An open file or directory.
+#[derive(Debug)]
+struct Handle {
+    fd: OwnedFd,
+    backing: Option<BackingId>,
+    /// Held while reading a directory, which moves the offset of `fd`.
+    readdir: Mutex<()>,
+}
+
+/// Filesystem ids of a caller, set on the current thread until dropped.
+struct CallerIds(Option<(u32, u32)>);
+
+impl Drop for CallerIds {
+    fn drop(&mut self) {
+        if let Some((uid, gid)) = self.0 {
+            sys::set_fs_ids(uid, gid);
+        }
+    }
+}
+
+impl PassthroughFs {
+    /// Mirrors the directory `source`.
+    ///
+    /// The umask of the process is cleared, since the kernel already applies
+    /// the umask of the callers to the modes of the files they create.
+    pub fn new(source: impl AsRef<Path>) -> io::Result<Self> {
+        let source = source.as_ref();
+        let opened = sys::cstr(source.as_os_str()).and_then(|path| {
+            let fd = sys::openat(None, &path, libc::O_PATH | libc::O_DIRECTORY, 0)?;
+            Ok((sys::fstat(fd.as_fd())?, fd))
+        });
+        let (stat, fd) = match opened {
+            Ok(opened) => opened,
+            Err(errno) => {
+                let err = io::Error::from(errno);
+                io_error!(err.kind(), "Failed to open {}: {err}", source.display());
+            },
+        };
+
+        unsafe { libc::umask(0) };
+        let root = Inode { fd, file_type: libc::S_IFDIR };
+        Ok(Self {
+            inodes: InodeTable::new(InodeKey { dev: stat.st_dev, ino: stat.st_ino }, root),
+            handles: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(1),
+            timeout: Duration::from_secs(1),
+            xattr: true,
+            backing: None,
+            caller_ids: unsafe { libc::geteuid() } == 0,
+        })
+    }
+
+    /// How long the kernel caches the entries and the attributes, defaults
+    /// to one second.
+    ///
+    /// Longer timeouts are only safe if the source directory is not modified
+    /// outside of the mount.
+    #[inline]
+    #[must_use = "A PassthroughFs does nothing unless passed to a Session"]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Serves the extended attributes of the files, enabled by default.
+    #[inline]
+    #[must_use = "A PassthroughFs does nothing unless passed to a Session"]
+    pub fn xattr(mut self, enable: bool) -> Self {
+        self.xattr = enable;
+        self
+    }
+
+    /// Opens the regular files with `FOPEN_PASSTHROUGH`, so that the kernel
+    /// reads and writes them directly.
+    ///
+    /// Passthrough must be negotiated with
+    /// [`SessionConfig::max_stack_depth`](crate::SessionConfig::max_stack_depth),
+    /// the files that can't be registered as backing files are served by the
+    /// file system instead.
+    #[inline]
+    #[must_use = "A PassthroughFs does nothing unless passed to a Session"]
+    pub fn passthrough(mut self, backing: BackingFiles) -> Self {
+        self.backing = Some(backing);
+        self
+    }
+
+    fn inode(&self, ino: u64) -> Result<Arc<Inode>> {
+        self.inodes.get(ino).ok_or(Errno::ESTALE)
+    }
+
+    fn handle(&self, fh: u64) -> Result<Arc<Handle>> {
+        self.handles.lock().unwrap().get(&fh).cloned().ok_or(Errno::EBADF)
+    }
+
+    fn add_handle(&self, fd: OwnedFd, passthrough: bool) -> fuse_open_out {
+        let backing = self.backing.as_ref()
+            .filter(|_| passthrough)
+            .and_then(|files| files.open(fd.as_fd()).ok());
+        let open_flags = match backing {
+            Some(_) => OpenOutFlags::FOPEN_PASSTHROUGH,
+            None => OpenOutFlags::empty(),
+        };
+
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        let handle = Handle { fd, backing, readdir: Mutex::new(()) };
+        self.handles.lock().unwrap().insert(fh, Arc::new(handle));
+        fuse_open_out { fh, open_flags, backing_id: backing.map_or(0, BackingId::get) }
+    }
+
+    fn remove_handle(&self, fh: u64) -> Option<Arc<Handle>> {
+        let handle = self.handles.lock().unwrap().remove(&fh)?;
+        if let (Some(files), Some(id)) = (&self.backing, handle.backing) {
+            let _ = files.close(id);
+        }
+        Some(handle)
+    }
+
+    /// Switches to the ids of the caller, for the requests that create files.
+    fn caller_ids(&self, req: &Request) -> CallerIds {
+        CallerIds(self.caller_ids.then(|| sys::set_fs_ids(req.uid(), req.gid())))
+    }
+
+    fn do_lookup(&self, parent: &Inode, name: &CStr) -> Result<fuse_entry_out> {
+        self.add_inode(sys::open_path(parent.fd.as_fd(), name)?)
+    }
+
+    /// Counts a lookup of the file referred to by an `O_PATH` file
+    /// descriptor.
+    fn add_inode(&self, fd: OwnedFd) -> Result<fuse_entry_out> {
+        let stat = sys::fstat(fd.as_fd())?;
+        let key = InodeKey { dev: stat.st_dev, ino: stat.st_ino };
+        let file_type = stat.st_mode & libc::S_IFMT;
+        let (nodeid, _) = self.inodes.lookup(key, || Ok::<_, Errno>(Inode { fd, file_type }))?;
+        Ok(self.entry(nodeid, &stat))
+    }
+
+    /// Saves an inode as its key, its file type and its path in the source
+    /// directory `root`, `None` if it is not below it anymore.
+    fn save_inode(root: &Path, key: &InodeKey, inode: &Inode) -> Option<Vec<u8>> {
+        let path = fd_path(&inode.fd).ok()?;
+        let path = path.strip_prefix(root).ok()?;
+        let mut data = Vec::new();
+        data.extend_from_slice(&key.dev.to_le_bytes());
+        data.extend_from_slice(&key.ino.to_le_bytes());
+        data.extend_from_slice(&inode.file_type.to_le_bytes());
+        data.extend_from_slice(path.as_os_str().as_bytes());
+        Some(data)
+    }
+
+    /// Opens an inode saved by [`PassthroughFs::save_inode`] again, `None` if
+    /// its path leads to another file, when it was removed or renamed
+    /// meanwhile.
+    fn load_inode(root: &Inode, data: &[u8]) -> Option<(InodeKey, Inode)> {
+        let mut reader = Reader(data);
+        let key = InodeKey { dev: reader.u64().ok()?, ino: reader.u64().ok()? };
+        let file_type = reader.u32().ok()?;
+        let path = match reader.0 {
+            b"" => c".".to_owned(),
+            path => sys::cstr(OsStr::from_bytes(path)).ok()?,
+        };
+        let fd = sys::open_path(root.fd.as_fd(), &path).ok()?;
+        let stat = sys::fstat(fd.as_fd()).ok()?;
+        if stat.st_dev != key.dev || stat.st_ino != key.ino {
+            return None;
+        }
+        Some((key, Inode { fd, file_type }))
+    }
+
+    fn entry(&self, nodeid: u64, stat: &libc::stat) -> fuse_entry_out {
+        fuse_entry_out {
+            nodeid,
+            generation: 0,
+            entry_valid: self.timeout.as_secs(),
+            attr_valid: self.timeout.as_secs(),
+            entry_valid_nsec: self.timeout.subsec_nanos(),
+            attr_valid_nsec: self.timeout.subsec_nanos(),
+            attr: sys::to_attr(stat),
+        }
+    }
+
+    fn attr_out(&self, stat: &libc::stat) -> fuse_attr_out {
+        fuse_attr_out {
+            attr_valid: self.timeout.as_secs(),
+            attr_valid_nsec: self.timeout.subsec_nanos(),
+            dummy: Padding::new(),
+            attr: sys::to_attr(stat),
+        }
+    }
+
+    /// Path to access the extended attributes of a file, `None` for symbolic
+    /// links, which can't be reopened.
+    fn xattr_path(&self, ino: u64) -> Result<Option<CString>> {
+        if !self.xattr {
+            return Err(Errno::ENOSYS);
+        }
+        let inode = self.inode(ino)?;
+        Ok((!inode.is_symlink()).then(|| sys::proc_path(inode.fd.as_fd())))
+    }
+
+    /// Reads the entries of a directory starting from `offset`, until `push`
+    /// returns `false` or the end of the directory.
+    fn read_dir(
+        &self,
+        fh: u64,
+        offset: u64,
+        mut push: impl FnMut(sys::Dirent) -> bool
+    ) -> Result<()> {
+        let handle = self.handle(fh)?;
+        let _guard = handle.readdir.lock().unwrap();
+        sys::lseek(handle.fd.as_fd(), offset, libc::SEEK_SET as u32)?;
+
+        let mut buf = vec![0u8; DIRENT_BUFFER];
+        loop {
+            let len = sys::getdents64(handle.fd.as_fd(), &mut buf)?;
+            if len == 0 {
+                return Ok(());
+            }
+            for dirent in sys::dirents(&buf[..len]) {
+                if !push(dirent) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+impl crate::Filesystem for PassthroughFs {
+    async fn destroy(&self) {
+        let handles: Vec<_> = self.handles.lock().unwrap().keys().copied().collect();
+        for fh in handles {
+            self.remove_handle(fh);
+        }
+        self.inodes.clear();
+    }
+
+    /// Saves the inodes by their path, the open files are not saved.
+    async fn snapshot(&self) -> Result<Vec<u8>> {
+        let root = fd_path(&self.inode(FUSE_ROOT_ID)?.fd)?;
+        let snapshot = self.inodes.snapshot(|key, inode| Self::save_inode(&root, key, inode));
+        Ok(snapshot.to_bytes())
+    }
+
+    /// Opens the saved inodes by their path in the source directory, those
+    /// that were removed or replaced meanwhile are left out.
+    async fn restore(&self, snapshot: &[u8]) -> Result<()> {
+        let snapshot = InodeSnapshot::from_bytes(snapshot)?;
+        let root = self.inode(FUSE_ROOT_ID)?;
+        self.inodes.restore(snapshot, |_, data| Self::load_inode(&root, &data));
+        Ok(())
+    }
+
+    async fn lookup(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<fuse_entry_out> {
+        let parent = self.inode(parent)?;
+        self.do_lookup(&parent, &sys::cstr(name)?)
+    }
+
+    async fn forget(&self, _req: &Request, ino: u64, nlookup: u64) {
+        self.inodes.forget(ino, nlookup);
+    }
+
+    async fn getattr(
+        &self,
+        _req: &Request,
+        ino: u64,
+        arg: &fuse_getattr_in
+    ) -> Result<fuse_attr_out> {
+        let stat = match arg.getattr_flags.contains(GetattrFlags::FUSE_GETATTR_FH) {
+            true => sys::fstat(self.handle(arg.fh)?.fd.as_fd())?,
+            false => sys::fstat(self.inode(ino)?.fd.as_fd())?,
+        };
+        Ok(self.attr_out(&stat))
+    }
+
+    async fn setattr(
+        &self,
+        _req: &Request,
+        ino: u64,
+        arg: SetattrRequest
+    ) -> Result<fuse_attr_out> {
+        let inode = self.inode(ino)?;
+        let handle = arg.fh.map(|fh| self.handle(fh)).transpose()?;
+        let fd = handle.as_ref().map_or(inode.fd.as_fd(), |handle| handle.fd.as_fd());
+
+        // The mode and the times are changed through /proc, which would follow
+        // symbolic links
+        if let Some(mode) = arg.mode {
+            if inode.is_symlink() {
+                return Err(Errno::EOPNOTSUPP);
+            }
+            sys::fchmodat(fd, mode & 0o7777)?;
+        }
+        if arg.uid.is_some() || arg.gid.is_some() {
+            sys::fchownat(fd, arg.uid.unwrap_or(u32::MAX), arg.gid.unwrap_or(u32::MAX))?;
+        }
+        if let Some(size) = arg.size {
+            match handle {
+                Some(_) => sys::ftruncate(fd, size)?,
+                None => sys::truncate(fd, size)?,
+            }
+        }
+        if arg.atime.is_some() || arg.mtime.is_some() {
+            let times = [timespec(arg.atime), timespec(arg.mtime)];
+            match handle {
+                Some(_) => sys::futimens(fd, &times)?,
+                None if inode.is_symlink() => return Err(Errno::EOPNOTSUPP),
+                None => sys::utimensat(fd, &times)?,
+            }
+        }
+
+        Ok(self.attr_out(&sys::fstat(fd)?))
+    }
+
+    async fn readlink(&self, _req: &Request, ino: u64) -> Result<Vec<u8>> {
+        sys::readlinkat(self.inode(ino)?.fd.as_fd())
+    }
+
+    async fn symlink(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        target: &OsStr
+    ) -> Result<fuse_entry_out> {
+        let parent = self.inode(parent)?;
+        let name = sys::cstr(name)?;
+        {
+            let _ids = self.caller_ids(req);
+            sys::symlinkat(&sys::cstr(target)?, parent.fd.as_fd(), &name)?;
+        }
+        self.do_lookup(&parent, &name)
+    }
+
+    async fn mknod(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_mknod_in,
+        name: &OsStr
+    ) -> Result<fuse_entry_out> {
+        let parent = self.inode(parent)?;
+        let name = sys::cstr(name)?;
+        {
+            let _ids = self.caller_ids(req);
+            sys::mknodat(parent.fd.as_fd(), &name, arg.mode, arg.rdev)?;
+        }
+        self.do_lookup(&parent, &name)
+    }
+
+    async fn mkdir(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_mkdir_in,
+        name: &OsStr
+    ) -> Result<fuse_entry_out> {
+        let parent = self.inode(parent)?;
+        let name = sys::cstr(name)?;
+        {
+            let _ids = self.caller_ids(req);
+            sys::mkdirat(parent.fd.as_fd(), &name, arg.mode)?;
+        }
+        self.do_lookup(&parent, &name)
+    }
+
+    async fn unlink(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<()> {
+        sys::unlinkat(self.inode(parent)?.fd.as_fd(), &sys::cstr(name)?, 0)
+    }
+
+    async fn rmdir(&self, _req: &Request, parent: u64, name: &OsStr) -> Result<()> {
+        sys::unlinkat(self.inode(parent)?.fd.as_fd(), &sys::cstr(name)?, libc::AT_REMOVEDIR)
+    }
+
+    async fn rename(
+        &self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32
+    ) -> Result<()> {
+        let parent = self.inode(parent)?;
+        let newparent = self.inode(newparent)?;
+        sys::renameat2(
+            parent.fd.as_fd(),
+            &sys::cstr(name)?,
+            newparent.fd.as_fd(),
+            &sys::cstr(newname)?,
+            flags
+        )
+    }
+
+    async fn link(
+        &self,
+        _req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr
+    ) -> Result<fuse_entry_out> {
+        let inode = self.inode(ino)?;
+        let newparent = self.inode(newparent)?;
+        let newname = sys::cstr(newname)?;
+        sys::linkat(inode.fd.as_fd(), newparent.fd.as_fd(), &newname)?;
+        self.do_lookup(&newparent, &newname)
+    }
+
+    async fn open(&self, _req: &Request, ino: u64, arg: &fuse_open_in) -> Result<fuse_open_out> {
+        let inode = self.inode(ino)?;
+        let fd = sys::reopen(inode.fd.as_fd(), arg.flags as i32)?;
+        Ok(self.add_handle(fd, inode.file_type == libc::S_IFREG))
+    }
+
+    async fn read(&self, _req: &Request, _ino: u64, arg: &fuse_read_in) -> Result<Vec<u8>> {
+        let handle = self.handle(arg.fh)?;
+        let (size, offset) = (arg.size, arg.offset);
+        blocking(move || sys::pread(handle.fd.as_fd(), size, offset)).await
+    }
+
+    async fn write(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        arg: &fuse_write_in,
+        data: &[u8]
+    ) -> Result<u32> {
+        let handle = self.handle(arg.fh)?;
+        let (data, offset) = (data.to_vec(), arg.offset);
+        blocking(move || sys::pwrite(handle.fd.as_fd(), &data, offset)).await
+    }
+
+    async fn statfs(&self, _req: &Request, ino: u64) -> Result<fuse_kstatfs> {
+        sys::fstatfs(self.inode(ino)?.fd.as_fd())
+    }
+
+    async fn release(&self, _req: &Request, _ino: u64, arg: &fuse_release_in) -> Result<()> {
+        let Some(handle) = self.remove_handle(arg.fh) else { return Ok(()) };
+        let flags = ReleaseFlags::from_bits_retain(arg.release_flags);
+        if flags.contains(ReleaseFlags::FUSE_RELEASE_FLOCK_UNLOCK) {
+            sys::flock(handle.fd.as_fd(), libc::LOCK_UN)?;
+        }
+        Ok(())
+    }
+
+    async fn fsync(&self, _req: &Request, _ino: u64, arg: &fuse_fsync_in) -> Result<()> {
+        let handle = self.handle(arg.fh)?;
+        let datasync = arg.fsync_flags.contains(FsyncFlags::FUSE_FSYNC_FDATASYNC);
+        blocking(move || sys::fsync(handle.fd.as_fd(), datasync)).await
+    }
+
+    async fn setxattr(
+        &self,
+        _req: &Request,
+        ino: u64,
+        arg: &fuse_setxattr_in,
+        name: &OsStr,
+        value: &[u8]
+    ) -> Result<()> {
+        let path = self.xattr_path(ino)?.ok_or(Errno::EPERM)?;
+        sys::setxattr(&path, &sys::cstr(name)?, value, arg.flags)
+    }
+
+    async fn getxattr(&self, _req: &Request, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        let path = self.xattr_path(ino)?.ok_or(Errno::ENODATA)?;
+        sys::getxattr(&path, &sys::cstr(name)?)
+    }
+
+    async fn listxattr(&self, _req: &Request, ino: u64) -> Result<Vec<u8>> {
+        match self.xattr_path(ino)? {
+            Some(path) => sys::listxattr(&path),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn removexattr(&self, _req: &Request, ino: u64, name: &OsStr) -> Result<()> {
+        let path = self.xattr_path(ino)?.ok_or(Errno::ENODATA)?;
+        sys::removexattr(&path, &sys::cstr(name)?)
+    }
+
+    async fn flush(&self, _req: &Request, _ino: u64, arg: &fuse_flush_in) -> Result<()> {
+        // Closing a duplicate reports the errors of the delayed writes of
+        // some file systems, like NFS
+        let fd = self.handle(arg.fh)?.fd.try_clone()?;
+        drop(fd);
+        Ok(())
+    }
+
+    async fn opendir(
+        &self,
+        _req: &Request,
+        ino: u64,
+        arg: &fuse_open_in
+    ) -> Result<fuse_open_out> {
+        let inode = self.inode(ino)?;
+        let flags = arg.flags as i32 | libc::O_RDONLY | libc::O_DIRECTORY;
+        let fd = sys::reopen(inode.fd.as_fd(), flags & !libc::O_ACCMODE)?;
+        Ok(self.add_handle(fd, false))
+    }
+
+    async fn readdir(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        arg: &fuse_read_in,
+        buf: &mut DirBuf
+    ) -> Result<()> {
+        self.read_dir(arg.fh, arg.offset, |dirent| {
+            buf.push(dirent.ino, dirent.offset, dirent.file_type, dirent.name)
+        })
+    }
+
+    async fn readdirplus(
+        &self,
+        _req: &Request,
+        ino: u64,
+        arg: &fuse_read_in,
+        buf: &mut DirBuf
+    ) -> Result<()> {
+        let dir = self.inode(ino)?;
+        self.read_dir(arg.fh, arg.offset, |dirent| {
+            // The kernel ignores the entries of `.` and `..`
+            if dirent.name == "." || dirent.name == ".." {
+                // SAFETY: the structure is plain data
+                let stat: libc::stat = unsafe { std::mem::zeroed() };
+                let mut entry = self.entry(0, &stat);
+                entry.attr.ino = dirent.ino;
+                entry.attr.mode = dirent.file_type;
+                return buf.push_plus(entry, dirent.offset, dirent.name);
+            }
+
+            // Skip the entries removed since they were read
+            let Ok(entry) = sys::cstr(dirent.name)
+                .and_then(|name| self.do_lookup(&dir, &name)) else { return true };
+            let nodeid = entry.nodeid;
+            let pushed = buf.push_plus(entry, dirent.offset, dirent.name);
+            if !pushed {
+                self.inodes.forget(nodeid, 1);
+            }
+            pushed
+        })
+    }
+
+    async fn releasedir(&self, _req: &Request, _ino: u64, arg: &fuse_release_in) -> Result<()> {
+        self.remove_handle(arg.fh);
+        Ok(())
+    }
+
+    async fn fsyncdir(&self, _req: &Request, _ino: u64, arg: &fuse_fsync_in) -> Result<()> {
+        let handle = self.handle(arg.fh)?;
+        let datasync = arg.fsync_flags.contains(FsyncFlags::FUSE_FSYNC_FDATASYNC);
+        blocking(move || sys::fsync(handle.fd.as_fd(), datasync)).await
+    }
+
+    async fn getlk(&self, _req: &Request, _ino: u64, arg: &fuse_lk_in) -> Result<fuse_file_lock> {
+        let handle = self.handle(arg.fh)?;
+        let mut lock = to_flock(&arg.lk);
+        sys::fcntl_lock(handle.fd.as_fd(), libc::F_OFD_GETLK, &mut lock)?;
+        Ok(from_flock(&lock))
+    }
+
+    async fn setlk(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        arg: &fuse_lk_in,
+        sleep: bool
+    ) -> Result<()> {
+        let handle = self.handle(arg.fh)?;
+        if arg.lk_flags.contains(LockFlags::FUSE_LK_FLOCK) {
+            let operation = match arg.lk.r#type as i32 {
+                libc::F_RDLCK => libc::LOCK_SH,
+                libc::F_WRLCK => libc::LOCK_EX,
+                libc::F_UNLCK => libc::LOCK_UN,
+                _ => return Err(Errno::EINVAL),
+            };
+            return match sleep {
+                true => blocking(move || sys::flock(handle.fd.as_fd(), operation)).await,
+                false => sys::flock(handle.fd.as_fd(), operation | libc::LOCK_NB),
+            };
+        }
+
+        let mut lock = to_flock(&arg.lk);
+        match sleep {
+            true => {
+                blocking(move || {
+                    sys::fcntl_lock(handle.fd.as_fd(), libc::F_OFD_SETLKW, &mut lock)
+                }).await
+            },
+            false => sys::fcntl_lock(handle.fd.as_fd(), libc::F_OFD_SETLK, &mut lock),
+        }
+    }
+
+    /// Checks the permissions against the mode of the file, ignoring the
+    /// supplementary groups of the caller.
+    async fn access(&self, req: &Request, ino: u64, arg: &fuse_access_in) -> Result<()> {
+        let stat = sys::fstat(self.inode(ino)?.fd.as_fd())?;
+        let mask = arg.mask & 0o7;
+        let mode = stat.st_mode;
+
+        let allowed = if req.uid() == 0 {
+            // Root can execute a file only if someone can
+            let executable = mode & libc::S_IFMT == libc::S_IFDIR || mode & 0o111 != 0;
+            match executable {
+                true => 0o7,
+                false => 0o6,
+            }
+        } else if req.uid() == stat.st_uid {
+            mode >> 6 & 0o7
+        } else if req.gid() == stat.st_gid {
+            mode >> 3 & 0o7
+        } else {
+            mode & 0o7
+        };
+        match mask & !allowed {
+            0 => Ok(()),
+            _ => Err(Errno::EACCES),
+        }
+    }
+
+    async fn create(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_create_in,
+        name: &OsStr
+    ) -> Result<(fuse_entry_out, fuse_open_out)> {
+        let parent = self.inode(parent)?;
+        let name = sys::cstr(name)?;
+        let fd = {
+            let _ids = self.caller_ids(req);
+            // Don't follow a symbolic link created outside of the mount
+            let flags = arg.flags as i32 | libc::O_CREAT | libc::O_NOFOLLOW;
+            sys::openat(Some(parent.fd.as_fd()), &name, flags, arg.mode)?
+        };
+        let entry = self.do_lookup(&parent, &name)?;
+        Ok((entry, self.add_handle(fd, true)))
+    }
+
+    async fn tmpfile(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_create_in
+    ) -> Result<(fuse_entry_out, fuse_open_out)> {
+        let parent = self.inode(parent)?;
+        let fd = {
+            let _ids = self.caller_ids(req);
+            let flags = arg.flags as i32 & !libc::O_CREAT | libc::O_TMPFILE;
+            sys::openat(Some(parent.fd.as_fd()), c".", flags, arg.mode)?
+        };
+        let path = sys::openat(None, &sys::proc_path(fd.as_fd()), libc::O_PATH, 0)?;
+        let entry = self.add_inode(path)?;
+        Ok((entry, self.add_handle(fd, true)))
+    }
+
+    async fn fallocate(&self, _req: &Request, _ino: u64, arg: &fuse_fallocate_in) -> Result<()> {
+        let handle = self.handle(arg.fh)?;
+        let (mode, offset, length) = (arg.mode, arg.offset, arg.length);
+        blocking(move || sys::fallocate(handle.fd.as_fd(), mode, offset, length)).await
+    }
+
+    async fn lseek(&self, _req: &Request, _ino: u64, arg: &fuse_lseek_in) -> Result<u64> {
+        sys::lseek(self.handle(arg.fh)?.fd.as_fd(), arg.offset, arg.whence)
+    }
+
+    async fn copy_file_range(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        arg: &fuse_copy_file_range_in
+    ) -> Result<u32> {
+        let input = self.handle(arg.fh_in)?;
+        let output = self.handle(arg.fh_out)?;
+        let arg = arg.clone();
+        let len = arg.len.min(u32::MAX as u64);
+        let copied = blocking(move || {
+            sys::copy_file_range(
+                input.fd.as_fd(),
+                arg.off_in,
+                output.fd.as_fd(),
+                arg.off_out,
+                len,
+                arg.flags
+            )
+        }).await?;
+        Ok(copied as u32)
+    }
+
+    async fn syncfs(&self, _req: &Request, _ino: u64) -> Result<()> {
+        let root = self.inode(FUSE_ROOT_ID)?;
+        let fd = sys::reopen(root.fd.as_fd(), libc::O_RDONLY | libc::O_DIRECTORY)?;
+        blocking(move || sys::syncfs(fd.as_fd())).await
+    }
+
+    async fn setupmapping(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        arg: &fuse_setupmapping_in,
+        window: &dyn DaxWindow
+    ) -> Result<()> {
+        let handle = self.handle(arg.fh)?;
+        window.map(handle.fd.as_fd(), arg).await?;
+        Ok(())
+    }
+}
+
+/// Runs a syscall that can block for long on the blocking threads of tokio.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static
+) -> Result<T> {
+    tokio::task::spawn_blocking(f).await.unwrap_or(Err(Errno::EIO))
+}
+
+fn timespec(time: Option<TimeOrNow>) -> libc::timespec {
+    let (tv_sec, tv_nsec) = match time {
+        None => (0, libc::UTIME_OMIT),
+        Some(TimeOrNow::Now) => (0, libc::UTIME_NOW),
+        Some(TimeOrNow::Time(time)) => match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => (since.as_secs() as i64, since.subsec_nanos() as i64),
+            Err(err) => before_epoch(err.duration()),
+        },
+    };
+    libc::timespec { tv_sec, tv_nsec }
+}
+
+/// Seconds and nanoseconds of a time before the epoch, the nanoseconds are
+/// always positive.
+fn before_epoch(before: Duration) -> (i64, i64) {
+    let secs = -(before.as_secs() as i64);
+    match before.subsec_nanos() {
+        0 => (secs, 0),
+        nanos => (secs - 1, 1_000_000_000 - nanos as i64),
+    }
+}
+
+/// Largest offset of a lock, the end of a lock that extends to the end of
+/// the file.
+const OFFSET_MAX: u64 = i64::MAX as u64;
+
+fn to_flock(lock: &fuse_file_lock) -> libc::flock {
+    let len = match lock.end {
+        OFFSET_MAX.. => 0,
+        end => end.saturating_sub(lock.start) + 1,
+    };
+    libc::flock {
+        l_type: lock.r#type as i16,
+        l_whence: libc::SEEK_SET as i16,
+        l_start: lock.start as i64,
+        l_len: len as i64,
+        // Must be zero for open file description locks
+        l_pid: 0,
+    }
+}
+
+fn from_flock(lock: &libc::flock) -> fuse_file_lock {
+    let end = match lock.l_len {
+        0 => OFFSET_MAX,
+        len => (lock.l_start + len - 1) as u64,
+    };
+    fuse_file_lock {
+        start: lock.l_start as u64,
+        end,
+        r#type: lock.l_type as u32,
+        pid: 0,
+    }
+}
+
+/// Path of the file referred to by a file descriptor.
+fn fd_path(fd: &OwnedFd) -> Result<PathBuf> {
+    let link = sys::proc_path(fd.as_fd());
+    std::fs::read_link(OsStr::from_bytes(link.as_bytes())).map_err(Errno::from)
+}
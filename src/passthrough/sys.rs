@@ -0,0 +1,422 @@
+//! Wrappers for the syscalls used by the passthrough file system.
+//!
+//! The inodes are only held by `O_PATH` file descriptors, the syscalls that
+//! don't accept them go through `/proc/self/fd`.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::ptr;
+
+use crate::Errno;
+use crate::protocol::{AttrFlags, fuse_attr, fuse_kstatfs, Padding};
+
+pub(super) type Result<T> = std::result::Result<T, Errno>;
+
+fn check(result: libc::c_long) -> Result<libc::c_long> {
+    if result == -1 {
+        Err(Errno::from(io::Error::last_os_error()))
+    } else {
+        Ok(result)
+    }
+}
+
+fn check_fd(fd: libc::c_int) -> Result<OwnedFd> {
+    check(fd as _)?;
+    // SAFETY: the syscall returned a new file descriptor
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+pub(super) fn cstr(name: &OsStr) -> Result<CString> {
+    CString::new(name.as_bytes()).map_err(|_| Errno::EINVAL)
+}
+
+/// Path of a file descriptor in `/proc/self/fd`, which reopens the file it
+/// refers to.
+pub(super) fn proc_path(fd: BorrowedFd) -> CString {
+    CString::new(format!("/proc/self/fd/{}", fd.as_raw_fd())).unwrap()
+}
+
+pub(super) fn openat(
+    dir: Option<BorrowedFd>,
+    path: &CStr,
+    flags: libc::c_int,
+    mode: u32
+) -> Result<OwnedFd> {
+    let dir = dir.map_or(libc::AT_FDCWD, |dir| dir.as_raw_fd());
+    check_fd(unsafe { libc::openat(dir, path.as_ptr(), flags | libc::O_CLOEXEC, mode) })
+}
+
+/// Opens an `O_PATH` file descriptor for `name` in `dir`, without following
+/// symbolic links.
+pub(super) fn open_path(dir: BorrowedFd, name: &CStr) -> Result<OwnedFd> {
+    openat(Some(dir), name, libc::O_PATH | libc::O_NOFOLLOW, 0)
+}
+
+/// Opens the file referred to by an `O_PATH` file descriptor.
+pub(super) fn reopen(fd: BorrowedFd, flags: libc::c_int) -> Result<OwnedFd> {
+    // Creating the file was done by the lookup, and following symbolic links
+    // is not possible anyway
+    let flags = flags & !(libc::O_CREAT | libc::O_EXCL | libc::O_NOCTTY | libc::O_NOFOLLOW);
+    openat(None, &proc_path(fd), flags, 0)
+}
+
+pub(super) fn fstatat(dir: BorrowedFd, name: &CStr, flags: libc::c_int) -> Result<libc::stat> {
+    let mut stat = MaybeUninit::uninit();
+    check(unsafe {
+        libc::fstatat(dir.as_raw_fd(), name.as_ptr(), stat.as_mut_ptr(), flags)
+    } as _)?;
+    // SAFETY: fstatat filled the structure
+    Ok(unsafe { stat.assume_init() })
+}
+
+/// The attributes of the file referred to by `fd`, which can be an `O_PATH`
+/// file descriptor.
+pub(super) fn fstat(fd: BorrowedFd) -> Result<libc::stat> {
+    fstatat(fd, c"", libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW)
+}
+
+pub(super) fn fstatfs(fd: BorrowedFd) -> Result<fuse_kstatfs> {
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    check(unsafe { libc::fstatvfs(fd.as_raw_fd(), stat.as_mut_ptr()) } as _)?;
+    // SAFETY: fstatvfs filled the structure
+    let stat = unsafe { stat.assume_init() };
+    Ok(fuse_kstatfs {
+        blocks: stat.f_blocks,
+        bfree: stat.f_bfree,
+        bavail: stat.f_bavail,
+        files: stat.f_files,
+        ffree: stat.f_ffree,
+        bsize: stat.f_bsize as u32,
+        namelen: stat.f_namemax as u32,
+        frsize: stat.f_frsize as u32,
+        padding: Padding::new(),
+        spare: Padding::new(),
+    })
+}
+
+pub(super) fn to_attr(stat: &libc::stat) -> fuse_attr {
+    fuse_attr {
+        ino: stat.st_ino,
+        size: stat.st_size as u64,
+        blocks: stat.st_blocks as u64,
+        atime: stat.st_atime as u64,
+        mtime: stat.st_mtime as u64,
+        ctime: stat.st_ctime as u64,
+        atimensec: stat.st_atime_nsec as u32,
+        mtimensec: stat.st_mtime_nsec as u32,
+        ctimensec: stat.st_ctime_nsec as u32,
+        mode: stat.st_mode,
+        nlink: stat.st_nlink as u32,
+        uid: stat.st_uid,
+        gid: stat.st_gid,
+        rdev: stat.st_rdev as u32,
+        blksize: stat.st_blksize as u32,
+        flags: AttrFlags::empty(),
+    }
+}
+
+pub(super) fn readlinkat(fd: BorrowedFd) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    let len = check(unsafe {
+        libc::readlinkat(fd.as_raw_fd(), c"".as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+    } as _)?;
+    buf.truncate(len as usize);
+    Ok(buf)
+}
+
+pub(super) fn symlinkat(target: &CStr, dir: BorrowedFd, name: &CStr) -> Result<()> {
+    check(unsafe { libc::symlinkat(target.as_ptr(), dir.as_raw_fd(), name.as_ptr()) } as _)?;
+    Ok(())
+}
+
+pub(super) fn mknodat(dir: BorrowedFd, name: &CStr, mode: u32, rdev: u32) -> Result<()> {
+    check(unsafe {
+        libc::mknodat(dir.as_raw_fd(), name.as_ptr(), mode, rdev as libc::dev_t)
+    } as _)?;
+    Ok(())
+}
+
+pub(super) fn mkdirat(dir: BorrowedFd, name: &CStr, mode: u32) -> Result<()> {
+    check(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), mode) } as _)?;
+    Ok(())
+}
+
+pub(super) fn unlinkat(dir: BorrowedFd, name: &CStr, flags: libc::c_int) -> Result<()> {
+    check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) } as _)?;
+    Ok(())
+}
+
+pub(super) fn renameat2(
+    dir: BorrowedFd,
+    name: &CStr,
+    newdir: BorrowedFd,
+    newname: &CStr,
+    flags: u32
+) -> Result<()> {
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            newdir.as_raw_fd(),
+            newname.as_ptr(),
+            flags
+        )
+    })?;
+    Ok(())
+}
+
+/// Creates a hard link to the file referred to by an `O_PATH` file
+/// descriptor.
+pub(super) fn linkat(fd: BorrowedFd, newdir: BorrowedFd, newname: &CStr) -> Result<()> {
+    let empty = check(unsafe {
+        libc::linkat(
+            fd.as_raw_fd(),
+            c"".as_ptr(),
+            newdir.as_raw_fd(),
+            newname.as_ptr(),
+            libc::AT_EMPTY_PATH
+        )
+    } as _);
+    match empty {
+        // AT_EMPTY_PATH needs CAP_DAC_READ_SEARCH
+        Err(Errno::ENOENT) => {
+            check(unsafe {
+                libc::linkat(
+                    libc::AT_FDCWD,
+                    proc_path(fd).as_ptr(),
+                    newdir.as_raw_fd(),
+                    newname.as_ptr(),
+                    libc::AT_SYMLINK_FOLLOW
+                )
+            } as _)?;
+            Ok(())
+        },
+        result => result.map(|_| ()),
+    }
+}
+
+pub(super) fn fchmodat(fd: BorrowedFd, mode: u32) -> Result<()> {
+    check(unsafe { libc::fchmodat(libc::AT_FDCWD, proc_path(fd).as_ptr(), mode, 0) } as _)?;
+    Ok(())
+}
+
+pub(super) fn fchownat(fd: BorrowedFd, uid: u32, gid: u32) -> Result<()> {
+    check(unsafe {
+        libc::fchownat(
+            fd.as_raw_fd(),
+            c"".as_ptr(),
+            uid,
+            gid,
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW
+        )
+    } as _)?;
+    Ok(())
+}
+
+pub(super) fn truncate(fd: BorrowedFd, size: u64) -> Result<()> {
+    check(unsafe { libc::truncate(proc_path(fd).as_ptr(), size as libc::off_t) } as _)?;
+    Ok(())
+}
+
+pub(super) fn ftruncate(fd: BorrowedFd, size: u64) -> Result<()> {
+    check(unsafe { libc::ftruncate(fd.as_raw_fd(), size as libc::off_t) } as _)?;
+    Ok(())
+}
+
+pub(super) fn utimensat(fd: BorrowedFd, times: &[libc::timespec; 2]) -> Result<()> {
+    check(unsafe {
+        libc::utimensat(libc::AT_FDCWD, proc_path(fd).as_ptr(), times.as_ptr(), 0)
+    } as _)?;
+    Ok(())
+}
+
+pub(super) fn futimens(fd: BorrowedFd, times: &[libc::timespec; 2]) -> Result<()> {
+    check(unsafe { libc::futimens(fd.as_raw_fd(), times.as_ptr()) } as _)?;
+    Ok(())
+}
+
+pub(super) fn pread(fd: BorrowedFd, size: u32, offset: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; size as usize];
+    let len = check(unsafe {
+        libc::pread(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), offset as libc::off_t)
+    } as _)?;
+    buf.truncate(len as usize);
+    Ok(buf)
+}
+
+pub(super) fn pwrite(fd: BorrowedFd, data: &[u8], offset: u64) -> Result<u32> {
+    let len = check(unsafe {
+        libc::pwrite(fd.as_raw_fd(), data.as_ptr().cast(), data.len(), offset as libc::off_t)
+    } as _)?;
+    Ok(len as u32)
+}
+
+pub(super) fn fsync(fd: BorrowedFd, datasync: bool) -> Result<()> {
+    let ret = match datasync {
+        true => unsafe { libc::fdatasync(fd.as_raw_fd()) },
+        false => unsafe { libc::fsync(fd.as_raw_fd()) },
+    };
+    check(ret as _)?;
+    Ok(())
+}
+
+pub(super) fn syncfs(fd: BorrowedFd) -> Result<()> {
+    check(unsafe { libc::syncfs(fd.as_raw_fd()) } as _)?;
+    Ok(())
+}
+
+pub(super) fn fallocate(fd: BorrowedFd, mode: u32, offset: u64, length: u64) -> Result<()> {
+    check(unsafe {
+        libc::fallocate(
+            fd.as_raw_fd(),
+            mode as libc::c_int,
+            offset as libc::off_t,
+            length as libc::off_t
+        )
+    } as _)?;
+    Ok(())
+}
+
+pub(super) fn lseek(fd: BorrowedFd, offset: u64, whence: u32) -> Result<u64> {
+    let offset = unsafe {
+        libc::lseek(fd.as_raw_fd(), offset as libc::off_t, whence as libc::c_int)
+    };
+    Ok(check(offset as _)? as u64)
+}
+
+pub(super) fn copy_file_range(
+    fd_in: BorrowedFd,
+    off_in: u64,
+    fd_out: BorrowedFd,
+    off_out: u64,
+    len: u64,
+    flags: u64
+) -> Result<u64> {
+    let mut off_in = off_in as libc::loff_t;
+    let mut off_out = off_out as libc::loff_t;
+    let len = check(unsafe {
+        libc::syscall(
+            libc::SYS_copy_file_range,
+            fd_in.as_raw_fd(),
+            &mut off_in,
+            fd_out.as_raw_fd(),
+            &mut off_out,
+            len as libc::size_t,
+            flags as libc::c_uint
+        )
+    })?;
+    Ok(len as u64)
+}
+
+/// Reads directory entries into `buf`, starting from the current offset of
+/// `fd`.
+pub(super) fn getdents64(fd: BorrowedFd, buf: &mut [u8]) -> Result<usize> {
+    let len = check(unsafe {
+        libc::syscall(libc::SYS_getdents64, fd.as_raw_fd(), buf.as_mut_ptr(), buf.len())
+    })?;
+    Ok(len as usize)
+}
+
+/// Entry read by [`getdents64`].
+pub(super) struct Dirent<'a> {
+    pub ino: u64,
+    /// Offset of the next entry.
+    pub offset: u64,
+    /// File type, as in the `S_IFMT` bits of the mode.
+    pub file_type: u32,
+    pub name: &'a OsStr,
+}
+
+/// Size of the fixed part of a `struct linux_dirent64`.
+const DIRENT_HEADER: usize = 19;
+
+/// Parses the entries read by [`getdents64`].
+pub(super) fn dirents(mut buf: &[u8]) -> impl Iterator<Item = Dirent<'_>> {
+    std::iter::from_fn(move || {
+        let header = buf.get(..DIRENT_HEADER)?;
+        let reclen = u16::from_ne_bytes([header[16], header[17]]) as usize;
+        let (entry, rest) = buf.split_at_checked(reclen.max(DIRENT_HEADER))?;
+        buf = rest;
+
+        // The name is padded with NULs
+        let name = &entry[DIRENT_HEADER..];
+        let len = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+        Some(Dirent {
+            ino: u64::from_ne_bytes(header[..8].try_into().unwrap()),
+            offset: u64::from_ne_bytes(header[8..16].try_into().unwrap()),
+            file_type: (header[18] as u32) << 12,
+            name: OsStr::from_bytes(&name[..len]),
+        })
+    })
+}
+
+pub(super) fn fcntl_lock(fd: BorrowedFd, cmd: libc::c_int, lock: &mut libc::flock) -> Result<()> {
+    check(unsafe { libc::fcntl(fd.as_raw_fd(), cmd, lock as *mut libc::flock) } as _)?;
+    Ok(())
+}
+
+pub(super) fn flock(fd: BorrowedFd, operation: libc::c_int) -> Result<()> {
+    check(unsafe { libc::flock(fd.as_raw_fd(), operation) } as _)?;
+    Ok(())
+}
+
+pub(super) fn setxattr(path: &CStr, name: &CStr, value: &[u8], flags: u32) -> Result<()> {
+    check(unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            flags as libc::c_int
+        )
+    } as _)?;
+    Ok(())
+}
+
+pub(super) fn getxattr(path: &CStr, name: &CStr) -> Result<Vec<u8>> {
+    read_list(|buf, len| unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), buf, len) })
+}
+
+pub(super) fn listxattr(path: &CStr) -> Result<Vec<u8>> {
+    read_list(|buf, len| unsafe { libc::listxattr(path.as_ptr(), buf.cast(), len) })
+}
+
+pub(super) fn removexattr(path: &CStr, name: &CStr) -> Result<()> {
+    check(unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) } as _)?;
+    Ok(())
+}
+
+/// Calls a function of the `getxattr(2)` family, first to get the size of the
+/// value and then the value itself, retrying if it grew in between.
+fn read_list(
+    read: impl Fn(*mut libc::c_void, usize) -> libc::ssize_t
+) -> Result<Vec<u8>> {
+    loop {
+        let size = check(read(ptr::null_mut(), 0) as _)? as usize;
+        let mut buf = vec![0u8; size];
+        match check(read(buf.as_mut_ptr().cast(), size) as _) {
+            Ok(len) => {
+                buf.truncate(len as usize);
+                return Ok(buf);
+            },
+            Err(Errno::ERANGE) => continue,
+            Err(errno) => return Err(errno),
+        }
+    }
+}
+
+/// Sets the filesystem ids of the calling thread, returning the previous
+/// ones.
+///
+/// Unlike `setresuid(2)`, the libc wrappers only change the current thread.
+pub(super) fn set_fs_ids(uid: u32, gid: u32) -> (u32, u32) {
+    // The group is changed first, changing the user drops the capability
+    let old_gid = unsafe { libc::setfsgid(gid) } as u32;
+    let old_uid = unsafe { libc::setfsuid(uid) } as u32;
+    (old_uid, old_gid)
+}
+
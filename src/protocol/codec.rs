@@ -0,0 +1,209 @@
+use std::ffi::CStr;
+use std::mem::size_of;
+
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, TryFromBytes};
+
+use crate::Errno;
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Error returned when the header of a request cannot be decoded.
+pub enum HeaderError {
+    /// The buffer is shorter than a [`fuse_in_header`].
+    Truncated,
+    /// The opcode is not known, the request should be answered with
+    /// [`Errno::ENOSYS`].
+    UnknownOpcode { unique: u64, opcode: u32 },
+}
+
+#[derive(Debug, Clone)]
+/// Decoder for the arguments of a request.
+///
+/// Every method consumes the decoded bytes, so the arguments must be fetched
+/// in the order they appear in the request. Malformed requests are reported
+/// as [`Errno::EINVAL`].
+pub struct ArgReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ArgReader<'a> {
+    #[inline]
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    #[inline]
+    /// The bytes that have not been decoded yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.data
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Decodes the request header.
+    pub fn fetch_header(&mut self) -> Result<fuse_in_header, HeaderError> {
+        const OPCODE: usize = 4;
+        const UNIQUE: usize = 8;
+
+        match fuse_in_header::try_read_from_prefix(self.data) {
+            Ok((header, rest)) => {
+                self.data = rest;
+                Ok(header)
+            },
+            Err(_) if self.data.len() < size_of::<fuse_in_header>() => {
+                Err(HeaderError::Truncated)
+            },
+            Err(_) => {
+                // The only field that can fail validation is the opcode
+                let read_u32 = |at: usize| u32::from_ne_bytes(
+                    self.data[at..at + 4].try_into().unwrap()
+                );
+                let read_u64 = |at: usize| u64::from_ne_bytes(
+                    self.data[at..at + 8].try_into().unwrap()
+                );
+                Err(HeaderError::UnknownOpcode {
+                    unique: read_u64(UNIQUE),
+                    opcode: read_u32(OPCODE),
+                })
+            }
+        }
+    }
+
+    /// Decodes a structure with a fixed size.
+    pub fn fetch<T>(&mut self) -> Result<T, Errno>
+    where
+        T: FromBytes + KnownLayout + Immutable
+    {
+        let (value, rest) = T::read_from_prefix(self.data)
+            .map_err(|_| Errno::EINVAL)?;
+        self.data = rest;
+        Ok(value)
+    }
+
+    /// Decodes a structure whose size depends on the protocol version.
+    ///
+    /// The fields missing in older versions are filled with zeros.
+    pub fn fetch_compat<T>(&mut self, version: ProtocolVersion) -> Result<T, Errno>
+    where
+        T: CompatSize + FromBytes + IntoBytes + KnownLayout + Immutable
+    {
+        self.fetch_sized(T::compat_size(version))
+    }
+
+    /// Decodes a structure that was sent with the provided size.
+    ///
+    /// If the size is smaller than the structure the missing fields are filled
+    /// with zeros, if it is bigger the unknown fields are skipped.
+    pub fn fetch_sized<T>(&mut self, size: usize) -> Result<T, Errno>
+    where
+        T: FromBytes + IntoBytes + KnownLayout + Immutable
+    {
+        let bytes = self.fetch_bytes(size)?;
+        let mut value = T::new_zeroed();
+        let len = size.min(size_of::<T>());
+        value.as_mut_bytes()[..len].copy_from_slice(&bytes[..len]);
+        Ok(value)
+    }
+
+    /// Decodes a NUL terminated string.
+    pub fn fetch_str(&mut self) -> Result<&'a CStr, Errno> {
+        let s = CStr::from_bytes_until_nul(self.data)
+            .map_err(|_| Errno::EINVAL)?;
+        self.data = &self.data[s.count_bytes() + 1..];
+        Ok(s)
+    }
+
+    /// Decodes a fixed number of bytes.
+    pub fn fetch_bytes(&mut self, len: usize) -> Result<&'a [u8], Errno> {
+        let Some((bytes, rest)) = self.data.split_at_checked(len) else {
+            return Err(Errno::EINVAL)
+        };
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Decodes all the remaining bytes.
+    #[inline]
+    pub fn fetch_all(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.data)
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Encoder for a reply.
+///
+/// The buffer starts with a [`fuse_out_header`], the `len` field is filled in
+/// by [`ReplyBuf::finish`].
+pub struct ReplyBuf {
+    buf: Vec<u8>,
+}
+
+impl ReplyBuf {
+    /// Starts a successful reply to the request `unique`.
+    #[inline]
+    pub fn new(unique: u64) -> Self {
+        Self::with_header(unique, OutError::SUCCESS)
+    }
+
+    /// Builds an error reply to the request `unique`.
+    #[inline]
+    pub fn error(unique: u64, errno: Errno) -> Self {
+        Self::with_header(unique, errno.into())
+    }
+
+    /// Starts a notification, see [`fuse_notify_code`].
+    #[inline]
+    pub fn notify(code: fuse_notify_code) -> Self {
+        Self::with_header(0, OutError::notify(code))
+    }
+
+    fn with_header(unique: u64, error: OutError) -> Self {
+        let header = fuse_out_header { len: 0, error, unique };
+        Self { buf: header.as_bytes().to_vec() }
+    }
+
+    /// Appends a structure with a fixed size.
+    #[inline]
+    pub fn push<T: IntoBytes + Immutable + ?Sized>(&mut self, value: &T) {
+        self.buf.extend_from_slice(value.as_bytes());
+    }
+
+    /// Appends a structure whose size depends on the protocol version.
+    ///
+    /// The fields not known by older versions are truncated.
+    pub fn push_compat<T>(&mut self, value: &T, version: ProtocolVersion)
+    where
+        T: CompatSize + IntoBytes + Immutable
+    {
+        let size = T::compat_size(version);
+        self.buf.extend_from_slice(&value.as_bytes()[..size]);
+    }
+
+    /// Appends raw bytes.
+    #[inline]
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Size of the reply, including the header.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Whether the reply only contains the header.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.buf.len() == size_of::<fuse_out_header>()
+    }
+
+    /// Completes the reply, returning the bytes to write to the device.
+    pub fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
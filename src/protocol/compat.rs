@@ -0,0 +1,118 @@
+use std::mem::size_of;
+
+use super::*;
+
+// Sizes of structures used by older protocol versions, from `fuse_kernel.h`
+pub const FUSE_COMPAT_ENTRY_OUT_SIZE: usize = 120;
+pub const FUSE_COMPAT_ATTR_OUT_SIZE: usize = 96;
+pub const FUSE_COMPAT_MKNOD_IN_SIZE: usize = 8;
+pub const FUSE_COMPAT_WRITE_IN_SIZE: usize = 24;
+pub const FUSE_COMPAT_STATFS_SIZE: usize = 48;
+pub const FUSE_COMPAT_INIT_OUT_SIZE: usize = 8;
+pub const FUSE_COMPAT_22_INIT_OUT_SIZE: usize = 24;
+pub const FUSE_COMPAT_SETXATTR_IN_SIZE: usize = 8;
+
+// These are not defined in `fuse_kernel.h`, but the structures still changed
+/// Size of [`fuse_read_in`] before 7.9, without `lock_owner` and `flags`.
+pub const FUSE_COMPAT_READ_IN_SIZE: usize = 24;
+/// Size of [`fuse_create_in`] before 7.12, it was a [`fuse_open_in`].
+pub const FUSE_COMPAT_CREATE_IN_SIZE: usize = 8;
+/// Size of [`fuse_init_in`] before 7.36, without `flags2`.
+pub const FUSE_COMPAT_INIT_IN_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Version of the FUSE protocol.
+///
+/// The kernel sends its version in the `FUSE_INIT` request, the version used
+/// for the session is the lowest between the kernel's and ours. Some request
+/// and reply structures grew over time, the sizes used by a version are
+/// exposed by [`CompatSize`].
+pub struct ProtocolVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ProtocolVersion {
+    /// The version implemented by this crate.
+    pub const CURRENT: Self = Self::new(
+        FUSE_KERNEL_VERSION,
+        FUSE_KERNEL_MINOR_VERSION
+    );
+
+    /// The oldest version supported, every structure that changed since then
+    /// is covered by [`CompatSize`].
+    pub const MIN_SUPPORTED: Self = Self::new(FUSE_KERNEL_VERSION, 0);
+
+    #[inline]
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+
+    /// Picks the version to use for a session given the kernel's version.
+    ///
+    /// Returns `None` if the kernel's version is too old to be supported.
+    /// If the kernel has a newer major version [`Self::CURRENT`] is returned,
+    /// the kernel will then send a new `FUSE_INIT` request using our version.
+    pub fn negotiate(kernel: Self) -> Option<Self> {
+        if kernel < Self::MIN_SUPPORTED {
+            None
+        } else {
+            Some(kernel.min(Self::CURRENT))
+        }
+    }
+
+    #[inline]
+    /// Whether the version is at least `7.minor`.
+    pub const fn at_least(self, minor: u32) -> bool {
+        self.major > FUSE_KERNEL_VERSION
+            || (self.major == FUSE_KERNEL_VERSION && self.minor >= minor)
+    }
+}
+
+impl Default for ProtocolVersion {
+    #[inline]
+    fn default() -> Self {
+        Self::CURRENT
+    }
+}
+
+/// Size of a structure in a given protocol version.
+///
+/// Request structures sent by older kernels are shorter, the missing fields
+/// must be treated as zero. Reply structures must be truncated to the size
+/// known by the kernel, or the reply will be rejected.
+pub trait CompatSize: Sized {
+    fn compat_size(version: ProtocolVersion) -> usize {
+        let _ = version;
+        size_of::<Self>()
+    }
+}
+
+macro_rules! compat_sizes {
+    ($($ty:ty => [$($minor:literal => $size:expr),*];)*) => {$(
+        impl CompatSize for $ty {
+            fn compat_size(version: ProtocolVersion) -> usize {
+                $(if !version.at_least($minor) { return $size; })*
+                size_of::<Self>()
+            }
+        }
+    )*};
+}
+
+// Each entry lists the first minor version that uses a bigger size, and the
+// size used before it. The entries must be ordered from the oldest version.
+compat_sizes! {
+    fuse_init_in => [36 => FUSE_COMPAT_INIT_IN_SIZE];
+    fuse_init_out => [
+        5 => FUSE_COMPAT_INIT_OUT_SIZE,
+        23 => FUSE_COMPAT_22_INIT_OUT_SIZE
+    ];
+    fuse_entry_out => [9 => FUSE_COMPAT_ENTRY_OUT_SIZE];
+    fuse_attr_out => [9 => FUSE_COMPAT_ATTR_OUT_SIZE];
+    fuse_statfs_out => [4 => FUSE_COMPAT_STATFS_SIZE];
+    fuse_getattr_in => [9 => 0];
+    fuse_read_in => [9 => FUSE_COMPAT_READ_IN_SIZE];
+    fuse_write_in => [9 => FUSE_COMPAT_WRITE_IN_SIZE];
+    fuse_mknod_in => [12 => FUSE_COMPAT_MKNOD_IN_SIZE];
+    fuse_create_in => [12 => FUSE_COMPAT_CREATE_IN_SIZE];
+}
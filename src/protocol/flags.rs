@@ -1,85 +1,87 @@
 #![warn(non_camel_case_types)]
 
+use std::fmt;
+
 use zerocopy::{KnownLayout, Immutable, FromBytes, IntoBytes};
 use bitflags::bitflags;
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct GetattrFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct AttrFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct SetattrValid(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct OpenInFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct OpenOutFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct ReadFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct WriteFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct ReleaseFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct FsyncFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct SetxattrFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct InitFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct InitFlags2(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct LockFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct IoctlFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct PollFlags(u32);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
 pub struct SetupMappingFlags(u64);
 
@@ -213,6 +215,42 @@ bitflags! {
 
     impl SetupMappingFlags: u64 {
         const FUSE_SETUPMAPPING_FLAG_WRITE = 1 << 0;
-        const FUSE_SETUPMAPPING_FLAG_READ = 1 << 0;
+        const FUSE_SETUPMAPPING_FLAG_READ = 1 << 1;
     }
 }
+
+// Shows the names of the flags, like `OpenOutFlags(FOPEN_KEEP_CACHE | 0x400)`
+// for a known flag and an unknown one
+macro_rules! debug_flags {
+    ($($ty:ident),*) => {$(
+        impl fmt::Debug for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}(", stringify!($ty))?;
+                match self.is_empty() {
+                    true => write!(f, "{:#x}", self.bits())?,
+                    false => bitflags::parser::to_writer(self, &mut *f)?,
+                }
+                f.write_str(")")
+            }
+        }
+    )*};
+}
+
+debug_flags!(
+    GetattrFlags,
+    AttrFlags,
+    SetattrValid,
+    OpenInFlags,
+    OpenOutFlags,
+    ReadFlags,
+    WriteFlags,
+    ReleaseFlags,
+    FsyncFlags,
+    SetxattrFlags,
+    InitFlags,
+    InitFlags2,
+    LockFlags,
+    IoctlFlags,
+    PollFlags,
+    SetupMappingFlags
+);
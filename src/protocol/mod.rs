@@ -3,27 +3,51 @@
 
 use zerocopy::{FromZeros, Immutable, IntoBytes, KnownLayout};
 
+use crate::Errno;
+
 pub const FUSE_KERNEL_VERSION: u32 = 7;
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 45;
 
 pub const FUSE_ROOT_ID: u64 = 1;
 pub const FUSE_IOCTL_MAX_IOV: u32 = 256;
 
+// Ioctls of the FUSE device
+pub const FUSE_DEV_IOC_MAGIC: u32 = 229;
+/// `_IOR(FUSE_DEV_IOC_MAGIC, 0, uint32_t)`
+pub const FUSE_DEV_IOC_CLONE: u32 = 0x8004e500;
+/// `_IOW(FUSE_DEV_IOC_MAGIC, 1, struct fuse_backing_map)`
+pub const FUSE_DEV_IOC_BACKING_OPEN: u32 = 0x4010e501;
+/// `_IOW(FUSE_DEV_IOC_MAGIC, 2, uint32_t)`
+pub const FUSE_DEV_IOC_BACKING_CLOSE: u32 = 0x4004e502;
+
+/// The minimum size of the buffer used to read requests.
+pub const FUSE_MIN_READ_BUFFER: usize = 8192;
+
 // Type aliases for clarity
 type seconds = u64;
 type nanos = u32;
 
 mod requests;
 pub use requests::*;
-//
-// mod notify
-//
+
+mod notify;
+pub use notify::*;
+
 mod flags;
 pub use flags::*;
 //
 mod types;
 pub use types::*;
 
+mod compat;
+pub use compat::*;
+
+mod codec;
+pub use codec::*;
+
+mod operation;
+pub use operation::*;
+
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy)]
 #[derive(KnownLayout, Immutable, IntoBytes)]
@@ -43,6 +67,8 @@ impl<T: FromZeros + Copy> Padding<T> {
     #[inline]
     /// Constructs a padding value filled with the chosen value
     ///
+    /// # Safety
+    ///
     /// The user must check that provided value is acceptable, for the given
     /// field.
     pub unsafe fn with_nonzero(value: T) -> Self {
@@ -56,3 +82,54 @@ impl<T: FromZeros + Copy> Default for Padding<T> {
         Self::new()
     }
 }
+
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(KnownLayout, Immutable, IntoBytes)]
+/// Error field of a reply.
+///
+/// The kernel expects either zero for a successful reply or a negated errno,
+/// notifications instead carry their code. This type can only be constructed
+/// from these values.
+pub struct OutError(i32);
+
+impl OutError {
+    /// Value for a successful reply.
+    pub const SUCCESS: Self = Self(0);
+
+    #[inline]
+    /// Returns the error sent to the kernel, `None` for a successful reply.
+    pub fn errno(self) -> Option<Errno> {
+        Errno::new(-self.0)
+    }
+
+    #[inline]
+    /// Header value of a notification.
+    pub fn notify(code: fuse_notify_code) -> Self {
+        Self(code as i32)
+    }
+
+    #[inline]
+    /// Raw value of the field, zero or a negative number, or a positive
+    /// notification code
+    pub fn get(self) -> i32 {
+        self.0
+    }
+}
+
+impl From<Errno> for OutError {
+    #[inline]
+    fn from(errno: Errno) -> Self {
+        Self(-errno.code())
+    }
+}
+
+impl From<Result<(), Errno>> for OutError {
+    #[inline]
+    fn from(result: Result<(), Errno>) -> Self {
+        match result {
+            Ok(()) => Self::SUCCESS,
+            Err(errno) => errno.into(),
+        }
+    }
+}
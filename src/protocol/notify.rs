@@ -0,0 +1,25 @@
+use zerocopy::{KnownLayout, Immutable, TryFromBytes, IntoBytes};
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(KnownLayout, Immutable, TryFromBytes, IntoBytes)]
+/// Code of a notification sent by the FUSE server to the kernel.
+///
+/// Notifications are written to the device like replies, with a zero `unique`
+/// and the code in the `error` field of the [`fuse_out_header`](super::fuse_out_header).
+pub enum fuse_notify_code {
+    FUSE_NOTIFY_POLL = 1,
+    FUSE_NOTIFY_INVAL_INODE = 2,
+    FUSE_NOTIFY_INVAL_ENTRY = 3,
+    FUSE_NOTIFY_STORE = 4,
+    FUSE_NOTIFY_RETRIEVE = 5,
+    FUSE_NOTIFY_DELETE = 6,
+    /// Asks the kernel to send again the requests that were read from the
+    /// device but not answered yet, requires `FUSE_HAS_RESEND`.
+    ///
+    /// The notification has no payload.
+    FUSE_NOTIFY_RESEND = 7,
+    FUSE_NOTIFY_INC_EPOCH = 8,
+}
+
+// TODO: payloads of the other notifications
@@ -0,0 +1,226 @@
+use std::ffi::{CStr, OsStr};
+use std::fmt;
+use std::mem::size_of;
+use std::ops::Deref;
+use std::os::unix::ffi::OsStrExt;
+
+use zerocopy::{FromBytes, Immutable, KnownLayout};
+
+use crate::Errno;
+use super::*;
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+/// Arguments of a request, decoded according to its opcode.
+///
+/// There is one variant per [`fuse_opcode`], holding the structures and the
+/// names sent after the [`fuse_in_header`]. The names and the data borrow the
+/// buffer of the request.
+pub enum Operation<'a> {
+    Lookup { name: &'a OsStr },
+    Forget { arg: fuse_forget_in },
+    Getattr { arg: fuse_getattr_in },
+    Setattr { arg: fuse_setattr_in },
+    Readlink,
+    Symlink { name: &'a OsStr, target: &'a OsStr },
+    Mknod { arg: fuse_mknod_in, name: &'a OsStr },
+    Mkdir { arg: fuse_mkdir_in, name: &'a OsStr },
+    Unlink { name: &'a OsStr },
+    Rmdir { name: &'a OsStr },
+    Rename { arg: fuse_rename_in, name: &'a OsStr, newname: &'a OsStr },
+    Link { arg: fuse_link_in, newname: &'a OsStr },
+    Open { arg: fuse_open_in },
+    Read { arg: fuse_read_in },
+    Write { arg: fuse_write_in, data: Payload<'a> },
+    Statfs,
+    Release { arg: fuse_release_in },
+    Fsync { arg: fuse_fsync_in },
+    Setxattr { arg: fuse_setxattr_in, name: &'a OsStr, value: Payload<'a> },
+    Getxattr { arg: fuse_getxattr_in, name: &'a OsStr },
+    Listxattr { arg: fuse_getxattr_in },
+    Removexattr { name: &'a OsStr },
+    Flush { arg: fuse_flush_in },
+    Init { arg: fuse_init_in },
+    Opendir { arg: fuse_open_in },
+    Readdir { arg: fuse_read_in },
+    Releasedir { arg: fuse_release_in },
+    Fsyncdir { arg: fuse_fsync_in },
+    Getlk { arg: fuse_lk_in },
+    Setlk { arg: fuse_lk_in },
+    Setlkw { arg: fuse_lk_in },
+    Access { arg: fuse_access_in },
+    Create { arg: fuse_create_in, name: &'a OsStr },
+    Interrupt { arg: fuse_interrupt_in },
+    Bmap { arg: fuse_bmap_in },
+    Destroy,
+    Ioctl { arg: fuse_ioctl_in, data: Payload<'a> },
+    Poll { arg: fuse_poll_in },
+    NotifyReply { data: Payload<'a> },
+    BatchForget { nodes: Vec<fuse_forget_one> },
+    Fallocate { arg: fuse_fallocate_in },
+    Readdirplus { arg: fuse_read_in },
+    Rename2 { arg: fuse_rename2_in, name: &'a OsStr, newname: &'a OsStr },
+    Lseek { arg: fuse_lseek_in },
+    CopyFileRange { arg: fuse_copy_file_range_in },
+    Setupmapping { arg: fuse_setupmapping_in },
+    Removemapping { mappings: Vec<fuse_removemapping_one> },
+    Syncfs { arg: fuse_syncfs_in },
+    /// The name is a placeholder, the file has none.
+    Tmpfile { arg: fuse_create_in },
+    Statx { arg: fuse_statx_in },
+    CopyFileRange64 { arg: fuse_copy_file_range_in },
+}
+
+#[derive(Clone, Copy)]
+/// Data of a request, only its length is shown by `Debug`.
+pub struct Payload<'a>(pub &'a [u8]);
+
+impl<'a> Operation<'a> {
+    /// Decodes the arguments of a request, `args` are the bytes following the
+    /// header.
+    ///
+    /// The structures whose size changed are decoded with their size in the
+    /// protocol `version`. Malformed arguments are reported as
+    /// [`Errno::EINVAL`].
+    pub fn decode(
+        opcode: fuse_opcode,
+        args: &'a [u8],
+        version: ProtocolVersion
+    ) -> Result<Self, Errno> {
+        use fuse_opcode::*;
+
+        let mut args = ArgReader::new(args);
+        let op = match opcode {
+            FUSE_LOOKUP => Self::Lookup { name: name(args.fetch_str()?) },
+            FUSE_FORGET => Self::Forget { arg: args.fetch()? },
+            FUSE_GETATTR => Self::Getattr { arg: args.fetch_compat(version)? },
+            FUSE_SETATTR => Self::Setattr { arg: args.fetch()? },
+            FUSE_READLINK => Self::Readlink,
+            FUSE_SYMLINK => Self::Symlink {
+                name: name(args.fetch_str()?),
+                target: name(args.fetch_str()?),
+            },
+            FUSE_MKNOD => Self::Mknod {
+                arg: args.fetch_compat(version)?,
+                name: name(args.fetch_str()?),
+            },
+            FUSE_MKDIR => Self::Mkdir { arg: args.fetch()?, name: name(args.fetch_str()?) },
+            FUSE_UNLINK => Self::Unlink { name: name(args.fetch_str()?) },
+            FUSE_RMDIR => Self::Rmdir { name: name(args.fetch_str()?) },
+            FUSE_RENAME => Self::Rename {
+                arg: args.fetch()?,
+                name: name(args.fetch_str()?),
+                newname: name(args.fetch_str()?),
+            },
+            FUSE_LINK => Self::Link { arg: args.fetch()?, newname: name(args.fetch_str()?) },
+            FUSE_OPEN => Self::Open { arg: args.fetch()? },
+            FUSE_READ => Self::Read { arg: args.fetch_compat(version)? },
+            FUSE_WRITE => {
+                let arg: fuse_write_in = args.fetch_compat(version)?;
+                let data = Payload(args.fetch_bytes(arg.size as usize)?);
+                Self::Write { arg, data }
+            },
+            FUSE_STATFS => Self::Statfs,
+            FUSE_RELEASE => Self::Release { arg: args.fetch()? },
+            FUSE_FSYNC => Self::Fsync { arg: args.fetch()? },
+            FUSE_SETXATTR => {
+                // FUSE_SETXATTR_EXT is not negotiated, so the old size is used
+                let arg: fuse_setxattr_in = args.fetch_sized(FUSE_COMPAT_SETXATTR_IN_SIZE)?;
+                let name = name(args.fetch_str()?);
+                let value = Payload(args.fetch_bytes(arg.size as usize)?);
+                Self::Setxattr { arg, name, value }
+            },
+            FUSE_GETXATTR => Self::Getxattr {
+                arg: args.fetch()?,
+                name: name(args.fetch_str()?),
+            },
+            FUSE_LISTXATTR => Self::Listxattr { arg: args.fetch()? },
+            FUSE_REMOVEXATTR => Self::Removexattr { name: name(args.fetch_str()?) },
+            FUSE_FLUSH => Self::Flush { arg: args.fetch()? },
+            FUSE_INIT => {
+                let size = args.remaining().len();
+                Self::Init { arg: args.fetch_sized(size)? }
+            },
+            FUSE_OPENDIR => Self::Opendir { arg: args.fetch()? },
+            FUSE_READDIR => Self::Readdir { arg: args.fetch_compat(version)? },
+            FUSE_RELEASEDIR => Self::Releasedir { arg: args.fetch()? },
+            FUSE_FSYNCDIR => Self::Fsyncdir { arg: args.fetch()? },
+            FUSE_GETLK => Self::Getlk { arg: args.fetch()? },
+            FUSE_SETLK => Self::Setlk { arg: args.fetch()? },
+            FUSE_SETLKW => Self::Setlkw { arg: args.fetch()? },
+            FUSE_ACCESS => Self::Access { arg: args.fetch()? },
+            FUSE_CREATE => Self::Create {
+                arg: args.fetch_compat(version)?,
+                name: name(args.fetch_str()?),
+            },
+            FUSE_INTERRUPT => Self::Interrupt { arg: args.fetch()? },
+            FUSE_BMAP => Self::Bmap { arg: args.fetch()? },
+            FUSE_DESTROY => Self::Destroy,
+            FUSE_IOCTL => {
+                let arg = args.fetch()?;
+                Self::Ioctl { arg, data: Payload(args.fetch_all()) }
+            },
+            FUSE_POLL => Self::Poll { arg: args.fetch()? },
+            FUSE_NOTIFY_REPLY => Self::NotifyReply { data: Payload(args.fetch_all()) },
+            FUSE_BATCH_FORGET => {
+                let arg: fuse_batch_forget_in = args.fetch()?;
+                Self::BatchForget { nodes: fetch_array(&mut args, arg.count)? }
+            },
+            FUSE_FALLOCATE => Self::Fallocate { arg: args.fetch()? },
+            FUSE_READDIRPLUS => Self::Readdirplus { arg: args.fetch_compat(version)? },
+            FUSE_RENAME2 => Self::Rename2 {
+                arg: args.fetch()?,
+                name: name(args.fetch_str()?),
+                newname: name(args.fetch_str()?),
+            },
+            FUSE_LSEEK => Self::Lseek { arg: args.fetch()? },
+            FUSE_COPY_FILE_RANGE => Self::CopyFileRange { arg: args.fetch()? },
+            FUSE_SETUPMAPPING => Self::Setupmapping { arg: args.fetch()? },
+            FUSE_REMOVEMAPPING => {
+                let arg: fuse_removemapping_in = args.fetch()?;
+                Self::Removemapping { mappings: fetch_array(&mut args, arg.count)? }
+            },
+            FUSE_SYNCFS => Self::Syncfs { arg: args.fetch()? },
+            FUSE_TMPFILE => {
+                let arg = args.fetch_compat(version)?;
+                let _ = args.fetch_str()?;
+                Self::Tmpfile { arg }
+            },
+            FUSE_STATX => Self::Statx { arg: args.fetch()? },
+            FUSE_COPY_FILE_RANGE_64 => Self::CopyFileRange64 { arg: args.fetch()? },
+        };
+        Ok(op)
+    }
+}
+
+impl Deref for Payload<'_> {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl fmt::Debug for Payload<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} bytes>", self.0.len())
+    }
+}
+
+/// Decodes `count` structures, checking first that they were sent so that a
+/// bogus count doesn't allocate.
+fn fetch_array<T>(args: &mut ArgReader<'_>, count: u32) -> Result<Vec<T>, Errno>
+where
+    T: FromBytes + KnownLayout + Immutable
+{
+    let size = (count as usize).checked_mul(size_of::<T>()).ok_or(Errno::EINVAL)?;
+    if args.remaining().len() < size {
+        return Err(Errno::EINVAL);
+    }
+    (0..count).map(|_| args.fetch()).collect()
+}
+
+fn name(name: &CStr) -> &OsStr {
+    OsStr::from_bytes(name.to_bytes())
+}
@@ -90,6 +90,19 @@ pub enum fuse_opcode {
     FUSE_COPY_FILE_RANGE_64	= 53,
 }
 
+impl fuse_opcode {
+    /// Whether the kernel waits for a reply to requests with this opcode.
+    pub fn expects_reply(self) -> bool {
+        !matches!(
+            self,
+            Self::FUSE_FORGET
+                | Self::FUSE_BATCH_FORGET
+                | Self::FUSE_INTERRUPT
+                | Self::FUSE_NOTIFY_REPLY
+        )
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 #[derive(KnownLayout, Immutable, TryFromBytes, IntoBytes)]
@@ -130,7 +143,7 @@ pub struct fuse_in_header {
 #[derive(KnownLayout, Immutable, IntoBytes)]
 pub struct fuse_out_header {
     pub len: u32,
-    pub error: i32,
+    pub error: OutError,
     pub unique: u64,
 }
 
@@ -400,8 +413,8 @@ pub struct fuse_init_in {
     pub major: u32,
     pub minor: u32,
     pub max_readahead: u32,
-    pub flags: u32,
-    pub flags2: u32,
+    pub flags: InitFlags,
+    pub flags2: InitFlags2,
     pub unused: [u32; 11],
 }
 
@@ -412,14 +425,14 @@ pub struct fuse_init_out {
     pub major: u32,
     pub minor: u32,
     pub max_readahead: u32,
-    pub flags: u32,
+    pub flags: InitFlags,
     pub max_background: u16,
     pub congestion_threshold: u16,
     pub max_write: u32,
     pub time_gran: u32,
     pub max_pages: u16,
     pub max_alignment: u16,
-    pub flags2: u32,
+    pub flags2: InitFlags2,
     pub max_stack_depth: u32,
     pub request_timeout: u16,
     pub unused: Padding<[u16; 11]>,
@@ -431,6 +444,22 @@ pub struct fuse_init_out {
 // FUSE_READDIR
 // uses fuse_read_in
 
+#[repr(C)]
+#[derive(Debug, Clone)]
+#[derive(KnownLayout, Immutable, IntoBytes)]
+/// Directory entry in the reply of `FUSE_READDIR`.
+///
+/// The structure is followed by the name of the entry, padded with zeros to a
+/// multiple of 8 bytes.
+pub struct fuse_dirent {
+    pub ino: u64,
+    /// Offset of the next entry.
+    pub off: u64,
+    pub namelen: u32,
+    /// File type, as in the `S_IFMT` bits of the mode shifted right by 12.
+    pub r#type: u32,
+}
+
 // FUSE_RELEASEDIR
 // uses fuse_release_in
 
@@ -604,6 +633,18 @@ pub struct fuse_fallocate_in {
 // FUSE_READDIRPLUS
 // uses fuse_read_in
 
+#[repr(C)]
+#[derive(Debug, Clone)]
+#[derive(KnownLayout, Immutable, IntoBytes)]
+/// Directory entry in the reply of `FUSE_READDIRPLUS`.
+///
+/// Like [`fuse_dirent`] the structure is followed by the padded name, each
+/// entry counts as a lookup of the inode, except `.` and `..`.
+pub struct fuse_direntplus {
+    pub entry_out: fuse_entry_out,
+    pub dirent: fuse_dirent,
+}
+
 // FUSE_RENAME2
 
 #[repr(C)]
@@ -689,7 +730,7 @@ pub struct fuse_syncfs_in {
 }
 
 // FUSE_TMPFILE
-// TODO
+// uses fuse_create_in
 
 // FUSE_STATX
 
@@ -85,3 +85,14 @@ pub struct fuse_file_lock {
     pub r#type: u32,
     pub pid: u32,
 }
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+#[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
+/// Argument of `FUSE_DEV_IOC_BACKING_OPEN`, registers `fd` as the backing
+/// file of passthrough open files.
+pub struct fuse_backing_map {
+    pub fd: i32,
+    pub flags: u32,
+    pub padding: u64,
+}
@@ -0,0 +1,99 @@
+use std::ffi::OsStr;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+
+use zerocopy::IntoBytes;
+
+use crate::protocol::{fuse_dirent, fuse_direntplus, fuse_entry_out};
+
+/// Buffer for the entries of a `FUSE_READDIR` or `FUSE_READDIRPLUS` reply.
+///
+/// The kernel sets the maximum size of the reply, entries are added until the
+/// buffer is full. Each entry carries the offset of the next one, the kernel
+/// passes it back in the next request to continue reading the directory.
+///
+/// A buffer must only contain entries of one kind, added with
+/// [`DirBuf::push`] for `FUSE_READDIR` and [`DirBuf::push_plus`] for
+/// `FUSE_READDIRPLUS`.
+#[derive(Debug, Clone)]
+pub struct DirBuf {
+    buf: Vec<u8>,
+    max_size: usize,
+}
+
+impl DirBuf {
+    /// Creates an empty buffer that can hold up to `max_size` bytes.
+    pub fn new(max_size: usize) -> Self {
+        Self { buf: Vec::new(), max_size }
+    }
+
+    /// Adds an entry of a `FUSE_READDIR` reply.
+    ///
+    /// `file_type` contains the `S_IFMT` bits of the mode of the entry.
+    /// Returns `false` if the buffer is full, in that case the entry is not
+    /// added.
+    pub fn push(
+        &mut self,
+        ino: u64,
+        next_offset: u64,
+        file_type: u32,
+        name: &OsStr
+    ) -> bool {
+        let dirent = fuse_dirent {
+            ino,
+            off: next_offset,
+            namelen: name.len() as u32,
+            r#type: (file_type & libc::S_IFMT) >> 12,
+        };
+        self.push_entry(dirent.as_bytes(), name.as_bytes())
+    }
+
+    /// Adds an entry of a `FUSE_READDIRPLUS` reply.
+    ///
+    /// Every entry, except `.` and `..`, counts as a lookup of the inode.
+    /// Returns `false` if the buffer is full, in that case the entry is not
+    /// added and the lookup count must not be incremented.
+    pub fn push_plus(
+        &mut self,
+        entry: fuse_entry_out,
+        next_offset: u64,
+        name: &OsStr
+    ) -> bool {
+        let dirent = fuse_dirent {
+            ino: entry.attr.ino,
+            off: next_offset,
+            namelen: name.len() as u32,
+            r#type: (entry.attr.mode & libc::S_IFMT) >> 12,
+        };
+        let direntplus = fuse_direntplus { entry_out: entry, dirent };
+        self.push_entry(direntplus.as_bytes(), name.as_bytes())
+    }
+
+    fn push_entry(&mut self, header: &[u8], name: &[u8]) -> bool {
+        let len = header.len() + name.len();
+        let padded = len.next_multiple_of(size_of::<u64>());
+        if self.buf.len() + padded > self.max_size {
+            return false;
+        }
+        self.buf.extend_from_slice(header);
+        self.buf.extend_from_slice(name);
+        self.buf.resize(self.buf.len() + padded - len, 0);
+        true
+    }
+
+    /// The encoded entries.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
@@ -0,0 +1,56 @@
+use crate::protocol::fuse_in_header;
+
+mod setattr;
+pub use setattr::{SetattrRequest, TimeOrNow};
+
+/// Context of a request sent by the kernel.
+///
+/// The ids are those of the process that made the system call, they are not
+/// translated if the process lives in a different pid or user namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    unique: u64,
+    nodeid: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+}
+
+impl Request {
+    pub(crate) fn new(header: &fuse_in_header) -> Self {
+        Self {
+            unique: header.unique,
+            nodeid: header.nodeid,
+            uid: header.uid,
+            gid: header.gid,
+            pid: header.pid,
+        }
+    }
+
+    /// The id the kernel assigned to the request.
+    #[inline]
+    pub fn unique(&self) -> u64 {
+        self.unique
+    }
+
+    /// The inode the request refers to.
+    #[inline]
+    pub fn nodeid(&self) -> u64 {
+        self.nodeid
+    }
+
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+}
@@ -0,0 +1,116 @@
+use std::time::{Duration, SystemTime};
+
+use crate::Errno;
+use crate::protocol::{fuse_setattr_in, SetattrValid};
+
+/// A timestamp set by a `FUSE_SETATTR` request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeOrNow {
+    /// Set the timestamp to the provided time.
+    Time(SystemTime),
+    /// Set the timestamp to the current time of the FUSE server.
+    ///
+    /// This is sent by the kernel for `utimensat(2)` calls using `UTIME_NOW`.
+    Now,
+}
+
+/// Decoded `FUSE_SETATTR` request.
+///
+/// Most of the fields of [`fuse_setattr_in`] are only meaningful when the
+/// matching [`SetattrValid`] bit is set, this structure exposes only the
+/// attributes that the kernel is asking to change, every other field is `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SetattrRequest {
+    /// New file mode, includes the file type bits.
+    pub mode: Option<u32>,
+    /// New owner of the file.
+    pub uid: Option<u32>,
+    /// New group of the file.
+    pub gid: Option<u32>,
+    /// New size of the file, this is set for `truncate(2)` and `O_TRUNC`.
+    pub size: Option<u64>,
+    /// New last access time.
+    pub atime: Option<TimeOrNow>,
+    /// New last modification time.
+    pub mtime: Option<TimeOrNow>,
+    /// New last status change time.
+    pub ctime: Option<SystemTime>,
+    /// File handle of the open file the request was made on, if any.
+    pub fh: Option<u64>,
+    /// Lock owner of the open file, only sent along with a size change.
+    pub lock_owner: Option<u64>,
+    /// The suid and sgid bits must be cleared, this is only sent when
+    /// `FUSE_HANDLE_KILLPRIV_V2` has been negotiated.
+    pub kill_suidgid: bool,
+}
+
+/// Fails with `EINVAL` if a timestamp can't be represented as a
+/// [`SystemTime`], or has more than a second of nanoseconds.
+impl TryFrom<&fuse_setattr_in> for SetattrRequest {
+    type Error = Errno;
+
+    fn try_from(arg: &fuse_setattr_in) -> Result<Self, Errno> {
+        let valid = arg.valid;
+        let when = |flag: SetattrValid| valid.contains(flag);
+
+        let atime = if when(SetattrValid::FATTR_ATIME_NOW) {
+            Some(TimeOrNow::Now)
+        } else if when(SetattrValid::FATTR_ATIME) {
+            Some(TimeOrNow::Time(to_system_time(arg.atime, arg.atimensec)?))
+        } else {
+            None
+        };
+
+        let mtime = if when(SetattrValid::FATTR_MTIME_NOW) {
+            Some(TimeOrNow::Now)
+        } else if when(SetattrValid::FATTR_MTIME) {
+            Some(TimeOrNow::Time(to_system_time(arg.mtime, arg.mtimensec)?))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            mode: when(SetattrValid::FATTR_MODE).then_some(arg.mode),
+            uid: when(SetattrValid::FATTR_UID).then_some(arg.uid),
+            gid: when(SetattrValid::FATTR_GID).then_some(arg.gid),
+            size: when(SetattrValid::FATTR_SIZE).then_some(arg.size),
+            atime,
+            mtime,
+            ctime: when(SetattrValid::FATTR_CTIME)
+                .then(|| to_system_time(arg.ctime, arg.ctimensec))
+                .transpose()?,
+            fh: when(SetattrValid::FATTR_FH).then_some(arg.fh),
+            lock_owner: when(SetattrValid::FATTR_LOCKOWNER)
+                .then_some(arg.lock_owner),
+            kill_suidgid: when(SetattrValid::FATTR_KILL_SUIDGID),
+        })
+    }
+}
+
+impl TryFrom<fuse_setattr_in> for SetattrRequest {
+    type Error = Errno;
+
+    #[inline]
+    fn try_from(arg: fuse_setattr_in) -> Result<Self, Errno> {
+        Self::try_from(&arg)
+    }
+}
+
+/// Converts a kernel timestamp to a [`SystemTime`].
+///
+/// The kernel sends the seconds as an unsigned value, but they are really the
+/// bits of a signed `time64_t`, so timestamps before the epoch are negative.
+fn to_system_time(secs: u64, nsecs: u32) -> Result<SystemTime, Errno> {
+    if nsecs >= 1_000_000_000 {
+        return Err(Errno::EINVAL);
+    }
+    let secs = secs as i64;
+    let nsecs = Duration::from_nanos(nsecs.into());
+    let time = if secs >= 0 {
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64) + nsecs)
+    } else {
+        SystemTime::UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+            .and_then(|time| time.checked_add(nsecs))
+    };
+    time.ok_or(Errno::EINVAL)
+}
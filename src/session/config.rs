@@ -0,0 +1,286 @@
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::Errno;
+use crate::protocol::FUSE_MIN_READ_BUFFER;
+use super::Metrics;
+
+/// Default maximum size of the data of a `FUSE_WRITE` request.
+const DEFAULT_MAX_WRITE: u32 = 128 * 1024;
+/// Smallest `max_write` accepted by the kernel.
+const MIN_MAX_WRITE: u32 = 4096;
+/// Default value of `/proc/sys/fs/fuse/max_pages_limit`.
+const MAX_PAGES_LIMIT: u16 = 256;
+/// Deepest stacking of file systems allowed by the kernel.
+const FILESYSTEM_MAX_STACK_DEPTH: u32 = 2;
+/// Room for the headers of a request, in addition to its data.
+const HEADER_ROOM: usize = 4096;
+
+/// Settings of a [`Session`](super::Session).
+///
+/// Some settings are negotiated with the kernel during the `FUSE_INIT`
+/// handshake, they are ignored by the kernels that don't support them. The
+/// settings are checked when the session is created.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub(super) max_write: u32,
+    pub(super) max_pages: Option<u16>,
+    pub(super) max_readahead: Option<u32>,
+    pub(super) max_background: Option<u16>,
+    pub(super) congestion_threshold: Option<u16>,
+    pub(super) time_gran: Option<u32>,
+    pub(super) max_stack_depth: Option<u32>,
+    pub(super) request_timeout: Option<u16>,
+    pub(super) handler_timeout: Option<Duration>,
+    pub(super) timeout_errno: Errno,
+    pub(super) locks: bool,
+    pub(super) metrics: Option<Arc<Metrics>>,
+}
+
+impl SessionConfig {
+    pub fn new() -> Self {
+        Self {
+            max_write: DEFAULT_MAX_WRITE,
+            max_pages: None,
+            max_readahead: None,
+            max_background: None,
+            congestion_threshold: None,
+            time_gran: None,
+            max_stack_depth: None,
+            request_timeout: None,
+            handler_timeout: None,
+            timeout_errno: Errno::ETIMEDOUT,
+            locks: false,
+            metrics: None,
+        }
+    }
+
+    /// Maximum size of the data of a `FUSE_WRITE` request, at least 4096.
+    ///
+    /// Writes bigger than a page require `FUSE_BIG_WRITES`, if the kernel
+    /// doesn't support it the size is reduced to 4096. Defaults to 128 KiB.
+    #[inline]
+    #[must_use = "A SessionConfig does nothing unless passed to a Session"]
+    pub fn max_write(mut self, bytes: u32) -> Self {
+        self.max_write = bytes;
+        self
+    }
+
+    /// Maximum number of pages of the data of a request, negotiated with
+    /// `FUSE_MAX_PAGES`.
+    ///
+    /// The pages must fit [`SessionConfig::max_write`], by default the
+    /// smallest number of pages that does is used. The buffer used to read
+    /// requests is sized to hold this many pages. Without `FUSE_MAX_PAGES` the
+    /// kernel uses 32 pages, and `max_write` is reduced to fit.
+    #[inline]
+    #[must_use = "A SessionConfig does nothing unless passed to a Session"]
+    pub fn max_pages(mut self, pages: u16) -> Self {
+        self.max_pages = Some(pages);
+        self
+    }
+
+    /// Maximum size of the readahead, the kernel's value is used if it's
+    /// smaller.
+    #[inline]
+    #[must_use = "A SessionConfig does nothing unless passed to a Session"]
+    pub fn max_readahead(mut self, bytes: u32) -> Self {
+        self.max_readahead = Some(bytes);
+        self
+    }
+
+    /// Maximum number of background requests, like readahead and
+    /// asynchronous direct I/O, queued by the kernel.
+    ///
+    /// Without `CAP_SYS_ADMIN` the kernel caps this to
+    /// `/proc/sys/fs/fuse/max_user_bgreq`. This can be changed on a live
+    /// connection with [`FuseCtl::set_max_background`](crate::FuseCtl::set_max_background).
+    #[inline]
+    #[must_use = "A SessionConfig does nothing unless passed to a Session"]
+    pub fn max_background(mut self, requests: u16) -> Self {
+        self.max_background = Some(requests);
+        self
+    }
+
+    /// Number of background requests after which the file system is reported
+    /// as congested, it must not exceed [`SessionConfig::max_background`].
+    #[inline]
+    #[must_use = "A SessionConfig does nothing unless passed to a Session"]
+    pub fn congestion_threshold(mut self, requests: u16) -> Self {
+        self.congestion_threshold = Some(requests);
+        self
+    }
+
+    /// Granularity of the timestamps of the file system in nanoseconds, a
+    /// power of 10 between 1 and 10^9.
+    #[inline]
+    #[must_use = "A SessionConfig does nothing unless passed to a Session"]
+    pub fn time_gran(mut self, nanos: u32) -> Self {
+        self.time_gran = Some(nanos);
+        self
+    }
+
+    /// Enables passthrough, negotiated with `FUSE_PASSTHROUGH`, allowing
+    /// file systems up to `depth` levels deep below this one.
+    ///
+    /// The file system counts as stacked on the backing files, so a backing
+    /// file on overlayfs, which has depth 1, needs a depth of 2, the maximum
+    /// allowed by the kernel. Likewise another FUSE file system can be mounted
+    /// on top of this one only if `depth` is 1. Backing files are registered
+    /// with [`BackingFiles`](crate::BackingFiles).
+    #[inline]
+    #[must_use = "A SessionConfig does nothing unless passed to a Session"]
+    pub fn max_stack_depth(mut self, depth: u32) -> Self {
+        self.max_stack_depth = Some(depth);
+        self
+    }
+
+    /// Time after which the kernel aborts the connection if a request is not
+    /// answered, negotiated with `FUSE_REQUEST_TIMEOUT`.
+    ///
+    /// The kernel works with whole seconds, so the timeout is rounded up and
+    /// capped to `u16::MAX` seconds. Aborting the connection fails every
+    /// pending and future request, use [`SessionConfig::handler_timeout`] to
+    /// only fail the stuck requests.
+    #[inline]
+    #[must_use = "A SessionConfig does nothing unless passed to a Session"]
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        let secs = timeout.as_secs() + (timeout.subsec_nanos() != 0) as u64;
+        self.request_timeout = Some(secs.clamp(1, u16::MAX as u64) as u16);
+        self
+    }
+
+    /// Maximum time a handler can take to answer a request.
+    ///
+    /// When the deadline expires the handler is cancelled and the request is
+    /// answered with the errno set by [`SessionConfig::timeout_errno`], so a
+    /// stuck backend doesn't block the processes using the file system.
+    #[inline]
+    #[must_use = "A SessionConfig does nothing unless passed to a Session"]
+    pub fn handler_timeout(mut self, timeout: Duration) -> Self {
+        self.handler_timeout = Some(timeout);
+        self
+    }
+
+    /// Error returned for requests whose handler timed out, defaults to
+    /// [`Errno::ETIMEDOUT`].
+    ///
+    /// Some programs don't expect `ETIMEDOUT` from file operations,
+    /// [`Errno::EIO`] is a common alternative.
+    #[inline]
+    #[must_use = "A SessionConfig does nothing unless passed to a Session"]
+    pub fn timeout_errno(mut self, errno: Errno) -> Self {
+        self.timeout_errno = errno;
+        self
+    }
+
+    /// Forwards the POSIX and `flock(2)` locks to the file system, negotiated
+    /// with `FUSE_POSIX_LOCKS` and `FUSE_FLOCK_LOCKS`.
+    ///
+    /// By default the kernel handles the locks locally, which is enough
+    /// unless the files are shared with other processes outside the mount.
+    #[inline]
+    #[must_use = "A SessionConfig does nothing unless passed to a Session"]
+    pub fn locks(mut self, enable: bool) -> Self {
+        self.locks = enable;
+        self
+    }
+
+    /// Updates `metrics` for every request, see [`Metrics`].
+    #[inline]
+    #[must_use = "A SessionConfig does nothing unless passed to a Session"]
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Checks that the settings are consistent.
+    ///
+    /// This is done when the session is created, calling it before mounting
+    /// avoids leaving a mount without a session.
+    pub fn validate(&self) -> io::Result<()> {
+        if self.max_write < MIN_MAX_WRITE {
+            io_error!(
+                ErrorKind::InvalidInput,
+                "max_write must be at least {MIN_MAX_WRITE}, got {}", self.max_write
+            );
+        }
+
+        if let Some(pages) = self.max_pages {
+            if pages == 0 || pages > MAX_PAGES_LIMIT {
+                io_error!(
+                    ErrorKind::InvalidInput,
+                    "max_pages must be between 1 and {MAX_PAGES_LIMIT}, got {pages}"
+                );
+            }
+            if pages as usize * page_size() < self.max_write as usize {
+                io_error!(
+                    ErrorKind::InvalidInput,
+                    "max_write ({}) does not fit in max_pages ({pages})", self.max_write
+                );
+            }
+        } else if self.default_max_pages() > MAX_PAGES_LIMIT as usize {
+            io_error!(
+                ErrorKind::InvalidInput,
+                "max_write ({}) needs more than {MAX_PAGES_LIMIT} pages", self.max_write
+            );
+        }
+
+        if let Some(threshold) = self.congestion_threshold {
+            let max_background = self.max_background.unwrap_or(u16::MAX);
+            if threshold > max_background {
+                io_error!(
+                    ErrorKind::InvalidInput,
+                    "congestion_threshold ({threshold}) exceeds max_background ({max_background})"
+                );
+            }
+        }
+
+        if let Some(depth) = self.max_stack_depth
+            && !(1..=FILESYSTEM_MAX_STACK_DEPTH).contains(&depth)
+        {
+            io_error!(
+                ErrorKind::InvalidInput,
+                "max_stack_depth must be between 1 and {FILESYSTEM_MAX_STACK_DEPTH}, got {depth}"
+            );
+        }
+
+        if let Some(gran) = self.time_gran
+            && !(0..=9).any(|exp| 10u32.pow(exp) == gran)
+        {
+            io_error!(
+                ErrorKind::InvalidInput,
+                "time_gran must be a power of 10 up to 10^9, got {gran}"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Pages requested in `FUSE_INIT`.
+    pub(super) fn pages(&self) -> u16 {
+        self.max_pages.unwrap_or(self.default_max_pages() as u16)
+    }
+
+    fn default_max_pages(&self) -> usize {
+        (self.max_write as usize).div_ceil(page_size())
+    }
+}
+
+/// Size of the buffer needed to read the requests of a session using
+/// `max_pages`.
+pub(super) fn buffer_size(max_pages: u16) -> usize {
+    (max_pages as usize * page_size() + HEADER_ROOM).max(FUSE_MIN_READ_BUFFER)
+}
+
+pub(super) fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+impl Default for SessionConfig {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,272 @@
+use zerocopy::{Immutable, IntoBytes};
+
+use crate::protocol::*;
+use crate::reply::DirBuf;
+use crate::transport::DaxWindow;
+use super::Metrics;
+use crate::{Errno, Filesystem, Request, SetattrRequest};
+
+/// Decodes a request, calls the matching method of the file system and encodes
+/// its reply.
+///
+/// Returns `None` for the requests that must not be answered.
+pub(super) async fn dispatch<F: Filesystem>(
+    fs: &F,
+    version: ProtocolVersion,
+    header: &fuse_in_header,
+    args: &[u8],
+    dax: Option<&dyn DaxWindow>,
+    metrics: Option<&Metrics>
+) -> Result<Option<ReplyBuf>, Errno> {
+    let req = Request::new(header);
+    let unique = header.unique;
+    let ino = header.nodeid;
+
+    let op = match Operation::decode(header.opcode, args, version) {
+        Ok(op) => op,
+        Err(errno) => {
+            #[cfg(feature = "tracing")]
+            super::trace::malformed("bad arguments", args);
+            return Err(errno);
+        },
+    };
+    #[cfg(feature = "tracing")]
+    super::trace::request(&op);
+
+    let reply = match op {
+        Operation::Lookup { name } => {
+            let entry = fs.lookup(&req, ino, name).await?;
+            reply_compat(unique, &entry, version)
+        },
+        Operation::Forget { arg } => {
+            if let Some(metrics) = metrics {
+                metrics.add_forgets(1);
+            }
+            fs.forget(&req, ino, arg.nlookup).await;
+            return Ok(None);
+        },
+        Operation::BatchForget { nodes } => {
+            if let Some(metrics) = metrics {
+                metrics.add_forgets(nodes.len());
+            }
+            for one in nodes {
+                fs.forget(&req, one.nodeid, one.nlookup).await;
+            }
+            return Ok(None);
+        },
+        Operation::Getattr { arg } => {
+            let attr = fs.getattr(&req, ino, &arg).await?;
+            reply_compat(unique, &attr, version)
+        },
+        Operation::Setattr { arg } => {
+            let attr = fs.setattr(&req, ino, SetattrRequest::try_from(arg)?).await?;
+            reply_compat(unique, &attr, version)
+        },
+        Operation::Readlink => {
+            let target = fs.readlink(&req, ino).await?;
+            reply(unique, target.as_slice())
+        },
+        Operation::Symlink { name, target } => {
+            let entry = fs.symlink(&req, ino, name, target).await?;
+            reply_compat(unique, &entry, version)
+        },
+        Operation::Mknod { arg, name } => {
+            let entry = fs.mknod(&req, ino, &arg, name).await?;
+            reply_compat(unique, &entry, version)
+        },
+        Operation::Mkdir { arg, name } => {
+            let entry = fs.mkdir(&req, ino, &arg, name).await?;
+            reply_compat(unique, &entry, version)
+        },
+        Operation::Unlink { name } => {
+            fs.unlink(&req, ino, name).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Rmdir { name } => {
+            fs.rmdir(&req, ino, name).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Rename { arg, name, newname } => {
+            fs.rename(&req, ino, name, arg.newdir, newname, 0).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Rename2 { arg, name, newname } => {
+            fs.rename(&req, ino, name, arg.newdir, newname, arg.flags).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Link { arg, newname } => {
+            let entry = fs.link(&req, arg.oldnodeid, ino, newname).await?;
+            reply_compat(unique, &entry, version)
+        },
+        Operation::Open { arg } => {
+            let open = fs.open(&req, ino, &arg).await?;
+            reply(unique, &open)
+        },
+        Operation::Read { arg } => {
+            let mut data = fs.read(&req, ino, &arg).await?;
+            data.truncate(arg.size as usize);
+            if let Some(metrics) = metrics {
+                metrics.add_read(data.len());
+            }
+            reply(unique, data.as_slice())
+        },
+        Operation::Write { arg, data } => {
+            let size = fs.write(&req, ino, &arg, &data).await?;
+            if let Some(metrics) = metrics {
+                metrics.add_written(size);
+            }
+            reply(unique, &fuse_write_out { size, padding: Padding::new() })
+        },
+        Operation::Statfs => {
+            let st = fs.statfs(&req, ino).await?;
+            reply_compat(unique, &fuse_statfs_out { st }, version)
+        },
+        Operation::Release { arg } => {
+            fs.release(&req, ino, &arg).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Fsync { arg } => {
+            fs.fsync(&req, ino, &arg).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Setxattr { arg, name, value } => {
+            fs.setxattr(&req, ino, &arg, name, &value).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Getxattr { arg, name } => {
+            let value = fs.getxattr(&req, ino, name).await?;
+            reply_xattr(unique, arg.size, &value)?
+        },
+        Operation::Listxattr { arg } => {
+            let names = fs.listxattr(&req, ino).await?;
+            reply_xattr(unique, arg.size, &names)?
+        },
+        Operation::Removexattr { name } => {
+            fs.removexattr(&req, ino, name).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Flush { arg } => {
+            fs.flush(&req, ino, &arg).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Opendir { arg } => {
+            let open = fs.opendir(&req, ino, &arg).await?;
+            reply(unique, &open)
+        },
+        Operation::Readdir { arg } => {
+            let mut buf = DirBuf::new(arg.size as usize);
+            fs.readdir(&req, ino, &arg, &mut buf).await?;
+            reply(unique, buf.as_bytes())
+        },
+        Operation::Readdirplus { arg } => {
+            let mut buf = DirBuf::new(arg.size as usize);
+            fs.readdirplus(&req, ino, &arg, &mut buf).await?;
+            reply(unique, buf.as_bytes())
+        },
+        Operation::Releasedir { arg } => {
+            fs.releasedir(&req, ino, &arg).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Fsyncdir { arg } => {
+            fs.fsyncdir(&req, ino, &arg).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Getlk { arg } => {
+            let lk = fs.getlk(&req, ino, &arg).await?;
+            reply(unique, &fuse_lk_out { lk })
+        },
+        Operation::Setlk { arg } => {
+            fs.setlk(&req, ino, &arg, false).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Setlkw { arg } => {
+            fs.setlk(&req, ino, &arg, true).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Access { arg } => {
+            fs.access(&req, ino, &arg).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Create { arg, name } => {
+            let (entry, open) = fs.create(&req, ino, &arg, name).await?;
+            let mut reply = reply_compat(unique, &entry, version);
+            reply.push(&open);
+            reply
+        },
+        Operation::Tmpfile { arg } => {
+            let (entry, open) = fs.tmpfile(&req, ino, &arg).await?;
+            let mut reply = reply_compat(unique, &entry, version);
+            reply.push(&open);
+            reply
+        },
+        Operation::Bmap { arg } => {
+            let block = fs.bmap(&req, ino, &arg).await?;
+            reply(unique, &fuse_bmap_out { block })
+        },
+        Operation::Fallocate { arg } => {
+            fs.fallocate(&req, ino, &arg).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Lseek { arg } => {
+            let offset = fs.lseek(&req, ino, &arg).await?;
+            reply(unique, &fuse_lseek_out { offset })
+        },
+        Operation::CopyFileRange { arg } => {
+            let size = fs.copy_file_range(&req, ino, &arg).await?;
+            reply(unique, &fuse_write_out { size, padding: Padding::new() })
+        },
+        Operation::Syncfs { .. } => {
+            fs.syncfs(&req, ino).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Setupmapping { arg } => {
+            let window = dax.ok_or(Errno::ENOSYS)?;
+            fs.setupmapping(&req, ino, &arg, window).await?;
+            ReplyBuf::new(unique)
+        },
+        Operation::Removemapping { mappings } => {
+            let window = dax.ok_or(Errno::ENOSYS)?;
+            fs.removemapping(&req, &mappings, window).await?;
+            ReplyBuf::new(unique)
+        },
+        // Handled by the session
+        Operation::Init { .. } | Operation::Destroy => return Err(Errno::EIO),
+        Operation::Interrupt { .. } | Operation::NotifyReply { .. } => return Ok(None),
+        Operation::Ioctl { .. }
+        | Operation::Poll { .. }
+        | Operation::Statx { .. }
+        | Operation::CopyFileRange64 { .. } => return Err(Errno::ENOSYS),
+    };
+
+    Ok(Some(reply))
+}
+
+fn reply<T: IntoBytes + Immutable + ?Sized>(unique: u64, value: &T) -> ReplyBuf {
+    let mut reply = ReplyBuf::new(unique);
+    reply.push(value);
+    reply
+}
+
+fn reply_compat<T>(unique: u64, value: &T, version: ProtocolVersion) -> ReplyBuf
+where
+    T: CompatSize + IntoBytes + Immutable
+{
+    let mut reply = ReplyBuf::new(unique);
+    reply.push_compat(value, version);
+    reply
+}
+
+/// Replies to `FUSE_GETXATTR` and `FUSE_LISTXATTR`, a zero size asks for the
+/// size of the value.
+fn reply_xattr(unique: u64, size: u32, value: &[u8]) -> Result<ReplyBuf, Errno> {
+    if size == 0 {
+        let Ok(size) = u32::try_from(value.len()) else {
+            return Err(Errno::E2BIG);
+        };
+        Ok(reply(unique, &fuse_getxattr_out { size, padding: Padding::new() }))
+    } else if value.len() > size as usize {
+        Err(Errno::ERANGE)
+    } else {
+        Ok(reply(unique, value))
+    }
+}
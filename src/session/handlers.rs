@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::future::Future;
+
+use tokio::task::{AbortHandle, Id, JoinSet};
+
+use crate::protocol::fuse_opcode;
+
+/// The tasks handling the requests, by the unique of their request.
+#[derive(Default)]
+pub(super) struct Handlers {
+    tasks: JoinSet<()>,
+    requests: HashMap<Id, Running>,
+    uniques: HashMap<u64, AbortHandle>,
+}
+
+struct Running {
+    unique: u64,
+    opcode: fuse_opcode,
+    interrupted: bool,
+}
+
+/// How the handling of a request ended.
+pub(super) enum Finished {
+    /// The handler completed, it sent the reply itself.
+    Done,
+    /// The handler panicked, the request was not answered.
+    Panicked { unique: u64, opcode: fuse_opcode },
+    /// The handler was cancelled by a `FUSE_INTERRUPT`, the request was not
+    /// answered.
+    Interrupted { unique: u64 },
+    /// The handler was cancelled by the session.
+    Cancelled { unique: u64 },
+}
+
+impl Handlers {
+    pub fn spawn<T>(&mut self, unique: u64, opcode: fuse_opcode, task: T)
+    where
+        T: Future<Output = ()> + Send + 'static
+    {
+        let handle = self.tasks.spawn(task);
+        let running = Running { unique, opcode, interrupted: false };
+        self.requests.insert(handle.id(), running);
+        self.uniques.insert(unique, handle);
+    }
+
+    /// Cancels the handler of the request `unique`, returns whether it was
+    /// still running.
+    ///
+    /// The request is reported as [`Finished::Interrupted`] once cancelled,
+    /// unless the handler completed first.
+    pub fn interrupt(&mut self, unique: u64) -> bool {
+        let Some(handle) = self.uniques.get(&unique) else {
+            return false;
+        };
+        if let Some(running) = self.requests.get_mut(&handle.id()) {
+            running.interrupted = true;
+        }
+        handle.abort();
+        true
+    }
+
+    /// Waits for a handler to end, `None` if there are none.
+    pub async fn join_next(&mut self) -> Option<Finished> {
+        let (id, result) = match self.tasks.join_next_with_id().await? {
+            Ok((id, ())) => (id, Ok(())),
+            Err(err) => (err.id(), Err(err)),
+        };
+        let Some(running) = self.requests.remove(&id) else {
+            return Some(Finished::Done);
+        };
+        self.uniques.remove(&running.unique);
+        let finished = match result {
+            Ok(()) => Finished::Done,
+            Err(err) if err.is_panic() => Finished::Panicked {
+                unique: running.unique,
+                opcode: running.opcode,
+            },
+            Err(_) if running.interrupted => Finished::Interrupted { unique: running.unique },
+            Err(_) => Finished::Cancelled { unique: running.unique },
+        };
+        Some(finished)
+    }
+
+    /// Cancels every handler, returns the requests that were running.
+    pub async fn shutdown(&mut self) -> Vec<u64> {
+        self.tasks.shutdown().await;
+        self.requests.clear();
+        self.uniques.drain().map(|(unique, _)| unique).collect()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+}
@@ -0,0 +1,280 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem::{size_of, size_of_val};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::ffi::OsStr;
+
+use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
+
+use crate::MountState;
+use crate::protocol::{InitFlags, InitFlags2, ProtocolVersion};
+use super::InitState;
+
+/// Identifies a handover message, and its format.
+const MAGIC: u64 = u64::from_be_bytes(*b"FUSEHO02");
+
+const HAS_NAMESPACE: u32 = 1 << 0;
+const HAS_WATCHDOG: u32 = 1 << 1;
+
+/// Maximum number of file descriptors sent along with the message.
+const MAX_FDS: usize = 3;
+/// Maximum size of the payload and of the snapshot, the lengths come from
+/// the peer so they are checked before reading.
+const MAX_PAYLOAD: u64 = 1 << 30;
+
+#[repr(C)]
+#[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
+/// Fixed part of a handover message, followed by the mountpoint, the
+/// payload and the inode snapshot.
+struct HandoverHeader {
+    magic: u64,
+    major: u32,
+    minor: u32,
+    flags: u32,
+    flags2: u32,
+    max_write: u32,
+    max_pages: u32,
+    connection_id: u32,
+    blksize: u32,
+    fds: u32,
+    allow_idmap: u32,
+    mountpoint_len: u32,
+    padding: u32,
+    payload_len: u64,
+    snapshot_len: u64,
+}
+
+/// A session received from the previous server process.
+///
+/// The previous process sends the session with [`ShutdownHandle::handover`],
+/// then the session continues with [`Session::resume_from`], without
+/// unmounting the file system.
+///
+/// [`ShutdownHandle::handover`]: super::ShutdownHandle::handover
+/// [`Session::resume_from`]: super::Session::resume_from
+pub struct Handover {
+    pub(super) mount: MountState,
+    pub(super) init: InitState,
+    pub(super) snapshot: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+impl Handover {
+    /// Receives a session from the socket, this blocks until the previous
+    /// process has sent it.
+    pub fn receive(socket: &UnixStream) -> io::Result<Self> {
+        let mut header = HandoverHeader::new_zeroed();
+        let (len, mut fds) = recv_with_fds(socket, header.as_mut_bytes())?;
+        if len == 0 {
+            io_error!(ErrorKind::UnexpectedEof, "The socket was closed before the handover");
+        }
+        let mut socket = socket;
+        socket.read_exact(&mut header.as_mut_bytes()[len..])?;
+
+        if header.magic != MAGIC {
+            io_error!(ErrorKind::InvalidData, "The handover message has an unknown format");
+        }
+        if header.mountpoint_len as usize > libc::PATH_MAX as usize {
+            io_error!(ErrorKind::InvalidData, "The mountpoint of the handover is too long");
+        }
+        if header.payload_len > MAX_PAYLOAD || header.snapshot_len > MAX_PAYLOAD {
+            io_error!(ErrorKind::InvalidData, "The payload of the handover is too large");
+        }
+
+        let expected = 1 + (header.fds & HAS_NAMESPACE != 0) as usize
+            + (header.fds & HAS_WATCHDOG != 0) as usize;
+        if fds.len() != expected {
+            io_error!(
+                ErrorKind::InvalidData,
+                "Expected {expected} file descriptors in the handover, got {}", fds.len()
+            );
+        }
+        let watchdog = (header.fds & HAS_WATCHDOG != 0).then(|| fds.pop().unwrap());
+        let namespace = (header.fds & HAS_NAMESPACE != 0).then(|| fds.pop().unwrap());
+        let fuse_dev = fds.pop().unwrap();
+
+        let mountpoint = read_len(socket, header.mountpoint_len.into())?;
+        let payload = read_len(socket, header.payload_len)?;
+        let snapshot = read_len(socket, header.snapshot_len)?;
+
+        Ok(Self {
+            mount: MountState {
+                fuse_dev,
+                namespace,
+                watchdog,
+                mountpoint: PathBuf::from(OsStr::from_bytes(&mountpoint)),
+                blksize: (header.blksize != 0).then_some(header.blksize),
+                allow_idmap: header.allow_idmap != 0,
+                connection_id: header.connection_id,
+            },
+            init: InitState {
+                version: ProtocolVersion::new(header.major, header.minor),
+                flags: InitFlags::from_bits_retain(header.flags),
+                flags2: InitFlags2::from_bits_retain(header.flags2),
+                max_write: header.max_write,
+                max_pages: header.max_pages as u16,
+            },
+            snapshot,
+            payload,
+        })
+    }
+
+    /// Data attached by the previous process, for example its settings.
+    #[inline]
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Path where the file system is mounted.
+    #[inline]
+    pub fn mountpoint(&self) -> &Path {
+        &self.mount.mountpoint
+    }
+
+    /// Protocol version negotiated by the previous process.
+    #[inline]
+    pub fn version(&self) -> ProtocolVersion {
+        self.init.version
+    }
+}
+
+/// Sends the session to the next server process.
+pub(super) fn send(
+    socket: &UnixStream,
+    mount: &MountState,
+    init: &InitState,
+    payload: &[u8],
+    snapshot: &[u8]
+) -> io::Result<()> {
+    let mountpoint = mount.mountpoint.as_os_str().as_bytes();
+
+    let mut fds = vec![mount.fuse_dev.as_raw_fd()];
+    let mut flags = 0;
+    if let Some(ns) = &mount.namespace {
+        fds.push(ns.as_raw_fd());
+        flags |= HAS_NAMESPACE;
+    }
+    if let Some(watchdog) = &mount.watchdog {
+        fds.push(watchdog.as_raw_fd());
+        flags |= HAS_WATCHDOG;
+    }
+
+    let header = HandoverHeader {
+        magic: MAGIC,
+        major: init.version.major,
+        minor: init.version.minor,
+        flags: init.flags.bits(),
+        flags2: init.flags2.bits(),
+        max_write: init.max_write,
+        max_pages: init.max_pages as u32,
+        connection_id: mount.connection_id,
+        blksize: mount.blksize.unwrap_or(0),
+        fds: flags,
+        allow_idmap: mount.allow_idmap as u32,
+        mountpoint_len: mountpoint.len() as u32,
+        padding: 0,
+        payload_len: payload.len() as u64,
+        snapshot_len: snapshot.len() as u64,
+    };
+
+    // The descriptors travel with the first byte, the rest is a plain stream
+    let header = header.as_bytes();
+    let sent = send_with_fds(socket, header, &fds)?;
+    let mut socket = socket;
+    socket.write_all(&header[sent..])?;
+    socket.write_all(mountpoint)?;
+    socket.write_all(payload)?;
+    socket.write_all(snapshot)?;
+    Ok(())
+}
+
+/// Reads `len` bytes, growing the buffer as they arrive rather than trusting
+/// the length up front.
+fn read_len(mut socket: &UnixStream, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if (&mut socket).take(len).read_to_end(&mut buf)? as u64 != len {
+        io_error!(ErrorKind::UnexpectedEof, "The socket was closed during the handover");
+    }
+    Ok(buf)
+}
+
+fn send_with_fds(socket: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    let mut cmsg_buf = [0u64; cmsg_space(MAX_FDS) / size_of::<u64>()];
+    let fds_len = size_of_val(fds) as u32;
+
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(fds_len) } as _;
+
+    // SAFETY: the control buffer has room for MAX_FDS descriptors
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+    }
+
+    let ret = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+    if ret == -1 {
+        let err = io::Error::last_os_error();
+        io_error!(err.kind(), "Failed to send the handover: {err}");
+    }
+    Ok(ret as usize)
+}
+
+fn recv_with_fds(socket: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+    let mut cmsg_buf = [0u64; cmsg_space(MAX_FDS) / size_of::<u64>()];
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+    msg.msg_controllen = size_of_val(&cmsg_buf) as _;
+
+    let ret = unsafe {
+        libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC)
+    };
+    if ret == -1 {
+        let err = io::Error::last_os_error();
+        io_error!(err.kind(), "Failed to receive the handover: {err}");
+    }
+
+    let mut fds = Vec::new();
+    // SAFETY: the kernel filled the control buffer
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        io_error!(ErrorKind::InvalidData, "Too many file descriptors in the handover");
+    }
+    Ok((ret as usize, fds))
+}
+
+const fn cmsg_space(fds: usize) -> usize {
+    // SAFETY: CMSG_SPACE only does arithmetic
+    unsafe { libc::CMSG_SPACE((fds * size_of::<RawFd>()) as u32) as usize }
+}
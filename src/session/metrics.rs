@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use zerocopy::TryFromBytes;
+
+use crate::Errno;
+use crate::protocol::fuse_opcode;
+
+/// Number of opcodes, indexed by their value.
+const OPCODES: usize = fuse_opcode::FUSE_COPY_FILE_RANGE_64 as usize + 1;
+/// Buckets of the latency histograms, the bucket `i` counts the latencies up
+/// to 2^i µs and the last one the latencies above.
+const BUCKETS: usize = 24;
+
+/// Counters updated by a [`Session`](super::Session) for every request.
+///
+/// The metrics are shared with the session by
+/// [`SessionConfig::metrics`](super::SessionConfig::metrics), and can be read
+/// at any time with [`Metrics::snapshot`], for example to export them or to
+/// alert on slow requests. Several sessions can share the same metrics.
+pub struct Metrics {
+    opcodes: [OpcodeMetrics; OPCODES],
+    in_flight: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    forgets: AtomicU64,
+}
+
+#[derive(Default)]
+struct OpcodeMetrics {
+    count: AtomicU64,
+    /// Only updated on errors, which are rare enough for a lock.
+    errors: Mutex<HashMap<Errno, u64>>,
+    latency: [AtomicU64; BUCKETS],
+    /// Sum of the latencies in nanoseconds.
+    latency_sum: AtomicU64,
+}
+
+/// Values of the [`Metrics`] at some point.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct MetricsSnapshot {
+    /// Statistics of the opcodes received at least once, ordered by opcode.
+    pub opcodes: Vec<(fuse_opcode, OpcodeStats)>,
+    /// Number of requests being handled.
+    pub in_flight: u64,
+    /// Bytes returned by `FUSE_READ` requests.
+    pub bytes_read: u64,
+    /// Bytes written by `FUSE_WRITE` requests.
+    pub bytes_written: u64,
+    /// Number of inodes forgotten by `FUSE_FORGET` and `FUSE_BATCH_FORGET`.
+    pub forgets: u64,
+}
+
+/// Statistics of the requests with an opcode.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct OpcodeStats {
+    /// Number of requests received.
+    pub count: u64,
+    /// Number of requests answered with each error.
+    pub errors: HashMap<Errno, u64>,
+    /// Time taken to handle the requests, for those that completed.
+    pub latency: Histogram,
+}
+
+/// Histogram of latencies, with buckets doubling from 1 µs to about 4 s.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+    sum: Duration,
+}
+
+/// Decrements the in-flight gauge when the handling of a request ends, even
+/// if its handler was cancelled.
+pub(super) struct InFlight<'a> {
+    metrics: &'a Metrics,
+    opcode: fuse_opcode,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            opcodes: std::array::from_fn(|_| OpcodeMetrics::default()),
+            in_flight: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            forgets: AtomicU64::new(0),
+        }
+    }
+
+    /// Reads the current values.
+    ///
+    /// The counters are read one by one while the session updates them, so
+    /// they may be slightly inconsistent with each other.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let opcodes = self.opcodes.iter()
+            .enumerate()
+            .filter(|(_, metrics)| metrics.count.load(Ordering::Relaxed) > 0)
+            .filter_map(|(index, metrics)| Some((opcode(index)?, metrics.snapshot())))
+            .collect();
+        MetricsSnapshot {
+            opcodes,
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            forgets: self.forgets.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counts a request that is handled right away, without a latency.
+    pub(super) fn count(&self, opcode: fuse_opcode) {
+        self.opcodes[opcode as usize].count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request whose handling starts.
+    pub(super) fn start(&self, opcode: fuse_opcode) -> InFlight<'_> {
+        self.count(opcode);
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight { metrics: self, opcode }
+    }
+
+    pub(super) fn add_read(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_written(&self, bytes: u32) {
+        self.bytes_written.fetch_add(bytes.into(), Ordering::Relaxed);
+    }
+
+    pub(super) fn add_forgets(&self, inodes: usize) {
+        self.forgets.fetch_add(inodes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Default for Metrics {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Metrics").field(&self.snapshot()).finish()
+    }
+}
+
+impl InFlight<'_> {
+    /// Records the outcome of the request, `errno` is the error of the reply.
+    pub(super) fn finish(self, errno: Option<Errno>, latency: Duration) {
+        let metrics = &self.metrics.opcodes[self.opcode as usize];
+        metrics.latency[bucket(latency)].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        metrics.latency_sum.fetch_add(nanos, Ordering::Relaxed);
+        if let Some(errno) = errno {
+            let mut errors = metrics.errors.lock().unwrap();
+            *errors.entry(errno).or_default() += 1;
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl OpcodeMetrics {
+    fn snapshot(&self) -> OpcodeStats {
+        OpcodeStats {
+            count: self.count.load(Ordering::Relaxed),
+            errors: self.errors.lock().unwrap().clone(),
+            latency: Histogram {
+                counts: self.latency.each_ref().map(|count| count.load(Ordering::Relaxed)),
+                sum: Duration::from_nanos(self.latency_sum.load(Ordering::Relaxed)),
+            },
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    /// Statistics of an opcode, `None` if it was never received.
+    pub fn opcode(&self, opcode: fuse_opcode) -> Option<&OpcodeStats> {
+        self.opcodes.iter().find(|(op, _)| *op == opcode).map(|(_, stats)| stats)
+    }
+
+    /// Number of requests received, with any opcode.
+    pub fn requests(&self) -> u64 {
+        self.opcodes.iter().map(|(_, stats)| stats.count).sum()
+    }
+}
+
+impl OpcodeStats {
+    /// Number of requests answered with an error.
+    pub fn error_count(&self) -> u64 {
+        self.errors.values().sum()
+    }
+}
+
+impl Histogram {
+    /// Number of latencies recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Sum of the latencies recorded.
+    #[inline]
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Average latency, `None` if none was recorded.
+    pub fn mean(&self) -> Option<Duration> {
+        let count = u128::from(self.count());
+        (count > 0).then(|| Duration::from_nanos((self.sum.as_nanos() / count) as u64))
+    }
+
+    /// The upper bound of the bucket of the latency at the quantile `q`,
+    /// between 0 and 1, `None` if no latency was recorded.
+    ///
+    /// Latencies in the last bucket, above about 4 s, give [`Duration::MAX`].
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.buckets()
+            .find(|&(_, count)| {
+                seen += count;
+                seen >= rank
+            })
+            .map(|(bound, _)| bound.unwrap_or(Duration::MAX))
+    }
+
+    /// The buckets as their upper bound and their count, the bound of the
+    /// last bucket is `None` since it counts all the latencies above.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.counts.iter().enumerate().map(|(index, &count)| {
+            let bound = (index < BUCKETS - 1).then(|| Duration::from_micros(1 << index));
+            (bound, count)
+        })
+    }
+}
+
+/// Index of the bucket of a latency, the smallest `i` with `latency <= 2^i µs`.
+fn bucket(latency: Duration) -> usize {
+    let micros = latency.as_nanos().div_ceil(1000);
+    let index = match micros {
+        0 | 1 => 0,
+        _ => (u128::BITS - (micros - 1).leading_zeros()) as usize,
+    };
+    index.min(BUCKETS - 1)
+}
+
+fn opcode(index: usize) -> Option<fuse_opcode> {
+    fuse_opcode::try_read_from_bytes(&(index as u32).to_ne_bytes()).ok()
+}
@@ -0,0 +1,830 @@
+use std::future::pending;
+use std::io::{self, ErrorKind, IoSlice};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{Instant, sleep_until};
+use zerocopy::IntoBytes;
+
+use crate::protocol::*;
+use crate::{Errno, Filesystem, Mount, MountState, Request};
+use crate::transport::{ClonedDevice, FuseDevice, TraceTransport, Tracer, Transport};
+
+mod dispatch;
+use dispatch::dispatch;
+
+mod handover;
+pub use handover::Handover;
+
+mod handlers;
+use handlers::{Finished, Handlers};
+
+mod config;
+pub use config::SessionConfig;
+
+mod metrics;
+pub use metrics::{Histogram, Metrics, MetricsSnapshot, OpcodeStats};
+
+#[cfg(feature = "tracing")]
+mod trace;
+
+/// Pages of a request when `FUSE_MAX_PAGES` is not negotiated.
+const DEFAULT_MAX_PAGES: u16 = 32;
+/// Size of a write when `FUSE_BIG_WRITES` is not negotiated.
+const SMALL_MAX_WRITE: u32 = 4096;
+
+/// Flags of `FUSE_INIT` supported by the session.
+const INIT_FLAGS: InitFlags = InitFlags::FUSE_ASYNC_READ
+    .union(InitFlags::FUSE_BIG_WRITES)
+    .union(InitFlags::FUSE_MAX_PAGES)
+    .union(InitFlags::FUSE_PARALLEL_DIROPS)
+    .union(InitFlags::FUSE_INIT_EXT);
+
+/// A FUSE session, serving the requests for a mounted file system.
+///
+/// The requests are received from the FUSE device of the mount, or from
+/// another [`Transport`] when created with [`Session::with_transport`].
+///
+/// With the `tracing` feature each request is handled in a `request` span
+/// with its header, the decoded arguments are logged at the debug level and
+/// the latency and the error of the reply are recorded in the span. Requests
+/// that cannot be decoded are logged with a dump of their bytes.
+pub struct Session<F, T = FuseDevice> {
+    fs: Arc<F>,
+    transport: Arc<T>,
+    mount: Option<Arc<Mount>>,
+    config: SessionConfig,
+    init: Option<InitState>,
+    /// Inodes saved by the previous process, restored before serving.
+    snapshot: Option<Vec<u8>>,
+    stop: watch::Sender<Option<Instant>>,
+    handover: mpsc::UnboundedSender<HandoverRequest>,
+    handover_rx: mpsc::UnboundedReceiver<HandoverRequest>,
+    finished: watch::Sender<bool>,
+}
+
+/// Parameters negotiated with the `FUSE_INIT` handshake.
+#[derive(Debug, Clone, Copy)]
+struct InitState {
+    version: ProtocolVersion,
+    flags: InitFlags,
+    flags2: InitFlags2,
+    max_write: u32,
+    max_pages: u16,
+}
+
+/// Request to send the session to another process.
+struct HandoverRequest {
+    socket: UnixStream,
+    payload: Vec<u8>,
+    /// Until when the requests in flight are completed before the handover.
+    deadline: Instant,
+    done: oneshot::Sender<io::Result<()>>,
+}
+
+/// Statistics of a session returned by [`Session::run`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SessionSummary {
+    /// Number of requests received, including those without a reply.
+    pub requests: u64,
+    /// Number of requests answered with an error, including those whose
+    /// handler timed out, panicked or was interrupted.
+    pub errors: u64,
+    /// Number of handlers cancelled by [`SessionConfig::handler_timeout`].
+    pub timed_out: u64,
+    /// Number of requests that were still being handled when the shutdown or
+    /// handover timeout expired, their handlers were cancelled without a
+    /// reply.
+    pub cancelled: u64,
+}
+
+/// Handle to stop a running [`Session`].
+///
+/// The handle can be cloned and sent to other tasks.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    stop: watch::Sender<Option<Instant>>,
+    handover: mpsc::UnboundedSender<HandoverRequest>,
+    finished: watch::Receiver<bool>,
+}
+
+impl<F: Filesystem> Session<F> {
+    pub fn new(mount: Mount, fs: F) -> Self {
+        Self::from_parts(mount.device(), Some(mount), fs, SessionConfig::new())
+    }
+
+    /// Creates a session with custom settings, fails if the settings are not
+    /// valid.
+    pub fn with_config(mount: Mount, fs: F, config: SessionConfig) -> io::Result<Self> {
+        config.validate()?;
+        Ok(Self::from_parts(mount.device(), Some(mount), fs, config))
+    }
+
+    /// Continues a session received from the previous server process.
+    ///
+    /// The `FUSE_INIT` handshake is skipped, so [`Filesystem::init`] is not
+    /// called. Instead the inodes saved by the previous process are given to
+    /// [`Filesystem::restore`] before serving, the rest of the state can be
+    /// restored from [`Handover::payload`]. Once running, the session asks
+    /// the kernel to resend the requests that the previous process did not
+    /// answer.
+    pub fn resume_from(handover: Handover, fs: F) -> Self {
+        let mount = Mount::from_state(handover.mount);
+        let mut session = Self::from_parts(mount.device(), Some(mount), fs, SessionConfig::new());
+        session.init = Some(handover.init);
+        session.snapshot = Some(handover.snapshot);
+        session
+    }
+
+    /// Like [`Session::resume_from`], the settings negotiated with the kernel
+    /// are kept from the previous process, only the other ones are taken from
+    /// `config`.
+    pub fn resume_with_config(
+        handover: Handover,
+        fs: F,
+        config: SessionConfig
+    ) -> io::Result<Self> {
+        config.validate()?;
+        let mount = Mount::from_state(handover.mount);
+        let mut session = Self::from_parts(mount.device(), Some(mount), fs, config);
+        session.init = Some(handover.init);
+        session.snapshot = Some(handover.snapshot);
+        Ok(session)
+    }
+}
+
+impl<F: Filesystem> Session<F, ClonedDevice> {
+    /// Creates a session reading the requests from the device of `mount` and
+    /// from `clones` more devices attached to the same connection.
+    pub fn with_clones(
+        mount: Mount,
+        fs: F,
+        config: SessionConfig,
+        clones: usize
+    ) -> io::Result<Self> {
+        config.validate()?;
+        let transport = ClonedDevice::new(mount.device(), clones)?;
+        Ok(Self::from_parts(transport, Some(mount), fs, config))
+    }
+}
+
+impl<F: Filesystem> Session<F, TraceTransport<FuseDevice>> {
+    /// Creates a session printing the requests read from the device of
+    /// `mount` and their replies with `tracer`.
+    pub fn with_tracer(
+        mount: Mount,
+        fs: F,
+        config: SessionConfig,
+        tracer: Tracer
+    ) -> io::Result<Self> {
+        config.validate()?;
+        let transport = TraceTransport::new(mount.device(), tracer);
+        Ok(Self::from_parts(transport, Some(mount), fs, config))
+    }
+}
+
+impl<F: Filesystem, T: Transport> Session<F, T> {
+    /// Creates a session receiving the requests from `transport`.
+    ///
+    /// The session is not attached to a mount, so it can't be handed over,
+    /// and on shutdown it relies on [`Transport::shutdown`] to end the
+    /// connection.
+    pub fn with_transport(transport: T, fs: F, config: SessionConfig) -> io::Result<Self> {
+        config.validate()?;
+        Ok(Self::from_parts(transport, None, fs, config))
+    }
+
+    fn from_parts(transport: T, mount: Option<Mount>, fs: F, config: SessionConfig) -> Self {
+        let (handover, handover_rx) = mpsc::unbounded_channel();
+        Self {
+            fs: Arc::new(fs),
+            transport: Arc::new(transport),
+            mount: mount.map(Arc::new),
+            config,
+            init: None,
+            snapshot: None,
+            stop: watch::Sender::new(None),
+            handover,
+            handover_rx,
+            finished: watch::Sender::new(false),
+        }
+    }
+
+    /// The mount served by the session, `None` if the session was created
+    /// with [`Session::with_transport`].
+    #[inline]
+    pub fn mount(&self) -> Option<&Mount> {
+        self.mount.as_deref()
+    }
+
+    #[inline]
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    #[inline]
+    pub fn filesystem(&self) -> &F {
+        &self.fs
+    }
+
+    /// Returns a handle that can stop the session once it's running.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            stop: self.stop.clone(),
+            handover: self.handover.clone(),
+            finished: self.finished.subscribe(),
+        }
+    }
+
+    /// Serves requests until the file system is unmounted.
+    ///
+    /// Each request is handled in its own task, so this must be called inside
+    /// a tokio runtime. A handler that panics is answered with `EIO`, and the
+    /// handler of a request interrupted with `FUSE_INTERRUPT` is cancelled and
+    /// answered with `EINTR`. The session ends when the kernel sends `FUSE_DESTROY`,
+    /// when reading the device fails with `ENODEV` because the file system was
+    /// unmounted, or after a [`ShutdownHandle::shutdown`]. In every case the
+    /// requests still in flight are completed before returning, and
+    /// [`Filesystem::destroy`] is called.
+    ///
+    /// After a [`ShutdownHandle::handover`] the session returns as soon as it
+    /// has been sent, the file system stays mounted and `destroy` is not
+    /// called.
+    pub async fn run(mut self) -> io::Result<SessionSummary> {
+        let result = self.serve().await;
+        self.finished.send_replace(true);
+        result
+    }
+
+    async fn serve(&mut self) -> io::Result<SessionSummary> {
+        let transport = self.transport.clone();
+        let errors = Arc::new(AtomicU64::new(0));
+        let timed_out = Arc::new(AtomicU64::new(0));
+        let mut summary = SessionSummary::default();
+        let mut handlers = Handlers::default();
+
+        let mut stop = self.stop.subscribe();
+        // A shutdown requested before the session started is still pending
+        if stop.borrow().is_some() {
+            stop.mark_changed();
+        }
+        let mut deadline = None;
+        let mut init = self.init;
+        let mut destroyed = false;
+        let mut unmount = None;
+        let mut unmount_error = None;
+        let max_pages = match &init {
+            Some(init) => init.max_pages,
+            None => self.config.pages(),
+        };
+        let mut buf = vec![0; config::buffer_size(max_pages)];
+
+        if let Some(snapshot) = self.snapshot.take()
+            && !snapshot.is_empty()
+            && let Err(errno) = self.fs.restore(&snapshot).await
+        {
+            io_error!(ErrorKind::InvalidData, "Failed to restore the inodes: {errno}");
+        }
+        // The requests read by the previous process were not answered
+        if self.init.is_some() {
+            let resend = transport.notify(fuse_notify_code::FUSE_NOTIFY_RESEND, &[]);
+            if let Err(err) = resend {
+                io_error!(err.kind(), "Failed to resume the session: {err}");
+            }
+        }
+
+        loop {
+            let len = tokio::select! {
+                biased;
+                Some(finished) = handlers.join_next(), if !handlers.is_empty() => {
+                    complete(&*transport, &errors, finished);
+                    continue;
+                },
+                _ = stop.changed(), if deadline.is_none() => {
+                    deadline = *stop.borrow_and_update();
+                    match &self.mount {
+                        // The unmount may wait for the namespace helper
+                        Some(mount) => {
+                            let mount = mount.clone();
+                            unmount = Some(tokio::task::spawn_blocking(move || mount.unmount()));
+                        },
+                        None => transport.shutdown().await,
+                    }
+                    continue;
+                },
+                joined = async { unmount.as_mut().unwrap().await }, if unmount.is_some() => {
+                    unmount = None;
+                    match joined.unwrap_or_else(|err| Err(io::Error::other(err))) {
+                        Ok(()) => (),
+                        // Already unmounted, the session is ending anyway
+                        Err(err) if err.kind() == ErrorKind::NotFound => (),
+                        // Nothing else ends the connection, the requests in
+                        // flight are completed before returning the error
+                        Err(err) => {
+                            unmount_error = Some(err);
+                            break;
+                        },
+                    }
+                    continue;
+                },
+                _ = sleep_until_deadline(deadline) => break,
+                Some(request) = self.handover_rx.recv() => {
+                    let sent = self.handover(&mut handlers, &errors, init, request).await;
+                    if let Some(cancelled) = sent {
+                        summary.cancelled = cancelled as u64;
+                        summary.errors = errors.load(Ordering::Relaxed);
+                        summary.timed_out = timed_out.load(Ordering::Relaxed);
+                        return Ok(summary);
+                    }
+                    continue;
+                },
+                read = transport.receive(&mut buf) => match read {
+                    Ok(len) => len,
+                    // The request was interrupted before we could read it
+                    Err(err) if err.raw_os_error() == Some(libc::ENOENT) => continue,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                    // The file system was unmounted or the connection aborted
+                    Err(err) if err.raw_os_error() == Some(libc::ENODEV) => break,
+                    Err(err) => io_error!(
+                        err.kind(),
+                        "Failed to receive a request: {err}"
+                    ),
+                },
+            };
+
+            let mut args = ArgReader::new(&buf[..len]);
+            let header = match args.fetch_header() {
+                Ok(header) => header,
+                Err(HeaderError::UnknownOpcode { unique, .. }) => {
+                    #[cfg(feature = "tracing")]
+                    trace::malformed("unknown opcode", &buf[..len]);
+                    summary.requests += 1;
+                    errors.fetch_add(1, Ordering::Relaxed);
+                    send_reply(&*transport, ReplyBuf::error(unique, Errno::ENOSYS));
+                    continue;
+                },
+                Err(HeaderError::Truncated) => {
+                    #[cfg(feature = "tracing")]
+                    trace::malformed("truncated header", &buf[..len]);
+                    io_error!(ErrorKind::InvalidData, "Received a truncated request");
+                },
+            };
+            summary.requests += 1;
+
+            match header.opcode {
+                fuse_opcode::FUSE_INIT => {
+                    let start = Instant::now();
+                    let in_flight = self.config.metrics.as_ref().map(|m| m.start(header.opcode));
+                    let reply = self.init(&header, &mut args);
+                    #[cfg(feature = "tracing")]
+                    let reply = tracing::Instrument::instrument(reply, trace::span(&header));
+                    let reply = reply.await;
+                    if let Some(in_flight) = in_flight {
+                        in_flight.finish(reply.as_ref().err().copied(), start.elapsed());
+                    }
+                    let reply = match reply {
+                        Ok((reply, negotiated)) => {
+                            init = negotiated;
+                            reply
+                        },
+                        Err(errno) => {
+                            errors.fetch_add(1, Ordering::Relaxed);
+                            ReplyBuf::error(header.unique, errno)
+                        },
+                    };
+                    send_reply(&*transport, reply);
+                },
+                fuse_opcode::FUSE_DESTROY => {
+                    #[cfg(feature = "tracing")]
+                    trace::span(&header).in_scope(|| tracing::debug!("request"));
+                    let start = Instant::now();
+                    let in_flight = self.config.metrics.as_ref().map(|m| m.start(header.opcode));
+                    while let Some(finished) = handlers.join_next().await {
+                        complete(&*transport, &errors, finished);
+                    }
+                    self.fs.destroy().await;
+                    destroyed = true;
+                    if let Some(in_flight) = in_flight {
+                        in_flight.finish(None, start.elapsed());
+                    }
+                    send_reply(&*transport, ReplyBuf::new(header.unique));
+                    break;
+                },
+                // The handler is cancelled, and the request answered with
+                // EINTR once it stopped. The request was read before the
+                // interrupt, so an unknown request has already been answered
+                fuse_opcode::FUSE_INTERRUPT => {
+                    if let Some(metrics) = &self.config.metrics {
+                        metrics.count(header.opcode);
+                    }
+                    #[cfg(feature = "tracing")]
+                    trace::span(&header).in_scope(|| {
+                        // The arguments don't depend on the version
+                        let args = args.remaining();
+                        let op = Operation::decode(header.opcode, args, ProtocolVersion::CURRENT);
+                        if let Ok(op) = op {
+                            trace::request(&op);
+                        }
+                    });
+                    if let Ok(arg) = args.fetch::<fuse_interrupt_in>() {
+                        handlers.interrupt(arg.unique);
+                    }
+                },
+                _ => {
+                    let Some(InitState { version, .. }) = init else {
+                        errors.fetch_add(1, Ordering::Relaxed);
+                        send_reply(&*transport, ReplyBuf::error(header.unique, Errno::EIO));
+                        continue;
+                    };
+
+                    let (unique, opcode) = (header.unique, header.opcode);
+                    let fs = self.fs.clone();
+                    let transport = transport.clone();
+                    let errors = errors.clone();
+                    let timed_out = timed_out.clone();
+                    let timeout = self.config.handler_timeout;
+                    let timeout_errno = self.config.timeout_errno;
+                    let metrics = self.config.metrics.clone();
+                    let args = args.remaining().to_vec();
+                    #[cfg(feature = "tracing")]
+                    let span = trace::span(&header);
+                    #[cfg(feature = "tracing")]
+                    let reply_span = span.clone();
+                    let task = async move {
+                        let start = Instant::now();
+                        let metrics = metrics.as_deref();
+                        let in_flight = metrics.map(|m| m.start(header.opcode));
+                        let dax = transport.dax_window();
+                        let handler = dispatch(&*fs, version, &header, &args, dax, metrics);
+                        let result = match timeout {
+                            Some(timeout) => match tokio::time::timeout(timeout, handler).await {
+                                Ok(result) => result,
+                                Err(_) => {
+                                    timed_out.fetch_add(1, Ordering::Relaxed);
+                                    match header.opcode.expects_reply() {
+                                        true => Err(timeout_errno),
+                                        false => Ok(None),
+                                    }
+                                },
+                            },
+                            None => handler.await,
+                        };
+                        let latency = start.elapsed();
+                        #[cfg(feature = "tracing")]
+                        trace::reply(&reply_span, &result, latency);
+                        if let Some(in_flight) = in_flight {
+                            in_flight.finish(result.as_ref().err().copied(), latency);
+                        }
+                        let reply = match result {
+                            Ok(Some(reply)) => reply,
+                            Ok(None) => return,
+                            Err(errno) => {
+                                errors.fetch_add(1, Ordering::Relaxed);
+                                ReplyBuf::error(header.unique, errno)
+                            },
+                        };
+                        send_reply(&*transport, reply);
+                    };
+                    #[cfg(feature = "tracing")]
+                    let task = tracing::Instrument::instrument(task, span);
+                    handlers.spawn(unique, opcode, task);
+                },
+            }
+        }
+
+        // Complete the requests in flight, until the shutdown deadline
+        let drain = async {
+            while let Some(finished) = handlers.join_next().await {
+                complete(&*transport, &errors, finished);
+            }
+        };
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline, drain).await.is_err() {
+                    let cancelled = handlers.shutdown().await;
+                    cancelled.iter().for_each(|&unique| transport.cancel(unique));
+                    summary.cancelled = cancelled.len() as u64;
+                }
+            },
+            None => drain.await,
+        }
+
+        if !destroyed {
+            self.fs.destroy().await;
+        }
+        if let Some(err) = unmount_error {
+            return Err(err);
+        }
+
+        summary.errors = errors.load(Ordering::Relaxed);
+        summary.timed_out = timed_out.load(Ordering::Relaxed);
+        Ok(summary)
+    }
+
+    /// Handles the `FUSE_INIT` handshake.
+    ///
+    /// Returns the reply along with the negotiated parameters, which are
+    /// `None` when the kernel must send a new request with our version.
+    async fn init(
+        &self,
+        header: &fuse_in_header,
+        args: &mut ArgReader<'_>
+    ) -> Result<(ReplyBuf, Option<InitState>), Errno> {
+        let size = args.remaining().len();
+        let arg: fuse_init_in = args.fetch_sized(size)?;
+        #[cfg(feature = "tracing")]
+        trace::request(&Operation::Init { arg: arg.clone() });
+
+        let mut out = fuse_init_out {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 0,
+            flags: InitFlags::empty(),
+            max_background: 0,
+            congestion_threshold: 0,
+            max_write: 0,
+            time_gran: 0,
+            max_pages: 0,
+            max_alignment: 0,
+            flags2: InitFlags2::empty(),
+            max_stack_depth: 0,
+            request_timeout: 0,
+            unused: Padding::new(),
+        };
+
+        // A newer kernel will retry with our version
+        if arg.major > FUSE_KERNEL_VERSION {
+            let mut reply = ReplyBuf::new(header.unique);
+            reply.push_bytes(&out.as_bytes()[..FUSE_COMPAT_INIT_OUT_SIZE]);
+            return Ok((reply, None));
+        }
+
+        let kernel = ProtocolVersion::new(arg.major, arg.minor);
+        let version = ProtocolVersion::negotiate(kernel).ok_or(Errno::EPROTO)?;
+
+        let flags = arg.flags;
+        let flags2 = match flags.contains(InitFlags::FUSE_INIT_EXT) {
+            true => arg.flags2,
+            false => InitFlags2::empty(),
+        };
+        let mut wanted2 = InitFlags2::FUSE_HAS_RESEND;
+        if self.mount.as_deref().is_some_and(Mount::allows_idmap) {
+            wanted2 |= InitFlags2::FUSE_ALLOW_IDMAP;
+        }
+        if let Some(depth) = self.config.max_stack_depth
+            && flags2.contains(InitFlags2::FUSE_PASSTHROUGH)
+        {
+            wanted2 |= InitFlags2::FUSE_PASSTHROUGH;
+            out.max_stack_depth = depth;
+        }
+        if let Some(timeout) = self.config.request_timeout
+            && flags2.contains(InitFlags2::FUSE_REQUEST_TIMEOUT)
+        {
+            wanted2 |= InitFlags2::FUSE_REQUEST_TIMEOUT;
+            out.request_timeout = timeout;
+        }
+
+        self.fs.init(&Request::new(header)).await?;
+
+        let mut wanted = INIT_FLAGS;
+        if self.config.locks {
+            wanted |= InitFlags::FUSE_POSIX_LOCKS | InitFlags::FUSE_FLOCK_LOCKS;
+        }
+        let flags = flags & wanted;
+        let (max_write, max_pages) = self.limits(flags);
+        let init = InitState {
+            version,
+            flags,
+            flags2: flags2 & wanted2,
+            max_write,
+            max_pages,
+        };
+
+        let config = &self.config;
+        out.minor = version.minor;
+        out.max_readahead = config.max_readahead
+            .map_or(arg.max_readahead, |max| max.min(arg.max_readahead));
+        out.flags = init.flags;
+        out.flags2 = init.flags2;
+        out.max_background = config.max_background.unwrap_or(0);
+        out.congestion_threshold = config.congestion_threshold.unwrap_or(0);
+        out.max_write = init.max_write;
+        out.time_gran = config.time_gran.unwrap_or(0);
+        out.max_pages = init.max_pages;
+
+        #[cfg(feature = "tracing")]
+        trace::init_reply(&out);
+        let mut reply = ReplyBuf::new(header.unique);
+        reply.push_compat(&out, version);
+        Ok((reply, Some(init)))
+    }
+
+    /// Size limits of the requests, given the flags supported by the kernel.
+    fn limits(&self, flags: InitFlags) -> (u32, u16) {
+        let mut max_pages = self.config.pages();
+        let mut max_write = self.config.max_write;
+        if !flags.contains(InitFlags::FUSE_MAX_PAGES) {
+            max_pages = max_pages.min(DEFAULT_MAX_PAGES);
+            let pages_size = max_pages as usize * config::page_size();
+            max_write = max_write.min(pages_size as u32);
+        }
+        if !flags.contains(InitFlags::FUSE_BIG_WRITES) {
+            max_write = SMALL_MAX_WRITE;
+        }
+        (max_write, max_pages)
+    }
+
+    /// Sends the session to another process, returns the number of handlers
+    /// cancelled if it was sent.
+    ///
+    /// No request is received meanwhile, the requests in flight are completed
+    /// until the deadline, since they may have changed the file system
+    /// already and the kernel would send them again. The handlers still
+    /// running after the deadline are cancelled, the kernel will resend their
+    /// requests to the next process. If sending fails the kernel is asked to
+    /// resend them to this session instead.
+    async fn handover(
+        &self,
+        handlers: &mut Handlers,
+        errors: &AtomicU64,
+        init: Option<InitState>,
+        request: HandoverRequest
+    ) -> Option<usize> {
+        let HandoverRequest { socket, payload, deadline, done } = request;
+
+        let Some(mount) = &self.mount else {
+            let err = io::Error::new(
+                ErrorKind::Unsupported,
+                "Only sessions connected to the FUSE device can be handed over"
+            );
+            let _ = done.send(Err(err));
+            return None;
+        };
+        let Some(init) = init else {
+            let err = io::Error::new(
+                ErrorKind::NotConnected,
+                "The session cannot be handed over before FUSE_INIT"
+            );
+            let _ = done.send(Err(err));
+            return None;
+        };
+        if !init.flags2.contains(InitFlags2::FUSE_HAS_RESEND) {
+            let err = io::Error::new(
+                ErrorKind::Unsupported,
+                "The kernel does not support FUSE_NOTIFY_RESEND, required for the handover"
+            );
+            let _ = done.send(Err(err));
+            return None;
+        }
+
+        let drain = async {
+            while let Some(finished) = handlers.join_next().await {
+                complete(&*self.transport, errors, finished);
+            }
+        };
+        let cancelled = match tokio::time::timeout_at(deadline, drain).await {
+            Ok(()) => Vec::new(),
+            Err(_) => handlers.shutdown().await,
+        };
+        cancelled.iter().for_each(|&unique| self.transport.cancel(unique));
+
+        let result = match (mount.export_state(), self.fs.snapshot().await) {
+            (Ok(state), Ok(snapshot)) => {
+                send_handover(socket, state, init, payload, snapshot).await
+            },
+            (Err(err), _) => Err(err),
+            (_, Err(errno)) => Err(io::Error::new(
+                io::Error::from(errno).kind(),
+                format!("Failed to save the inodes: {errno}")
+            )),
+        };
+
+        let sent = result.is_ok();
+        if !sent {
+            let _ = self.transport.notify(fuse_notify_code::FUSE_NOTIFY_RESEND, &[]);
+        }
+        let _ = done.send(result);
+        sent.then_some(cancelled.len())
+    }
+}
+
+impl ShutdownHandle {
+    /// Stops the session.
+    ///
+    /// The file system is lazily unmounted and the session keeps serving the
+    /// requests until the kernel ends the connection, this happens once the
+    /// file system is no longer in use. If this takes longer than `timeout`
+    /// the session stops reading requests and cancels the handlers still
+    /// running, then the connection is aborted.
+    ///
+    /// Waits until the session has ended, the outcome is returned by
+    /// [`Session::run`].
+    pub async fn shutdown(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        self.stop.send_if_modified(|stop| match stop {
+            Some(_) => false,
+            None => {
+                *stop = Some(deadline);
+                true
+            },
+        });
+
+        let mut finished = self.finished.clone();
+        // The session was dropped if the sender is gone
+        let _ = finished.wait_for(|finished| *finished).await;
+    }
+
+    /// Sends the session to another process, for example a newer version of
+    /// the server.
+    ///
+    /// The session stops reading requests and waits for the requests in
+    /// flight to complete, up to `timeout`, then cancels the handlers still
+    /// running. It then sends the FUSE device and the negotiated parameters
+    /// over `socket`, along with `payload` and the inodes saved by
+    /// [`Filesystem::snapshot`]. The other process receives them with
+    /// [`Handover::receive`] and continues with [`Session::resume_from`], the
+    /// kernel then resends the requests that were not answered.
+    ///
+    /// The open files are not carried over, the kernel gets
+    /// [`Errno::EBADF`](crate::Errno::EBADF) for them after the handover.
+    ///
+    /// Requires a kernel supporting `FUSE_HAS_RESEND`. If the handover fails
+    /// the session keeps running.
+    pub async fn handover(
+        &self,
+        socket: UnixStream,
+        payload: Vec<u8>,
+        timeout: Duration
+    ) -> io::Result<()> {
+        let (done, result) = oneshot::channel();
+        let deadline = Instant::now() + timeout;
+        let request = HandoverRequest { socket, payload, deadline, done };
+        if self.handover.send(request).is_err() {
+            io_error!(ErrorKind::NotConnected, "The session is not running");
+        }
+        match result.await {
+            Ok(result) => result,
+            Err(_) => io_error!(ErrorKind::NotConnected, "The session is not running"),
+        }
+    }
+
+    /// Whether the session has ended.
+    pub fn is_finished(&self) -> bool {
+        *self.finished.borrow()
+    }
+}
+
+async fn send_handover(
+    socket: UnixStream,
+    state: MountState,
+    init: InitState,
+    payload: Vec<u8>,
+    snapshot: Vec<u8>
+) -> io::Result<()> {
+    let send = move || handover::send(&socket, &state, &init, &payload, &snapshot);
+    match tokio::task::spawn_blocking(send).await {
+        Ok(result) => result,
+        Err(err) => Err(io::Error::other(err)),
+    }
+}
+
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => pending().await,
+    }
+}
+
+/// Answers the request of a handler that ended without replying.
+fn complete(transport: &impl Transport, errors: &AtomicU64, finished: Finished) {
+    let reply = match finished {
+        Finished::Done => return,
+        Finished::Cancelled { unique } => {
+            transport.cancel(unique);
+            return;
+        },
+        // Without a reply the caller would wait until the connection is
+        // aborted
+        Finished::Panicked { unique, opcode } if opcode.expects_reply() => {
+            ReplyBuf::error(unique, Errno::EIO)
+        },
+        Finished::Panicked { .. } => {
+            errors.fetch_add(1, Ordering::Relaxed);
+            return;
+        },
+        Finished::Interrupted { unique } => ReplyBuf::error(unique, Errno::EINTR),
+    };
+    errors.fetch_add(1, Ordering::Relaxed);
+    send_reply(transport, reply);
+}
+
+fn send_reply(transport: &impl Transport, reply: ReplyBuf) {
+    // Replies to requests that were interrupted or sent after the connection
+    // was aborted fail, there is nobody to report the error to
+    let _ = transport.send(&[IoSlice::new(&reply.finish())]);
+}
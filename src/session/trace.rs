@@ -0,0 +1,83 @@
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+use tracing::Span;
+use tracing::field::{Empty, debug};
+
+use crate::Errno;
+use crate::protocol::*;
+
+/// Bytes of a malformed request shown in its dump, the rest is elided.
+const MAX_DUMP: usize = 1024;
+
+/// Span of a request, the handling of the request is traced inside it.
+///
+/// The latency and the error are recorded when the request is answered.
+pub(super) fn span(header: &fuse_in_header) -> Span {
+    tracing::debug_span!(
+        "request",
+        unique = header.unique,
+        opcode = ?header.opcode,
+        nodeid = header.nodeid,
+        uid = header.uid,
+        pid = header.pid,
+        latency = Empty,
+        errno = Empty,
+    )
+}
+
+/// Logs the arguments of the request of the current span.
+pub(super) fn request(op: &Operation<'_>) {
+    tracing::debug!(args = ?op, "request");
+}
+
+/// Records the outcome of the request of `span`, from inside it.
+pub(super) fn reply(span: &Span, result: &Result<Option<ReplyBuf>, Errno>, latency: Duration) {
+    span.record("latency", debug(latency));
+    match result {
+        Ok(Some(reply)) => tracing::debug!(len = reply.len(), "reply"),
+        Ok(None) => tracing::debug!("no reply"),
+        Err(errno) => {
+            span.record("errno", debug(errno));
+            tracing::debug!("reply");
+        },
+    }
+}
+
+/// Logs the parameters negotiated by `FUSE_INIT`.
+pub(super) fn init_reply(out: &fuse_init_out) {
+    tracing::debug!(?out, "reply");
+}
+
+/// Logs a request that could not be decoded, with a dump of its bytes.
+pub(super) fn malformed(reason: &str, bytes: &[u8]) {
+    tracing::debug!(len = bytes.len(), "malformed request, {reason}:\n{}", HexDump(bytes));
+}
+
+/// Lines of 16 bytes in hexadecimal then in ASCII, like `hexdump -C`.
+struct HexDump<'a>(&'a [u8]);
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shown = &self.0[..self.0.len().min(MAX_DUMP)];
+        for (index, line) in shown.chunks(16).enumerate() {
+            write!(f, "{:08x} ", index * 16)?;
+            for column in 0..16 {
+                match line.get(column) {
+                    Some(byte) => write!(f, " {byte:02x}")?,
+                    None => f.write_str("   ")?,
+                }
+            }
+            f.write_str("  |")?;
+            for &byte in line {
+                let printable = byte.is_ascii_graphic() || byte == b' ';
+                f.write_char(if printable { byte as char } else { '.' })?;
+            }
+            f.write_str("|\n")?;
+        }
+        if shown.len() < self.0.len() {
+            write!(f, "... {} more bytes", self.0.len() - shown.len())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use std::ffi::OsString;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStringExt;
+
+use crate::Errno;
+use crate::protocol::{fuse_dirent, fuse_direntplus, fuse_entry_out};
+use super::kernel::decode;
+
+/// Entry of a `FUSE_READDIR` reply.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub ino: u64,
+    /// Offset of the next entry, to continue reading the directory.
+    pub offset: u64,
+    /// File type, as in the `S_IFMT` bits of the mode.
+    pub file_type: u32,
+    pub name: OsString,
+}
+
+/// Entry of a `FUSE_READDIRPLUS` reply.
+#[derive(Debug, Clone)]
+pub struct DirEntryPlus {
+    pub entry: fuse_entry_out,
+    pub dirent: DirEntry,
+}
+
+impl DirEntry {
+    fn new(dirent: &fuse_dirent, name: &[u8]) -> Self {
+        Self {
+            ino: dirent.ino,
+            offset: dirent.off,
+            file_type: dirent.r#type << 12,
+            name: OsString::from_vec(name.to_vec()),
+        }
+    }
+}
+
+pub(crate) fn parse_dirents(mut data: &[u8]) -> Result<Vec<DirEntry>, Errno> {
+    let mut entries = Vec::new();
+    while !data.is_empty() {
+        let dirent: fuse_dirent = decode(data)?;
+        let (name, rest) = split_entry(data, size_of::<fuse_dirent>(), dirent.namelen)?;
+        entries.push(DirEntry::new(&dirent, name));
+        data = rest;
+    }
+    Ok(entries)
+}
+
+pub(crate) fn parse_direntplus(mut data: &[u8]) -> Result<Vec<DirEntryPlus>, Errno> {
+    let mut entries = Vec::new();
+    while !data.is_empty() {
+        let plus: fuse_direntplus = decode(data)?;
+        let namelen = plus.dirent.namelen;
+        let (name, rest) = split_entry(data, size_of::<fuse_direntplus>(), namelen)?;
+        entries.push(DirEntryPlus {
+            dirent: DirEntry::new(&plus.dirent, name),
+            entry: plus.entry_out,
+        });
+        data = rest;
+    }
+    Ok(entries)
+}
+
+/// Splits the name of the entry at the start of `data` from the following
+/// entries, the entries are padded to 8 bytes.
+fn split_entry(data: &[u8], header: usize, namelen: u32) -> Result<(&[u8], &[u8]), Errno> {
+    let len = header + namelen as usize;
+    let padded = len.next_multiple_of(size_of::<u64>()).min(data.len());
+    if data.len() < len {
+        return Err(Errno::EPROTO);
+    }
+    Ok((&data[header..len], &data[padded..]))
+}
@@ -0,0 +1,860 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, ErrorKind};
+use std::mem::{offset_of, size_of};
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
+
+use crate::protocol::*;
+use crate::{Errno, Filesystem, Session, SessionConfig, SessionSummary};
+use crate::transport::{MemoryPeer, MemoryTransport, TraceTransport, Tracer, Transport};
+use super::dir::{DirEntry, DirEntryPlus, parse_direntplus, parse_dirents};
+
+/// Replies that are waiting for their request, by unique id.
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Vec<u8>>>>>;
+
+/// Plays the part of the kernel for a [`Session`], without `/dev/fuse`.
+///
+/// Each method sends a request, built like the kernel builds it, and waits
+/// for the reply, an error reply is returned as the [`Errno`] of the
+/// handler. Requests can be sent concurrently, like the kernel the session
+/// may answer them in any order. If the session is gone the requests fail
+/// with [`Errno::ENOTCONN`].
+///
+/// The requests are sent on behalf of the calling process, the credentials
+/// can be changed with [`MockKernel::set_caller`].
+///
+/// Dropping the kernel ends the session, as if the file system was unmounted.
+pub struct MockKernel {
+    peer: Arc<MemoryPeer>,
+    pending: Pending,
+    unique: AtomicU64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    init: fuse_init_out,
+    session: JoinHandle<io::Result<SessionSummary>>,
+}
+
+impl MockKernel {
+    /// Starts a session for `fs` with the default settings, and performs the
+    /// `FUSE_INIT` handshake.
+    pub async fn start<F: Filesystem>(fs: F) -> io::Result<Self> {
+        Self::start_with(fs, SessionConfig::new(), Self::default_init()).await
+    }
+
+    /// Starts a session for `fs` with custom settings, the kernel offers the
+    /// parameters in `init` during the `FUSE_INIT` handshake.
+    pub async fn start_with<F: Filesystem>(
+        fs: F,
+        config: SessionConfig,
+        init: fuse_init_in
+    ) -> io::Result<Self> {
+        let (transport, peer) = MemoryTransport::new();
+        let session = Session::with_transport(transport, fs, config)?;
+        Self::connect(session, peer, init).await
+    }
+
+    /// Like [`MockKernel::start_with`], the messages exchanged with the session
+    /// are printed and recorded by `tracer`.
+    pub async fn start_traced<F: Filesystem>(
+        fs: F,
+        config: SessionConfig,
+        init: fuse_init_in,
+        tracer: Tracer
+    ) -> io::Result<Self> {
+        let (transport, peer) = MemoryTransport::new();
+        let transport = TraceTransport::new(transport, tracer);
+        let session = Session::with_transport(transport, fs, config)?;
+        Self::connect(session, peer, init).await
+    }
+
+    /// Runs `session`, and performs the `FUSE_INIT` handshake through `peer`.
+    async fn connect<F: Filesystem, T: Transport>(
+        session: Session<F, T>,
+        peer: MemoryPeer,
+        init: fuse_init_in
+    ) -> io::Result<Self> {
+        let session = tokio::spawn(session.run());
+
+        let peer = Arc::new(peer);
+        let pending = Pending::default();
+        tokio::spawn(route_replies(peer.clone(), pending.clone()));
+
+        let mut kernel = Self {
+            peer,
+            pending,
+            unique: AtomicU64::new(0),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            pid: std::process::id(),
+            init: zeroed(),
+            session,
+        };
+
+        let size = match init.flags.contains(InitFlags::FUSE_INIT_EXT) {
+            false => FUSE_COMPAT_INIT_IN_SIZE,
+            true => size_of::<fuse_init_in>(),
+        };
+        let reply = kernel.request(fuse_opcode::FUSE_INIT, 0, &[&init.as_bytes()[..size]]).await;
+        match reply {
+            Ok(reply) => kernel.init = decode(&reply)?,
+            Err(errno) => io_error!(
+                ErrorKind::ConnectionRefused,
+                "The session refused FUSE_INIT: {errno}"
+            ),
+        }
+        Ok(kernel)
+    }
+
+    /// The `FUSE_INIT` request sent by [`MockKernel::start`], with the current
+    /// protocol version and every flag.
+    pub fn default_init() -> fuse_init_in {
+        fuse_init_in {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 128 * 1024,
+            flags: InitFlags::all(),
+            flags2: InitFlags2::all(),
+            unused: [0; 11],
+        }
+    }
+
+    /// The reply of the session to `FUSE_INIT`.
+    #[inline]
+    pub fn init_reply(&self) -> &fuse_init_out {
+        &self.init
+    }
+
+    /// Sets the credentials of the process sending the next requests.
+    pub fn set_caller(&mut self, uid: u32, gid: u32, pid: u32) {
+        self.uid = uid;
+        self.gid = gid;
+        self.pid = pid;
+    }
+
+    /// The unique id of the last request sent, to interrupt it while it is
+    /// pending.
+    pub fn last_unique(&self) -> u64 {
+        self.unique.load(Ordering::Relaxed)
+    }
+
+    /// Sends `FUSE_DESTROY`, then waits for the session to end.
+    pub async fn shutdown(mut self) -> io::Result<SessionSummary> {
+        let _ = self.request(fuse_opcode::FUSE_DESTROY, 0, &[]).await;
+        self.peer.close();
+        match (&mut self.session).await {
+            Ok(result) => result,
+            Err(err) => Err(io::Error::other(err)),
+        }
+    }
+
+    /// Sends a request with the arguments in `args` and waits for the reply,
+    /// returning the data after the [`fuse_out_header`].
+    pub async fn request(
+        &self,
+        opcode: fuse_opcode,
+        nodeid: u64,
+        args: &[&[u8]]
+    ) -> Result<Vec<u8>, Errno> {
+        self.request_raw(opcode as u32, nodeid, args).await
+    }
+
+    /// Like [`MockKernel::request`], with an opcode that the session may not
+    /// know, as sent by a newer kernel.
+    pub async fn request_raw(
+        &self,
+        opcode: u32,
+        nodeid: u64,
+        args: &[&[u8]]
+    ) -> Result<Vec<u8>, Errno> {
+        let (unique, request) = self.encode(opcode, nodeid, args);
+        let (done, reply) = oneshot::channel();
+        self.pending.lock().unwrap().insert(unique, done);
+        if self.peer.send(request).is_err() {
+            self.pending.lock().unwrap().remove(&unique);
+            return Err(Errno::ENOTCONN);
+        }
+
+        let reply = reply.await.map_err(|_| Errno::ENOTCONN)?;
+        let error = i32::from_ne_bytes(reply[4..8].try_into().unwrap());
+        match error {
+            0 => Ok(reply[size_of::<fuse_out_header>()..].to_vec()),
+            error => Err(Errno::new(-error).unwrap_or(Errno::EPROTO)),
+        }
+    }
+
+    /// Sends a request that doesn't get a reply, like `FUSE_FORGET`.
+    pub fn send(&self, opcode: fuse_opcode, nodeid: u64, args: &[&[u8]]) -> Result<(), Errno> {
+        let (_, request) = self.encode(opcode as u32, nodeid, args);
+        self.peer.send(request).map_err(|_| Errno::ENOTCONN)
+    }
+
+    fn encode(&self, opcode: u32, nodeid: u64, args: &[&[u8]]) -> (u64, Vec<u8>) {
+        // Like the kernel, odd ids are left for interrupts
+        let unique = self.unique.fetch_add(2, Ordering::Relaxed) + 2;
+        let len = size_of::<fuse_in_header>() + args.iter().map(|arg| arg.len()).sum::<usize>();
+        let header = fuse_in_header {
+            len: len as u32,
+            opcode: fuse_opcode::FUSE_LOOKUP,
+            unique,
+            nodeid,
+            uid: self.uid,
+            gid: self.gid,
+            pid: self.pid,
+            total_extlen: 0,
+            padding: 0,
+        };
+
+        let mut request = Vec::with_capacity(len);
+        request.extend_from_slice(header.as_bytes());
+        // Written as is, since it may not be a known opcode
+        let at = offset_of!(fuse_in_header, opcode);
+        request[at..at + 4].copy_from_slice(&opcode.to_ne_bytes());
+        for arg in args {
+            request.extend_from_slice(arg);
+        }
+        (unique, request)
+    }
+
+    pub async fn lookup(&self, parent: u64, name: impl AsRef<OsStr>) -> Result<fuse_entry_out, Errno> {
+        let name = cstr(name.as_ref());
+        let reply = self.request(fuse_opcode::FUSE_LOOKUP, parent, &[&name]).await?;
+        decode(&reply)
+    }
+
+    pub fn forget(&self, ino: u64, nlookup: u64) -> Result<(), Errno> {
+        let arg = fuse_forget_in { nlookup };
+        self.send(fuse_opcode::FUSE_FORGET, ino, &[arg.as_bytes()])
+    }
+
+    /// Asks the session to interrupt the request `unique`.
+    pub fn interrupt(&self, unique: u64) -> Result<(), Errno> {
+        let arg = fuse_interrupt_in { unique };
+        self.send(fuse_opcode::FUSE_INTERRUPT, 0, &[arg.as_bytes()])
+    }
+
+    /// Forgets several inodes at once, with `(ino, nlookup)` pairs.
+    pub fn batch_forget(&self, inodes: &[(u64, u64)]) -> Result<(), Errno> {
+        let arg = fuse_batch_forget_in { count: inodes.len() as u32, dummy: 0 };
+        let forgets: Vec<_> = inodes.iter()
+            .map(|&(nodeid, nlookup)| fuse_forget_one { nodeid, nlookup })
+            .collect();
+        self.send(fuse_opcode::FUSE_BATCH_FORGET, 0, &[arg.as_bytes(), forgets.as_bytes()])
+    }
+
+    pub async fn getattr(&self, ino: u64) -> Result<fuse_attr_out, Errno> {
+        let arg = fuse_getattr_in::new_zeroed();
+        let reply = self.request(fuse_opcode::FUSE_GETATTR, ino, &[arg.as_bytes()]).await?;
+        decode(&reply)
+    }
+
+    /// Changes the attributes selected by `arg.valid`.
+    pub async fn setattr(&self, ino: u64, arg: &fuse_setattr_in) -> Result<fuse_attr_out, Errno> {
+        let reply = self.request(fuse_opcode::FUSE_SETATTR, ino, &[arg.as_bytes()]).await?;
+        decode(&reply)
+    }
+
+    pub async fn readlink(&self, ino: u64) -> Result<Vec<u8>, Errno> {
+        self.request(fuse_opcode::FUSE_READLINK, ino, &[]).await
+    }
+
+    pub async fn symlink(
+        &self,
+        parent: u64,
+        name: impl AsRef<OsStr>,
+        target: impl AsRef<OsStr>
+    ) -> Result<fuse_entry_out, Errno> {
+        let name = cstr(name.as_ref());
+        let target = cstr(target.as_ref());
+        let reply = self.request(fuse_opcode::FUSE_SYMLINK, parent, &[&name, &target]).await?;
+        decode(&reply)
+    }
+
+    pub async fn mknod(
+        &self,
+        parent: u64,
+        name: impl AsRef<OsStr>,
+        mode: u32,
+        rdev: u32
+    ) -> Result<fuse_entry_out, Errno> {
+        let arg = fuse_mknod_in { mode, rdev, umask: 0, padding: 0 };
+        let name = cstr(name.as_ref());
+        let reply = self.request(fuse_opcode::FUSE_MKNOD, parent, &[arg.as_bytes(), &name]).await?;
+        decode(&reply)
+    }
+
+    pub async fn mkdir(
+        &self,
+        parent: u64,
+        name: impl AsRef<OsStr>,
+        mode: u32
+    ) -> Result<fuse_entry_out, Errno> {
+        let arg = fuse_mkdir_in { mode, umask: 0 };
+        let name = cstr(name.as_ref());
+        let reply = self.request(fuse_opcode::FUSE_MKDIR, parent, &[arg.as_bytes(), &name]).await?;
+        decode(&reply)
+    }
+
+    pub async fn unlink(&self, parent: u64, name: impl AsRef<OsStr>) -> Result<(), Errno> {
+        let name = cstr(name.as_ref());
+        self.request(fuse_opcode::FUSE_UNLINK, parent, &[&name]).await?;
+        Ok(())
+    }
+
+    pub async fn rmdir(&self, parent: u64, name: impl AsRef<OsStr>) -> Result<(), Errno> {
+        let name = cstr(name.as_ref());
+        self.request(fuse_opcode::FUSE_RMDIR, parent, &[&name]).await?;
+        Ok(())
+    }
+
+    /// Renames an entry, like the kernel `FUSE_RENAME2` is only used when
+    /// there are `flags`.
+    pub async fn rename(
+        &self,
+        parent: u64,
+        name: impl AsRef<OsStr>,
+        newparent: u64,
+        newname: impl AsRef<OsStr>,
+        flags: u32
+    ) -> Result<(), Errno> {
+        let name = cstr(name.as_ref());
+        let newname = cstr(newname.as_ref());
+        if flags == 0 {
+            let arg = fuse_rename_in { newdir: newparent };
+            let args: [&[u8]; 3] = [arg.as_bytes(), &name, &newname];
+            self.request(fuse_opcode::FUSE_RENAME, parent, &args).await?;
+        } else {
+            let arg = fuse_rename2_in { newdir: newparent, flags, padding: 0 };
+            let args: [&[u8]; 3] = [arg.as_bytes(), &name, &newname];
+            self.request(fuse_opcode::FUSE_RENAME2, parent, &args).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn link(
+        &self,
+        ino: u64,
+        newparent: u64,
+        newname: impl AsRef<OsStr>
+    ) -> Result<fuse_entry_out, Errno> {
+        let arg = fuse_link_in { oldnodeid: ino };
+        let newname = cstr(newname.as_ref());
+        let args: [&[u8]; 2] = [arg.as_bytes(), &newname];
+        let reply = self.request(fuse_opcode::FUSE_LINK, newparent, &args).await?;
+        decode(&reply)
+    }
+
+    /// Opens a file, `flags` are the flags of `open(2)`.
+    pub async fn open(&self, ino: u64, flags: i32) -> Result<fuse_open_out, Errno> {
+        let arg = fuse_open_in { flags: flags as u32, open_flags: OpenInFlags::empty() };
+        let reply = self.request(fuse_opcode::FUSE_OPEN, ino, &[arg.as_bytes()]).await?;
+        decode(&reply)
+    }
+
+    pub async fn read(&self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, Errno> {
+        let arg = read_in(fh, offset, size);
+        self.request(fuse_opcode::FUSE_READ, ino, &[arg.as_bytes()]).await
+    }
+
+    pub async fn write(&self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> Result<u32, Errno> {
+        let arg = fuse_write_in {
+            fh,
+            offset,
+            size: data.len() as u32,
+            write_flags: WriteFlags::empty(),
+            lock_owner: 0,
+            flags: 0,
+            padding: 0,
+        };
+        let reply = self.request(fuse_opcode::FUSE_WRITE, ino, &[arg.as_bytes(), data]).await?;
+        decode::<fuse_write_out>(&reply).map(|out| out.size)
+    }
+
+    pub async fn statfs(&self, ino: u64) -> Result<fuse_kstatfs, Errno> {
+        let reply = self.request(fuse_opcode::FUSE_STATFS, ino, &[]).await?;
+        decode::<fuse_statfs_out>(&reply).map(|out| out.st)
+    }
+
+    pub async fn release(&self, ino: u64, fh: u64) -> Result<(), Errno> {
+        let arg = fuse_release_in { fh, flags: 0, release_flags: 0, lock_owner: 0 };
+        self.request(fuse_opcode::FUSE_RELEASE, ino, &[arg.as_bytes()]).await?;
+        Ok(())
+    }
+
+    pub async fn fsync(&self, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        let arg = fsync_in(fh, datasync);
+        self.request(fuse_opcode::FUSE_FSYNC, ino, &[arg.as_bytes()]).await?;
+        Ok(())
+    }
+
+    /// Sets an extended attribute, `flags` are the flags of `setxattr(2)`.
+    pub async fn setxattr(
+        &self,
+        ino: u64,
+        name: impl AsRef<OsStr>,
+        value: &[u8],
+        flags: u32
+    ) -> Result<(), Errno> {
+        // FUSE_SETXATTR_EXT is not negotiated, so only the old fields are sent
+        let arg = fuse_setxattr_in {
+            size: value.len() as u32,
+            flags,
+            setxattr_flags: 0,
+            padding: 0,
+        };
+        let name = cstr(name.as_ref());
+        let args: [&[u8]; 3] = [&arg.as_bytes()[..FUSE_COMPAT_SETXATTR_IN_SIZE], &name, value];
+        self.request(fuse_opcode::FUSE_SETXATTR, ino, &args).await?;
+        Ok(())
+    }
+
+    /// Reads an extended attribute, fails with [`Errno::ERANGE`] if it's
+    /// bigger than `size`.
+    pub async fn getxattr(
+        &self,
+        ino: u64,
+        name: impl AsRef<OsStr>,
+        size: u32
+    ) -> Result<Vec<u8>, Errno> {
+        let arg = fuse_getxattr_in { size: size.max(1), padding: 0 };
+        let name = cstr(name.as_ref());
+        self.request(fuse_opcode::FUSE_GETXATTR, ino, &[arg.as_bytes(), &name]).await
+    }
+
+    /// Size of an extended attribute.
+    pub async fn getxattr_size(&self, ino: u64, name: impl AsRef<OsStr>) -> Result<u32, Errno> {
+        let arg = fuse_getxattr_in { size: 0, padding: 0 };
+        let name = cstr(name.as_ref());
+        let reply = self.request(fuse_opcode::FUSE_GETXATTR, ino, &[arg.as_bytes(), &name]).await?;
+        decode::<fuse_getxattr_out>(&reply).map(|out| out.size)
+    }
+
+    /// Lists the extended attributes, as NUL terminated names, fails with
+    /// [`Errno::ERANGE`] if the list is bigger than `size`.
+    pub async fn listxattr(&self, ino: u64, size: u32) -> Result<Vec<u8>, Errno> {
+        let arg = fuse_getxattr_in { size: size.max(1), padding: 0 };
+        self.request(fuse_opcode::FUSE_LISTXATTR, ino, &[arg.as_bytes()]).await
+    }
+
+    /// Size of the list of extended attributes.
+    pub async fn listxattr_size(&self, ino: u64) -> Result<u32, Errno> {
+        let arg = fuse_getxattr_in { size: 0, padding: 0 };
+        let reply = self.request(fuse_opcode::FUSE_LISTXATTR, ino, &[arg.as_bytes()]).await?;
+        decode::<fuse_getxattr_out>(&reply).map(|out| out.size)
+    }
+
+    pub async fn removexattr(&self, ino: u64, name: impl AsRef<OsStr>) -> Result<(), Errno> {
+        let name = cstr(name.as_ref());
+        self.request(fuse_opcode::FUSE_REMOVEXATTR, ino, &[&name]).await?;
+        Ok(())
+    }
+
+    pub async fn flush(&self, ino: u64, fh: u64, lock_owner: u64) -> Result<(), Errno> {
+        let arg = fuse_flush_in { fh, unused: 0, padding: 0, lock_owner };
+        self.request(fuse_opcode::FUSE_FLUSH, ino, &[arg.as_bytes()]).await?;
+        Ok(())
+    }
+
+    pub async fn opendir(&self, ino: u64, flags: i32) -> Result<fuse_open_out, Errno> {
+        let arg = fuse_open_in { flags: flags as u32, open_flags: OpenInFlags::empty() };
+        let reply = self.request(fuse_opcode::FUSE_OPENDIR, ino, &[arg.as_bytes()]).await?;
+        decode(&reply)
+    }
+
+    /// Reads the entries of a directory starting at `offset`, an empty list
+    /// marks the end of the directory.
+    pub async fn readdir(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        size: u32
+    ) -> Result<Vec<DirEntry>, Errno> {
+        let arg = read_in(fh, offset, size);
+        let reply = self.request(fuse_opcode::FUSE_READDIR, ino, &[arg.as_bytes()]).await?;
+        parse_dirents(&reply)
+    }
+
+    /// Like [`MockKernel::readdir`], every entry but `.` and `..` counts as a
+    /// lookup of its inode.
+    pub async fn readdirplus(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        size: u32
+    ) -> Result<Vec<DirEntryPlus>, Errno> {
+        let arg = read_in(fh, offset, size);
+        let reply = self.request(fuse_opcode::FUSE_READDIRPLUS, ino, &[arg.as_bytes()]).await?;
+        parse_direntplus(&reply)
+    }
+
+    pub async fn releasedir(&self, ino: u64, fh: u64) -> Result<(), Errno> {
+        let arg = fuse_release_in { fh, flags: 0, release_flags: 0, lock_owner: 0 };
+        self.request(fuse_opcode::FUSE_RELEASEDIR, ino, &[arg.as_bytes()]).await?;
+        Ok(())
+    }
+
+    pub async fn fsyncdir(&self, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        let arg = fsync_in(fh, datasync);
+        self.request(fuse_opcode::FUSE_FSYNCDIR, ino, &[arg.as_bytes()]).await?;
+        Ok(())
+    }
+
+    pub async fn getlk(&self, ino: u64, arg: &fuse_lk_in) -> Result<fuse_file_lock, Errno> {
+        let reply = self.request(fuse_opcode::FUSE_GETLK, ino, &[arg.as_bytes()]).await?;
+        decode::<fuse_lk_out>(&reply).map(|out| out.lk)
+    }
+
+    /// Sets a lock, if `sleep` is set the request is `FUSE_SETLKW`, which
+    /// waits for conflicting locks to be released.
+    pub async fn setlk(&self, ino: u64, arg: &fuse_lk_in, sleep: bool) -> Result<(), Errno> {
+        let opcode = match sleep {
+            true => fuse_opcode::FUSE_SETLKW,
+            false => fuse_opcode::FUSE_SETLK,
+        };
+        self.request(opcode, ino, &[arg.as_bytes()]).await?;
+        Ok(())
+    }
+
+    /// Checks the permissions of an inode, `mask` is the mask of `access(2)`.
+    pub async fn access(&self, ino: u64, mask: u32) -> Result<(), Errno> {
+        let arg = fuse_access_in { mask, padding: 0 };
+        self.request(fuse_opcode::FUSE_ACCESS, ino, &[arg.as_bytes()]).await?;
+        Ok(())
+    }
+
+    pub async fn create(
+        &self,
+        parent: u64,
+        name: impl AsRef<OsStr>,
+        mode: u32,
+        flags: i32
+    ) -> Result<(fuse_entry_out, fuse_open_out), Errno> {
+        let arg = fuse_create_in {
+            flags: flags as u32,
+            mode,
+            umask: 0,
+            open_flags: OpenInFlags::empty(),
+        };
+        let name = cstr(name.as_ref());
+        let reply = self.request(fuse_opcode::FUSE_CREATE, parent, &[arg.as_bytes(), &name]).await?;
+        let entry = decode(&reply)?;
+        let open = decode(reply.get(size_of::<fuse_entry_out>()..).unwrap_or_default())?;
+        Ok((entry, open))
+    }
+
+    /// Creates an unnamed file in `parent`, like `O_TMPFILE`.
+    pub async fn tmpfile(
+        &self,
+        parent: u64,
+        mode: u32,
+        flags: i32
+    ) -> Result<(fuse_entry_out, fuse_open_out), Errno> {
+        let arg = fuse_create_in {
+            flags: flags as u32,
+            mode,
+            umask: 0,
+            open_flags: OpenInFlags::empty(),
+        };
+        // Like the kernel, which names the dentry of the file "/"
+        let args = [arg.as_bytes(), b"/\0"];
+        let reply = self.request(fuse_opcode::FUSE_TMPFILE, parent, &args).await?;
+        let entry = decode(&reply)?;
+        let open = decode(reply.get(size_of::<fuse_entry_out>()..).unwrap_or_default())?;
+        Ok((entry, open))
+    }
+
+    pub async fn bmap(&self, ino: u64, block: u64, blocksize: u32) -> Result<u64, Errno> {
+        let arg = fuse_bmap_in { block, blocksize, padding: 0 };
+        let reply = self.request(fuse_opcode::FUSE_BMAP, ino, &[arg.as_bytes()]).await?;
+        decode::<fuse_bmap_out>(&reply).map(|out| out.block)
+    }
+
+    /// Allocates space in a file, `mode` is the mode of `fallocate(2)`.
+    pub async fn fallocate(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32
+    ) -> Result<(), Errno> {
+        let arg = fuse_fallocate_in { fh, offset, length, mode, padding: 0 };
+        self.request(fuse_opcode::FUSE_FALLOCATE, ino, &[arg.as_bytes()]).await?;
+        Ok(())
+    }
+
+    /// Finds data or holes in a file, `whence` is `SEEK_DATA` or `SEEK_HOLE`,
+    /// the kernel handles the other values by itself.
+    pub async fn lseek(&self, ino: u64, fh: u64, offset: u64, whence: u32) -> Result<u64, Errno> {
+        let arg = fuse_lseek_in { fh, offset, whence, padding: 0 };
+        let reply = self.request(fuse_opcode::FUSE_LSEEK, ino, &[arg.as_bytes()]).await?;
+        decode::<fuse_lseek_out>(&reply).map(|out| out.offset)
+    }
+
+    pub async fn copy_file_range(
+        &self,
+        ino: u64,
+        arg: &fuse_copy_file_range_in
+    ) -> Result<u32, Errno> {
+        let reply = self.request(fuse_opcode::FUSE_COPY_FILE_RANGE, ino, &[arg.as_bytes()]).await?;
+        decode::<fuse_write_out>(&reply).map(|out| out.size)
+    }
+
+    pub async fn syncfs(&self, ino: u64) -> Result<(), Errno> {
+        let arg = fuse_syncfs_in { padding: 0 };
+        self.request(fuse_opcode::FUSE_SYNCFS, ino, &[arg.as_bytes()]).await?;
+        Ok(())
+    }
+}
+
+impl Drop for MockKernel {
+    fn drop(&mut self) {
+        self.peer.close();
+    }
+}
+
+/// Hands the replies to the requests waiting for them.
+async fn route_replies(peer: Arc<MemoryPeer>, pending: Pending) {
+    while let Some(reply) = peer.receive().await {
+        let Some(unique) = reply.get(8..16) else { continue };
+        let unique = u64::from_ne_bytes(unique.try_into().unwrap());
+        // Notifications have no request waiting for them
+        if let Some(done) = pending.lock().unwrap().remove(&unique) {
+            let _ = done.send(reply);
+        }
+    }
+    // The session ended, the requests still waiting fail with ENOTCONN
+    pending.lock().unwrap().clear();
+}
+
+/// Structures of the replies, read by the tests like the kernel reads them.
+///
+/// The replies can't be read from any bytes, their padding must be zero, so
+/// they are read as a mirror structure with plain fields first.
+pub(crate) trait Reply: Sized {
+    /// The structure with the same layout, with integers for the padding.
+    type Raw: FromBytes + IntoBytes + KnownLayout + Immutable;
+
+    /// Fails with [`Errno::EINVAL`] if a padding field is not zero.
+    fn from_raw(raw: Self::Raw) -> Result<Self, Errno>;
+}
+
+/// Declares the mirror of each reply structure, whose fields are either
+/// `plain`, `pad` for the padding or `nested` for another reply structure.
+macro_rules! replies {
+    ($($ty:ident as $raw:ident {
+        $($field:ident: $kind:ident $fty:ty),* $(,)?
+    })*) => {$(
+        #[repr(C)]
+        #[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
+        pub(crate) struct $raw {
+            $($field: replies!(@raw $kind $fty)),*
+        }
+
+        // The mirror must match the real structure field by field
+        const _: () = {
+            assert!(size_of::<$raw>() == size_of::<$ty>());
+            $(assert!(offset_of!($raw, $field) == offset_of!($ty, $field));)*
+        };
+
+        impl Reply for $ty {
+            type Raw = $raw;
+
+            fn from_raw(raw: $raw) -> Result<Self, Errno> {
+                Ok(Self { $($field: replies!(@from $kind raw.$field)),* })
+            }
+        }
+    )*};
+
+    (@raw plain $fty:ty) => { $fty };
+    (@raw pad $fty:ty) => { $fty };
+    (@raw nested $fty:ty) => { <$fty as Reply>::Raw };
+
+    (@from plain $value:expr) => { $value };
+    (@from pad $value:expr) => {{
+        if $value.as_bytes().iter().any(|&byte| byte != 0) {
+            return Err(Errno::EINVAL);
+        }
+        Padding::new()
+    }};
+    (@from nested $value:expr) => { Reply::from_raw($value)? };
+}
+
+replies! {
+    fuse_entry_out as RawEntryOut {
+        nodeid: plain u64,
+        generation: plain u64,
+        entry_valid: plain u64,
+        attr_valid: plain u64,
+        entry_valid_nsec: plain u32,
+        attr_valid_nsec: plain u32,
+        attr: plain fuse_attr,
+    }
+    fuse_attr_out as RawAttrOut {
+        attr_valid: plain u64,
+        attr_valid_nsec: plain u32,
+        dummy: pad u32,
+        attr: plain fuse_attr,
+    }
+    fuse_open_out as RawOpenOut {
+        fh: plain u64,
+        open_flags: plain OpenOutFlags,
+        backing_id: plain i32,
+    }
+    fuse_write_out as RawWriteOut {
+        size: plain u32,
+        padding: pad u32,
+    }
+    fuse_kstatfs as RawKstatfs {
+        blocks: plain u64,
+        bfree: plain u64,
+        bavail: plain u64,
+        files: plain u64,
+        ffree: plain u64,
+        bsize: plain u32,
+        namelen: plain u32,
+        frsize: plain u32,
+        padding: pad u32,
+        spare: pad [u32; 6],
+    }
+    fuse_statfs_out as RawStatfsOut {
+        st: nested fuse_kstatfs,
+    }
+    fuse_getxattr_out as RawGetxattrOut {
+        size: plain u32,
+        padding: pad u32,
+    }
+    fuse_init_out as RawInitOut {
+        major: plain u32,
+        minor: plain u32,
+        max_readahead: plain u32,
+        flags: plain InitFlags,
+        max_background: plain u16,
+        congestion_threshold: plain u16,
+        max_write: plain u32,
+        time_gran: plain u32,
+        max_pages: plain u16,
+        max_alignment: plain u16,
+        flags2: plain InitFlags2,
+        max_stack_depth: plain u32,
+        request_timeout: plain u16,
+        unused: pad [u16; 11],
+    }
+    fuse_lk_out as RawLkOut {
+        lk: plain fuse_file_lock,
+    }
+    fuse_bmap_out as RawBmapOut {
+        block: plain u64,
+    }
+    fuse_lseek_out as RawLseekOut {
+        offset: plain u64,
+    }
+    fuse_dirent as RawDirent {
+        ino: plain u64,
+        off: plain u64,
+        namelen: plain u32,
+        r#type: plain u32,
+    }
+    fuse_direntplus as RawDirentplus {
+        entry_out: nested fuse_entry_out,
+        dirent: nested fuse_dirent,
+    }
+    fuse_ioctl_out as RawIoctlOut {
+        result: plain i32,
+        flags: plain IoctlFlags,
+        in_iovs: plain u32,
+        out_iovs: plain u32,
+    }
+    fuse_poll_out as RawPollOut {
+        revents: plain u32,
+        padding: pad u32,
+    }
+    fuse_sx_time as RawSxTime {
+        tv_sec: plain u64,
+        tv_nsec: plain u32,
+        __reserved: pad i32,
+    }
+    fuse_statx as RawStatx {
+        mask: plain u32,
+        blksize: plain u32,
+        attributes: plain u64,
+        nlink: plain u32,
+        uid: plain u32,
+        gid: plain u32,
+        mode: plain u16,
+        __spare0: pad u16,
+        ino: plain u64,
+        size: plain u64,
+        blocks: plain u64,
+        attributes_mask: plain u64,
+        atime: nested fuse_sx_time,
+        btime: nested fuse_sx_time,
+        ctime: nested fuse_sx_time,
+        mtime: nested fuse_sx_time,
+        rdev_major: plain u32,
+        rdev_minor: plain u32,
+        dev_major: plain u32,
+        dev_minor: plain u32,
+        __spare2: pad [u64; 14],
+    }
+    fuse_statx_out as RawStatxOut {
+        attr_valid: plain u64,
+        attr_valid_nsec: plain u32,
+        flags: plain u32,
+        spare: pad [u64; 2],
+        stat: nested fuse_statx,
+    }
+}
+
+/// Decodes a reply structure, a shorter reply, sent using an older protocol
+/// version, leaves the missing fields zeroed. Fails with [`Errno::EINVAL`]
+/// if a padding field is not zero.
+pub(crate) fn decode<T: Reply>(bytes: &[u8]) -> Result<T, Errno> {
+    if bytes.is_empty() {
+        return Err(Errno::EPROTO);
+    }
+    let mut raw = T::Raw::new_zeroed();
+    let raw_bytes = raw.as_mut_bytes();
+    let len = bytes.len().min(raw_bytes.len());
+    raw_bytes[..len].copy_from_slice(&bytes[..len]);
+    T::from_raw(raw)
+}
+
+fn zeroed<T: Reply>() -> T {
+    T::from_raw(T::Raw::new_zeroed()).unwrap()
+}
+
+fn cstr(name: &OsStr) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(name.len() + 1);
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.push(0);
+    bytes
+}
+
+fn fsync_in(fh: u64, datasync: bool) -> fuse_fsync_in {
+    let fsync_flags = match datasync {
+        true => FsyncFlags::FUSE_FSYNC_FDATASYNC,
+        false => FsyncFlags::empty(),
+    };
+    fuse_fsync_in { fh, fsync_flags, padding: 0 }
+}
+
+fn read_in(fh: u64, offset: u64, size: u32) -> fuse_read_in {
+    fuse_read_in {
+        fh,
+        offset,
+        size,
+        read_flags: ReadFlags::empty(),
+        lock_owner: 0,
+        flags: 0,
+        padding: 0,
+    }
+}
@@ -0,0 +1,17 @@
+//! Utilities to test [`Filesystem`](crate::Filesystem) implementations
+//! without mounting them.
+//!
+//! [`MockKernel`] plays the part of the kernel: it runs a [`Session`] over an
+//! in-memory channel, performs the `FUSE_INIT` handshake, and has a method
+//! for each request that encodes the arguments like the kernel does and
+//! decodes the reply, so a test reads like the syscalls it stands for.
+//!
+//! [`Session`]: crate::Session
+
+mod kernel;
+pub use kernel::MockKernel;
+pub(crate) use kernel::{Reply, decode};
+
+mod dir;
+pub use dir::{DirEntry, DirEntryPlus};
+pub(crate) use dir::{parse_direntplus, parse_dirents};
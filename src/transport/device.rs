@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::future::{Future, poll_fn};
+use std::io::{self, IoSlice};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::Poll;
+
+use tokio::io::Interest;
+use tokio::io::unix::{AsyncFd, AsyncFdReadyGuard};
+use tokio::sync::OnceCell;
+
+use crate::protocol::{ArgReader, FUSE_DEV_IOC_CLONE, HeaderError};
+use super::Transport;
+
+/// When the connection is aborted the device only reports an error, reads must
+/// be woken up to receive `ENODEV`.
+const READ_INTEREST: Interest = Interest::READABLE.add(Interest::ERROR);
+
+const FUSE_DEVICE: &CStr = c"/dev/fuse";
+
+/// The FUSE device of a mount, see [`Mount::device`](crate::Mount::device).
+#[derive(Debug)]
+pub struct FuseDevice {
+    /// Non blocking duplicate of the device, registered on first use so the
+    /// device can be created outside of a tokio runtime.
+    reader: OnceCell<AsyncFd<OwnedFd>>,
+    fd: Arc<OwnedFd>,
+}
+
+impl FuseDevice {
+    pub(crate) fn new(fd: Arc<OwnedFd>) -> Self {
+        Self { reader: OnceCell::new(), fd }
+    }
+
+    /// Opens a new FUSE device attached to the same connection, with
+    /// `FUSE_DEV_IOC_CLONE`.
+    ///
+    /// The kernel hands each request to one of the devices, and expects the
+    /// reply on the device the request was read from. See [`ClonedDevice`].
+    pub fn clone_device(&self) -> io::Result<Self> {
+        let fd = unsafe { libc::open(FUSE_DEVICE.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd == -1 {
+            let err = io::Error::last_os_error();
+            io_error!(err.kind(), "Failed to open {FUSE_DEVICE:?}: {err}");
+        }
+        // SAFETY: the file descriptor was just opened
+        let clone = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let session_fd = self.fd.as_raw_fd() as u32;
+        let ret = unsafe { libc::ioctl(fd, FUSE_DEV_IOC_CLONE as _, &session_fd) };
+        if ret == -1 {
+            let err = io::Error::last_os_error();
+            io_error!(err.kind(), "Failed to clone the FUSE device: {err}");
+        }
+        Ok(Self::new(Arc::new(clone)))
+    }
+
+    /// Duplicates the device file descriptor and makes it non blocking.
+    ///
+    /// The flag is shared with the original file descriptor, which must not be
+    /// used for blocking reads afterwards.
+    async fn reader(&self) -> io::Result<&AsyncFd<OwnedFd>> {
+        self.reader.get_or_try_init(|| async {
+            let fd = self.fd.try_clone()?;
+            let raw = fd.as_raw_fd();
+            let flags = unsafe { libc::fcntl(raw, libc::F_GETFL) };
+            if flags == -1
+                || unsafe { libc::fcntl(raw, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1
+            {
+                let err = io::Error::last_os_error();
+                io_error!(err.kind(), "Failed to make the FUSE device non blocking: {err}");
+            }
+            // SAFETY: the file descriptor is owned by the AsyncFd
+            unsafe { AsyncFd::register_with_interest(fd, READ_INTEREST) }
+                .map_err(io::Error::from)
+        }).await
+    }
+}
+
+impl Transport for FuseDevice {
+    /// Reads one request, the buffer must be big enough for the biggest
+    /// request, otherwise the kernel fails the read with `EINVAL`.
+    async fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let reader = self.reader().await?;
+        reader.async_io(READ_INTEREST, |fd| read(fd.as_fd(), buf)).await
+    }
+
+    /// Writes one reply, writes to the device never block.
+    fn send(&self, reply: &[IoSlice<'_>]) -> io::Result<()> {
+        write(self.fd.as_fd(), reply)
+    }
+}
+
+impl AsFd for FuseDevice {
+    #[inline]
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Several devices attached to the same connection, created with
+/// [`FuseDevice::clone_device`].
+///
+/// Each device has its own queue of requests being processed, which lowers
+/// the contention in the kernel when many requests are in flight. Requests
+/// are received from every device, and each reply is sent to the device its
+/// request came from.
+#[derive(Debug)]
+pub struct ClonedDevice {
+    devices: Vec<FuseDevice>,
+    /// Device of each request waiting for a reply.
+    owners: Mutex<HashMap<u64, usize>>,
+    /// Device polled first, rotated to receive from every device fairly.
+    next: AtomicUsize,
+}
+
+impl ClonedDevice {
+    /// Uses `device` along with `clones` more devices on its connection.
+    pub fn new(device: FuseDevice, clones: usize) -> io::Result<Self> {
+        let mut devices = Vec::with_capacity(clones + 1);
+        for _ in 0..clones {
+            devices.push(device.clone_device()?);
+        }
+        devices.insert(0, device);
+        Ok(Self {
+            devices,
+            owners: Mutex::new(HashMap::new()),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Number of devices, including the original one.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Waits until one of the devices has a request or an error.
+    async fn ready<'a>(
+        &self,
+        readers: &'a [&'a AsyncFd<OwnedFd>]
+    ) -> io::Result<(usize, AsyncFdReadyGuard<'a, OwnedFd>)> {
+        type Ready<'a> = Pin<Box<
+            dyn Future<Output = io::Result<AsyncFdReadyGuard<'a, OwnedFd>>> + Send + 'a
+        >>;
+
+        let first = self.next.fetch_add(1, Ordering::Relaxed) % readers.len();
+        let mut ready: Vec<(usize, Ready<'a>)> = (0..readers.len())
+            .map(|i| (first + i) % readers.len())
+            .map(|i| (i, Box::pin(readers[i].ready(READ_INTEREST)) as Ready<'a>))
+            .collect();
+
+        poll_fn(|cx| {
+            for (index, ready) in &mut ready {
+                if let Poll::Ready(guard) = ready.as_mut().poll(cx) {
+                    return Poll::Ready(guard.map(|guard| (*index, guard)));
+                }
+            }
+            Poll::Pending
+        }).await
+    }
+}
+
+impl Transport for ClonedDevice {
+    async fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut readers = Vec::with_capacity(self.devices.len());
+        for device in &self.devices {
+            readers.push(device.reader().await?);
+        }
+
+        let (index, len) = loop {
+            let (index, mut guard) = self.ready(&readers).await?;
+            if let Ok(result) = guard.try_io(|fd| read(fd.as_fd(), buf)) {
+                break (index, result?);
+            }
+        };
+
+        let unique = match ArgReader::new(&buf[..len]).fetch_header() {
+            Ok(header) => header.opcode.expects_reply().then_some(header.unique),
+            Err(HeaderError::UnknownOpcode { unique, .. }) => Some(unique),
+            Err(HeaderError::Truncated) => None,
+        };
+        if let Some(unique) = unique {
+            self.owners.lock().unwrap().insert(unique, index);
+        }
+        Ok(len)
+    }
+
+    fn send(&self, reply: &[IoSlice<'_>]) -> io::Result<()> {
+        let unique = reply.first()
+            .and_then(|header| header.get(8..16))
+            .map(|unique| u64::from_ne_bytes(unique.try_into().unwrap()));
+        // Notifications can be sent to any device
+        let index = match unique {
+            Some(0) | None => 0,
+            Some(unique) => match self.owners.lock().unwrap().remove(&unique) {
+                Some(index) => index,
+                // Like the device, when the request is not waiting for a reply
+                None => return Err(io::Error::from_raw_os_error(libc::ENOENT)),
+            },
+        };
+        write(self.devices[index].as_fd(), reply)
+    }
+
+    fn cancel(&self, unique: u64) {
+        self.owners.lock().unwrap().remove(&unique);
+    }
+}
+
+fn read(fd: BorrowedFd, buf: &mut [u8]) -> io::Result<usize> {
+    let ret = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+fn write(fd: BorrowedFd, reply: &[IoSlice<'_>]) -> io::Result<()> {
+    let ret = unsafe {
+        libc::writev(fd.as_raw_fd(), reply.as_ptr().cast(), reply.len() as i32)
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
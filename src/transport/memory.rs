@@ -0,0 +1,110 @@
+use std::io::{self, IoSlice};
+
+use std::sync::Mutex as SyncMutex;
+
+use tokio::sync::{Mutex, mpsc};
+
+use crate::Errno;
+use crate::protocol::{ArgReader, HeaderError, ReplyBuf};
+use super::Transport;
+
+/// Session end of an in-memory connection, the other end is a [`MemoryPeer`].
+///
+/// Like the FUSE device, receiving fails with `ENODEV` once the peer is
+/// closed or the transport is shut down, and the requests that don't fit the
+/// buffer are answered with `EIO`.
+#[derive(Debug)]
+pub struct MemoryTransport {
+    requests: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+    replies: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+/// Kernel end of an in-memory connection, see
+/// [`MockKernel`](crate::testing::MockKernel).
+#[derive(Debug)]
+pub struct MemoryPeer {
+    requests: SyncMutex<Option<mpsc::UnboundedSender<Vec<u8>>>>,
+    replies: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> (Self, MemoryPeer) {
+        let (request_tx, request_rx) = mpsc::unbounded_channel();
+        let (reply_tx, reply_rx) = mpsc::unbounded_channel();
+        let transport = Self {
+            requests: Mutex::new(request_rx),
+            replies: reply_tx,
+        };
+        let peer = MemoryPeer {
+            requests: SyncMutex::new(Some(request_tx)),
+            replies: Mutex::new(reply_rx),
+        };
+        (transport, peer)
+    }
+}
+
+impl Transport for MemoryTransport {
+    async fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut requests = self.requests.lock().await;
+        loop {
+            let Some(request) = requests.recv().await else {
+                return Err(io::Error::from_raw_os_error(libc::ENODEV));
+            };
+            if request.len() <= buf.len() {
+                buf[..request.len()].copy_from_slice(&request);
+                return Ok(request.len());
+            }
+
+            // Only this request fails, the session goes on with the next one
+            let unique = match ArgReader::new(&request).fetch_header() {
+                Ok(header) => Some(header.unique),
+                Err(HeaderError::UnknownOpcode { unique, .. }) => Some(unique),
+                Err(HeaderError::Truncated) => None,
+            };
+            if let Some(unique) = unique {
+                let reply = ReplyBuf::error(unique, Errno::EIO).finish();
+                let _ = self.replies.send(reply);
+            }
+        }
+    }
+
+    fn send(&self, reply: &[IoSlice<'_>]) -> io::Result<()> {
+        let reply = reply.iter().flat_map(|slice| slice.iter().copied()).collect();
+        match self.replies.send(reply) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(io::Error::from_raw_os_error(libc::ENODEV)),
+        }
+    }
+
+    /// Stops accepting requests, the ones already sent can still be received.
+    async fn shutdown(&self) {
+        self.requests.lock().await.close();
+    }
+}
+
+impl MemoryPeer {
+    /// Sends a request, a whole [`fuse_in_header`] followed by its arguments.
+    ///
+    /// Fails with `ENODEV` if either end was closed.
+    ///
+    /// [`fuse_in_header`]: crate::protocol::fuse_in_header
+    pub fn send(&self, request: Vec<u8>) -> io::Result<()> {
+        let requests = self.requests.lock().unwrap();
+        match requests.as_ref().map(|requests| requests.send(request)) {
+            Some(Ok(())) => Ok(()),
+            _ => Err(io::Error::from_raw_os_error(libc::ENODEV)),
+        }
+    }
+
+    /// Ends the connection, like unmounting the file system: the transport
+    /// fails with `ENODEV` once the requests already sent are received.
+    pub fn close(&self) {
+        self.requests.lock().unwrap().take();
+    }
+
+    /// Receives a reply or a notification, returns `None` once the transport
+    /// is dropped and every reply has been received.
+    pub async fn receive(&self) -> Option<Vec<u8>> {
+        self.replies.lock().await.recv().await
+    }
+}
@@ -0,0 +1,114 @@
+//! Connections that carry the requests of a [`Session`](crate::Session).
+//!
+//! A session is usually connected to the kernel through the FUSE device, but
+//! the same [`Filesystem`](crate::Filesystem) can be served over any
+//! [`Transport`], like the in-memory channel used for testing.
+
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::mem::size_of;
+use std::os::fd::BorrowedFd;
+use std::pin::Pin;
+
+use zerocopy::IntoBytes;
+
+use crate::protocol::{OutError, fuse_notify_code, fuse_out_header};
+use crate::protocol::{fuse_removemapping_one, fuse_setupmapping_in};
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+mod device;
+pub use device::{ClonedDevice, FuseDevice};
+
+mod memory;
+pub use memory::{MemoryPeer, MemoryTransport};
+
+mod trace;
+pub use trace::{Direction, Record, Recording, TraceTransport, Tracer};
+
+#[cfg(feature = "vhost-user")]
+mod vhost_user;
+#[cfg(feature = "vhost-user")]
+pub use vhost_user::VhostUserFs;
+
+/// A connection that carries requests to a session, and its replies back.
+///
+/// Requests are read by a single task, while replies are sent concurrently by
+/// the handlers, as soon as they complete.
+pub trait Transport: Send + Sync + 'static {
+    /// Receives one request in `buf`, returning its size.
+    ///
+    /// The buffer is big enough for the biggest request allowed by the
+    /// `FUSE_INIT` handshake. Errors are interpreted like those of the FUSE
+    /// device: `ENODEV` means the connection has ended, while `ENOENT`,
+    /// `EINTR` and `EAGAIN` are retried. Any other error ends the session.
+    fn receive(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Sends a reply, made of the concatenation of `reply`.
+    ///
+    /// The first slice holds the whole [`fuse_out_header`], so a transport
+    /// can tell which request is being answered.
+    fn send(&self, reply: &[IoSlice<'_>]) -> io::Result<()>;
+
+    /// Sends a notification, `data` follows the header built from `code`.
+    fn notify(&self, code: fuse_notify_code, data: &[IoSlice<'_>]) -> io::Result<()> {
+        let len = size_of::<fuse_out_header>() + data.iter().map(|s| s.len()).sum::<usize>();
+        let header = fuse_out_header {
+            len: len as u32,
+            error: OutError::notify(code),
+            unique: 0,
+        };
+        let mut message = Vec::with_capacity(data.len() + 1);
+        message.push(IoSlice::new(header.as_bytes()));
+        message.extend_from_slice(data);
+        self.send(&message)
+    }
+
+    /// Asks the other end to end the connection, called when the session is
+    /// shut down.
+    ///
+    /// The session keeps receiving requests until [`Transport::receive`]
+    /// fails with `ENODEV`, or the shutdown timeout expires. Mounted sessions
+    /// are unmounted instead, so this does nothing by default.
+    fn shutdown(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Forgets the request `unique`, which won't be answered because its
+    /// handler was cancelled when the session shut down.
+    fn cancel(&self, unique: u64) {
+        let _ = unique;
+    }
+
+    /// The DAX window of the device, used by `FUSE_SETUPMAPPING` and
+    /// `FUSE_REMOVEMAPPING`, only virtio-fs devices have one.
+    fn dax_window(&self) -> Option<&dyn DaxWindow> {
+        None
+    }
+}
+
+/// Memory shared with the guest of a virtio-fs device, where the files are
+/// mapped to be accessed directly, without requests.
+///
+/// The offsets in the window are chosen by the guest, it also takes care of
+/// not overlapping the mappings.
+///
+/// The mappings are made by the device, so the methods wait for it without
+/// blocking the thread.
+pub trait DaxWindow: Send + Sync {
+    /// Maps `arg.len` bytes of `file` at `arg.foffset` to `arg.moffset` in the
+    /// window, `arg.flags` are the [`SetupMappingFlags`].
+    ///
+    /// [`SetupMappingFlags`]: crate::protocol::SetupMappingFlags
+    fn map<'a>(
+        &'a self,
+        file: BorrowedFd<'a>,
+        arg: &'a fuse_setupmapping_in
+    ) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Removes the mappings of the ranges in `mappings`.
+    fn unmap<'a>(
+        &'a self,
+        mappings: &'a [fuse_removemapping_one]
+    ) -> BoxFuture<'a, io::Result<()>>;
+}
@@ -0,0 +1,528 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt::{self, Write as _};
+use std::io::{self, IoSlice, Read, Write};
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use zerocopy::{IntoBytes, TryFromBytes};
+
+use crate::Errno;
+use crate::protocol::*;
+use crate::testing::{Reply, decode, parse_direntplus, parse_dirents};
+use super::{DaxWindow, Transport};
+
+/// First bytes of a recording, see [`Tracer::record`].
+const MAGIC: &[u8; 8] = b"FUSEREC1";
+/// Size of the header of a record: the direction, the time in nanoseconds and
+/// the length of the message.
+const RECORD_HEADER_SIZE: usize = 1 + 8 + 4;
+
+/// A [`Transport`] printing the requests and the replies going through it,
+/// like `strace` for the FUSE protocol.
+///
+/// The messages are passed unchanged to and from the inner transport, they
+/// are decoded and printed by a [`Tracer`]. The tracer is locked for each
+/// message, so tracing slows down the session, it is meant for debugging.
+pub struct TraceTransport<T> {
+    inner: T,
+    start: Instant,
+    tracer: Mutex<Tracer>,
+}
+
+/// Prints the messages seen by a [`TraceTransport`], and records them.
+///
+/// A request is printed on one line with its header and its [`Operation`],
+/// the reply on another line with its latency and the decoded reply
+/// structure, or its error:
+///
+/// ```text
+///     0.004513 <- 8 FUSE_LOOKUP nodeid=1 uid=1000 gid=1000 pid=4242 Lookup { name: "a" }
+///     0.004561 -> 8 FUSE_LOOKUP (48.2µs) ENOENT
+/// ```
+///
+/// The filters only apply to what is printed, every message is recorded.
+pub struct Tracer {
+    output: Box<dyn Write + Send>,
+    opcodes: Vec<fuse_opcode>,
+    nodeids: Vec<u64>,
+    recording: Option<Box<dyn Write + Send>>,
+    /// Version negotiated by `FUSE_INIT`, used to decode the requests.
+    version: ProtocolVersion,
+    /// Requests waiting for their reply, by unique.
+    pending: HashMap<u64, Pending>,
+}
+
+struct Pending {
+    opcode: fuse_opcode,
+    reply: ReplyKind,
+    time: Duration,
+    shown: bool,
+}
+
+/// How the reply to a request is decoded.
+#[derive(Clone, Copy)]
+enum ReplyKind {
+    Entry,
+    Attr,
+    Open,
+    Create,
+    Write,
+    Statfs,
+    XattrSize,
+    Init,
+    Lock,
+    Bmap,
+    Ioctl,
+    Poll,
+    Lseek,
+    Statx,
+    Dirents,
+    DirentsPlus,
+    Target,
+    /// Data, or nothing, only the size is shown.
+    Raw,
+}
+
+/// Who sent a recorded message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A request, from the kernel.
+    Request,
+    /// A reply or a notification, to the kernel.
+    Reply,
+}
+
+/// A message of a recording.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub direction: Direction,
+    /// Time since the start of the recording.
+    pub time: Duration,
+    /// The whole message, starting with its header.
+    pub data: Vec<u8>,
+}
+
+/// Reads the messages recorded by [`Tracer::record`].
+///
+/// The requests can be printed again with [`Tracer::trace`], or sent to
+/// another session with [`MemoryPeer::send`](super::MemoryPeer::send).
+#[derive(Debug)]
+pub struct Recording<R> {
+    reader: R,
+}
+
+impl<T: Transport> TraceTransport<T> {
+    pub fn new(inner: T, tracer: Tracer) -> Self {
+        Self { inner, start: Instant::now(), tracer: Mutex::new(tracer) }
+    }
+
+    #[inline]
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Transport> Transport for TraceTransport<T> {
+    async fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.receive(buf).await?;
+        self.tracer.lock().unwrap().request(self.start.elapsed(), &buf[..len]);
+        Ok(len)
+    }
+
+    fn send(&self, reply: &[IoSlice<'_>]) -> io::Result<()> {
+        let message: Vec<u8> = reply.iter().flat_map(|slice| slice.iter().copied()).collect();
+        self.tracer.lock().unwrap().reply(self.start.elapsed(), &message);
+        self.inner.send(reply)
+    }
+
+    fn notify(&self, code: fuse_notify_code, data: &[IoSlice<'_>]) -> io::Result<()> {
+        let len = size_of::<fuse_out_header>() + data.iter().map(|s| s.len()).sum::<usize>();
+        let header = fuse_out_header {
+            len: len as u32,
+            error: OutError::notify(code),
+            unique: 0,
+        };
+        let mut message = Vec::with_capacity(len);
+        message.extend_from_slice(header.as_bytes());
+        data.iter().for_each(|slice| message.extend_from_slice(slice));
+        self.tracer.lock().unwrap().reply(self.start.elapsed(), &message);
+        self.inner.notify(code, data)
+    }
+
+    async fn shutdown(&self) {
+        self.tracer.lock().unwrap().flush();
+        self.inner.shutdown().await
+    }
+
+    fn cancel(&self, unique: u64) {
+        self.inner.cancel(unique)
+    }
+
+    fn dax_window(&self) -> Option<&dyn DaxWindow> {
+        self.inner.dax_window()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for TraceTransport<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceTransport")
+            .field("inner", &self.inner)
+            .field("tracer", &self.tracer)
+            .finish()
+    }
+}
+
+impl Tracer {
+    /// Creates a tracer printing every message to the standard error.
+    pub fn new() -> Self {
+        Self {
+            output: Box::new(io::stderr()),
+            opcodes: Vec::new(),
+            nodeids: Vec::new(),
+            recording: None,
+            version: ProtocolVersion::CURRENT,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Prints to `output` instead of the standard error.
+    #[inline]
+    #[must_use = "A Tracer does nothing unless passed to a TraceTransport"]
+    pub fn output(mut self, output: impl Write + Send + 'static) -> Self {
+        self.output = Box::new(output);
+        self
+    }
+
+    /// Only prints the requests with `opcode`, and their replies.
+    ///
+    /// Can be called several times to print several opcodes.
+    #[inline]
+    #[must_use = "A Tracer does nothing unless passed to a TraceTransport"]
+    pub fn opcode(mut self, opcode: fuse_opcode) -> Self {
+        self.opcodes.push(opcode);
+        self
+    }
+
+    /// Only prints the requests on the inode `nodeid`, and their replies.
+    ///
+    /// Can be called several times to print several inodes. The requests
+    /// naming an inode in their arguments, like the new parent of
+    /// `FUSE_RENAME`, are only matched by their header.
+    #[inline]
+    #[must_use = "A Tracer does nothing unless passed to a TraceTransport"]
+    pub fn nodeid(mut self, nodeid: u64) -> Self {
+        self.nodeids.push(nodeid);
+        self
+    }
+
+    /// Also writes every message to `recording`, to be read later with
+    /// [`Recording`].
+    ///
+    /// The recording is a magic number followed by the messages, each one
+    /// preceded by its direction, a byte that is 0 for a request and 1 for a
+    /// reply, the time since the start as little endian nanoseconds on 64
+    /// bits, and its length as a little endian 32 bits integer. If writing
+    /// fails, the error is printed and the recording stops.
+    pub fn record(mut self, mut recording: impl Write + Send + 'static) -> io::Result<Self> {
+        recording.write_all(MAGIC)?;
+        self.recording = Some(Box::new(recording));
+        Ok(self)
+    }
+
+    /// Prints a recorded message, like if it was going through a
+    /// [`TraceTransport`].
+    pub fn trace(&mut self, record: &Record) {
+        match record.direction {
+            Direction::Request => self.request(record.time, &record.data),
+            Direction::Reply => self.reply(record.time, &record.data),
+        }
+    }
+
+    fn request(&mut self, time: Duration, request: &[u8]) {
+        self.write_record(Direction::Request, time, request);
+
+        let mut args = ArgReader::new(request);
+        let header = match args.fetch_header() {
+            Ok(header) => header,
+            Err(HeaderError::UnknownOpcode { unique, opcode }) => {
+                let payload = Payload(request);
+                return self.print(time, format_args!("<- {unique} opcode {opcode} {payload:?}"));
+            },
+            Err(HeaderError::Truncated) => {
+                let payload = Payload(request);
+                return self.print(time, format_args!("<- truncated request {payload:?}"));
+            },
+        };
+        let shown = self.is_shown(header.opcode, header.nodeid);
+        let op = Operation::decode(header.opcode, args.remaining(), self.version);
+        let reply = match &op {
+            Ok(op) => reply_kind(op),
+            Err(_) => Some(ReplyKind::Raw),
+        };
+        if let Some(reply) = reply {
+            let pending = Pending { opcode: header.opcode, reply, time, shown };
+            self.pending.insert(header.unique, pending);
+        }
+        if !shown {
+            return;
+        }
+
+        let fuse_in_header { unique, opcode, nodeid, uid, gid, pid, .. } = header;
+        let args = match op {
+            Ok(op) => format!("{op:?}"),
+            Err(errno) => format!("malformed ({errno:?}) {:?}", Payload(args.remaining())),
+        };
+        let caller = format!("nodeid={nodeid} uid={uid} gid={gid} pid={pid}");
+        self.print(time, format_args!("<- {unique} {opcode:?} {caller} {args}"));
+    }
+
+    fn reply(&mut self, time: Duration, reply: &[u8]) {
+        self.write_record(Direction::Reply, time, reply);
+
+        let Some((error, unique, body)) = split_reply(reply) else {
+            return self.print(time, format_args!("-> truncated reply {:?}", Payload(reply)));
+        };
+        if unique == 0 {
+            if !self.opcodes.is_empty() || !self.nodeids.is_empty() {
+                return;
+            }
+            let code = match fuse_notify_code::try_read_from_bytes(error.as_bytes()) {
+                Ok(code) => format!("{code:?}"),
+                Err(_) => format!("notification {error}"),
+            };
+            return self.print(time, format_args!("-> {code} {:?}", Payload(body)));
+        }
+
+        let Some(pending) = self.pending.remove(&unique) else {
+            if self.opcodes.is_empty() && self.nodeids.is_empty() {
+                let outcome = outcome(error, ReplyKind::Raw, body, self.version);
+                self.print(time, format_args!("-> {unique} {outcome}"));
+            }
+            return;
+        };
+        if let ReplyKind::Init = pending.reply
+            && let Ok(out) = decode::<fuse_init_out>(body)
+        {
+            self.version = ProtocolVersion::new(out.major, out.minor);
+        }
+        if pending.shown {
+            let latency = time.saturating_sub(pending.time);
+            let opcode = pending.opcode;
+            let outcome = outcome(error, pending.reply, body, self.version);
+            self.print(time, format_args!("-> {unique} {opcode:?} ({latency:.1?}) {outcome}"));
+        }
+    }
+
+    fn is_shown(&self, opcode: fuse_opcode, nodeid: u64) -> bool {
+        (self.opcodes.is_empty() || self.opcodes.contains(&opcode))
+            && (self.nodeids.is_empty() || self.nodeids.contains(&nodeid))
+    }
+
+    /// Prints a line at once, so that it isn't mixed with other output.
+    fn print(&mut self, time: Duration, message: fmt::Arguments<'_>) {
+        let line = format!("{:12.6} {message}\n", time.as_secs_f64());
+        let _ = self.output.write_all(line.as_bytes());
+    }
+
+    fn write_record(&mut self, direction: Direction, time: Duration, message: &[u8]) {
+        let Some(recording) = &mut self.recording else { return };
+        let nanos = u64::try_from(time.as_nanos()).unwrap_or(u64::MAX);
+        let mut header = [0; RECORD_HEADER_SIZE];
+        header[0] = direction as u8;
+        header[1..9].copy_from_slice(&nanos.to_le_bytes());
+        header[9..].copy_from_slice(&(message.len() as u32).to_le_bytes());
+        let result = recording.write_all(&header)
+            .and_then(|()| recording.write_all(message));
+        if let Err(err) = result {
+            self.recording = None;
+            self.print(time, format_args!("recording stopped: {err}"));
+        }
+    }
+
+    fn flush(&mut self) {
+        let _ = self.output.flush();
+        if let Some(recording) = &mut self.recording
+            && let Err(err) = recording.flush()
+        {
+            self.recording = None;
+            let _ = writeln!(self.output, "recording stopped: {err}");
+        }
+    }
+}
+
+impl Default for Tracer {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("opcodes", &self.opcodes)
+            .field("nodeids", &self.nodeids)
+            .field("recording", &self.recording.is_some())
+            .field("pending", &self.pending.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl<R: Read> Recording<R> {
+    /// Starts reading a recording, fails with `InvalidData` if `reader` is not
+    /// one.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        match reader.read_exact(&mut magic) {
+            Ok(()) if magic == *MAGIC => Ok(Self { reader }),
+            Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => Err(err),
+            _ => io_error!(io::ErrorKind::InvalidData, "Not a FUSE recording"),
+        }
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0; RECORD_HEADER_SIZE];
+        match self.reader.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut header[1..])?,
+        }
+        let direction = match header[0] {
+            0 => Direction::Request,
+            1 => Direction::Reply,
+            other => io_error!(io::ErrorKind::InvalidData, "Bad record direction {other}"),
+        };
+        let time = Duration::from_nanos(u64::from_le_bytes(header[1..9].try_into().unwrap()));
+        let len = u32::from_le_bytes(header[9..].try_into().unwrap());
+
+        // Read progressively, a bogus length must not allocate up front
+        let mut data = Vec::new();
+        (&mut self.reader).take(len.into()).read_to_end(&mut data)?;
+        if data.len() != len as usize {
+            io_error!(io::ErrorKind::UnexpectedEof, "Truncated record");
+        }
+        Ok(Some(Record { direction, time, data }))
+    }
+}
+
+impl<R: Read> Iterator for Recording<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// How the reply to `op` is decoded, `None` if the request has no reply.
+fn reply_kind(op: &Operation<'_>) -> Option<ReplyKind> {
+    use Operation::*;
+
+    let kind = match op {
+        Forget { .. } | BatchForget { .. } | Interrupt { .. } | NotifyReply { .. } => {
+            return None;
+        },
+        Lookup { .. } | Symlink { .. } | Mknod { .. } | Mkdir { .. } | Link { .. } => {
+            ReplyKind::Entry
+        },
+        Getattr { .. } | Setattr { .. } => ReplyKind::Attr,
+        Open { .. } | Opendir { .. } => ReplyKind::Open,
+        Create { .. } | Tmpfile { .. } => ReplyKind::Create,
+        Write { .. } | CopyFileRange { .. } => ReplyKind::Write,
+        Statfs => ReplyKind::Statfs,
+        Getxattr { arg, .. } | Listxattr { arg } if arg.size == 0 => ReplyKind::XattrSize,
+        Init { .. } => ReplyKind::Init,
+        Getlk { .. } => ReplyKind::Lock,
+        Bmap { .. } => ReplyKind::Bmap,
+        Ioctl { .. } => ReplyKind::Ioctl,
+        Poll { .. } => ReplyKind::Poll,
+        Lseek { .. } => ReplyKind::Lseek,
+        Statx { .. } => ReplyKind::Statx,
+        Readdir { .. } => ReplyKind::Dirents,
+        Readdirplus { .. } => ReplyKind::DirentsPlus,
+        Readlink => ReplyKind::Target,
+        _ => ReplyKind::Raw,
+    };
+    Some(kind)
+}
+
+/// Splits a reply into the error of its header, its unique and its body.
+fn split_reply(reply: &[u8]) -> Option<(i32, u64, &[u8])> {
+    let header = reply.get(..size_of::<fuse_out_header>())?;
+    let error = i32::from_ne_bytes(header[4..8].try_into().unwrap());
+    let unique = u64::from_ne_bytes(header[8..16].try_into().unwrap());
+    Some((error, unique, &reply[header.len()..]))
+}
+
+/// The error of a reply, or its decoded body.
+fn outcome(error: i32, kind: ReplyKind, body: &[u8], version: ProtocolVersion) -> String {
+    if error != 0 {
+        return match Errno::new(-error) {
+            Some(errno) => format!("{errno:?}"),
+            None => format!("error {error}"),
+        };
+    }
+    decode_body(kind, body, version)
+        .unwrap_or_else(|errno| format!("malformed ({errno:?}) {:?}", Payload(body)))
+}
+
+fn decode_body(kind: ReplyKind, body: &[u8], version: ProtocolVersion) -> Result<String, Errno> {
+    let text = match kind {
+        ReplyKind::Entry => show::<fuse_entry_out>(body)?,
+        ReplyKind::Attr => show::<fuse_attr_out>(body)?,
+        ReplyKind::Open => show::<fuse_open_out>(body)?,
+        ReplyKind::Create => {
+            let open = body.get(fuse_entry_out::compat_size(version)..).unwrap_or_default();
+            format!("{} {}", show::<fuse_entry_out>(body)?, show::<fuse_open_out>(open)?)
+        },
+        ReplyKind::Write => show::<fuse_write_out>(body)?,
+        ReplyKind::Statfs => show::<fuse_statfs_out>(body)?,
+        ReplyKind::XattrSize => show::<fuse_getxattr_out>(body)?,
+        ReplyKind::Init => show::<fuse_init_out>(body)?,
+        ReplyKind::Lock => show::<fuse_lk_out>(body)?,
+        ReplyKind::Bmap => show::<fuse_bmap_out>(body)?,
+        ReplyKind::Ioctl => {
+            let data = body.get(size_of::<fuse_ioctl_out>()..).unwrap_or_default();
+            format!("{} {:?}", show::<fuse_ioctl_out>(body)?, Payload(data))
+        },
+        ReplyKind::Poll => show::<fuse_poll_out>(body)?,
+        ReplyKind::Lseek => show::<fuse_lseek_out>(body)?,
+        ReplyKind::Statx => show::<fuse_statx_out>(body)?,
+        ReplyKind::Dirents => {
+            let entries = parse_dirents(body)?;
+            names(entries.iter().map(|entry| (&entry.name, entry.ino)))
+        },
+        ReplyKind::DirentsPlus => {
+            let entries = parse_direntplus(body)?;
+            names(entries.iter().map(|plus| (&plus.dirent.name, plus.entry.nodeid)))
+        },
+        ReplyKind::Target => format!("{:?}", OsStr::from_bytes(body)),
+        ReplyKind::Raw => format!("{:?}", Payload(body)),
+    };
+    Ok(text)
+}
+
+fn show<T: Reply + fmt::Debug>(body: &[u8]) -> Result<String, Errno> {
+    Ok(format!("{:?}", decode::<T>(body)?))
+}
+
+/// Lists the entries of a directory as `name=ino`.
+fn names<'a, S>(entries: impl Iterator<Item = (&'a S, u64)>) -> String
+where
+    S: AsRef<OsStr> + ?Sized + 'a
+{
+    let mut list = String::from("[");
+    for (index, (name, ino)) in entries.enumerate() {
+        let separator = if index == 0 { "" } else { ", " };
+        let _ = write!(list, "{separator}{:?}={ino}", name.as_ref());
+    }
+    list.push(']');
+    list
+}
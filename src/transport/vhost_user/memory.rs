@@ -0,0 +1,121 @@
+use std::io::{self, ErrorKind};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::ptr;
+
+use super::message::VhostUserMemoryRegion;
+
+/// Memory of the guest, shared by the frontend with `SET_MEM_TABLE`.
+///
+/// Addresses are either guest physical addresses, used by the descriptors, or
+/// addresses in the frontend process, used for the rings.
+pub(super) struct GuestMemory {
+    regions: Vec<Region>,
+}
+
+struct Region {
+    guest_phys_addr: u64,
+    userspace_addr: u64,
+    size: u64,
+    /// Start of the region in this process.
+    host: *mut u8,
+    /// Whole mapping, including the offset of the region in its file.
+    mapping: (*mut libc::c_void, usize),
+}
+
+// SAFETY: the regions are only accessed through raw pointers, the guest may
+// change them at any time anyway
+unsafe impl Send for GuestMemory {}
+unsafe impl Sync for GuestMemory {}
+
+impl GuestMemory {
+    /// Maps the regions, each one backed by the file descriptor at the same
+    /// position in `fds`.
+    pub fn new(regions: &[VhostUserMemoryRegion], fds: Vec<OwnedFd>) -> io::Result<Self> {
+        if regions.len() != fds.len() {
+            io_error!(
+                ErrorKind::InvalidData,
+                "Got {} file descriptors for {} memory regions", fds.len(), regions.len()
+            );
+        }
+
+        let mut memory = Self { regions: Vec::with_capacity(regions.len()) };
+        for (region, fd) in regions.iter().zip(fds) {
+            let len = region.memory_size.checked_add(region.mmap_offset)
+                .and_then(|len| usize::try_from(len).ok());
+            let Some(len) = len else {
+                io_error!(ErrorKind::InvalidData, "Memory region too big: {region:?}");
+            };
+            let addr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED | libc::MAP_NORESERVE,
+                    fd.as_raw_fd(),
+                    0
+                )
+            };
+            if addr == libc::MAP_FAILED {
+                let err = io::Error::last_os_error();
+                io_error!(err.kind(), "Failed to map the guest memory: {err}");
+            }
+            memory.regions.push(Region {
+                guest_phys_addr: region.guest_phys_addr,
+                userspace_addr: region.userspace_addr,
+                size: region.memory_size,
+                host: unsafe { addr.cast::<u8>().add(region.mmap_offset as usize) },
+                mapping: (addr, len),
+            });
+        }
+        Ok(memory)
+    }
+
+    /// Pointer to `len` bytes at the guest physical address `addr`, the range
+    /// must be within one region.
+    pub fn guest(&self, addr: u64, len: u64) -> Option<*mut u8> {
+        self.regions.iter()
+            .find_map(|region| region.translate(region.guest_phys_addr, addr, len))
+    }
+
+    /// Pointer to `len` bytes at the address `addr` of the frontend process.
+    pub fn user(&self, addr: u64, len: u64) -> Option<*mut u8> {
+        self.regions.iter()
+            .find_map(|region| region.translate(region.userspace_addr, addr, len))
+    }
+
+    /// Copies from the guest physical address `addr` to `buf`.
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> Option<()> {
+        let src = self.guest(addr, buf.len() as u64)?;
+        // SAFETY: the range is within a region
+        unsafe { ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Some(())
+    }
+
+    /// Copies `data` to the guest physical address `addr`.
+    pub fn write(&self, addr: u64, data: &[u8]) -> Option<()> {
+        let dst = self.guest(addr, data.len() as u64)?;
+        // SAFETY: the range is within a region
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        Some(())
+    }
+}
+
+impl Region {
+    fn translate(&self, start: u64, addr: u64, len: u64) -> Option<*mut u8> {
+        let offset = addr.checked_sub(start)?;
+        if offset.checked_add(len)? > self.size {
+            return None;
+        }
+        // SAFETY: the offset is within the region
+        Some(unsafe { self.host.add(offset as usize) })
+    }
+}
+
+impl Drop for GuestMemory {
+    fn drop(&mut self) {
+        for region in &self.regions {
+            let (addr, len) = region.mapping;
+            unsafe { libc::munmap(addr, len) };
+        }
+    }
+}
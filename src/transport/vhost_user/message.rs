@@ -0,0 +1,225 @@
+//! Messages of the vhost-user protocol, as described in the QEMU
+//! documentation (`docs/interop/vhost-user.rst`).
+
+use std::io;
+use std::mem::{size_of, size_of_val};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+/// Version of the protocol, in the low bits of [`VhostUserHeader::flags`].
+pub(super) const VHOST_USER_VERSION: u32 = 0x1;
+pub(super) const VHOST_USER_VERSION_MASK: u32 = 0x3;
+/// Set in replies.
+pub(super) const VHOST_USER_REPLY: u32 = 1 << 2;
+/// Asks for a [`VHOST_USER_PROTOCOL_F_REPLY_ACK`] reply.
+pub(super) const VHOST_USER_NEED_REPLY: u32 = 1 << 3;
+
+/// Biggest payload accepted, `SET_MEM_TABLE` with the maximum number of
+/// regions.
+pub(super) const MAX_PAYLOAD: usize =
+    size_of::<VhostUserMemory>() + MAX_REGIONS * size_of::<VhostUserMemoryRegion>();
+/// Maximum number of memory regions, and of file descriptors in a message.
+pub(super) const MAX_REGIONS: usize = 8;
+
+// Messages sent by the frontend
+pub(super) const VHOST_USER_GET_FEATURES: u32 = 1;
+pub(super) const VHOST_USER_SET_FEATURES: u32 = 2;
+pub(super) const VHOST_USER_SET_OWNER: u32 = 3;
+pub(super) const VHOST_USER_RESET_OWNER: u32 = 4;
+pub(super) const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+pub(super) const VHOST_USER_SET_VRING_NUM: u32 = 8;
+pub(super) const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+pub(super) const VHOST_USER_SET_VRING_BASE: u32 = 10;
+pub(super) const VHOST_USER_GET_VRING_BASE: u32 = 11;
+pub(super) const VHOST_USER_SET_VRING_KICK: u32 = 12;
+pub(super) const VHOST_USER_SET_VRING_CALL: u32 = 13;
+pub(super) const VHOST_USER_SET_VRING_ERR: u32 = 14;
+pub(super) const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+pub(super) const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+pub(super) const VHOST_USER_GET_QUEUE_NUM: u32 = 17;
+pub(super) const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+pub(super) const VHOST_USER_SET_BACKEND_REQ_FD: u32 = 21;
+
+// Messages sent by the backend, the numbers used by the virtio-fs DAX
+// extension of the protocol
+pub(super) const VHOST_USER_BACKEND_FS_MAP: u32 = 6;
+pub(super) const VHOST_USER_BACKEND_FS_UNMAP: u32 = 7;
+
+// Virtio features
+pub(super) const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+pub(super) const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+
+// Protocol features
+pub(super) const VHOST_USER_PROTOCOL_F_MQ: u64 = 1 << 0;
+pub(super) const VHOST_USER_PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+pub(super) const VHOST_USER_PROTOCOL_F_BACKEND_REQ: u64 = 1 << 5;
+
+/// Set in the argument of `SET_VRING_KICK` and `SET_VRING_CALL` when no file
+/// descriptor is attached.
+pub(super) const VHOST_USER_VRING_NOFD_MASK: u64 = 1 << 8;
+pub(super) const VHOST_USER_VRING_IDX_MASK: u64 = 0xff;
+
+/// Number of ranges in a `FS_MAP` or `FS_UNMAP` message.
+pub(super) const VHOST_USER_FS_BACKEND_ENTRIES: usize = 8;
+pub(super) const VHOST_USER_FS_FLAG_MAP_R: u64 = 1 << 0;
+pub(super) const VHOST_USER_FS_FLAG_MAP_W: u64 = 1 << 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
+pub(super) struct VhostUserHeader {
+    pub request: u32,
+    pub flags: u32,
+    /// Size of the payload following the header.
+    pub size: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
+pub(super) struct VhostUserVringState {
+    pub index: u32,
+    pub num: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
+pub(super) struct VhostUserVringAddr {
+    pub index: u32,
+    pub flags: u32,
+    /// Addresses of the rings, in the address space of the frontend.
+    pub desc_user_addr: u64,
+    pub used_user_addr: u64,
+    pub avail_user_addr: u64,
+    pub log_guest_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
+pub(super) struct VhostUserMemory {
+    pub nregions: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
+pub(super) struct VhostUserMemoryRegion {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    /// Offset of the region in the file descriptor sent with it.
+    pub mmap_offset: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+#[derive(KnownLayout, Immutable, FromBytes, IntoBytes)]
+pub(super) struct VhostUserFsBackendMsg {
+    pub fd_offset: [u64; VHOST_USER_FS_BACKEND_ENTRIES],
+    /// Offsets in the DAX window.
+    pub cache_offset: [u64; VHOST_USER_FS_BACKEND_ENTRIES],
+    pub len: [u64; VHOST_USER_FS_BACKEND_ENTRIES],
+    pub flags: [u64; VHOST_USER_FS_BACKEND_ENTRIES],
+}
+
+/// Sends one message, with `fds` attached to it.
+pub(super) fn send_message(
+    socket: &UnixStream,
+    header: &VhostUserHeader,
+    payload: &[u8],
+    fds: &[RawFd]
+) -> io::Result<()> {
+    let mut cmsg_buf = [0u64; cmsg_space(MAX_REGIONS) / size_of::<u64>()];
+    let fds_len = size_of_val(fds) as u32;
+
+    let header = header.as_bytes();
+    let mut iov = [
+        libc::iovec { iov_base: header.as_ptr() as *mut _, iov_len: header.len() },
+        libc::iovec { iov_base: payload.as_ptr() as *mut _, iov_len: payload.len() },
+    ];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = iov.as_mut_ptr();
+    msg.msg_iovlen = iov.len() as _;
+    if !fds.is_empty() {
+        msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(fds_len) } as _;
+
+        // SAFETY: the control buffer has room for MAX_REGIONS descriptors
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+        }
+    }
+
+    let ret = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if ret as usize != header.len() + payload.len() {
+        return Err(io::Error::new(io::ErrorKind::WriteZero, "Short write of a vhost-user message"));
+    }
+    Ok(())
+}
+
+/// Receives the start of a message in `buf`, along with the file descriptors
+/// attached to it.
+pub(super) fn recv_with_fds(
+    socket: &UnixStream,
+    buf: &mut [u8]
+) -> io::Result<(usize, Vec<OwnedFd>)> {
+    let mut cmsg_buf = [0u64; cmsg_space(MAX_REGIONS) / size_of::<u64>()];
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+    msg.msg_controllen = size_of_val(&cmsg_buf) as _;
+
+    let ret = unsafe {
+        libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC)
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut fds = Vec::new();
+    // SAFETY: the kernel filled the control buffer
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Too many file descriptors in a vhost-user message"
+        ));
+    }
+    Ok((ret as usize, fds))
+}
+
+const fn cmsg_space(fds: usize) -> usize {
+    // SAFETY: CMSG_SPACE only does arithmetic
+    unsafe { libc::CMSG_SPACE((fds * size_of::<RawFd>()) as u32) as usize }
+}
@@ -0,0 +1,637 @@
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::io::{self, ErrorKind, IoSlice, Read};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::Poll;
+
+use tokio::io::Interest;
+use tokio::io::unix::{AsyncFd, AsyncFdReadyGuard};
+use zerocopy::{FromBytes, FromZeros, IntoBytes};
+
+use crate::Errno;
+use crate::protocol::{ArgReader, HeaderError, ReplyBuf, SetupMappingFlags};
+use crate::protocol::{fuse_removemapping_one, fuse_setupmapping_in};
+use super::{BoxFuture, DaxWindow, Transport};
+
+mod memory;
+use memory::GuestMemory;
+
+mod message;
+use message::*;
+
+mod queue;
+use queue::{Chain, MAX_QUEUE_SIZE, Vring, read_eventfd};
+
+/// Queue of the requests that must not wait behind the others, like
+/// `FUSE_FORGET` and `FUSE_INTERRUPT`.
+const HIPRIO_QUEUE: usize = 0;
+
+/// What woke up the task receiving the requests.
+enum Ready<'a> {
+    Kick(AsyncFdReadyGuard<'a, OwnedFd>),
+    Message(AsyncFdReadyGuard<'a, UnixStream>),
+}
+
+/// The backend of a vhost-user-fs device, serving a file system to a virtual
+/// machine.
+///
+/// The frontend, usually QEMU, connects to a Unix socket and shares the
+/// memory of the guest, then the guest driver sends the same requests as the
+/// kernel through virtqueues. The notification queue is not supported.
+///
+/// The DAX window is managed through the backend channel of the frontend, see
+/// [`Filesystem::setupmapping`](crate::Filesystem::setupmapping).
+pub struct VhostUserFs {
+    socket: AsyncFd<UnixStream>,
+    state: Mutex<State>,
+    /// Channel for the requests of the backend, set by the frontend.
+    backend: Mutex<Option<Arc<AsyncFd<UnixStream>>>>,
+    /// Acknowledgements left on the backend channel by the requests that
+    /// were cancelled, the lock sends one request at a time since the
+    /// acknowledgements don't say which request they answer.
+    backend_acks: tokio::sync::Mutex<usize>,
+    /// Queue polled first, rotated to serve every queue fairly.
+    next: AtomicUsize,
+}
+
+struct State {
+    features: u64,
+    protocol_features: u64,
+    memory: Option<GuestMemory>,
+    vrings: Vec<Vring>,
+    /// Requests waiting for a reply, with their queue.
+    pending: HashMap<u64, (usize, Chain)>,
+}
+
+impl VhostUserFs {
+    /// Serves the frontend connected to `socket`, with `request_queues`
+    /// queues for the requests besides the high priority one.
+    ///
+    /// Must be called from a tokio runtime.
+    pub fn new(socket: UnixStream, request_queues: usize) -> io::Result<Self> {
+        if !(1..=VHOST_USER_VRING_IDX_MASK as usize).contains(&request_queues) {
+            io_error!(
+                ErrorKind::InvalidInput,
+                "The number of request queues must be between 1 and {VHOST_USER_VRING_IDX_MASK}"
+            );
+        }
+        socket.set_nonblocking(true)?;
+        let vrings = (0..=request_queues).map(|_| Vring::default()).collect();
+        // SAFETY: the socket is owned by the AsyncFd
+        let socket = unsafe { AsyncFd::register(socket) }.map_err(io::Error::from)?;
+        Ok(Self {
+            socket,
+            state: Mutex::new(State {
+                features: 0,
+                protocol_features: 0,
+                memory: None,
+                vrings,
+                pending: HashMap::new(),
+            }),
+            backend: Mutex::new(None),
+            backend_acks: tokio::sync::Mutex::new(0),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Creates a socket at `path` and waits for the frontend to connect, the
+    /// socket is removed once connected.
+    pub async fn listen(path: impl AsRef<Path>, request_queues: usize) -> io::Result<Self> {
+        let path = path.as_ref();
+        let listener = match UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(err) => io_error!(
+                err.kind(),
+                "Failed to create the vhost-user socket {}: {err}", path.display()
+            ),
+        };
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::UnixListener::from_std(listener)?;
+        let accepted = listener.accept().await;
+        let _ = std::fs::remove_file(path);
+
+        let socket = accepted?.0.into_std()?;
+        Self::new(socket, request_queues)
+    }
+
+    /// Handles the messages of the frontend until a request is available in
+    /// one of the queues.
+    async fn wait_request(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let kicks = {
+                let mut state = self.state.lock().unwrap();
+                let first = self.next.fetch_add(1, Ordering::Relaxed);
+                if let Some(len) = state.pop_request(first, buf) {
+                    return Ok(len);
+                }
+                state.vrings.iter()
+                    .filter(|vring| vring.is_started())
+                    .filter_map(|vring| vring.kick.clone())
+                    .collect::<Vec<_>>()
+            };
+
+            let ready = poll_fn(|cx| {
+                for kick in &kicks {
+                    if let Poll::Ready(guard) = kick.poll_read_ready(cx) {
+                        return Poll::Ready(guard.map(Ready::Kick));
+                    }
+                }
+                self.socket.poll_read_ready(cx).map(|guard| guard.map(Ready::Message))
+            }).await?;
+
+            match ready {
+                Ready::Kick(mut guard) => {
+                    let _ = guard.try_io(read_eventfd);
+                },
+                Ready::Message(mut guard) => {
+                    let mut header = VhostUserHeader::new_zeroed();
+                    let received = guard.try_io(|socket| {
+                        recv_with_fds(socket.get_ref(), header.as_mut_bytes())
+                    });
+                    // Spurious wake up, the kicks must not wait for the next
+                    // message
+                    let Ok(received) = received else { continue };
+                    let (len, fds) = received?;
+                    self.handle_message(header, len, fds).await?;
+                },
+            }
+        }
+    }
+
+    /// Receives the rest of a message of the frontend, of which `len` bytes
+    /// of the header were received, then replies to it.
+    async fn handle_message(
+        &self,
+        mut header: VhostUserHeader,
+        len: usize,
+        fds: Vec<OwnedFd>
+    ) -> io::Result<()> {
+        if len == 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENODEV));
+        }
+        self.read_exact(&mut header.as_mut_bytes()[len..]).await?;
+
+        if header.flags & VHOST_USER_VERSION_MASK != VHOST_USER_VERSION {
+            io_error!(
+                ErrorKind::InvalidData,
+                "Unsupported vhost-user version {}", header.flags & VHOST_USER_VERSION_MASK
+            );
+        }
+        if header.size as usize > MAX_PAYLOAD {
+            io_error!(ErrorKind::InvalidData, "vhost-user message too big: {}", header.size);
+        }
+        let mut payload = vec![0; header.size as usize];
+        self.read_exact(&mut payload).await?;
+
+        let result = self.process(header.request, &payload, fds);
+        let need_ack = header.flags & VHOST_USER_NEED_REPLY != 0
+            && self.state.lock().unwrap().protocol_features & VHOST_USER_PROTOCOL_F_REPLY_ACK != 0;
+        let reply = match &result {
+            Ok(Some(reply)) => Some(reply.clone()),
+            Ok(None) if need_ack => Some(0u64.as_bytes().to_vec()),
+            Err(_) if need_ack => Some(1u64.as_bytes().to_vec()),
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            let header = VhostUserHeader {
+                request: header.request,
+                flags: VHOST_USER_VERSION | VHOST_USER_REPLY,
+                size: reply.len() as u32,
+            };
+            self.socket
+                .async_io(Interest::WRITABLE, |socket| send_message(socket, &header, &reply, &[]))
+                .await?;
+        }
+        result.map(|_| ())
+    }
+
+    /// Applies a message of the frontend, returning the payload of the
+    /// reply, if it has one.
+    fn process(
+        &self,
+        request: u32,
+        payload: &[u8],
+        mut fds: Vec<OwnedFd>
+    ) -> io::Result<Option<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        let reply = match request {
+            VHOST_USER_GET_FEATURES => {
+                let features = VIRTIO_F_VERSION_1 | VHOST_USER_F_PROTOCOL_FEATURES;
+                Some(features.as_bytes().to_vec())
+            },
+            VHOST_USER_SET_FEATURES => {
+                state.features = parse::<u64>(payload)?;
+                None
+            },
+            VHOST_USER_GET_PROTOCOL_FEATURES => {
+                let features = VHOST_USER_PROTOCOL_F_MQ
+                    | VHOST_USER_PROTOCOL_F_REPLY_ACK
+                    | VHOST_USER_PROTOCOL_F_BACKEND_REQ;
+                Some(features.as_bytes().to_vec())
+            },
+            VHOST_USER_SET_PROTOCOL_FEATURES => {
+                state.protocol_features = parse::<u64>(payload)?;
+                None
+            },
+            VHOST_USER_GET_QUEUE_NUM => Some((state.vrings.len() as u64).as_bytes().to_vec()),
+            VHOST_USER_SET_OWNER => None,
+            VHOST_USER_RESET_OWNER => {
+                for vring in &mut state.vrings {
+                    *vring = Vring::default();
+                }
+                state.pending.clear();
+                None
+            },
+            VHOST_USER_SET_MEM_TABLE => {
+                let (table, mut regions) = VhostUserMemory::read_from_prefix(payload)
+                    .map_err(|_| invalid_message("SET_MEM_TABLE"))?;
+                let mut list = Vec::with_capacity(table.nregions as usize);
+                for _ in 0..table.nregions {
+                    let (region, rest) = VhostUserMemoryRegion::read_from_prefix(regions)
+                        .map_err(|_| invalid_message("SET_MEM_TABLE"))?;
+                    list.push(region);
+                    regions = rest;
+                }
+                state.memory = Some(GuestMemory::new(&list, fds)?);
+                None
+            },
+            VHOST_USER_SET_VRING_NUM => {
+                let arg: VhostUserVringState = parse(payload)?;
+                if !(1..=MAX_QUEUE_SIZE).contains(&arg.num) {
+                    io_error!(ErrorKind::InvalidData, "Invalid queue size {}", arg.num);
+                }
+                state.vring(arg.index)?.size = arg.num as u16;
+                None
+            },
+            VHOST_USER_SET_VRING_ADDR => {
+                let arg: VhostUserVringAddr = parse(payload)?;
+                let State { memory, vrings, .. } = &mut *state;
+                let vring = vrings.get_mut(arg.index as usize)
+                    .ok_or_else(|| invalid_message("SET_VRING_ADDR"))?;
+                vring.addr = Some(arg);
+                vring.broken = false;
+                if let Some(memory) = memory {
+                    vring.sync_used(memory);
+                }
+                None
+            },
+            VHOST_USER_SET_VRING_BASE => {
+                let arg: VhostUserVringState = parse(payload)?;
+                let vring = state.vring(arg.index)?;
+                vring.next_avail = arg.num as u16;
+                vring.next_used = arg.num as u16;
+                None
+            },
+            VHOST_USER_GET_VRING_BASE => {
+                let arg: VhostUserVringState = parse(payload)?;
+                let vring = state.vring(arg.index)?;
+                vring.stop();
+                let base = VhostUserVringState { index: arg.index, num: vring.next_avail as u32 };
+                state.pending.retain(|_, (index, _)| *index != arg.index as usize);
+                Some(base.as_bytes().to_vec())
+            },
+            VHOST_USER_SET_VRING_KICK => {
+                let arg: u64 = parse(payload)?;
+                let enable = state.features & VHOST_USER_F_PROTOCOL_FEATURES == 0;
+                let vring = state.vring((arg & VHOST_USER_VRING_IDX_MASK) as u32)?;
+                vring.kick = match vring_fd(arg, &mut fds)? {
+                    Some(fd) => Some(Arc::new(kick_fd(fd)?)),
+                    // Polling the queue is not supported
+                    None => None,
+                };
+                // Without protocol features, the rings are enabled once
+                // started
+                if enable {
+                    vring.enabled = true;
+                }
+                None
+            },
+            VHOST_USER_SET_VRING_CALL => {
+                let arg: u64 = parse(payload)?;
+                let call = vring_fd(arg, &mut fds)?;
+                state.vring((arg & VHOST_USER_VRING_IDX_MASK) as u32)?.call = call;
+                None
+            },
+            // Errors are not reported to the frontend
+            VHOST_USER_SET_VRING_ERR => None,
+            VHOST_USER_SET_VRING_ENABLE => {
+                let arg: VhostUserVringState = parse(payload)?;
+                state.vring(arg.index)?.enabled = arg.num != 0;
+                None
+            },
+            VHOST_USER_SET_BACKEND_REQ_FD => {
+                let Some(fd) = fds.pop() else {
+                    return Err(invalid_message("SET_BACKEND_REQ_FD"));
+                };
+                let backend = UnixStream::from(fd);
+                backend.set_nonblocking(true)?;
+                // SAFETY: the socket is owned by the AsyncFd
+                let backend = unsafe { AsyncFd::register(backend) }.map_err(io::Error::from)?;
+                *self.backend.lock().unwrap() = Some(Arc::new(backend));
+                None
+            },
+            request => io_error!(
+                ErrorKind::Unsupported,
+                "Unsupported vhost-user request {request}"
+            ),
+        };
+        Ok(reply)
+    }
+
+    async fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        read_exact(&self.socket, buf).await
+    }
+
+    /// Sends a message on the backend channel and waits for the frontend to
+    /// acknowledge it.
+    async fn backend_request(
+        &self,
+        request: u32,
+        msg: &VhostUserFsBackendMsg,
+        fds: &[RawFd]
+    ) -> io::Result<()> {
+        let features = self.state.lock().unwrap().protocol_features;
+        let ack = features & VHOST_USER_PROTOCOL_F_REPLY_ACK != 0;
+        let Some(backend) = self.backend.lock().unwrap().clone() else {
+            io_error!(ErrorKind::NotConnected, "The frontend has no backend channel");
+        };
+
+        let mut acks = self.backend_acks.lock().await;
+        while *acks != 0 {
+            read_ack(&backend).await?;
+            *acks -= 1;
+        }
+
+        let header = VhostUserHeader {
+            request,
+            flags: VHOST_USER_VERSION | if ack { VHOST_USER_NEED_REPLY } else { 0 },
+            size: size_of::<VhostUserFsBackendMsg>() as u32,
+        };
+        let payload = msg.as_bytes();
+        backend
+            .async_io(Interest::WRITABLE, |socket| send_message(socket, &header, payload, fds))
+            .await?;
+        if !ack {
+            return Ok(());
+        }
+
+        // Counted until read, in case the request is cancelled while waiting
+        *acks += 1;
+        let (reply, status) = read_ack(&backend).await?;
+        *acks -= 1;
+        if reply.request != request {
+            io_error!(ErrorKind::InvalidData, "Unexpected reply on the backend channel");
+        }
+        if status != 0 {
+            io_error!(ErrorKind::Other, "The frontend failed the request with {status}");
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    fn vring(&mut self, index: u32) -> io::Result<&mut Vring> {
+        match self.vrings.get_mut(index as usize) {
+            Some(vring) => Ok(vring),
+            None => io_error!(ErrorKind::InvalidData, "Invalid queue index {index}"),
+        }
+    }
+
+    /// Takes a request from the queues, starting with the queue `first`.
+    fn pop_request(&mut self, first: usize, buf: &mut [u8]) -> Option<usize> {
+        let State { memory, vrings, pending, .. } = self;
+        let memory = memory.as_ref()?;
+
+        let count = vrings.len();
+        // The high priority queue goes first
+        let order = std::iter::once(HIPRIO_QUEUE)
+            .chain((0..count).map(|i| (first + i) % count).filter(|&i| i != HIPRIO_QUEUE));
+        for index in order {
+            let vring = &mut vrings[index];
+            if !vring.is_started() {
+                continue;
+            }
+            while let Some(chain) = vring.pop(memory) {
+                let Some(len) = read_request(memory, &chain, buf) else {
+                    // Too big for the buffer, or outside the guest memory
+                    let len = write_error(memory, &chain, buf);
+                    vring.complete(memory, chain, len);
+                    continue;
+                };
+
+                let unique = match ArgReader::new(&buf[..len]).fetch_header() {
+                    Ok(header) => header.opcode.expects_reply().then_some(header.unique),
+                    Err(HeaderError::UnknownOpcode { unique, .. }) => Some(unique),
+                    Err(HeaderError::Truncated) => None,
+                };
+                match unique {
+                    Some(unique) => {
+                        pending.insert(unique, (index, chain));
+                    },
+                    None => vring.complete(memory, chain, 0),
+                }
+                return Some(len);
+            }
+        }
+        None
+    }
+}
+
+impl Transport for VhostUserFs {
+    async fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.wait_request(buf).await
+    }
+
+    fn send(&self, reply: &[IoSlice<'_>]) -> io::Result<()> {
+        let unique = reply.first()
+            .and_then(|header| header.get(8..16))
+            .map(|unique| u64::from_ne_bytes(unique.try_into().unwrap()));
+        let mut state = self.state.lock().unwrap();
+        let State { memory, vrings, pending, .. } = &mut *state;
+        // Notifications would need the notification queue
+        let Some((index, chain)) = unique.and_then(|unique| pending.remove(&unique)) else {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        };
+        let Some(memory) = memory.as_ref() else {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        };
+
+        let mut len = write_reply(memory, &chain, reply);
+        if len.is_none() {
+            // The reply doesn't fit, the request fails instead
+            let error = ReplyBuf::error(unique.unwrap(), Errno::EIO).finish();
+            len = write_reply(memory, &chain, &[IoSlice::new(&error)]);
+        }
+        vrings[index].complete(memory, chain, len.unwrap_or(0));
+        Ok(())
+    }
+
+    /// Stops every queue, the frontend is expected to disconnect.
+    async fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        for vring in &mut state.vrings {
+            vring.stop();
+        }
+        let _ = self.socket.get_ref().shutdown(std::net::Shutdown::Both);
+    }
+
+    fn dax_window(&self) -> Option<&dyn DaxWindow> {
+        Some(self)
+    }
+}
+
+impl DaxWindow for VhostUserFs {
+    fn map<'a>(
+        &'a self,
+        file: BorrowedFd<'a>,
+        arg: &'a fuse_setupmapping_in
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let flags = SetupMappingFlags::from_bits_retain(arg.flags);
+        let mut msg = VhostUserFsBackendMsg::default();
+        msg.fd_offset[0] = arg.foffset;
+        msg.cache_offset[0] = arg.moffset;
+        msg.len[0] = arg.len;
+        if flags.contains(SetupMappingFlags::FUSE_SETUPMAPPING_FLAG_READ) {
+            msg.flags[0] |= VHOST_USER_FS_FLAG_MAP_R;
+        }
+        if flags.contains(SetupMappingFlags::FUSE_SETUPMAPPING_FLAG_WRITE) {
+            msg.flags[0] |= VHOST_USER_FS_FLAG_MAP_W;
+        }
+        Box::pin(async move {
+            self.backend_request(VHOST_USER_BACKEND_FS_MAP, &msg, &[file.as_raw_fd()]).await
+        })
+    }
+
+    fn unmap<'a>(
+        &'a self,
+        mappings: &'a [fuse_removemapping_one]
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            for mappings in mappings.chunks(VHOST_USER_FS_BACKEND_ENTRIES) {
+                let mut msg = VhostUserFsBackendMsg::default();
+                for (i, mapping) in mappings.iter().enumerate() {
+                    msg.cache_offset[i] = mapping.moffset;
+                    msg.len[i] = mapping.len;
+                }
+                self.backend_request(VHOST_USER_BACKEND_FS_UNMAP, &msg, &[]).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+async fn read_exact(socket: &AsyncFd<UnixStream>, mut buf: &mut [u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        let len = socket
+            .async_io(Interest::READABLE, |mut socket| socket.read(buf))
+            .await?;
+        if len == 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENODEV));
+        }
+        buf = &mut buf[len..];
+    }
+    Ok(())
+}
+
+/// Reads an acknowledgement of the frontend on the backend channel, with its
+/// status.
+async fn read_ack(backend: &AsyncFd<UnixStream>) -> io::Result<(VhostUserHeader, u64)> {
+    let mut reply = VhostUserHeader::new_zeroed();
+    let mut status = 0u64;
+    read_exact(backend, reply.as_mut_bytes()).await?;
+    if reply.size as usize != size_of::<u64>() {
+        io_error!(ErrorKind::InvalidData, "Unexpected reply on the backend channel");
+    }
+    read_exact(backend, status.as_mut_bytes()).await?;
+    Ok((reply, status))
+}
+
+/// Copies the readable buffers of a chain to `buf`.
+fn read_request(memory: &GuestMemory, chain: &Chain, buf: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    for &(addr, size) in &chain.readable {
+        let dst = buf.get_mut(len..len + size as usize)?;
+        memory.read(addr, dst)?;
+        len += size as usize;
+    }
+    Some(len)
+}
+
+/// Copies a reply to the writable buffers of a chain, returns `None` if it
+/// doesn't fit.
+fn write_reply(memory: &GuestMemory, chain: &Chain, reply: &[IoSlice<'_>]) -> Option<u32> {
+    let total: usize = reply.iter().map(|slice| slice.len()).sum();
+    let space: usize = chain.writable.iter().map(|&(_, size)| size as usize).sum();
+    if total > space {
+        return None;
+    }
+
+    let mut buffers = chain.writable.iter().copied();
+    let (mut addr, mut size) = (0, 0);
+    for mut data in reply.iter().map(|slice| &**slice) {
+        while !data.is_empty() {
+            if size == 0 {
+                (addr, size) = buffers.next()?;
+                continue;
+            }
+            let len = data.len().min(size as usize);
+            memory.write(addr, &data[..len])?;
+            data = &data[len..];
+            addr += len as u64;
+            size -= len as u32;
+        }
+    }
+    Some(total as u32)
+}
+
+/// Answers a request that can't be read with `EIO`, if it has a unique id.
+fn write_error(memory: &GuestMemory, chain: &Chain, buf: &mut [u8]) -> u32 {
+    let header = &mut buf[..size_of::<crate::protocol::fuse_in_header>()];
+    let Some(&(addr, _)) = chain.readable.first() else { return 0 };
+    if memory.read(addr, header).is_none() {
+        return 0;
+    }
+    let unique = match ArgReader::new(header).fetch_header() {
+        Ok(header) => header.unique,
+        Err(HeaderError::UnknownOpcode { unique, .. }) => unique,
+        Err(HeaderError::Truncated) => return 0,
+    };
+    let error = ReplyBuf::error(unique, Errno::EIO).finish();
+    write_reply(memory, chain, &[IoSlice::new(&error)]).unwrap_or(0)
+}
+
+/// The file descriptor attached to `SET_VRING_KICK` or `SET_VRING_CALL`.
+fn vring_fd(arg: u64, fds: &mut Vec<OwnedFd>) -> io::Result<Option<OwnedFd>> {
+    if arg & VHOST_USER_VRING_NOFD_MASK != 0 {
+        return Ok(None);
+    }
+    match fds.pop() {
+        Some(fd) => Ok(Some(fd)),
+        None => Err(invalid_message("SET_VRING_KICK")),
+    }
+}
+
+fn kick_fd(fd: OwnedFd) -> io::Result<AsyncFd<OwnedFd>> {
+    let raw = fd.as_raw_fd();
+    let flags = unsafe { libc::fcntl(raw, libc::F_GETFL) };
+    if flags == -1 || unsafe { libc::fcntl(raw, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor is owned by the AsyncFd
+    unsafe { AsyncFd::register_with_interest(fd, Interest::READABLE) }.map_err(io::Error::from)
+}
+
+fn parse<T: FromBytes>(payload: &[u8]) -> io::Result<T> {
+    T::read_from_prefix(payload)
+        .map(|(value, _)| value)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Truncated vhost-user message"))
+}
+
+fn invalid_message(name: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("Invalid vhost-user {name} message"))
+}
@@ -0,0 +1,230 @@
+use std::io;
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, Ordering, fence};
+
+use tokio::io::unix::AsyncFd;
+
+use super::memory::GuestMemory;
+use super::message::VhostUserVringAddr;
+
+/// Biggest queue allowed by the virtio specification.
+pub(super) const MAX_QUEUE_SIZE: u32 = 32768;
+
+const VRING_DESC_F_NEXT: u16 = 1 << 0;
+const VRING_DESC_F_WRITE: u16 = 1 << 1;
+/// Set by the driver when it doesn't want to be notified of used buffers.
+const VRING_AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VringDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VringUsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// Buffers made available by the driver, the device reads the request from
+/// the readable ones and writes the reply in the writable ones.
+#[derive(Debug)]
+pub(super) struct Chain {
+    head: u16,
+    /// Guest physical address and length of each buffer.
+    pub readable: Vec<(u64, u32)>,
+    pub writable: Vec<(u64, u32)>,
+}
+
+/// A split virtqueue, as configured by the frontend.
+#[derive(Default)]
+pub(super) struct Vring {
+    pub size: u16,
+    pub addr: Option<VhostUserVringAddr>,
+    pub kick: Option<Arc<AsyncFd<OwnedFd>>>,
+    pub call: Option<OwnedFd>,
+    pub enabled: bool,
+    /// Set when the driver put the ring in an invalid state, the ring stays
+    /// stopped until it is configured again.
+    pub broken: bool,
+    /// Next entry of the available ring to process.
+    pub next_avail: u16,
+    /// Next entry of the used ring to fill.
+    pub next_used: u16,
+}
+
+impl Vring {
+    /// Whether the ring is processed.
+    #[inline]
+    pub fn is_started(&self) -> bool {
+        self.size != 0 && self.addr.is_some() && self.kick.is_some() && self.enabled
+            && !self.broken
+    }
+
+    /// Stops the ring, the requests received from it can't be answered
+    /// anymore.
+    pub fn stop(&mut self) {
+        self.kick = None;
+        self.enabled = false;
+    }
+
+    /// Reads the index of the used ring, to continue from where the previous
+    /// backend stopped.
+    pub fn sync_used(&mut self, memory: &GuestMemory) {
+        if let Some(used) = self.used_idx(memory) {
+            self.next_used = used.load(Ordering::Acquire);
+        }
+    }
+
+    /// Takes the next chain of buffers made available by the driver.
+    ///
+    /// Invalid chains are returned to the driver without being processed,
+    /// while an invalid ring is marked as broken.
+    pub fn pop(&mut self, memory: &GuestMemory) -> Option<Chain> {
+        loop {
+            let head = self.pop_head(memory)?;
+            match self.walk(memory, head) {
+                Some(chain) => return Some(chain),
+                None => self.push(memory, head, 0),
+            }
+        }
+    }
+
+    /// Returns a chain to the driver, `len` bytes were written to its
+    /// writable buffers.
+    pub fn complete(&mut self, memory: &GuestMemory, chain: Chain, len: u32) {
+        self.push(memory, chain.head, len);
+    }
+
+    fn pop_head(&mut self, memory: &GuestMemory) -> Option<u16> {
+        let size = self.size as u64;
+        let addr = self.addr?.avail_user_addr;
+        let Some(avail) = memory.user(addr, 4 + 2 * size)
+            .filter(|avail| avail.cast::<u16>().is_aligned()) else {
+            self.broken = true;
+            return None;
+        };
+
+        // SAFETY: the available ring is within the guest memory and aligned
+        let idx = unsafe { AtomicU16::from_ptr(avail.add(2).cast()) }.load(Ordering::Acquire);
+        let pending = idx.wrapping_sub(self.next_avail);
+        if pending == 0 {
+            return None;
+        }
+        if pending > self.size {
+            self.broken = true;
+            return None;
+        }
+        // Read the ring entry after the index
+        fence(Ordering::Acquire);
+
+        let slot = (self.next_avail % self.size) as usize;
+        let head = unsafe { avail.add(4 + 2 * slot).cast::<u16>().read_volatile() };
+        self.next_avail = self.next_avail.wrapping_add(1);
+        if head >= self.size {
+            self.broken = true;
+            return None;
+        }
+        Some(head)
+    }
+
+    /// Follows the descriptors of a chain, returns `None` if it is invalid.
+    fn walk(&self, memory: &GuestMemory, head: u16) -> Option<Chain> {
+        let addr = self.addr?.desc_user_addr;
+        let table = memory.user(addr, self.size as u64 * size_of::<VringDesc>() as u64)?;
+
+        let mut chain = Chain { head, readable: Vec::new(), writable: Vec::new() };
+        let mut index = head;
+        // A chain can't be longer than the table, otherwise it has a loop
+        for _ in 0..self.size {
+            // SAFETY: the index is within the descriptor table
+            let desc = unsafe {
+                table.cast::<VringDesc>().add(index as usize).read_unaligned()
+            };
+            // The reply follows the request
+            if desc.flags & VRING_DESC_F_WRITE != 0 {
+                chain.writable.push((desc.addr, desc.len));
+            } else if chain.writable.is_empty() {
+                chain.readable.push((desc.addr, desc.len));
+            } else {
+                return None;
+            }
+
+            if desc.flags & VRING_DESC_F_NEXT == 0 {
+                return Some(chain);
+            }
+            if desc.next >= self.size {
+                return None;
+            }
+            index = desc.next;
+        }
+        None
+    }
+
+    fn push(&mut self, memory: &GuestMemory, head: u16, len: u32) {
+        let size = self.size as u64;
+        let Some(addr) = self.addr.filter(|_| size != 0) else { return };
+        let used = memory.user(addr.used_user_addr, 4 + 8 * size)
+            .filter(|used| used.cast::<u32>().is_aligned());
+        let Some(used) = used else {
+            self.broken = true;
+            return;
+        };
+
+        let slot = (self.next_used % self.size) as usize;
+        let elem = VringUsedElem { id: head as u32, len };
+        // SAFETY: the used ring is within the guest memory and aligned
+        unsafe { used.add(4 + 8 * slot).cast::<VringUsedElem>().write_volatile(elem) };
+        self.next_used = self.next_used.wrapping_add(1);
+        // Publish the entry before the index
+        unsafe { AtomicU16::from_ptr(used.add(2).cast()) }.store(self.next_used, Ordering::Release);
+        self.notify(memory);
+    }
+
+    fn used_idx(&self, memory: &GuestMemory) -> Option<&AtomicU16> {
+        let used = memory.user(self.addr?.used_user_addr, 4)?;
+        // SAFETY: the index is within the guest memory, and aligned
+        used.cast::<u16>().is_aligned().then(|| unsafe { AtomicU16::from_ptr(used.add(2).cast()) })
+    }
+
+    /// Signals the driver that buffers were used, unless it asked not to.
+    fn notify(&self, memory: &GuestMemory) {
+        fence(Ordering::SeqCst);
+        let flags = self.addr
+            .and_then(|addr| memory.user(addr.avail_user_addr, 2))
+            .map(|avail| unsafe { avail.cast::<u16>().read_volatile() })
+            .unwrap_or(0);
+        if flags & VRING_AVAIL_F_NO_INTERRUPT != 0 {
+            return;
+        }
+        if let Some(call) = &self.call {
+            let _ = write_eventfd(call);
+        }
+    }
+}
+
+/// Clears the notifications of an event fd.
+pub(super) fn read_eventfd(fd: &impl AsRawFd) -> io::Result<()> {
+    let mut value = 0u64;
+    let ret = unsafe { libc::read(fd.as_raw_fd(), (&raw mut value).cast(), size_of::<u64>()) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn write_eventfd(fd: &impl AsRawFd) -> io::Result<()> {
+    let value = 1u64;
+    let ret = unsafe { libc::write(fd.as_raw_fd(), (&raw const value).cast(), size_of::<u64>()) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,414 @@
+#![cfg(feature = "archive")]
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use flate2::Compression;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use fuse_async::protocol::*;
+use fuse_async::testing::MockKernel;
+use fuse_async::{ArchiveFs, Errno};
+
+/// A tar archive built in memory.
+#[derive(Default)]
+struct Tar(Vec<u8>);
+
+impl Tar {
+    fn header(&mut self, name: &[u8], kind: u8, size: &[u8], link: &[u8]) {
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name);
+        header[100..107].copy_from_slice(b"0000644");
+        header[108..115].copy_from_slice(b"0001750");
+        header[116..123].copy_from_slice(b"0001750");
+        header[124..124 + size.len()].copy_from_slice(size);
+        header[136..147].copy_from_slice(b"14000000000");
+        header[156] = kind;
+        header[157..157 + link.len()].copy_from_slice(link);
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[148..156].fill(b' ');
+        let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+        header[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+        self.0.extend_from_slice(&header);
+    }
+
+    fn member(&mut self, name: &str, kind: u8, data: &[u8]) -> &mut Self {
+        self.header(name.as_bytes(), kind, format!("{:011o}", data.len()).as_bytes(), b"");
+        self.data(data)
+    }
+
+    fn data(&mut self, data: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(data);
+        self.0.resize(self.0.len().next_multiple_of(512), 0);
+        self
+    }
+
+    fn file(&mut self, name: &str, data: &[u8]) -> &mut Self {
+        self.member(name, b'0', data)
+    }
+
+    fn link(&mut self, name: &str, kind: u8, target: &str) -> &mut Self {
+        self.header(name.as_bytes(), kind, b"00000000000", target.as_bytes());
+        self
+    }
+
+    /// A pax header with `<length> <key>=<value>\n` records.
+    fn pax(&mut self, records: &[(&str, &str)]) -> &mut Self {
+        let mut data = String::new();
+        for (key, value) in records {
+            // The length counts its own digits
+            let len = key.len() + value.len() + 3;
+            let mut total = len + 1;
+            while total != len + total.to_string().len() {
+                total = len + total.to_string().len();
+            }
+            data += &format!("{total} {key}={value}\n");
+        }
+        self.member("././@PaxHeader", b'x', data.as_bytes())
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        let mut tar = self.0.clone();
+        tar.resize(tar.len() + 1024, 0);
+        tar
+    }
+}
+
+/// A member of a zip archive.
+struct ZipMember<'a> {
+    name: &'a str,
+    data: &'a [u8],
+    mode: u32,
+    deflate: bool,
+    /// Stores the sizes and offset in a zip64 extra field.
+    zip64: bool,
+}
+
+impl<'a> ZipMember<'a> {
+    fn new(name: &'a str, data: &'a [u8]) -> Self {
+        Self { name, data, mode: libc::S_IFREG | 0o644, deflate: false, zip64: false }
+    }
+}
+
+fn zip(members: &[ZipMember<'_>], zip64: bool) -> Vec<u8> {
+    let mut zip = Vec::new();
+    let mut central = Vec::new();
+    for member in members {
+        let data = match member.deflate {
+            true => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(member.data).unwrap();
+                encoder.finish().unwrap()
+            },
+            false => member.data.to_vec(),
+        };
+        let method: u16 = if member.deflate { 8 } else { 0 };
+        let local = zip.len() as u32;
+
+        zip.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&[20, 0, 0, 0]);
+        zip.extend_from_slice(&method.to_le_bytes());
+        zip.extend_from_slice(&[0; 8]);
+        zip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&(member.data.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&(member.name.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&[0, 0]);
+        zip.extend_from_slice(member.name.as_bytes());
+        zip.extend_from_slice(&data);
+
+        let mut sizes = [data.len() as u32, member.data.len() as u32, local];
+        let mut extra = Vec::new();
+        if member.zip64 {
+            extra.extend_from_slice(&1u16.to_le_bytes());
+            extra.extend_from_slice(&24u16.to_le_bytes());
+            for value in [member.data.len(), data.len(), local as usize] {
+                extra.extend_from_slice(&(value as u64).to_le_bytes());
+            }
+            sizes = [u32::MAX; 3];
+        }
+        central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        // Made by Unix
+        central.extend_from_slice(&[20, 3, 20, 0, 0, 0]);
+        central.extend_from_slice(&method.to_le_bytes());
+        central.extend_from_slice(&[0; 8]);
+        central.extend_from_slice(&sizes[0].to_le_bytes());
+        central.extend_from_slice(&sizes[1].to_le_bytes());
+        central.extend_from_slice(&(member.name.len() as u16).to_le_bytes());
+        central.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        central.extend_from_slice(&[0; 6]);
+        central.extend_from_slice(&(member.mode << 16).to_le_bytes());
+        central.extend_from_slice(&sizes[2].to_le_bytes());
+        central.extend_from_slice(member.name.as_bytes());
+        central.extend_from_slice(&extra);
+    }
+
+    let offset = zip.len();
+    zip.extend_from_slice(&central);
+    let count = members.len() as u16;
+    if zip64 {
+        let end64 = zip.len() as u64;
+        zip.extend_from_slice(&0x0606_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&44u64.to_le_bytes());
+        zip.extend_from_slice(&[20, 3, 20, 0]);
+        zip.extend_from_slice(&[0; 8]);
+        for value in [count.into(), count.into(), central.len(), offset] {
+            zip.extend_from_slice(&(value as u64).to_le_bytes());
+        }
+        zip.extend_from_slice(&0x0706_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&0u32.to_le_bytes());
+        zip.extend_from_slice(&end64.to_le_bytes());
+        zip.extend_from_slice(&1u32.to_le_bytes());
+    }
+    zip.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    zip.extend_from_slice(&[0; 4]);
+    let (count, size, offset) = match zip64 {
+        true => (u16::MAX, u32::MAX, u32::MAX),
+        false => (count, central.len() as u32, offset as u32),
+    };
+    zip.extend_from_slice(&count.to_le_bytes());
+    zip.extend_from_slice(&count.to_le_bytes());
+    zip.extend_from_slice(&size.to_le_bytes());
+    zip.extend_from_slice(&offset.to_le_bytes());
+    zip.extend_from_slice(&[0, 0]);
+    zip
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Opens an archive written to a temporary file.
+fn open(name: &str, archive: &[u8]) -> io::Result<ArchiveFs> {
+    let path: PathBuf = std::env::temp_dir()
+        .join(format!("fuse-async-archive-{}-{name}", std::process::id()));
+    std::fs::write(&path, archive).unwrap();
+    let fs = ArchiveFs::open(&path);
+    std::fs::remove_file(&path).unwrap();
+    fs
+}
+
+async fn lookup(kernel: &MockKernel, path: &str) -> Result<fuse_entry_out, Errno> {
+    let mut names = path.split('/');
+    let mut entry = kernel.lookup(FUSE_ROOT_ID, names.next().unwrap()).await?;
+    for name in names {
+        entry = kernel.lookup(entry.nodeid, name).await?;
+    }
+    Ok(entry)
+}
+
+async fn read(kernel: &MockKernel, path: &str) -> Vec<u8> {
+    let ino = lookup(kernel, path).await.unwrap().nodeid;
+    let open = kernel.open(ino, libc::O_RDONLY).await.unwrap();
+    let data = kernel.read(ino, open.fh, 0, 1 << 16).await.unwrap();
+    kernel.release(ino, open.fh).await.unwrap();
+    data
+}
+
+async fn names(kernel: &MockKernel, path: &str) -> Vec<String> {
+    let ino = match path {
+        "" => FUSE_ROOT_ID,
+        path => lookup(kernel, path).await.unwrap().nodeid,
+    };
+    let open = kernel.opendir(ino, libc::O_RDONLY).await.unwrap();
+    let entries = kernel.readdir(ino, open.fh, 0, 4096).await.unwrap();
+    kernel.releasedir(ino, open.fh).await.unwrap();
+    entries.into_iter().map(|entry| entry.name.into_string().unwrap()).collect()
+}
+
+#[tokio::test]
+async fn tar_members_are_served() {
+    let tar = Tar::default()
+        .member("dir/", b'5', b"")
+        .file("dir/file", b"hello")
+        .link("dir/symlink", b'2', "file")
+        .link("hard", b'1', "dir/file")
+        .file("implied/nested", b"nested")
+        .finish();
+    let kernel = MockKernel::start(open("plain.tar", &tar).unwrap()).await.unwrap();
+
+    assert_eq!(names(&kernel, "").await, [".", "..", "dir", "hard", "implied"]);
+    assert_eq!(names(&kernel, "dir").await, [".", "..", "file", "symlink"]);
+    assert_eq!(read(&kernel, "dir/file").await, b"hello");
+    assert_eq!(read(&kernel, "implied/nested").await, b"nested");
+
+    let symlink = lookup(&kernel, "dir/symlink").await.unwrap();
+    assert_eq!(symlink.attr.mode & libc::S_IFMT, libc::S_IFLNK);
+    assert_eq!(kernel.readlink(symlink.nodeid).await.unwrap(), b"file");
+
+    // A hard link is the same inode as its target
+    let file = lookup(&kernel, "dir/file").await.unwrap();
+    let hard = lookup(&kernel, "hard").await.unwrap();
+    assert_eq!(hard.nodeid, file.nodeid);
+    assert_eq!((hard.attr.nlink, hard.attr.size, hard.attr.uid), (2, 5, 0o1750));
+    assert_eq!(hard.attr.mode, libc::S_IFREG | 0o644);
+
+    let err = lookup(&kernel, "dir/missing").await.unwrap_err();
+    assert_eq!(err, Errno::ENOENT);
+    let err = kernel.open(file.nodeid, libc::O_WRONLY).await.unwrap_err();
+    assert_eq!(err, Errno::EROFS);
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn long_names_and_pax_records_apply_to_the_next_member() {
+    let long = format!("{}/{}", "d".repeat(120), "f".repeat(120));
+    let long_link = "t".repeat(150);
+    let tar = Tar::default()
+        .member("././@LongLink", b'L', format!("{long}\0").as_bytes())
+        .file("truncated", b"gnu")
+        .member("././@LongLink", b'K', long_link.as_bytes())
+        .link("long-symlink", b'2', "short")
+        .pax(&[("path", "pax/name"), ("mtime", "1234.5"), ("uid", "4242")])
+        .file("ignored", b"pax")
+        .file("plain", b"after")
+        .finish();
+    let kernel = MockKernel::start(open("long.tar", &tar).unwrap()).await.unwrap();
+
+    let dir = "d".repeat(120);
+    assert_eq!(names(&kernel, "").await, [".", "..", &dir, "long-symlink", "pax", "plain"]);
+    assert_eq!(read(&kernel, &long).await, b"gnu");
+    let symlink = lookup(&kernel, "long-symlink").await.unwrap();
+    assert_eq!(kernel.readlink(symlink.nodeid).await.unwrap(), long_link.as_bytes());
+
+    let pax = lookup(&kernel, "pax/name").await.unwrap();
+    assert_eq!((pax.attr.mtime, pax.attr.uid), (1234, 4242));
+    assert_eq!(read(&kernel, "pax/name").await, b"pax");
+    // The extensions only apply to one member
+    let plain = lookup(&kernel, "plain").await.unwrap();
+    assert_eq!((plain.attr.mtime, plain.attr.uid), (0o14000000000, 0o1750));
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn gzip_tar_is_decompressed() {
+    let big: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let tar = Tar::default()
+        .file("first", b"first")
+        .file("big", &big)
+        .file("last", b"last")
+        .finish();
+    let kernel = MockKernel::start(open("compressed.tar.gz", &gzip(&tar)).unwrap()).await.unwrap();
+
+    assert_eq!(names(&kernel, "").await, [".", "..", "big", "first", "last"]);
+    assert_eq!(read(&kernel, "last").await, b"last");
+    assert_eq!(read(&kernel, "first").await, b"first");
+    let ino = lookup(&kernel, "big").await.unwrap().nodeid;
+    let open = kernel.open(ino, libc::O_RDONLY).await.unwrap();
+    let data = kernel.read(ino, open.fh, 70_000, 1 << 16).await.unwrap();
+    assert_eq!(data, big[70_000..]);
+    kernel.release(ino, open.fh).await.unwrap();
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn later_members_override_earlier_ones() {
+    let tar = Tar::default()
+        .file("file", b"old")
+        .file("dir/a", b"a")
+        .file("file", b"new")
+        .file("dir", b"not a directory anymore")
+        .finish();
+    let kernel = MockKernel::start(open("override.tar", &tar).unwrap()).await.unwrap();
+    assert_eq!(names(&kernel, "").await, [".", "..", "dir", "file"]);
+    assert_eq!(read(&kernel, "file").await, b"new");
+    assert_eq!(read(&kernel, "dir").await, b"not a directory anymore");
+    kernel.shutdown().await.unwrap();
+
+    let members = [ZipMember::new("same", b"old"), ZipMember::new("same", b"new")];
+    let kernel = MockKernel::start(open("override.zip", &zip(&members, false)).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(names(&kernel, "").await, [".", "..", "same"]);
+    assert_eq!(read(&kernel, "same").await, b"new");
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn zip_members_are_served() {
+    let text = b"compressed ".repeat(1000);
+    let members = [
+        ZipMember { mode: libc::S_IFDIR | 0o750, ..ZipMember::new("dir/", b"") },
+        ZipMember::new("dir/stored", b"stored"),
+        ZipMember { deflate: true, ..ZipMember::new("deflated", &text) },
+        ZipMember { mode: libc::S_IFLNK | 0o777, ..ZipMember::new("symlink", b"dir/stored") },
+    ];
+    let kernel = MockKernel::start(open("plain.zip", &zip(&members, false)).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(names(&kernel, "").await, [".", "..", "deflated", "dir", "symlink"]);
+    let dir = lookup(&kernel, "dir").await.unwrap();
+    assert_eq!(dir.attr.mode, libc::S_IFDIR | 0o750);
+    assert_eq!(read(&kernel, "dir/stored").await, b"stored");
+    assert_eq!(read(&kernel, "deflated").await, text);
+    assert_eq!(lookup(&kernel, "deflated").await.unwrap().attr.size, text.len() as u64);
+    let symlink = lookup(&kernel, "symlink").await.unwrap().nodeid;
+    assert_eq!(kernel.readlink(symlink).await.unwrap(), b"dir/stored");
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn zip64_records_are_read() {
+    let members = [
+        ZipMember { zip64: true, ..ZipMember::new("stored", b"zip64") },
+        ZipMember { zip64: true, deflate: true, ..ZipMember::new("deflated", b"deflated64") },
+    ];
+    let kernel = MockKernel::start(open("zip64.zip", &zip(&members, true)).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(names(&kernel, "").await, [".", "..", "deflated", "stored"]);
+    assert_eq!(read(&kernel, "stored").await, b"zip64");
+    assert_eq!(read(&kernel, "deflated").await, b"deflated64");
+    kernel.shutdown().await.unwrap();
+}
+
+#[test]
+fn corrupted_archives_are_rejected() {
+    let tar = Tar::default().file("file", &[7; 2000]).finish();
+    let zip = zip(&[ZipMember::new("file", b"data")], false);
+    let invalid = |name, archive: &[u8]| open(name, archive).unwrap_err().kind();
+
+    // Cut in a header, in the data and in the compressed stream
+    assert_eq!(invalid("header.tar", &tar[..2660]), io::ErrorKind::UnexpectedEof);
+    assert_eq!(invalid("data.tar", &tar[..1024]), io::ErrorKind::UnexpectedEof);
+    let gzip = gzip(&tar);
+    assert!(open("cut.tar.gz", &gzip[..gzip.len() / 2]).is_err());
+
+    let mut checksum = tar.clone();
+    checksum[0] = b'g';
+    assert_eq!(invalid("checksum.tar", &checksum), io::ErrorKind::InvalidData);
+
+    // Sizes whose padding overflows
+    let pax = Tar::default()
+        .pax(&[("size", &u64::MAX.to_string())])
+        .file("file", b"")
+        .finish();
+    assert_eq!(invalid("pax.tar", &pax), io::ErrorKind::InvalidData);
+    let mut base256 = Tar::default();
+    base256.header(b"file", b'0', &[0xff; 12], b"");
+    assert_eq!(invalid("base256.tar", &base256.finish()), io::ErrorKind::InvalidData);
+    let mut huge = Tar::default();
+    huge.header(b"file", b'0', b"77777777777", b"");
+    assert_eq!(invalid("huge.tar", &huge.finish()), io::ErrorKind::UnexpectedEof);
+    let pax = Tar::default().pax(&[("path", "x")]).finish();
+    let bad_record = [&pax[..512], b"99 path=x\n", &[0; 1526]].concat();
+    assert_eq!(invalid("record.tar", &bad_record), io::ErrorKind::InvalidData);
+
+    // Zip archives cut in the central directory, or pointing out of the file
+    assert_eq!(invalid("cut.zip", &zip[..zip.len() - 10]), io::ErrorKind::InvalidData);
+    // The local header of the member is at 0, the central directory at 38
+    let mut local = zip.clone();
+    local[38 + 42..38 + 46].copy_from_slice(&1u32.to_le_bytes());
+    assert_eq!(invalid("local.zip", &local), io::ErrorKind::InvalidData);
+    let end = zip.len() - 22;
+    let mut offset = zip.clone();
+    offset[end + 16..end + 20].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(invalid("offset.zip", &offset), io::ErrorKind::InvalidData);
+    let mut count = zip.clone();
+    count[end + 10..end + 12].copy_from_slice(&2u16.to_le_bytes());
+    assert_eq!(invalid("count.zip", &count), io::ErrorKind::InvalidData);
+
+    assert_eq!(invalid("unknown", b"not an archive"), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::fd::{AsFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use fuse_async::{BackingFiles, MemFs, Mount, Session, SessionConfig};
+
+fn executable() -> File {
+    File::open(std::env::current_exe().unwrap()).unwrap()
+}
+
+/// Mounts a MemFs on a temporary directory, `None` if mounting is not
+/// permitted.
+async fn mount(name: &str) -> Option<(PathBuf, Mount)> {
+    if !Path::new("/dev/fuse").exists() {
+        eprintln!("skipped, /dev/fuse is not available");
+        return None;
+    }
+    let dir = std::env::temp_dir()
+        .join(format!("fuse-async-backing-{}-{name}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    match Mount::builder(&dir, "memfs").build().await {
+        Ok(mount) => Some((dir, mount)),
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            eprintln!("skipped, mounting is not permitted: {err}");
+            std::fs::remove_dir(&dir).unwrap();
+            None
+        },
+        Err(err) => panic!("{err}"),
+    }
+}
+
+/// Registers the file returned by `open` as a backing file, from a blocking
+/// thread since the session may serve the file.
+async fn register(
+    backing: &BackingFiles,
+    open: impl FnOnce() -> File + Send + 'static
+) -> std::io::Result<()> {
+    let backing = backing.clone();
+    tokio::task::spawn_blocking(move || {
+        let file = open();
+        let id = backing.open(file.as_fd())?;
+        backing.close(id)
+    }).await.unwrap()
+}
+
+#[tokio::test]
+async fn backing_files_require_passthrough() {
+    let Some((dir, mount)) = mount("negotiated").await else { return };
+    let backing = mount.backing_files();
+
+    // Passthrough is not negotiated before the session starts
+    let err = register(&backing, executable).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied, "{err}");
+    assert!(err.to_string().contains("max_stack_depth"), "{err}");
+
+    let session = Session::new(mount, MemFs::new());
+    let shutdown = session.shutdown_handle();
+    let session = tokio::spawn(session.run());
+    let err = register(&backing, executable).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied, "{err}");
+
+    shutdown.shutdown(Duration::from_secs(5)).await;
+    session.await.unwrap().unwrap();
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn backing_files_errors_are_mapped() {
+    let Some((dir, mount)) = mount("errors").await else { return };
+    let backing = mount.backing_files();
+    let config = SessionConfig::new().max_stack_depth(1);
+    let session = Session::with_config(mount, MemFs::new(), config).unwrap();
+    let shutdown = session.shutdown_handle();
+    let session = tokio::spawn(session.run());
+
+    // Wait for the INIT handshake
+    let path = dir.join("file");
+    let file = path.clone();
+    tokio::task::spawn_blocking(move || std::fs::write(file, "data")).await.unwrap().unwrap();
+
+    if let Err(err) = register(&backing, executable).await {
+        // Not built with CONFIG_FUSE_PASSTHROUGH
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied, "{err}");
+        eprintln!("skipped, passthrough is not supported: {err}");
+    } else {
+        // A file on this file system is as deep as the allowed stacking
+        let err = register(&backing, move || File::open(path).unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput, "{err}");
+        assert!(err.to_string().contains("(fuse)"), "{err}");
+
+        // An eventfd can't be written with write_iter(), newer kernels only
+        // accept regular files and fail with EINVAL instead of EOPNOTSUPP
+        let eventfd = || unsafe { File::from_raw_fd(libc::eventfd(0, libc::EFD_CLOEXEC)) };
+        let err = register(&backing, eventfd).await.unwrap_err();
+        match err.kind() {
+            ErrorKind::Unsupported => assert!(err.to_string().contains("passthrough"), "{err}"),
+            kind => assert_eq!(kind, ErrorKind::InvalidInput, "{err}"),
+        }
+    }
+
+    shutdown.shutdown(Duration::from_secs(5)).await;
+    session.await.unwrap().unwrap();
+    std::fs::remove_dir(&dir).unwrap();
+}
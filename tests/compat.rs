@@ -0,0 +1,161 @@
+use std::mem::size_of;
+
+use fuse_async::protocol::*;
+use fuse_async::testing::MockKernel;
+use fuse_async::{Errno, MemFs};
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+/// Sizes of a structure around the minor versions where it grew, as pairs
+/// of a minor version and the size it uses.
+type Sizes<'a> = &'a [(u32, usize)];
+
+fn versions(sizes: Sizes<'_>) -> impl Iterator<Item = (ProtocolVersion, usize)> + '_ {
+    sizes.iter().map(|&(minor, size)| (ProtocolVersion::new(FUSE_KERNEL_VERSION, minor), size))
+}
+
+/// Bytes counting up from 1, so that every field is set and truncated fields
+/// can be told apart.
+fn pattern<T>() -> Vec<u8> {
+    (1..=size_of::<T>()).map(|i| i as u8).collect()
+}
+
+/// Encodes `value` like a reply for each version, returns the bytes after
+/// the header.
+fn push<T: CompatSize + IntoBytes + Immutable>(value: &T, version: ProtocolVersion) -> Vec<u8> {
+    let mut reply = ReplyBuf::new(1);
+    reply.push_compat(value, version);
+    reply.finish()[size_of::<fuse_out_header>()..].to_vec()
+}
+
+/// Checks that a request structure is decoded from as many bytes as the
+/// kernel sends in each version, the missing fields being zero.
+fn check_request<T>(sizes: Sizes)
+where
+    T: CompatSize + FromBytes + IntoBytes + KnownLayout + Immutable
+{
+    let bytes = pattern::<T>();
+    let value = T::read_from_bytes(&bytes).unwrap();
+    for (version, size) in versions(sizes) {
+        let name = std::any::type_name::<T>();
+        assert_eq!(T::compat_size(version), size, "{name} in {version:?}");
+
+        let sent = push(&value, version);
+        assert_eq!(sent, bytes[..size], "{name} in {version:?}");
+        let mut args = ArgReader::new(&sent);
+        let fetched: T = args.fetch_compat(version).unwrap();
+        assert!(args.is_empty(), "{name} in {version:?}");
+        let mut expected = bytes.clone();
+        expected[size..].fill(0);
+        assert_eq!(fetched.as_bytes(), expected, "{name} in {version:?}");
+
+        // One byte short of what the version sends
+        if size > 0 {
+            let mut args = ArgReader::new(&sent[..size - 1]);
+            assert_eq!(args.fetch_compat::<T>(version).err(), Some(Errno::EINVAL));
+        }
+    }
+}
+
+/// Checks that a reply structure is truncated to the size known by the
+/// kernel in each version.
+fn check_reply<T: CompatSize + IntoBytes + Immutable>(value: &T, sizes: Sizes) {
+    for (version, size) in versions(sizes) {
+        let name = std::any::type_name::<T>();
+        assert_eq!(T::compat_size(version), size, "{name} in {version:?}");
+        assert_eq!(push(value, version), value.as_bytes()[..size], "{name} in {version:?}");
+    }
+}
+
+fn attr() -> fuse_attr {
+    fuse_attr::read_from_bytes(&pattern::<fuse_attr>()).unwrap()
+}
+
+#[test]
+fn requests_round_trip_in_each_version() {
+    let current = FUSE_KERNEL_MINOR_VERSION;
+    check_request::<fuse_init_in>(&[(0, 16), (35, 16), (36, 64), (current, 64)]);
+    check_request::<fuse_getattr_in>(&[(0, 0), (8, 0), (9, 16), (current, 16)]);
+    check_request::<fuse_read_in>(&[(0, 24), (8, 24), (9, 40), (current, 40)]);
+    check_request::<fuse_write_in>(&[(0, 24), (8, 24), (9, 40), (current, 40)]);
+    check_request::<fuse_mknod_in>(&[(0, 8), (11, 8), (12, 16), (current, 16)]);
+    check_request::<fuse_create_in>(&[(0, 8), (11, 8), (12, 16), (current, 16)]);
+}
+
+#[test]
+fn replies_are_truncated_in_each_version() {
+    let current = FUSE_KERNEL_MINOR_VERSION;
+    let entry = fuse_entry_out {
+        nodeid: 2,
+        generation: 3,
+        entry_valid: 4,
+        attr_valid: 5,
+        entry_valid_nsec: 6,
+        attr_valid_nsec: 7,
+        attr: attr(),
+    };
+    check_reply(&entry, &[(0, 120), (8, 120), (9, 128), (current, 128)]);
+
+    let attr_out = fuse_attr_out {
+        attr_valid: 2,
+        attr_valid_nsec: 3,
+        dummy: Padding::new(),
+        attr: attr(),
+    };
+    check_reply(&attr_out, &[(0, 96), (8, 96), (9, 104), (current, 104)]);
+
+    let statfs = fuse_statfs_out {
+        st: fuse_kstatfs {
+            blocks: 1,
+            bfree: 2,
+            bavail: 3,
+            files: 4,
+            ffree: 5,
+            bsize: 6,
+            namelen: 7,
+            frsize: 8,
+            padding: Padding::new(),
+            spare: Padding::new(),
+        },
+    };
+    check_reply(&statfs, &[(0, 48), (3, 48), (4, 80), (current, 80)]);
+
+    let init = fuse_init_out {
+        major: FUSE_KERNEL_VERSION,
+        minor: current,
+        max_readahead: 1,
+        flags: InitFlags::FUSE_ASYNC_READ,
+        max_background: 2,
+        congestion_threshold: 3,
+        max_write: 4,
+        time_gran: 5,
+        max_pages: 6,
+        max_alignment: 7,
+        flags2: InitFlags2::empty(),
+        max_stack_depth: 8,
+        request_timeout: 9,
+        unused: Padding::new(),
+    };
+    check_reply(&init, &[(0, 8), (4, 8), (5, 24), (22, 24), (23, 64), (current, 64)]);
+}
+
+#[tokio::test]
+async fn setxattr_is_decoded_without_the_extension() {
+    // The session never asks for FUSE_SETXATTR_EXT, so the kernel sends the
+    // old structure, without setxattr_flags
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    assert!(!kernel.init_reply().flags.contains(InitFlags::FUSE_SETXATTR_EXT));
+    kernel.shutdown().await.unwrap();
+
+    let arg = fuse_setxattr_in { size: 5, flags: 1, setxattr_flags: 2, padding: 3 };
+    let mut args = arg.as_bytes()[..FUSE_COMPAT_SETXATTR_IN_SIZE].to_vec();
+    args.extend_from_slice(b"user.a\0value");
+    let op = Operation::decode(fuse_opcode::FUSE_SETXATTR, &args, ProtocolVersion::CURRENT);
+    let Ok(Operation::Setxattr { arg, name, value }) = op else { panic!("{op:?}") };
+    assert_eq!((arg.size, arg.flags, arg.setxattr_flags, arg.padding), (5, 1, 0, 0));
+    assert_eq!((name.as_encoded_bytes(), value.0), (&b"user.a"[..], &b"value"[..]));
+
+    // The value is sized by the argument
+    let truncated = &args[..args.len() - 1];
+    let op = Operation::decode(fuse_opcode::FUSE_SETXATTR, truncated, ProtocolVersion::CURRENT);
+    assert_eq!(op.unwrap_err(), Errno::EINVAL);
+}
@@ -0,0 +1,185 @@
+use std::io::ErrorKind;
+
+use fuse_async::protocol::*;
+use fuse_async::testing::MockKernel;
+use fuse_async::transport::MemoryTransport;
+use fuse_async::{MemFs, Session, SessionConfig};
+
+fn page_size() -> u32 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u32 }
+}
+
+fn assert_invalid(config: SessionConfig) {
+    let err = config.validate().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput, "{err}");
+}
+
+async fn negotiate(config: SessionConfig, init: fuse_init_in) -> fuse_init_out {
+    let kernel = MockKernel::start_with(MemFs::new(), config, init).await.unwrap();
+    let reply = kernel.init_reply().clone();
+    kernel.shutdown().await.unwrap();
+    reply
+}
+
+#[test]
+fn sizes_are_validated() {
+    SessionConfig::new().validate().unwrap();
+    SessionConfig::new().max_write(4096).validate().unwrap();
+    assert_invalid(SessionConfig::new().max_write(4095));
+    assert_invalid(SessionConfig::new().max_write(0));
+
+    let max_write = 256 * page_size();
+    SessionConfig::new().max_write(max_write).max_pages(256).validate().unwrap();
+    SessionConfig::new().max_write(max_write).validate().unwrap();
+    assert_invalid(SessionConfig::new().max_pages(0));
+    assert_invalid(SessionConfig::new().max_write(max_write).max_pages(257));
+    // The pages must hold max_write
+    assert_invalid(SessionConfig::new().max_write(max_write).max_pages(255));
+    assert_invalid(SessionConfig::new().max_write(max_write + 1));
+}
+
+#[test]
+fn congestion_threshold_is_at_most_max_background() {
+    let config = SessionConfig::new().max_background(16);
+    config.clone().congestion_threshold(16).validate().unwrap();
+    config.clone().congestion_threshold(0).validate().unwrap();
+    assert_invalid(config.congestion_threshold(17));
+    SessionConfig::new().congestion_threshold(u16::MAX).validate().unwrap();
+}
+
+#[test]
+fn sessions_are_not_created_with_invalid_settings() {
+    let (transport, _peer) = MemoryTransport::new();
+    let config = SessionConfig::new().max_write(1);
+    let err = Session::with_transport(transport, MemFs::new(), config).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn sizes_are_negotiated() {
+    let reply = negotiate(SessionConfig::new(), MockKernel::default_init()).await;
+    assert_eq!(reply.max_write, 128 * 1024);
+    assert_eq!(u32::from(reply.max_pages), (128 * 1024) / page_size());
+    assert!(reply.flags.contains(InitFlags::FUSE_BIG_WRITES | InitFlags::FUSE_MAX_PAGES));
+
+    let max_write = 64 * page_size();
+    let config = SessionConfig::new().max_write(max_write).max_pages(128);
+    let reply = negotiate(config, MockKernel::default_init()).await;
+    assert_eq!((reply.max_write, reply.max_pages), (max_write, 128));
+}
+
+#[tokio::test]
+async fn sizes_are_reduced_for_older_kernels() {
+    // Without FUSE_MAX_PAGES the kernel uses 32 pages
+    let config = SessionConfig::new().max_write(128 * page_size());
+    let init = fuse_init_in {
+        flags: InitFlags::all() - InitFlags::FUSE_MAX_PAGES,
+        ..MockKernel::default_init()
+    };
+    let reply = negotiate(config.clone(), init).await;
+    assert!(!reply.flags.contains(InitFlags::FUSE_MAX_PAGES));
+    assert_eq!((reply.max_write, reply.max_pages), (32 * page_size(), 32));
+
+    // Without FUSE_BIG_WRITES the writes are a page at most
+    let init = fuse_init_in {
+        flags: InitFlags::all() - InitFlags::FUSE_BIG_WRITES,
+        ..MockKernel::default_init()
+    };
+    let reply = negotiate(config, init).await;
+    assert!(!reply.flags.contains(InitFlags::FUSE_BIG_WRITES));
+    assert_eq!(reply.max_write, 4096);
+}
+
+#[tokio::test]
+async fn background_and_readahead_are_negotiated() {
+    let config = SessionConfig::new().max_background(32).congestion_threshold(24);
+    let reply = negotiate(config, MockKernel::default_init()).await;
+    assert_eq!((reply.max_background, reply.congestion_threshold), (32, 24));
+    // The kernel's readahead is kept unless the setting is smaller
+    assert_eq!(reply.max_readahead, MockKernel::default_init().max_readahead);
+
+    let config = SessionConfig::new().max_readahead(u32::MAX);
+    let reply = negotiate(config, MockKernel::default_init()).await;
+    assert_eq!(reply.max_readahead, MockKernel::default_init().max_readahead);
+    let config = SessionConfig::new().max_readahead(4096);
+    assert_eq!(negotiate(config, MockKernel::default_init()).await.max_readahead, 4096);
+
+    // Zero lets the kernel choose
+    let reply = negotiate(SessionConfig::new(), MockKernel::default_init()).await;
+    assert_eq!((reply.max_background, reply.congestion_threshold), (0, 0));
+}
+
+#[tokio::test]
+async fn requests_fit_the_buffer_of_max_pages() {
+    let max_write = 64 * page_size();
+    let config = SessionConfig::new().max_write(max_write).max_pages(64);
+    let kernel = MockKernel::start_with(MemFs::new(), config, MockKernel::default_init())
+        .await
+        .unwrap();
+    let (entry, open) = kernel.create(FUSE_ROOT_ID, "file", libc::S_IFREG | 0o644, libc::O_RDWR)
+        .await
+        .unwrap();
+
+    let data = vec![1; max_write as usize];
+    assert_eq!(kernel.write(entry.nodeid, open.fh, 0, &data).await, Ok(max_write));
+    // The buffer holds the pages and the headers, nothing more
+    let data = vec![1; max_write as usize + 2 * 4096];
+    assert!(kernel.write(entry.nodeid, open.fh, 0, &data).await.is_err());
+    kernel.shutdown().await.unwrap();
+}
+
+#[test]
+fn time_gran_is_a_power_of_10() {
+    for exp in 0..=9 {
+        SessionConfig::new().time_gran(10u32.pow(exp)).validate().unwrap();
+    }
+    for gran in [0, 2, 5, 20, 999, 1001, 10u32.pow(9) + 1, 2 * 10u32.pow(9), u32::MAX] {
+        assert_invalid(SessionConfig::new().time_gran(gran));
+    }
+}
+
+#[tokio::test]
+async fn time_gran_is_negotiated() {
+    let reply = negotiate(SessionConfig::new().time_gran(1000), MockKernel::default_init()).await;
+    assert_eq!(reply.time_gran, 1000);
+    // Zero means a granularity of one nanosecond for the kernel
+    let reply = negotiate(SessionConfig::new(), MockKernel::default_init()).await;
+    assert_eq!(reply.time_gran, 0);
+
+    // The field is new in 7.23, older kernels don't receive it
+    let init = fuse_init_in {
+        minor: 22,
+        flags: InitFlags::all() - InitFlags::FUSE_INIT_EXT,
+        ..MockKernel::default_init()
+    };
+    let reply = negotiate(SessionConfig::new().time_gran(1000), init).await;
+    assert_eq!((reply.minor, reply.time_gran), (22, 0));
+}
+
+#[test]
+fn max_stack_depth_is_1_or_2() {
+    SessionConfig::new().max_stack_depth(1).validate().unwrap();
+    SessionConfig::new().max_stack_depth(2).validate().unwrap();
+    assert_invalid(SessionConfig::new().max_stack_depth(0));
+    assert_invalid(SessionConfig::new().max_stack_depth(3));
+}
+
+#[tokio::test]
+async fn passthrough_is_negotiated() {
+    let config = SessionConfig::new().max_stack_depth(2);
+    let reply = negotiate(config.clone(), MockKernel::default_init()).await;
+    assert!(reply.flags2.contains(InitFlags2::FUSE_PASSTHROUGH));
+    assert_eq!(reply.max_stack_depth, 2);
+
+    // Not without the setting, or if the kernel doesn't offer it
+    let reply = negotiate(SessionConfig::new(), MockKernel::default_init()).await;
+    assert!(!reply.flags2.contains(InitFlags2::FUSE_PASSTHROUGH));
+    assert_eq!(reply.max_stack_depth, 0);
+    let init = fuse_init_in {
+        flags2: InitFlags2::all() - InitFlags2::FUSE_PASSTHROUGH,
+        ..MockKernel::default_init()
+    };
+    let reply = negotiate(config, init).await;
+    assert!(!reply.flags2.contains(InitFlags2::FUSE_PASSTHROUGH));
+    assert_eq!(reply.max_stack_depth, 0);
+}
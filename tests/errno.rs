@@ -0,0 +1,75 @@
+use std::io::{self, ErrorKind};
+
+use fuse_async::Errno;
+use fuse_async::protocol::OutError;
+
+#[test]
+fn kinds_are_mapped() {
+    let table = [
+        (ErrorKind::NotFound, Errno::ENOENT),
+        (ErrorKind::PermissionDenied, Errno::EACCES),
+        (ErrorKind::ConnectionReset, Errno::ENOTCONN),
+        (ErrorKind::ConnectionAborted, Errno::ENOTCONN),
+        (ErrorKind::NotConnected, Errno::ENOTCONN),
+        (ErrorKind::AlreadyExists, Errno::EEXIST),
+        (ErrorKind::WouldBlock, Errno::EAGAIN),
+        (ErrorKind::NotADirectory, Errno::ENOTDIR),
+        (ErrorKind::IsADirectory, Errno::EISDIR),
+        (ErrorKind::DirectoryNotEmpty, Errno::ENOTEMPTY),
+        (ErrorKind::ReadOnlyFilesystem, Errno::EROFS),
+        (ErrorKind::StaleNetworkFileHandle, Errno::ESTALE),
+        (ErrorKind::InvalidInput, Errno::EINVAL),
+        (ErrorKind::TimedOut, Errno::ETIMEDOUT),
+        (ErrorKind::StorageFull, Errno::ENOSPC),
+        (ErrorKind::NotSeekable, Errno::ESPIPE),
+        (ErrorKind::QuotaExceeded, Errno::EDQUOT),
+        (ErrorKind::FileTooLarge, Errno::EFBIG),
+        (ErrorKind::ResourceBusy, Errno::EBUSY),
+        (ErrorKind::ExecutableFileBusy, Errno::ETXTBSY),
+        (ErrorKind::Deadlock, Errno::EDEADLK),
+        (ErrorKind::CrossesDevices, Errno::EXDEV),
+        (ErrorKind::TooManyLinks, Errno::EMLINK),
+        (ErrorKind::InvalidFilename, Errno::ENAMETOOLONG),
+        (ErrorKind::ArgumentListTooLong, Errno::E2BIG),
+        (ErrorKind::Interrupted, Errno::EINTR),
+        (ErrorKind::Unsupported, Errno::EOPNOTSUPP),
+        (ErrorKind::OutOfMemory, Errno::ENOMEM),
+        (ErrorKind::InvalidData, Errno::EIO),
+        (ErrorKind::UnexpectedEof, Errno::EIO),
+        (ErrorKind::Other, Errno::EIO),
+    ];
+    for (kind, errno) in table {
+        assert_eq!(Errno::from(kind), errno, "{kind:?}");
+        assert_eq!(Errno::from(io::Error::new(kind, "message")), errno, "{kind:?}");
+        assert_eq!(Errno::from(&io::Error::from(kind)), errno, "{kind:?}");
+    }
+}
+
+#[test]
+fn os_codes_are_preserved() {
+    for code in [libc::EPERM, libc::ENOENT, libc::ENOSYS, libc::EOPNOTSUPP, libc::EHOSTDOWN] {
+        let errno = Errno::from(io::Error::from_raw_os_error(code));
+        assert_eq!(errno.code(), code);
+        assert_eq!(io::Error::from(errno).raw_os_error(), Some(code));
+    }
+    // An unknown code is kept as is
+    assert_eq!(Errno::from(io::Error::from_raw_os_error(4000)).code(), 4000);
+}
+
+#[test]
+fn codes_are_never_zero_or_positive_on_the_wire() {
+    assert_eq!(Errno::new(0), None);
+    assert_eq!(Errno::new(-libc::ENOENT), None);
+    assert_eq!(Errno::new(libc::ENOENT), Some(Errno::ENOENT));
+
+    // Invalid raw codes fall back to the kind of the error
+    for code in [0, -1, -libc::ENOENT, i32::MIN] {
+        let errno = Errno::from(io::Error::from_raw_os_error(code));
+        assert!(errno.code() > 0, "{code}: {errno:?}");
+        let out = OutError::from(errno);
+        assert_eq!(out.get(), -errno.code());
+        assert_eq!(out.errno(), Some(errno));
+    }
+    let err = io::Error::other(io::Error::from_raw_os_error(0));
+    assert_eq!(Errno::from(err), Errno::EIO);
+}
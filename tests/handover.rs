@@ -0,0 +1,214 @@
+use std::ffi::OsStr;
+use std::io::{ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+
+use fuse_async::protocol::*;
+use fuse_async::testing::MockKernel;
+use fuse_async::{Errno, Filesystem, Handover, InodeSnapshot, InodeTable, MemFs, OverlayFs};
+use fuse_async::Request;
+
+/// Serves a file system that the test keeps a hold of, to take its snapshot
+/// while the session runs.
+struct Shared<F>(Arc<F>);
+
+impl<F: Filesystem> Filesystem for Shared<F> {
+    async fn lookup(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr
+    ) -> Result<fuse_entry_out, Errno> {
+        self.0.lookup(req, parent, name).await
+    }
+
+    async fn getattr(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_getattr_in
+    ) -> Result<fuse_attr_out, Errno> {
+        self.0.getattr(req, ino, arg).await
+    }
+
+    async fn mkdir(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_mkdir_in,
+        name: &OsStr
+    ) -> Result<fuse_entry_out, Errno> {
+        self.0.mkdir(req, parent, arg, name).await
+    }
+
+    async fn open(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_open_in
+    ) -> Result<fuse_open_out, Errno> {
+        self.0.open(req, ino, arg).await
+    }
+
+    async fn read(&self, req: &Request, ino: u64, arg: &fuse_read_in) -> Result<Vec<u8>, Errno> {
+        self.0.read(req, ino, arg).await
+    }
+
+    async fn write(
+        &self,
+        req: &Request,
+        ino: u64,
+        arg: &fuse_write_in,
+        data: &[u8]
+    ) -> Result<u32, Errno> {
+        self.0.write(req, ino, arg, data).await
+    }
+
+    async fn release(&self, req: &Request, ino: u64, arg: &fuse_release_in) -> Result<(), Errno> {
+        self.0.release(req, ino, arg).await
+    }
+
+    async fn create(
+        &self,
+        req: &Request,
+        parent: u64,
+        arg: &fuse_create_in,
+        name: &OsStr
+    ) -> Result<(fuse_entry_out, fuse_open_out), Errno> {
+        self.0.create(req, parent, arg, name).await
+    }
+}
+
+/// Creates `dir/file` holding `data` past a hole, returns their node ids.
+async fn populate(kernel: &MockKernel, data: &[u8]) -> (u64, u64) {
+    let dir = kernel.mkdir(FUSE_ROOT_ID, "dir", 0o755).await.unwrap().nodeid;
+    let (entry, open) = kernel.create(dir, "file", libc::S_IFREG | 0o644, libc::O_RDWR)
+        .await
+        .unwrap();
+    kernel.write(entry.nodeid, open.fh, 8192, data).await.unwrap();
+    kernel.release(entry.nodeid, open.fh).await.unwrap();
+    (dir, entry.nodeid)
+}
+
+/// Checks that the node ids of `populate` still stand for its files.
+async fn check(kernel: &MockKernel, (dir, file): (u64, u64), data: &[u8]) {
+    assert_eq!(kernel.getattr(dir).await.unwrap().attr.mode & libc::S_IFMT, libc::S_IFDIR);
+    assert_eq!(kernel.lookup(dir, "file").await.unwrap().nodeid, file);
+    let open = kernel.open(file, libc::O_RDONLY).await.unwrap();
+    let read = kernel.read(file, open.fh, 0, 8192 + data.len() as u32).await.unwrap();
+    assert_eq!(&read[..8192], &[0; 8192]);
+    assert_eq!(&read[8192..], data);
+}
+
+#[test]
+fn inode_table_snapshot_keeps_node_ids_and_lookups() {
+    let table = InodeTable::new("root".to_owned(), ());
+    let (a, _) = table.lookup("a".to_owned(), || Ok::<_, Errno>(())).unwrap();
+    let (b, _) = table.lookup("b".to_owned(), || Ok::<_, Errno>(())).unwrap();
+    let (gone, _) = table.lookup("gone".to_owned(), || Ok::<_, Errno>(())).unwrap();
+    table.acquire(a);
+    let snapshot = table.snapshot(|key, ()| Some(key.as_bytes().to_vec())).to_bytes();
+
+    let restored = InodeTable::new("root".to_owned(), ());
+    let snapshot = InodeSnapshot::from_bytes(&snapshot).unwrap();
+    restored.restore(snapshot, |_, key| {
+        let key = String::from_utf8(key).unwrap();
+        (key != "gone").then_some((key, ()))
+    });
+    assert_eq!(restored.len(), 3);
+    assert_eq!(restored.find(&"a".to_owned()).unwrap().0, a);
+    assert_eq!(restored.find(&"b".to_owned()).unwrap().0, b);
+    assert!(restored.get(gone).is_none());
+
+    // Two lookups of a, one of b
+    assert!(restored.forget(a, 1).is_none());
+    assert!(restored.forget(a, 1).is_some());
+    assert!(restored.forget(b, 1).is_some());
+    // Node ids are not reused
+    let (c, _) = restored.lookup("c".to_owned(), || Ok::<_, Errno>(())).unwrap();
+    assert!(c > gone);
+}
+
+#[test]
+fn malformed_inode_snapshot_is_rejected() {
+    let table = InodeTable::new(0u8, ());
+    let mut snapshot = table.snapshot(|_, ()| Some(vec![1, 2, 3])).to_bytes();
+    snapshot.pop();
+    assert_eq!(InodeSnapshot::from_bytes(&snapshot).unwrap_err(), Errno::EINVAL);
+}
+
+#[tokio::test]
+async fn memfs_is_restored_from_its_snapshot() {
+    let fs = Arc::new(MemFs::new());
+    let kernel = MockKernel::start(Shared(fs.clone())).await.unwrap();
+    let nodes = populate(&kernel, b"data").await;
+    let snapshot = fs.snapshot().await.unwrap();
+    kernel.shutdown().await.unwrap();
+
+    let fs = MemFs::new();
+    fs.restore(&snapshot).await.unwrap();
+    let kernel = MockKernel::start(fs).await.unwrap();
+    check(&kernel, nodes, b"data").await;
+    kernel.shutdown().await.unwrap();
+
+    assert_eq!(MemFs::new().restore(&snapshot[1..]).await.unwrap_err(), Errno::EINVAL);
+}
+
+#[tokio::test]
+async fn overlay_is_restored_with_its_layers() {
+    let fs = Arc::new(OverlayFs::new(MemFs::new()).lower(MemFs::new()));
+    let kernel = MockKernel::start(Shared(fs.clone())).await.unwrap();
+    let nodes = populate(&kernel, b"layered").await;
+    let snapshot = fs.snapshot().await.unwrap();
+    kernel.shutdown().await.unwrap();
+
+    let fs = OverlayFs::new(MemFs::new()).lower(MemFs::new());
+    fs.restore(&snapshot).await.unwrap();
+    let kernel = MockKernel::start(fs).await.unwrap();
+    check(&kernel, nodes, b"layered").await;
+    kernel.shutdown().await.unwrap();
+
+    let fs = OverlayFs::new(MemFs::new());
+    assert_eq!(fs.restore(&snapshot).await.unwrap_err(), Errno::EINVAL);
+}
+
+#[cfg(feature = "passthrough")]
+#[tokio::test]
+async fn passthrough_inodes_are_reopened_by_path() {
+    use fuse_async::PassthroughFs;
+
+    let source = std::env::temp_dir().join(format!("fuse-async-handover-{}", std::process::id()));
+    std::fs::create_dir_all(&source).unwrap();
+
+    let fs = Arc::new(PassthroughFs::new(&source).unwrap());
+    let kernel = MockKernel::start(Shared(fs.clone())).await.unwrap();
+    let nodes = populate(&kernel, b"mirrored").await;
+    let removed = kernel.create(FUSE_ROOT_ID, "removed", libc::S_IFREG | 0o644, libc::O_RDWR)
+        .await
+        .unwrap();
+    kernel.release(removed.0.nodeid, removed.1.fh).await.unwrap();
+    let snapshot = fs.snapshot().await.unwrap();
+    kernel.shutdown().await.unwrap();
+    std::fs::remove_file(source.join("removed")).unwrap();
+
+    let fs = PassthroughFs::new(&source).unwrap();
+    fs.restore(&snapshot).await.unwrap();
+    let kernel = MockKernel::start(fs).await.unwrap();
+    check(&kernel, nodes, b"mirrored").await;
+    assert_eq!(kernel.getattr(removed.0.nodeid).await.unwrap_err(), Errno::ESTALE);
+    kernel.shutdown().await.unwrap();
+
+    std::fs::remove_dir_all(&source).unwrap();
+}
+
+#[test]
+fn oversized_handover_is_rejected_before_reading() {
+    let (sender, receiver) = UnixStream::pair().unwrap();
+    let mut header = [0u8; 72];
+    header[..8].copy_from_slice(&u64::from_be_bytes(*b"FUSEHO02").to_ne_bytes());
+    header[56..64].copy_from_slice(&u64::MAX.to_ne_bytes());
+    (&sender).write_all(&header).unwrap();
+
+    let err = Handover::receive(&receiver).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
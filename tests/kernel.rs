@@ -0,0 +1,104 @@
+use std::io::ErrorKind;
+
+use fuse_async::protocol::*;
+use fuse_async::testing::MockKernel;
+use fuse_async::{Errno, Filesystem, MemFs, Request, SessionConfig};
+
+/// Answers getattr with garbage in the padding.
+struct BadPadding;
+
+impl Filesystem for BadPadding {
+    async fn getattr(
+        &self,
+        _req: &Request,
+        ino: u64,
+        _arg: &fuse_getattr_in
+    ) -> Result<fuse_attr_out, Errno> {
+        let mut attr = fuse_attr_out {
+            attr_valid: 1,
+            attr_valid_nsec: 0,
+            // SAFETY: the kernel would reject it, which is what is tested
+            dummy: unsafe { Padding::with_nonzero(0xdead) },
+            attr: zerocopy::FromZeros::new_zeroed(),
+        };
+        attr.attr.ino = ino;
+        Ok(attr)
+    }
+}
+
+fn init_in(major: u32, minor: u32, flags: InitFlags) -> fuse_init_in {
+    fuse_init_in { major, minor, flags, ..MockKernel::default_init() }
+}
+
+#[tokio::test]
+async fn init_negotiates_the_version_of_an_older_kernel() {
+    let flags = InitFlags::all() - InitFlags::FUSE_INIT_EXT;
+    let init = init_in(FUSE_KERNEL_VERSION, 22, flags);
+    let kernel = MockKernel::start_with(MemFs::new(), SessionConfig::new(), init).await.unwrap();
+
+    let reply = kernel.init_reply();
+    assert_eq!((reply.major, reply.minor), (FUSE_KERNEL_VERSION, 22));
+    assert!(flags.contains(reply.flags));
+    // Sent with the size known by 7.22, without max_pages and flags2
+    assert_eq!(reply.max_pages, 0);
+    assert!(reply.flags2.is_empty());
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn init_keeps_the_current_version_for_a_newer_kernel() {
+    let init = MockKernel::default_init();
+    let kernel = MockKernel::start_with(MemFs::new(), SessionConfig::new(), init).await.unwrap();
+    let reply = kernel.init_reply();
+    assert_eq!((reply.major, reply.minor), (FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION));
+    assert!(reply.max_write > 0);
+    kernel.shutdown().await.unwrap();
+
+    // A newer major version only gets ours back, to retry with it
+    let init = init_in(FUSE_KERNEL_VERSION + 1, 0, InitFlags::all());
+    let kernel = MockKernel::start_with(MemFs::new(), SessionConfig::new(), init).await.unwrap();
+    assert_eq!(kernel.init_reply().major, FUSE_KERNEL_VERSION);
+    assert_eq!(kernel.init_reply().max_write, 0);
+}
+
+#[tokio::test]
+async fn init_refuses_an_unsupported_kernel() {
+    let init = init_in(FUSE_KERNEL_VERSION - 1, 0, InitFlags::all());
+    let err = MockKernel::start_with(MemFs::new(), SessionConfig::new(), init).await.err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+}
+
+#[tokio::test]
+async fn lookup_and_getattr_agree() {
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    let dir = kernel.mkdir(FUSE_ROOT_ID, "dir", 0o750).await.unwrap();
+    let entry = kernel.lookup(FUSE_ROOT_ID, "dir").await.unwrap();
+    assert_eq!(entry.nodeid, dir.nodeid);
+    assert_eq!(entry.attr.mode, libc::S_IFDIR | 0o750);
+
+    let attr = kernel.getattr(entry.nodeid).await.unwrap();
+    assert_eq!(attr.attr.ino, entry.attr.ino);
+    assert_eq!(attr.attr.mode, entry.attr.mode);
+    assert_eq!(attr.attr.nlink, 2);
+
+    assert_eq!(kernel.lookup(FUSE_ROOT_ID, "missing").await.unwrap_err(), Errno::ENOENT);
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn unknown_opcode_is_answered_with_enosys() {
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    let reply = kernel.request_raw(u32::MAX, FUSE_ROOT_ID, &[b"whatever"]).await;
+    assert_eq!(reply.unwrap_err(), Errno::ENOSYS);
+
+    // The session goes on
+    kernel.getattr(FUSE_ROOT_ID).await.unwrap();
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn reply_with_nonzero_padding_is_rejected() {
+    let kernel = MockKernel::start(BadPadding).await.unwrap();
+    assert_eq!(kernel.getattr(FUSE_ROOT_ID).await.unwrap_err(), Errno::EINVAL);
+    kernel.shutdown().await.unwrap();
+}
@@ -0,0 +1,237 @@
+use fuse_async::protocol::*;
+use fuse_async::testing::{DirEntry, MockKernel};
+use fuse_async::{Errno, MemFs};
+
+const BLOCK: usize = 4096;
+
+/// Creates an empty regular file, returns its node id.
+async fn file(kernel: &MockKernel, parent: u64, name: &str) -> u64 {
+    let (entry, open) = kernel.create(parent, name, libc::S_IFREG | 0o644, libc::O_RDWR)
+        .await
+        .unwrap();
+    kernel.release(entry.nodeid, open.fh).await.unwrap();
+    entry.nodeid
+}
+
+async fn nlink(kernel: &MockKernel, ino: u64) -> u32 {
+    kernel.getattr(ino).await.unwrap().attr.nlink
+}
+
+async fn entries(kernel: &MockKernel, dir: u64) -> Vec<DirEntry> {
+    let open = kernel.opendir(dir, libc::O_RDONLY).await.unwrap();
+    let entries = kernel.readdir(dir, open.fh, 0, 4096).await.unwrap();
+    kernel.releasedir(dir, open.fh).await.unwrap();
+    entries
+}
+
+async fn names(kernel: &MockKernel, dir: u64) -> Vec<String> {
+    let mut names: Vec<_> = entries(kernel, dir).await
+        .into_iter()
+        .map(|entry| entry.name.into_string().unwrap())
+        .filter(|name| name != "." && name != "..")
+        .collect();
+    names.sort();
+    names
+}
+
+/// Inode of the `..` entry, the kernel never looks it up.
+async fn parent(kernel: &MockKernel, dir: u64) -> u64 {
+    entries(kernel, dir).await.into_iter().find(|entry| entry.name == "..").unwrap().ino
+}
+
+#[tokio::test]
+async fn rename_noreplace_keeps_the_target() {
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    let a = file(&kernel, FUSE_ROOT_ID, "a").await;
+    let b = file(&kernel, FUSE_ROOT_ID, "b").await;
+
+    let noreplace = libc::RENAME_NOREPLACE;
+    let err = kernel.rename(FUSE_ROOT_ID, "a", FUSE_ROOT_ID, "b", noreplace).await.unwrap_err();
+    assert_eq!(err, Errno::EEXIST);
+    assert_eq!(kernel.lookup(FUSE_ROOT_ID, "a").await.unwrap().nodeid, a);
+    assert_eq!(kernel.lookup(FUSE_ROOT_ID, "b").await.unwrap().nodeid, b);
+
+    kernel.rename(FUSE_ROOT_ID, "a", FUSE_ROOT_ID, "c", noreplace).await.unwrap();
+    assert_eq!(names(&kernel, FUSE_ROOT_ID).await, ["b", "c"]);
+    assert_eq!(kernel.lookup(FUSE_ROOT_ID, "c").await.unwrap().nodeid, a);
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rename_exchange_swaps_the_entries() {
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    let left = kernel.mkdir(FUSE_ROOT_ID, "left", 0o755).await.unwrap().nodeid;
+    let right = kernel.mkdir(FUSE_ROOT_ID, "right", 0o755).await.unwrap().nodeid;
+    let file = file(&kernel, left, "file").await;
+    let dir = kernel.mkdir(right, "dir", 0o755).await.unwrap().nodeid;
+    assert_eq!((nlink(&kernel, left).await, nlink(&kernel, right).await), (2, 3));
+
+    let exchange = libc::RENAME_EXCHANGE;
+    kernel.rename(left, "file", right, "dir", exchange).await.unwrap();
+    assert_eq!(kernel.lookup(left, "file").await.unwrap().nodeid, dir);
+    assert_eq!(kernel.lookup(right, "dir").await.unwrap().nodeid, file);
+    // The directory moved from right to left, with its `..` link
+    assert_eq!((nlink(&kernel, left).await, nlink(&kernel, right).await), (3, 2));
+    assert_eq!(parent(&kernel, dir).await, left);
+
+    let err = kernel.rename(left, "file", right, "missing", exchange).await.unwrap_err();
+    assert_eq!(err, Errno::ENOENT);
+    let flags = exchange | libc::RENAME_NOREPLACE;
+    let err = kernel.rename(left, "file", right, "dir", flags).await.unwrap_err();
+    assert_eq!(err, Errno::EINVAL);
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rename_whiteout_leaves_a_whiteout_device() {
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    let a = file(&kernel, FUSE_ROOT_ID, "a").await;
+
+    kernel.rename(FUSE_ROOT_ID, "a", FUSE_ROOT_ID, "b", libc::RENAME_WHITEOUT).await.unwrap();
+    assert_eq!(kernel.lookup(FUSE_ROOT_ID, "b").await.unwrap().nodeid, a);
+    let whiteout = kernel.lookup(FUSE_ROOT_ID, "a").await.unwrap();
+    assert_ne!(whiteout.nodeid, a);
+    assert_eq!(whiteout.attr.mode & libc::S_IFMT, libc::S_IFCHR);
+    assert_eq!(whiteout.attr.rdev, 0);
+    assert_eq!(names(&kernel, FUSE_ROOT_ID).await, ["a", "b"]);
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn hard_links_count_their_names() {
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    let dir = kernel.mkdir(FUSE_ROOT_ID, "dir", 0o755).await.unwrap().nodeid;
+    let a = file(&kernel, FUSE_ROOT_ID, "a").await;
+    let other = file(&kernel, FUSE_ROOT_ID, "other").await;
+
+    let link = kernel.link(a, dir, "link").await.unwrap();
+    assert_eq!((link.nodeid, link.attr.nlink), (a, 2));
+    assert_eq!(kernel.link(dir, FUSE_ROOT_ID, "dir2").await.unwrap_err(), Errno::EPERM);
+    assert_eq!(kernel.link(a, dir, "link").await.unwrap_err(), Errno::EEXIST);
+
+    // Renaming a file over one of its own links does nothing
+    kernel.rename(FUSE_ROOT_ID, "a", dir, "link", 0).await.unwrap();
+    assert_eq!(nlink(&kernel, a).await, 2);
+    assert_eq!(kernel.lookup(FUSE_ROOT_ID, "a").await.unwrap().nodeid, a);
+
+    // Replacing a link drops one name of the file
+    kernel.rename(FUSE_ROOT_ID, "other", dir, "link", 0).await.unwrap();
+    assert_eq!(nlink(&kernel, a).await, 1);
+    assert_eq!(nlink(&kernel, other).await, 1);
+    assert_eq!(kernel.lookup(dir, "link").await.unwrap().nodeid, other);
+
+    kernel.unlink(FUSE_ROOT_ID, "a").await.unwrap();
+    assert_eq!(nlink(&kernel, a).await, 0);
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn moving_a_directory_moves_its_parent_link() {
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    let from = kernel.mkdir(FUSE_ROOT_ID, "from", 0o755).await.unwrap().nodeid;
+    let to = kernel.mkdir(FUSE_ROOT_ID, "to", 0o755).await.unwrap().nodeid;
+    let dir = kernel.mkdir(from, "dir", 0o755).await.unwrap().nodeid;
+    assert_eq!(nlink(&kernel, FUSE_ROOT_ID).await, 4);
+
+    kernel.rename(from, "dir", to, "dir", 0).await.unwrap();
+    assert_eq!((nlink(&kernel, from).await, nlink(&kernel, to).await), (2, 3));
+    assert_eq!(parent(&kernel, dir).await, to);
+
+    // Replacing an empty directory removes its link to the parent
+    let empty = kernel.mkdir(from, "empty", 0o755).await.unwrap().nodeid;
+    kernel.rename(to, "dir", from, "empty", 0).await.unwrap();
+    assert_eq!((nlink(&kernel, from).await, nlink(&kernel, to).await), (3, 2));
+    assert_eq!(nlink(&kernel, empty).await, 0);
+    assert_eq!(nlink(&kernel, FUSE_ROOT_ID).await, 4);
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn holes_read_as_zeros() {
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    let (entry, open) = kernel.create(FUSE_ROOT_ID, "sparse", libc::S_IFREG | 0o644, libc::O_RDWR)
+        .await
+        .unwrap();
+    let ino = entry.nodeid;
+    let offset = 16 * BLOCK as u64 + 100;
+    kernel.write(ino, open.fh, offset, b"data").await.unwrap();
+
+    let attr = kernel.getattr(ino).await.unwrap().attr;
+    assert_eq!(attr.size, offset + 4);
+    // Only the written block is allocated, in 512 bytes units
+    assert_eq!(attr.blocks, BLOCK as u64 / 512);
+
+    let read = kernel.read(ino, open.fh, 0, offset as u32 + 4).await.unwrap();
+    assert!(read[..offset as usize].iter().all(|&byte| byte == 0));
+    assert_eq!(&read[offset as usize..], b"data");
+    // Reads stop at the end of the file
+    assert_eq!(kernel.read(ino, open.fh, offset, 4096).await.unwrap(), b"data");
+    assert!(kernel.read(ino, open.fh, offset + 4, 10).await.unwrap().is_empty());
+
+    let seek_data = libc::SEEK_DATA as u32;
+    assert_eq!(kernel.lseek(ino, open.fh, 0, seek_data).await.unwrap(), 16 * BLOCK as u64);
+    assert_eq!(kernel.lseek(ino, open.fh, 0, libc::SEEK_HOLE as u32).await.unwrap(), 0);
+    kernel.release(ino, open.fh).await.unwrap();
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn tmpfile_is_unnamed_until_linked() {
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    let dir = kernel.mkdir(FUSE_ROOT_ID, "dir", 0o755).await.unwrap().nodeid;
+    let (entry, open) = kernel.tmpfile(dir, 0o600, libc::O_RDWR).await.unwrap();
+    let ino = entry.nodeid;
+    assert_eq!(entry.attr.mode, libc::S_IFREG | 0o600);
+    assert_eq!(entry.attr.nlink, 0);
+    assert!(names(&kernel, dir).await.is_empty());
+
+    kernel.write(ino, open.fh, 0, b"temporary").await.unwrap();
+    assert_eq!(kernel.read(ino, open.fh, 0, 100).await.unwrap(), b"temporary");
+
+    // Like linkat() of an O_TMPFILE file
+    assert_eq!(kernel.link(ino, dir, "kept").await.unwrap().attr.nlink, 1);
+    assert_eq!(names(&kernel, dir).await, ["kept"]);
+    assert_eq!(kernel.lookup(dir, "kept").await.unwrap().nodeid, ino);
+    kernel.release(ino, open.fh).await.unwrap();
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn limits_are_reported_and_enforced() {
+    let fs = MemFs::new().max_size(4 * BLOCK as u64).max_inodes(3);
+    let kernel = MockKernel::start(fs).await.unwrap();
+    let statfs = kernel.statfs(FUSE_ROOT_ID).await.unwrap();
+    assert_eq!((statfs.blocks, statfs.bfree, statfs.bsize), (4, 4, BLOCK as u32));
+    assert_eq!((statfs.files, statfs.ffree), (3, 2));
+
+    let (entry, open) = kernel.create(FUSE_ROOT_ID, "a", libc::S_IFREG | 0o644, libc::O_RDWR)
+        .await
+        .unwrap();
+    let ino = entry.nodeid;
+    kernel.write(ino, open.fh, 0, &[1; 3 * BLOCK]).await.unwrap();
+    let statfs = kernel.statfs(FUSE_ROOT_ID).await.unwrap();
+    assert_eq!((statfs.bfree, statfs.bavail, statfs.ffree), (1, 1, 1));
+
+    // Two more blocks don't fit, nothing is written
+    let err = kernel.write(ino, open.fh, 3 * BLOCK as u64, &[2; 2 * BLOCK]).await.unwrap_err();
+    assert_eq!(err, Errno::ENOSPC);
+    assert_eq!(kernel.getattr(ino).await.unwrap().attr.size, 3 * BLOCK as u64);
+    let err = kernel.fallocate(ino, open.fh, 0, 8 * BLOCK as u64, 0).await.unwrap_err();
+    assert_eq!(err, Errno::ENOSPC);
+    // Rewriting allocated blocks needs no space
+    kernel.write(ino, open.fh, 0, &[3; BLOCK]).await.unwrap();
+    kernel.release(ino, open.fh).await.unwrap();
+
+    kernel.mkdir(FUSE_ROOT_ID, "dir", 0o755).await.unwrap();
+    assert_eq!(kernel.mkdir(FUSE_ROOT_ID, "full", 0o755).await.unwrap_err(), Errno::ENOSPC);
+    let err = kernel.rename(FUSE_ROOT_ID, "a", FUSE_ROOT_ID, "b", libc::RENAME_WHITEOUT).await;
+    assert_eq!(err.unwrap_err(), Errno::ENOSPC);
+    assert_eq!(kernel.statfs(FUSE_ROOT_ID).await.unwrap().ffree, 0);
+
+    // Removing the file frees its blocks and its inode
+    kernel.unlink(FUSE_ROOT_ID, "a").await.unwrap();
+    kernel.forget(ino, 1).unwrap();
+    let statfs = kernel.statfs(FUSE_ROOT_ID).await.unwrap();
+    assert_eq!((statfs.bfree, statfs.ffree), (4, 1));
+    kernel.shutdown().await.unwrap();
+}
@@ -0,0 +1,132 @@
+use std::ffi::OsStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use fuse_async::protocol::*;
+use fuse_async::testing::MockKernel;
+use fuse_async::{Errno, Filesystem, Histogram, MemFs, Metrics, Request, SessionConfig};
+use zerocopy::IntoBytes;
+
+/// Never answers lookups.
+struct Stuck;
+
+impl Filesystem for Stuck {
+    async fn lookup(
+        &self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr
+    ) -> Result<fuse_entry_out, Errno> {
+        std::future::pending().await
+    }
+}
+
+async fn start(fs: impl Filesystem, config: SessionConfig) -> (MockKernel, Arc<Metrics>) {
+    let metrics = Arc::new(Metrics::new());
+    let config = config.metrics(metrics.clone());
+    let kernel = MockKernel::start_with(fs, config, MockKernel::default_init()).await.unwrap();
+    (kernel, metrics)
+}
+
+#[tokio::test]
+async fn requests_are_counted() {
+    let (kernel, metrics) = start(MemFs::new(), SessionConfig::new()).await;
+    let (entry, open) = kernel.create(FUSE_ROOT_ID, "file", libc::S_IFREG | 0o644, libc::O_RDWR)
+        .await
+        .unwrap();
+    kernel.write(entry.nodeid, open.fh, 0, b"0123456789").await.unwrap();
+    assert_eq!(kernel.read(entry.nodeid, open.fh, 2, 100).await.unwrap(), b"23456789");
+    kernel.release(entry.nodeid, open.fh).await.unwrap();
+    for _ in 0..2 {
+        assert_eq!(kernel.lookup(FUSE_ROOT_ID, "missing").await.unwrap_err(), Errno::ENOENT);
+    }
+    kernel.lookup(FUSE_ROOT_ID, "file").await.unwrap();
+
+    // Forgets are not answered, they are handled before the session ends
+    let forget = fuse_forget_in { nlookup: 1 };
+    kernel.send(fuse_opcode::FUSE_FORGET, entry.nodeid, &[forget.as_bytes()]).unwrap();
+    let batch = fuse_batch_forget_in { count: 2, dummy: 0 };
+    let one = fuse_forget_one { nodeid: entry.nodeid, nlookup: 1 };
+    let args = [batch.as_bytes(), one.as_bytes(), one.as_bytes()];
+    kernel.send(fuse_opcode::FUSE_BATCH_FORGET, 0, &args).unwrap();
+    kernel.shutdown().await.unwrap();
+
+    let snapshot = metrics.snapshot();
+    let count = |opcode| snapshot.opcode(opcode).map_or(0, |stats| stats.count);
+    assert_eq!(count(fuse_opcode::FUSE_INIT), 1);
+    assert_eq!(count(fuse_opcode::FUSE_CREATE), 1);
+    assert_eq!(count(fuse_opcode::FUSE_WRITE), 1);
+    assert_eq!(count(fuse_opcode::FUSE_READ), 1);
+    assert_eq!(count(fuse_opcode::FUSE_RELEASE), 1);
+    assert_eq!(count(fuse_opcode::FUSE_LOOKUP), 3);
+    assert_eq!(count(fuse_opcode::FUSE_FORGET), 1);
+    assert_eq!(count(fuse_opcode::FUSE_BATCH_FORGET), 1);
+    assert_eq!(count(fuse_opcode::FUSE_DESTROY), 1);
+    assert_eq!(count(fuse_opcode::FUSE_GETATTR), 0);
+    assert_eq!(snapshot.requests(), 11);
+    // The opcodes are ordered
+    assert!(snapshot.opcodes.is_sorted_by_key(|(opcode, _)| *opcode as u32));
+
+    assert_eq!(snapshot.bytes_written, 10);
+    assert_eq!(snapshot.bytes_read, 8);
+    assert_eq!(snapshot.forgets, 3);
+    assert_eq!(snapshot.in_flight, 0);
+
+    let lookup = snapshot.opcode(fuse_opcode::FUSE_LOOKUP).unwrap();
+    assert_eq!(lookup.errors.get(&Errno::ENOENT), Some(&2));
+    assert_eq!(lookup.error_count(), 2);
+    assert_eq!(snapshot.opcode(fuse_opcode::FUSE_WRITE).unwrap().error_count(), 0);
+
+    // Every answered request has a latency
+    for (opcode, stats) in &snapshot.opcodes {
+        let latency = &stats.latency;
+        assert_eq!(latency.count(), stats.count, "{opcode:?}");
+        assert_eq!(latency.buckets().map(|(_, count)| count).sum::<u64>(), stats.count);
+        assert!(latency.mean().unwrap() <= latency.quantile(1.0).unwrap(), "{opcode:?}");
+    }
+    let latency = &lookup.latency;
+    assert!(latency.sum() >= latency.mean().unwrap() * 3);
+    assert!(latency.quantile(0.5).unwrap() <= latency.quantile(1.0).unwrap());
+    assert_eq!(latency.quantile(0.0), latency.buckets()
+        .find(|&(_, count)| count > 0)
+        .map(|(bound, _)| bound.unwrap_or(Duration::MAX)));
+}
+
+#[tokio::test]
+async fn timed_out_requests_are_counted_as_errors() {
+    let config = SessionConfig::new().handler_timeout(Duration::from_millis(10));
+    let (kernel, metrics) = start(Stuck, config).await;
+    let lookup = kernel.lookup(FUSE_ROOT_ID, "a");
+
+    // The request is in flight until it times out
+    let in_flight = async {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        metrics.snapshot().in_flight
+    };
+    let (lookup, in_flight) = tokio::join!(lookup, in_flight);
+    assert_eq!(lookup.unwrap_err(), Errno::ETIMEDOUT);
+    assert_eq!(in_flight, 1);
+    kernel.shutdown().await.unwrap();
+
+    let snapshot = metrics.snapshot();
+    let stats = snapshot.opcode(fuse_opcode::FUSE_LOOKUP).unwrap();
+    assert_eq!(stats.errors.get(&Errno::ETIMEDOUT), Some(&1));
+    assert!(stats.latency.mean().unwrap() >= Duration::from_millis(10));
+    assert!(stats.latency.quantile(1.0).unwrap() >= Duration::from_millis(10));
+    assert_eq!(snapshot.in_flight, 0);
+}
+
+#[test]
+fn empty_histograms_have_no_statistics() {
+    let snapshot = Metrics::new().snapshot();
+    assert!(snapshot.opcodes.is_empty());
+    assert_eq!(snapshot.requests(), 0);
+
+    let histogram = Histogram::default();
+    assert_eq!(histogram.count(), 0);
+    assert_eq!((histogram.mean(), histogram.quantile(0.5)), (None, None));
+    let bounds: Vec<_> = histogram.buckets().map(|(bound, _)| bound).collect();
+    assert_eq!(bounds[0], Some(Duration::from_micros(1)));
+    assert_eq!(bounds[10], Some(Duration::from_micros(1024)));
+    assert_eq!(bounds.last(), Some(&None));
+}
@@ -0,0 +1,35 @@
+use std::io::ErrorKind;
+use std::path::Path;
+
+use fuse_async::Mount;
+
+#[tokio::test]
+async fn nul_bytes_are_rejected() {
+    if !Path::new("/dev/fuse").exists() {
+        eprintln!("skipped, /dev/fuse is not available");
+        return;
+    }
+
+    let mountpoint = std::env::temp_dir();
+    let mount = Mount::builder(&mountpoint, "mem\0fs").build().await;
+    assert_eq!(mount.err().unwrap().kind(), ErrorKind::InvalidInput);
+    let mount = Mount::builder(&mountpoint, "memfs").subtype("mem\0fs").build().await;
+    assert_eq!(mount.err().unwrap().kind(), ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn commas_are_rejected() {
+    if !Path::new("/dev/fuse").exists() {
+        eprintln!("skipped, /dev/fuse is not available");
+        return;
+    }
+
+    // A comma would inject another option into the mount options
+    let mountpoint = std::env::temp_dir();
+    let mount = Mount::builder(&mountpoint, "memfs,allow_other").build().await;
+    assert_eq!(mount.err().unwrap().kind(), ErrorKind::InvalidInput);
+    let mount = Mount::builder(&mountpoint, "memfs").subtype("mem,user_id=0").build().await;
+    let err = mount.err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("comma"), "{err}");
+}
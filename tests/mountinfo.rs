@@ -0,0 +1,135 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::Duration;
+
+use fuse_async::{FuseCtl, MemFs, Mount, MountInfo, Session};
+
+#[test]
+fn mountinfo_lines_are_parsed() {
+    let line = b"36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 shared:2 - ext3 /dev/root \
+        rw,errors=continue";
+    let mount = MountInfo::parse(line).unwrap();
+    assert_eq!(mount, MountInfo {
+        mount_id: 36,
+        parent_id: 35,
+        major: 98,
+        minor: 0,
+        root: "/mnt1".into(),
+        mountpoint: "/mnt2".into(),
+        mount_options: "rw,noatime".into(),
+        optional_fields: vec!["master:1".into(), "shared:2".into()],
+        fs_type: "ext3".into(),
+        source: "/dev/root".into(),
+        super_options: "rw,errors=continue".into(),
+    });
+    assert!(!mount.is_fuse());
+
+    let line = b"120 25 0:52 / /tmp/mnt rw,nosuid,nodev - fuse.memfs memfs rw,user_id=0";
+    let mount = MountInfo::parse(line).unwrap();
+    assert!(mount.optional_fields.is_empty());
+    assert!(mount.is_fuse());
+    assert_eq!(mount.connection_id(), 52);
+
+    for fs_type in ["fuse", "fuseblk"] {
+        let line = format!("1 2 259:3 / /mnt rw - {fs_type} /dev/sda1 rw");
+        let mount = MountInfo::parse(line.as_bytes()).unwrap();
+        assert!(mount.is_fuse());
+        assert_eq!(mount.connection_id(), 259 << 20 | 3);
+    }
+    let mount = MountInfo::parse(b"1 2 0:3 / /mnt rw - fusectl none rw").unwrap();
+    assert!(!mount.is_fuse());
+}
+
+#[test]
+fn octal_escapes_are_decoded() {
+    let line = br"1 2 0:40 /a\134b /mnt/with\040space\011tab\012newline rw - fuse.x my\040fs rw";
+    let mount = MountInfo::parse(line).unwrap();
+    assert_eq!(mount.root, Path::new(r"/a\b"));
+    assert_eq!(mount.mountpoint, Path::new("/mnt/with space\ttab\nnewline"));
+    assert_eq!(mount.source, "my fs");
+
+    // Incomplete or invalid escapes are kept as they are
+    let line = br"1 2 0:40 / /a\04 rw - fuse.x b\9999 rw";
+    let mount = MountInfo::parse(line).unwrap();
+    assert_eq!(mount.mountpoint, Path::new(r"/a\04"));
+    assert_eq!(mount.source, r"b\9999");
+}
+
+#[test]
+fn invalid_lines_are_rejected() {
+    for line in [
+        &b""[..],
+        b"36 35 98:0 /mnt1 /mnt2 rw master:1",
+        b"36 35 98:0 /mnt1 /mnt2 rw - ext3",
+        b"36 35 980 /mnt1 /mnt2 rw - ext3 /dev/root rw",
+        b"36 x 98:0 /mnt1 /mnt2 rw - ext3 /dev/root rw",
+        b"-1 35 98:0 /mnt1 /mnt2 rw - ext3 /dev/root rw",
+    ] {
+        let err = MountInfo::parse(line).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", String::from_utf8_lossy(line));
+    }
+}
+
+#[test]
+fn mount_table_is_read() {
+    let mounts = MountInfo::read_all().unwrap();
+    assert!(mounts.iter().any(|mount| mount.mountpoint == Path::new("/")));
+}
+
+#[tokio::test]
+async fn fusectl_paths_and_errors() {
+    let ctl = FuseCtl::new(u32::MAX);
+    assert_eq!(ctl.path(), Path::new("/sys/fs/fuse/connections/4294967295"));
+
+    let err = ctl.waiting().await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    let message = err.to_string();
+    assert!(message.contains("/sys/fs/fuse/connections/4294967295/waiting"), "{message}");
+    assert!(message.contains("Is fusectl mounted?"), "{message}");
+    assert_eq!(ctl.set_max_background(1).await.unwrap_err().kind(), ErrorKind::NotFound);
+}
+
+#[tokio::test]
+async fn mounts_are_found_with_their_connection() {
+    if !Path::new("/dev/fuse").exists() {
+        eprintln!("skipped, /dev/fuse is not available");
+        return;
+    }
+
+    let dir = std::env::temp_dir().join(format!("fuse-async-mountinfo-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("mnt")).unwrap();
+    // The mountpoint is canonicalized, like the kernel does
+    let mount = match Mount::builder(dir.join("mnt/../mnt/."), "memfs").build().await {
+        Ok(mount) => mount,
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            eprintln!("skipped, mounting is not permitted: {err}");
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        },
+        Err(err) => panic!("{err}"),
+    };
+    let mountpoint = dir.canonicalize().unwrap().join("mnt");
+    assert_eq!(mount.mountpoint(), mountpoint);
+
+    let info = mount.mount_info().unwrap();
+    assert_eq!(info.mountpoint, mountpoint);
+    assert_eq!(info.connection_id(), mount.connection_id());
+    assert_eq!(info.source, "memfs");
+    assert!(info.is_fuse());
+
+    let ctl = mount.fusectl();
+    if ctl.path().exists() {
+        // The INIT request waits until the session starts
+        assert_eq!(ctl.waiting().await.unwrap(), 1);
+        let max_background = ctl.max_background().await.unwrap();
+        assert!(max_background > 0);
+        assert!(ctl.congestion_threshold().await.unwrap() <= max_background);
+    }
+
+    let session = Session::new(mount, MemFs::new());
+    let shutdown = session.shutdown_handle();
+    let session = tokio::spawn(session.run());
+    shutdown.shutdown(Duration::from_secs(5)).await;
+    session.await.unwrap().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
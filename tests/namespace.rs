@@ -0,0 +1,95 @@
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use fuse_async::{MemFs, Mount, PreflightError, Session, unshare_user_namespace};
+
+/// `NS_GET_USERNS` from `linux/nsfs.h`
+const NS_GET_USERNS: u32 = 0xb701;
+
+/// Runs a shell script in the mount namespace `ns` and its user namespace,
+/// returns its output.
+fn run_in(ns: &OwnedFd, script: &str) -> String {
+    let fd = unsafe { libc::ioctl(ns.as_raw_fd(), NS_GET_USERNS as _) };
+    assert!(fd >= 0, "{}", std::io::Error::last_os_error());
+    // SAFETY: the ioctl returned a new file descriptor
+    let userns = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let (userns, ns) = (userns.as_raw_fd(), ns.as_raw_fd());
+    let mut command = Command::new("sh");
+    command.args(["-c", script]);
+    // SAFETY: only setns() is called between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            for (fd, kind) in [(userns, libc::CLONE_NEWUSER), (ns, libc::CLONE_NEWNS)] {
+                if libc::setns(fd, kind) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    let output = command.output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[tokio::test]
+async fn memfs_is_mounted_in_a_user_namespace() {
+    if !Path::new("/dev/fuse").exists() {
+        eprintln!("skipped, /dev/fuse is not available");
+        return;
+    }
+
+    // The test harness is multithreaded, the namespaces are created by a
+    // helper process
+    let ns = unshare_user_namespace().unwrap();
+    let mountpoint = std::env::temp_dir().join(format!("fuse-async-ns-{}", std::process::id()));
+    std::fs::create_dir_all(&mountpoint).unwrap();
+
+    let mount = Mount::builder(&mountpoint, "memfs")
+        .mount_namespace(ns.try_clone().unwrap())
+        .build()
+        .await
+        .unwrap();
+    let session = Session::new(mount, MemFs::new());
+    let shutdown = session.shutdown_handle();
+    let session = tokio::spawn(session.run());
+
+    let path = mountpoint.display();
+    let script = format!("echo hello > {path}/file && cat {path}/file && id -u");
+    let output = tokio::task::spawn_blocking(move || run_in(&ns, &script)).await.unwrap();
+    assert_eq!(output, "hello\n0\n");
+
+    // The mount is only visible inside the namespace
+    assert!(!mountpoint.join("file").exists());
+
+    shutdown.shutdown(Duration::from_secs(5)).await;
+    session.await.unwrap().unwrap();
+    std::fs::remove_dir(&mountpoint).unwrap();
+}
+
+#[tokio::test]
+async fn helper_errors_keep_their_type() {
+    if !Path::new("/dev/fuse").exists() {
+        eprintln!("skipped, /dev/fuse is not available");
+        return;
+    }
+
+    let ns = unshare_user_namespace().unwrap();
+    let mountpoint = std::env::temp_dir()
+        .join(format!("fuse-async-ns-missing-{}", std::process::id()));
+    let err = Mount::builder(&mountpoint, "memfs")
+        .mount_namespace(ns)
+        .preflight_checks(true)
+        .build()
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    let preflight = err.get_ref().and_then(|err| err.downcast_ref::<PreflightError>());
+    assert!(matches!(preflight, Some(PreflightError::Inaccessible { .. })), "{err}");
+}
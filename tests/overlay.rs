@@ -0,0 +1,198 @@
+#![cfg(feature = "passthrough")]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use fuse_async::protocol::*;
+use fuse_async::testing::MockKernel;
+use fuse_async::{Errno, MemFs, OverlayFs, PassthroughFs};
+
+/// A lower layer in a temporary directory, removed when dropped.
+struct Lower(PathBuf);
+
+impl Lower {
+    /// Creates the directory with `file`, `dir/a`, `dir/b` and `gone/x`.
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir()
+            .join(format!("fuse-async-overlay-{}-{name}", std::process::id()));
+        fs::create_dir_all(path.join("dir")).unwrap();
+        fs::create_dir_all(path.join("gone")).unwrap();
+        fs::write(path.join("file"), "lower").unwrap();
+        fs::write(path.join("dir/a"), "a").unwrap();
+        fs::write(path.join("dir/b"), "b").unwrap();
+        fs::write(path.join("gone/x"), "x").unwrap();
+        Self(path)
+    }
+
+    async fn mount(&self) -> MockKernel {
+        let fs = OverlayFs::new(MemFs::new()).lower(PassthroughFs::new(&self.0).unwrap());
+        MockKernel::start(fs).await.unwrap()
+    }
+
+    fn read(&self, path: impl AsRef<Path>) -> String {
+        fs::read_to_string(self.0.join(path)).unwrap()
+    }
+}
+
+impl Drop for Lower {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+async fn lookup(kernel: &MockKernel, path: &str) -> Result<fuse_entry_out, Errno> {
+    let mut names = path.split('/');
+    let mut entry = kernel.lookup(FUSE_ROOT_ID, names.next().unwrap()).await?;
+    for name in names {
+        entry = kernel.lookup(entry.nodeid, name).await?;
+    }
+    Ok(entry)
+}
+
+async fn read(kernel: &MockKernel, path: &str) -> Vec<u8> {
+    let ino = lookup(kernel, path).await.unwrap().nodeid;
+    let open = kernel.open(ino, libc::O_RDONLY).await.unwrap();
+    let data = kernel.read(ino, open.fh, 0, 4096).await.unwrap();
+    kernel.release(ino, open.fh).await.unwrap();
+    data
+}
+
+async fn create(kernel: &MockKernel, parent: u64, name: &str, data: &[u8]) -> u64 {
+    let (entry, open) = kernel.create(parent, name, libc::S_IFREG | 0o644, libc::O_RDWR)
+        .await
+        .unwrap();
+    kernel.write(entry.nodeid, open.fh, 0, data).await.unwrap();
+    kernel.release(entry.nodeid, open.fh).await.unwrap();
+    entry.nodeid
+}
+
+/// The names in a directory, sorted, without `.` and `..`.
+async fn names(kernel: &MockKernel, dir: u64) -> Vec<String> {
+    let open = kernel.opendir(dir, libc::O_RDONLY).await.unwrap();
+    let mut names = Vec::new();
+    let mut offset = 0;
+    loop {
+        let entries = kernel.readdir(dir, open.fh, offset, 256).await.unwrap();
+        let Some(last) = entries.last() else { break };
+        offset = last.offset;
+        names.extend(entries.into_iter().map(|entry| entry.name.into_string().unwrap()));
+    }
+    kernel.releasedir(dir, open.fh).await.unwrap();
+    names.retain(|name| name != "." && name != "..");
+    names.sort();
+    names
+}
+
+#[tokio::test]
+async fn lower_files_are_copied_up_before_changes() {
+    let lower = Lower::new("copy-up");
+    let kernel = lower.mount().await;
+    assert_eq!(read(&kernel, "file").await, b"lower");
+
+    let file = lookup(&kernel, "file").await.unwrap().nodeid;
+    let open = kernel.open(file, libc::O_RDWR).await.unwrap();
+    assert_eq!(kernel.write(file, open.fh, 0, b"upper!").await.unwrap(), 6);
+    kernel.release(file, open.fh).await.unwrap();
+    assert_eq!(read(&kernel, "file").await, b"upper!");
+    assert_eq!(kernel.getattr(file).await.unwrap().attr.size, 6);
+
+    // Copying up a file also copies up its directory, which stays merged
+    let a = lookup(&kernel, "dir/a").await.unwrap().nodeid;
+    let open = kernel.open(a, libc::O_WRONLY | libc::O_TRUNC).await.unwrap();
+    kernel.write(a, open.fh, 0, b"changed").await.unwrap();
+    kernel.release(a, open.fh).await.unwrap();
+    let dir = lookup(&kernel, "dir").await.unwrap().nodeid;
+    assert_eq!(names(&kernel, dir).await, ["a", "b"]);
+    assert_eq!(read(&kernel, "dir/a").await, b"changed");
+    assert_eq!(read(&kernel, "dir/b").await, b"b");
+
+    kernel.shutdown().await.unwrap();
+    assert_eq!(lower.read("file"), "lower");
+    assert_eq!(lower.read("dir/a"), "a");
+}
+
+#[tokio::test]
+async fn removed_lower_files_are_hidden_by_whiteouts() {
+    let lower = Lower::new("whiteout");
+    let kernel = lower.mount().await;
+
+    kernel.unlink(FUSE_ROOT_ID, "file").await.unwrap();
+    assert_eq!(lookup(&kernel, "file").await.unwrap_err(), Errno::ENOENT);
+    assert_eq!(names(&kernel, FUSE_ROOT_ID).await, ["dir", "gone"]);
+    assert_eq!(kernel.unlink(FUSE_ROOT_ID, "file").await.unwrap_err(), Errno::ENOENT);
+
+    // A new file replaces the whiteout
+    create(&kernel, FUSE_ROOT_ID, "file", b"again").await;
+    assert_eq!(read(&kernel, "file").await, b"again");
+    assert_eq!(names(&kernel, FUSE_ROOT_ID).await, ["dir", "file", "gone"]);
+
+    // Renaming over a whiteout in a merged directory
+    let dir = lookup(&kernel, "dir").await.unwrap().nodeid;
+    kernel.unlink(dir, "a").await.unwrap();
+    assert_eq!(names(&kernel, dir).await, ["b"]);
+    create(&kernel, FUSE_ROOT_ID, "new", b"renamed").await;
+    kernel.rename(FUSE_ROOT_ID, "new", dir, "a", 0).await.unwrap();
+    assert_eq!(read(&kernel, "dir/a").await, b"renamed");
+    assert_eq!(names(&kernel, dir).await, ["a", "b"]);
+    assert_eq!(names(&kernel, FUSE_ROOT_ID).await, ["dir", "file", "gone"]);
+
+    // Renaming a lower file leaves a whiteout behind, the kernel looks it up
+    // first
+    lookup(&kernel, "dir/b").await.unwrap();
+    kernel.rename(dir, "b", FUSE_ROOT_ID, "b", 0).await.unwrap();
+    assert_eq!(lookup(&kernel, "dir/b").await.unwrap_err(), Errno::ENOENT);
+    assert_eq!(read(&kernel, "b").await, b"b");
+    assert_eq!(names(&kernel, dir).await, ["a"]);
+
+    kernel.shutdown().await.unwrap();
+    assert_eq!(lower.read("file"), "lower");
+    assert_eq!(lower.read("dir/a"), "a");
+    assert_eq!(lower.read("dir/b"), "b");
+}
+
+#[tokio::test]
+async fn recreated_directories_are_opaque() {
+    let lower = Lower::new("opaque");
+    let kernel = lower.mount().await;
+    let gone = lookup(&kernel, "gone").await.unwrap().nodeid;
+
+    // Only the directories without merged entries can be removed
+    let err = kernel.rmdir(FUSE_ROOT_ID, "gone").await.unwrap_err();
+    assert_eq!(err, Errno::ENOTEMPTY);
+    kernel.unlink(gone, "x").await.unwrap();
+    kernel.rmdir(FUSE_ROOT_ID, "gone").await.unwrap();
+    assert_eq!(lookup(&kernel, "gone").await.unwrap_err(), Errno::ENOENT);
+
+    // The new directory doesn't show the lower one
+    let gone = kernel.mkdir(FUSE_ROOT_ID, "gone", 0o755).await.unwrap().nodeid;
+    assert!(names(&kernel, gone).await.is_empty());
+    assert_eq!(lookup(&kernel, "gone/x").await.unwrap_err(), Errno::ENOENT);
+    create(&kernel, gone, "y", b"y").await;
+    assert_eq!(names(&kernel, gone).await, ["y"]);
+
+    kernel.shutdown().await.unwrap();
+    assert_eq!(lower.read("gone/x"), "x");
+}
+
+#[tokio::test]
+async fn directories_are_merged() {
+    let lower = Lower::new("merged");
+    let kernel = lower.mount().await;
+    let dir = lookup(&kernel, "dir").await.unwrap().nodeid;
+
+    create(&kernel, dir, "c", b"c").await;
+    kernel.mkdir(FUSE_ROOT_ID, "upper", 0o755).await.unwrap();
+    assert_eq!(names(&kernel, dir).await, ["a", "b", "c"]);
+    assert_eq!(names(&kernel, FUSE_ROOT_ID).await, ["dir", "file", "gone", "upper"]);
+    assert_eq!(read(&kernel, "dir/c").await, b"c");
+    assert_eq!(read(&kernel, "dir/a").await, b"a");
+
+    // Directories with lower files can't be renamed without redirects
+    let err = kernel.rename(FUSE_ROOT_ID, "dir", FUSE_ROOT_ID, "moved", 0).await.unwrap_err();
+    assert_eq!(err, Errno::EXDEV);
+    kernel.rename(FUSE_ROOT_ID, "upper", FUSE_ROOT_ID, "moved", 0).await.unwrap();
+    assert_eq!(names(&kernel, FUSE_ROOT_ID).await, ["dir", "file", "gone", "moved"]);
+
+    kernel.shutdown().await.unwrap();
+    assert!(!lower.0.join("dir/c").exists());
+}
@@ -0,0 +1,132 @@
+#![cfg(feature = "passthrough")]
+
+use std::fs;
+use std::path::PathBuf;
+
+use fuse_async::protocol::*;
+use fuse_async::testing::MockKernel;
+use fuse_async::{Errno, PassthroughFs};
+use zerocopy::FromZeros;
+
+/// A temporary directory, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir()
+            .join(format!("fuse-async-passthrough-{}-{name}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    async fn mount(&self) -> MockKernel {
+        MockKernel::start(PassthroughFs::new(&self.0).unwrap()).await.unwrap()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+async fn create(kernel: &MockKernel, parent: u64, name: &str, data: &[u8]) -> u64 {
+    let (entry, open) = kernel.create(parent, name, libc::S_IFREG | 0o644, libc::O_RDWR)
+        .await
+        .unwrap();
+    assert_eq!(kernel.write(entry.nodeid, open.fh, 0, data).await.unwrap(), data.len() as u32);
+    kernel.release(entry.nodeid, open.fh).await.unwrap();
+    entry.nodeid
+}
+
+async fn read(kernel: &MockKernel, ino: u64) -> Vec<u8> {
+    let open = kernel.open(ino, libc::O_RDONLY).await.unwrap();
+    let data = kernel.read(ino, open.fh, 0, 4096).await.unwrap();
+    kernel.release(ino, open.fh).await.unwrap();
+    data
+}
+
+#[tokio::test]
+async fn files_are_mirrored() {
+    let dir = TempDir::new("mirrored");
+    fs::write(dir.0.join("outside"), "from the host").unwrap();
+    let kernel = dir.mount().await;
+
+    let outside = kernel.lookup(FUSE_ROOT_ID, "outside").await.unwrap();
+    assert_eq!(outside.attr.size, 13);
+    assert_eq!(outside.attr.mode & libc::S_IFMT, libc::S_IFREG);
+    assert_eq!(read(&kernel, outside.nodeid).await, b"from the host");
+
+    let sub = kernel.mkdir(FUSE_ROOT_ID, "sub", 0o755).await.unwrap().nodeid;
+    let file = create(&kernel, sub, "file", b"written").await;
+    assert_eq!(fs::read(dir.0.join("sub/file")).unwrap(), b"written");
+    assert_eq!(kernel.lookup(sub, "file").await.unwrap().nodeid, file);
+    assert_eq!(kernel.getattr(file).await.unwrap().attr.size, 7);
+
+    let open = kernel.open(file, libc::O_WRONLY).await.unwrap();
+    kernel.write(file, open.fh, 4, b"ing down").await.unwrap();
+    kernel.release(file, open.fh).await.unwrap();
+    assert_eq!(read(&kernel, file).await, b"writing down");
+
+    // The inode follows the file when it is renamed, even from the host
+    kernel.rename(sub, "file", FUSE_ROOT_ID, "moved", 0).await.unwrap();
+    assert!(dir.0.join("moved").exists() && !dir.0.join("sub/file").exists());
+    fs::rename(dir.0.join("moved"), dir.0.join("sub/back")).unwrap();
+    assert_eq!(read(&kernel, file).await, b"writing down");
+    assert_eq!(kernel.lookup(sub, "back").await.unwrap().nodeid, file);
+
+    kernel.unlink(sub, "back").await.unwrap();
+    assert!(!dir.0.join("sub/back").exists());
+    assert_eq!(kernel.lookup(sub, "back").await.unwrap_err(), Errno::ENOENT);
+    kernel.rmdir(FUSE_ROOT_ID, "sub").await.unwrap();
+    assert!(!dir.0.join("sub").exists());
+    kernel.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn errors_are_mapped_from_the_syscalls() {
+    let dir = TempDir::new("errors");
+    let kernel = dir.mount().await;
+    let sub = kernel.mkdir(FUSE_ROOT_ID, "sub", 0o755).await.unwrap().nodeid;
+    let file = create(&kernel, sub, "file", b"data").await;
+    create(&kernel, FUSE_ROOT_ID, "other", b"").await;
+
+    assert_eq!(kernel.lookup(FUSE_ROOT_ID, "missing").await.unwrap_err(), Errno::ENOENT);
+    assert_eq!(kernel.lookup(file, "child").await.unwrap_err(), Errno::ENOTDIR);
+    let err = kernel.mkdir(FUSE_ROOT_ID, "sub", 0o755).await.unwrap_err();
+    assert_eq!(err, Errno::EEXIST);
+    let flags = libc::O_RDWR | libc::O_EXCL;
+    let err = kernel.create(sub, "file", libc::S_IFREG | 0o644, flags).await.unwrap_err();
+    assert_eq!(err, Errno::EEXIST);
+    assert_eq!(kernel.rmdir(FUSE_ROOT_ID, "sub").await.unwrap_err(), Errno::ENOTEMPTY);
+    assert_eq!(kernel.rmdir(sub, "file").await.unwrap_err(), Errno::ENOTDIR);
+    assert_eq!(kernel.unlink(FUSE_ROOT_ID, "sub").await.unwrap_err(), Errno::EISDIR);
+    assert_eq!(kernel.open(sub, libc::O_RDWR).await.unwrap_err(), Errno::EISDIR);
+
+    let noreplace = libc::RENAME_NOREPLACE;
+    let err = kernel.rename(sub, "file", FUSE_ROOT_ID, "other", noreplace).await.unwrap_err();
+    assert_eq!(err, Errno::EEXIST);
+    let err = kernel.rename(sub, "missing", FUSE_ROOT_ID, "new", 0).await.unwrap_err();
+    assert_eq!(err, Errno::ENOENT);
+    let err = kernel.rename(FUSE_ROOT_ID, "sub", sub, "inside", 0).await.unwrap_err();
+    assert_eq!(err, Errno::EINVAL);
+
+    // Unknown inodes and file handles
+    assert_eq!(kernel.getattr(12345).await.unwrap_err(), Errno::ESTALE);
+    assert_eq!(kernel.read(file, 12345, 0, 10).await.unwrap_err(), Errno::EBADF);
+
+    // Symbolic links can't be changed through /proc
+    let link = kernel.symlink(FUSE_ROOT_ID, "link", "sub/file").await.unwrap().nodeid;
+    assert_eq!(kernel.readlink(link).await.unwrap(), b"sub/file");
+    let mut arg = fuse_setattr_in::new_zeroed();
+    (arg.valid, arg.mode) = (SetattrValid::FATTR_MODE, 0o600);
+    assert_eq!(kernel.setattr(link, &arg).await.unwrap_err(), Errno::EOPNOTSUPP);
+
+    // The access checks use the mode of the file for other users
+    let attr = kernel.getattr(file).await.unwrap().attr;
+    let mut kernel = kernel;
+    kernel.set_caller(attr.uid + 1, attr.gid + 1, 1);
+    kernel.access(file, libc::R_OK as u32).await.unwrap();
+    assert_eq!(kernel.access(file, libc::W_OK as u32).await.unwrap_err(), Errno::EACCES);
+    kernel.shutdown().await.unwrap();
+}
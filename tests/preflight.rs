@@ -0,0 +1,234 @@
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use fuse_async::{MemFs, Mount, PreflightError, Session};
+
+/// Environment variable set when the test runs as an unprivileged user.
+const UNPRIVILEGED: &str = "FUSE_ASYNC_PREFLIGHT_UNPRIVILEGED";
+
+/// A temporary directory, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir()
+            .join(format!("fuse-async-preflight-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn fuse_available() -> bool {
+    let available = Path::new("/dev/fuse").exists();
+    if !available {
+        eprintln!("skipped, /dev/fuse is not available");
+    }
+    available
+}
+
+/// Builds a mount on `mountpoint` with the preflight checks.
+async fn check(mountpoint: &Path) -> io::Result<Mount> {
+    Mount::builder(mountpoint, "memfs").preflight_checks(true).build().await
+}
+
+fn preflight_error(err: &io::Error) -> Option<&PreflightError> {
+    err.get_ref().and_then(|err| err.downcast_ref::<PreflightError>())
+}
+
+async fn serve_and_stop(mount: Mount) {
+    let session = Session::new(mount, MemFs::new());
+    let shutdown = session.shutdown_handle();
+    let session = tokio::spawn(session.run());
+    shutdown.shutdown(Duration::from_secs(5)).await;
+    session.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn mountpoints_must_exist() {
+    if !fuse_available() {
+        return;
+    }
+
+    let dir = TempDir::new("missing");
+    let err = check(&dir.0.join("missing")).await.err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    let Some(PreflightError::Inaccessible { mountpoint, source }) = preflight_error(&err) else {
+        panic!("{err}");
+    };
+    assert_eq!(mountpoint, &dir.0.join("missing"));
+    assert_eq!(source.kind(), ErrorKind::NotFound);
+}
+
+#[tokio::test]
+async fn mountpoints_must_match_the_root() {
+    if !fuse_available() {
+        return;
+    }
+
+    let err = check(Path::new("/dev/null")).await.err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let Some(PreflightError::WrongType { expected, found, .. }) = preflight_error(&err) else {
+        panic!("{err}");
+    };
+    assert_eq!((*expected, *found), ("directory", "character device"));
+
+    let dir = TempDir::new("type");
+    let err = Mount::builder(&dir.0, "memfs")
+        .root_mode(libc::S_IFREG as u16 | 0o644)
+        .preflight_checks(true)
+        .build()
+        .await
+        .err()
+        .unwrap();
+    let Some(PreflightError::WrongType { expected, found, .. }) = preflight_error(&err) else {
+        panic!("{err}");
+    };
+    assert_eq!((*expected, *found), ("regular file", "directory"));
+}
+
+#[tokio::test]
+async fn stale_mounts_are_detected() {
+    if !fuse_available() {
+        return;
+    }
+
+    let dir = TempDir::new("stale");
+    let mount = match Mount::builder(&dir.0, "memfs").build().await {
+        Ok(mount) => mount,
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            eprintln!("skipped, mounting is not permitted: {err}");
+            return;
+        },
+        Err(err) => panic!("{err}"),
+    };
+    // Closing the device without unmounting aborts the connection
+    drop(mount);
+
+    let err = check(&dir.0).await.err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::NotConnected);
+    let Some(PreflightError::StaleMount { fs_type, .. }) = preflight_error(&err) else {
+        panic!("{err}");
+    };
+    assert_eq!(fs_type, "fuse");
+
+    let mount = Mount::builder(&dir.0, "memfs").unmount_stale(true).build().await.unwrap();
+    let info = mount.mount_info().unwrap();
+    serve_and_stop(mount).await;
+    // The stale mount was below the new one, both are gone
+    let mounts = fuse_async::MountInfo::read_all().unwrap();
+    assert!(!mounts.iter().any(|mount| mount.mountpoint == info.mountpoint));
+}
+
+/// Runs this test again in a user namespace where root is mapped to the
+/// uid 1000, without capabilities on the files outside of the namespace.
+fn run_unprivileged(test: &str, dir: &Path) {
+    let uid_map = CString::new("1000 0 1").unwrap();
+    let gid_map = CString::new("1000 0 1").unwrap();
+    let mut command = Command::new(std::env::current_exe().unwrap());
+    command.args([test, "--exact", "--nocapture"]).env(UNPRIVILEGED, dir);
+    // SAFETY: only unshare(), open(), write() and close() are called between
+    // fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if libc::unshare(libc::CLONE_NEWUSER) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            for (path, content) in [
+                (c"/proc/self/setgroups", c"deny"),
+                (c"/proc/self/uid_map", uid_map.as_c_str()),
+                (c"/proc/self/gid_map", gid_map.as_c_str()),
+            ] {
+                let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                let len = content.count_bytes();
+                if fd == -1 || libc::write(fd, content.as_ptr().cast(), len) != len as isize {
+                    return Err(io::Error::last_os_error());
+                }
+                libc::close(fd);
+            }
+            Ok(())
+        });
+    }
+
+    match command.output() {
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success(), "{stdout}{}", String::from_utf8_lossy(&output.stderr));
+            assert!(stdout.contains("1 passed"), "{stdout}");
+        },
+        Err(err) => eprintln!("skipped, user namespaces are not available: {err}"),
+    }
+}
+
+#[tokio::test]
+async fn unprivileged_users_must_own_and_write_the_mountpoint() {
+    if !fuse_available() {
+        return;
+    }
+
+    // In the user namespace, the files of root belong to the uid 1000 and
+    // the ones of other users to the overflow uid
+    if let Some(dir) = std::env::var_os(UNPRIVILEGED) {
+        let dir = Path::new(&dir);
+        assert_eq!(unsafe { libc::geteuid() }, 1000);
+
+        let err = check(&dir.join("sticky")).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let Some(PreflightError::NotOwner { owner, uid, .. }) = preflight_error(&err) else {
+            panic!("{err}");
+        };
+        assert_eq!((*owner, *uid), (65534, 1000));
+
+        let err = check(&dir.join("file")).await.err().unwrap();
+        assert!(matches!(preflight_error(&err), Some(PreflightError::NotOwner { .. })), "{err}");
+
+        let err = check(&dir.join("readonly")).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let error = preflight_error(&err);
+        assert!(matches!(error, Some(PreflightError::NotWritable { .. })), "{err}");
+
+        // The checks pass, then mounting fails without CAP_SYS_ADMIN
+        for path in ["writable", "shared"] {
+            let err = check(&dir.join(path)).await.err().unwrap();
+            assert!(preflight_error(&err).is_none(), "{err}");
+        }
+        return;
+    }
+
+    if unsafe { libc::geteuid() } != 0 {
+        eprintln!("skipped, the files of other users can only be created by root");
+        return;
+    }
+    let dir = TempDir::new("unprivileged");
+    let mode = |path: &str, mode: u32| {
+        std::fs::set_permissions(dir.0.join(path), PermissionsExt::from_mode(mode)).unwrap();
+    };
+    let chown = |path: &str| std::os::unix::fs::chown(dir.0.join(path), Some(12345), None);
+
+    // The owner of the test directory is mapped
+    mode("", 0o755);
+    for path in ["sticky", "readonly", "writable", "shared"] {
+        std::fs::create_dir(dir.0.join(path)).unwrap();
+    }
+    std::fs::write(dir.0.join("file"), "").unwrap();
+    mode("sticky", 0o1777);
+    chown("sticky").unwrap();
+    mode("file", 0o666);
+    chown("file").unwrap();
+    mode("readonly", 0o555);
+    // Directories without the sticky bit can belong to other users
+    mode("shared", 0o777);
+    chown("shared").unwrap();
+
+    run_unprivileged("unprivileged_users_must_own_and_write_the_mountpoint", &dir.0);
+}
@@ -0,0 +1,82 @@
+use std::ffi::OsStr;
+use std::time::Duration;
+
+use fuse_async::protocol::*;
+use fuse_async::testing::MockKernel;
+use fuse_async::transport::MemoryTransport;
+use fuse_async::{Errno, Filesystem, MemFs, Request, Session, SessionConfig};
+
+/// Panics on lookups and never answers getattr.
+struct Faulty;
+
+impl Filesystem for Faulty {
+    async fn lookup(
+        &self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr
+    ) -> Result<fuse_entry_out, Errno> {
+        panic!("lookup failed");
+    }
+
+    async fn getattr(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _arg: &fuse_getattr_in
+    ) -> Result<fuse_attr_out, Errno> {
+        std::future::pending().await
+    }
+}
+
+#[tokio::test]
+async fn panicking_handler_is_answered_with_eio() {
+    let kernel = MockKernel::start(Faulty).await.unwrap();
+    assert_eq!(kernel.lookup(FUSE_ROOT_ID, "a").await.unwrap_err(), Errno::EIO);
+
+    let summary = kernel.shutdown().await.unwrap();
+    assert_eq!(summary.errors, 1);
+}
+
+#[tokio::test]
+async fn interrupted_handler_is_answered_with_eintr() {
+    let kernel = MockKernel::start(Faulty).await.unwrap();
+    let getattr = kernel.getattr(FUSE_ROOT_ID);
+    // The getattr is sent when it is first polled, before the interrupt
+    let interrupt = async { kernel.interrupt(kernel.last_unique()).unwrap() };
+    let (getattr, ()) = tokio::join!(getattr, interrupt);
+    assert_eq!(getattr.unwrap_err(), Errno::EINTR);
+
+    let summary = kernel.shutdown().await.unwrap();
+    assert_eq!(summary.errors, 1);
+}
+
+#[tokio::test]
+async fn oversized_request_fails_alone() {
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    let (entry, open) = kernel.create(FUSE_ROOT_ID, "file", libc::S_IFREG | 0o644, libc::O_RDWR)
+        .await
+        .unwrap();
+
+    let data = vec![0; 2 * kernel.init_reply().max_write as usize];
+    let err = kernel.write(entry.nodeid, open.fh, 0, &data).await.unwrap_err();
+    assert_eq!(err, Errno::EIO);
+    // The session keeps going
+    assert_eq!(kernel.write(entry.nodeid, open.fh, 0, b"data").await, Ok(4));
+
+    let summary = kernel.shutdown().await.unwrap();
+    assert_eq!(summary.errors, 0);
+}
+
+#[tokio::test]
+async fn shutdown_before_running_is_kept() {
+    let (transport, _peer) = MemoryTransport::new();
+    let session = Session::with_transport(transport, MemFs::new(), SessionConfig::new()).unwrap();
+    let shutdown = session.shutdown_handle();
+    let session = tokio::spawn(session.run());
+
+    // The session task has not started yet
+    let stopped = shutdown.shutdown(Duration::from_secs(5));
+    tokio::time::timeout(Duration::from_secs(1), stopped).await.unwrap();
+    assert_eq!(session.await.unwrap().unwrap().cancelled, 0);
+}
@@ -0,0 +1,27 @@
+use std::time::{Duration, SystemTime};
+
+use fuse_async::protocol::*;
+use fuse_async::{Errno, SetattrRequest, TimeOrNow};
+use zerocopy::FromZeros;
+
+fn set_mtime(secs: u64, nsecs: u32) -> Result<Option<TimeOrNow>, Errno> {
+    let mut arg = fuse_setattr_in::new_zeroed();
+    arg.valid = SetattrValid::FATTR_MTIME;
+    (arg.mtime, arg.mtimensec) = (secs, nsecs);
+    SetattrRequest::try_from(&arg).map(|request| request.mtime)
+}
+
+#[test]
+fn times_are_decoded_around_the_epoch() {
+    let after = SystemTime::UNIX_EPOCH + Duration::new(10, 5);
+    assert_eq!(set_mtime(10, 5), Ok(Some(TimeOrNow::Time(after))));
+    // The seconds are signed, the nanoseconds are counted forward
+    let before = SystemTime::UNIX_EPOCH - Duration::from_millis(500);
+    assert_eq!(set_mtime(-1i64 as u64, 500_000_000), Ok(Some(TimeOrNow::Time(before))));
+}
+
+#[test]
+fn invalid_times_are_rejected() {
+    assert_eq!(set_mtime(0, 1_000_000_000), Err(Errno::EINVAL));
+    assert_eq!(set_mtime(i64::MAX as u64, u32::MAX), Err(Errno::EINVAL));
+}
@@ -0,0 +1,247 @@
+use std::io::{self, ErrorKind, Write};
+use std::sync::{Arc, Mutex};
+
+use fuse_async::protocol::*;
+use fuse_async::testing::MockKernel;
+use fuse_async::transport::{Direction, Record, Recording, Tracer};
+use fuse_async::{Errno, MemFs, SessionConfig};
+use zerocopy::IntoBytes;
+
+/// An output shared with the tracer of the session.
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8(self.contents()).unwrap()
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs a session with a few requests, returning what was printed and the
+/// recording.
+async fn record() -> (String, Vec<u8>) {
+    let (output, recording) = (Buffer::default(), Buffer::default());
+    let tracer = Tracer::new().output(output.clone()).record(recording.clone()).unwrap();
+    let init = MockKernel::default_init();
+    let kernel = MockKernel::start_traced(MemFs::new(), SessionConfig::new(), init, tracer)
+        .await
+        .unwrap();
+
+    let (entry, open) = kernel.create(FUSE_ROOT_ID, "file", libc::S_IFREG | 0o644, libc::O_RDWR)
+        .await
+        .unwrap();
+    kernel.write(entry.nodeid, open.fh, 0, b"0123456789").await.unwrap();
+    kernel.read(entry.nodeid, open.fh, 0, 100).await.unwrap();
+    kernel.release(entry.nodeid, open.fh).await.unwrap();
+    assert_eq!(kernel.lookup(FUSE_ROOT_ID, "missing").await.unwrap_err(), Errno::ENOENT);
+    kernel.getattr(entry.nodeid).await.unwrap();
+    kernel.forget(entry.nodeid, 1).unwrap();
+    kernel.shutdown().await.unwrap();
+
+    (output.text(), recording.contents())
+}
+
+fn read(recording: &[u8]) -> Vec<Record> {
+    Recording::new(recording).unwrap().collect::<io::Result<_>>().unwrap()
+}
+
+fn replay(records: &[Record], tracer: Tracer) -> String {
+    let output = Buffer::default();
+    let mut tracer = tracer.output(output.clone());
+    for record in records {
+        tracer.trace(record);
+    }
+    drop(tracer);
+    output.text()
+}
+
+fn unique(record: &Record) -> u64 {
+    let at = match record.direction {
+        Direction::Request => std::mem::offset_of!(fuse_in_header, unique),
+        Direction::Reply => std::mem::offset_of!(fuse_out_header, unique),
+    };
+    u64::from_ne_bytes(record.data[at..at + 8].try_into().unwrap())
+}
+
+#[tokio::test]
+async fn replays_are_printed_like_the_session() {
+    let (live, recording) = record().await;
+    let records = read(&recording);
+
+    // Every message is recorded in order, each request before its reply
+    let requests: Vec<_> = records.iter()
+        .filter(|record| record.direction == Direction::Request)
+        .collect();
+    let opcode = |record: &Record| {
+        let at = std::mem::offset_of!(fuse_in_header, opcode);
+        u32::from_ne_bytes(record.data[at..at + 4].try_into().unwrap())
+    };
+    assert_eq!(opcode(requests[0]), fuse_opcode::FUSE_INIT as u32);
+    assert_eq!(opcode(requests.last().unwrap()), fuse_opcode::FUSE_DESTROY as u32);
+    assert_eq!(requests.len(), 9);
+    // The forget has no reply
+    assert_eq!(records.len(), 17);
+    for (index, record) in records.iter().enumerate() {
+        let len = u32::from_ne_bytes(record.data[..4].try_into().unwrap());
+        assert_eq!(len as usize, record.data.len());
+        match record.direction {
+            Direction::Request => (),
+            Direction::Reply => {
+                let request = records[..index].iter()
+                    .rfind(|request| request.direction == Direction::Request
+                        && unique(request) == unique(record));
+                assert!(request.is_some_and(|request| request.time <= record.time));
+            },
+        }
+    }
+    assert!(records.is_sorted_by_key(|record| record.time));
+
+    // Each message is printed on its line, with the same times and latencies
+    assert_eq!(live.lines().count(), records.len());
+    assert!(live.contains("FUSE_LOOKUP nodeid=1"), "{live}");
+    assert!(live.contains("ENOENT"), "{live}");
+    assert_eq!(replay(&records, Tracer::new()), live);
+}
+
+#[tokio::test]
+async fn replays_are_filtered() {
+    let (live, recording) = record().await;
+    let records = read(&recording);
+
+    let lookups: String = live.lines()
+        .filter(|line| line.contains(" FUSE_LOOKUP "))
+        .map(|line| format!("{line}\n"))
+        .collect();
+    assert_eq!(lookups.lines().count(), 2);
+    assert_eq!(replay(&records, Tracer::new().opcode(fuse_opcode::FUSE_LOOKUP)), lookups);
+
+    // The replies are shown with the request of the node
+    let nodeid = replay(&records, Tracer::new().nodeid(2));
+    let lines: Vec<_> = nodeid.lines().collect();
+    assert!(!lines.is_empty() && lines.iter().all(|line| live.contains(line)), "{nodeid}");
+    for opcode in ["FUSE_WRITE", "FUSE_READ", "FUSE_RELEASE", "FUSE_GETATTR", "FUSE_FORGET"] {
+        assert!(nodeid.contains(opcode), "{nodeid}");
+    }
+    assert!(!nodeid.contains("FUSE_LOOKUP") && !nodeid.contains("FUSE_INIT"), "{nodeid}");
+}
+
+#[tokio::test]
+async fn replayed_requests_get_the_same_replies() {
+    let (_, recording) = record().await;
+    let records = read(&recording);
+
+    // The requests are sent again to a new session, with their arguments
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    for (index, request) in records.iter().enumerate() {
+        let at = std::mem::offset_of!(fuse_in_header, opcode);
+        let opcode = u32::from_ne_bytes(request.data[at..at + 4].try_into().unwrap());
+        if request.direction == Direction::Reply
+            || opcode == fuse_opcode::FUSE_INIT as u32
+            || opcode == fuse_opcode::FUSE_DESTROY as u32
+            || opcode == fuse_opcode::FUSE_FORGET as u32
+        {
+            continue;
+        }
+        let at = std::mem::offset_of!(fuse_in_header, nodeid);
+        let nodeid = u64::from_ne_bytes(request.data[at..at + 8].try_into().unwrap());
+        let args = &request.data[size_of::<fuse_in_header>()..];
+        let reply = kernel.request_raw(opcode, nodeid, &[args]).await;
+
+        let recorded = records[index..].iter()
+            .find(|reply| reply.direction == Direction::Reply && unique(reply) == unique(request))
+            .unwrap();
+        let error = i32::from_ne_bytes(recorded.data[4..8].try_into().unwrap());
+        let body = &recorded.data[size_of::<fuse_out_header>()..];
+        match reply {
+            Ok(reply) => {
+                assert_eq!(error, 0);
+                assert_eq!(reply.len(), body.len());
+            },
+            Err(errno) => assert_eq!(Errno::new(-error), Some(errno)),
+        }
+    }
+    // The attributes hold the times of the new session, the file is the same
+    let attr = kernel.getattr(2).await.unwrap().attr;
+    assert_eq!((attr.ino, attr.size, attr.mode), (2, 10, libc::S_IFREG | 0o644));
+    kernel.shutdown().await.unwrap();
+}
+
+#[test]
+fn invalid_recordings_are_rejected() {
+    let err = Recording::new(&b"FUSEREC0"[..]).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    let err = Recording::new(&b"FUSE"[..]).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(Recording::new(&b"FUSEREC1"[..]).unwrap().count(), 0);
+
+    let header = fuse_in_header {
+        len: size_of::<fuse_in_header>() as u32,
+        opcode: fuse_opcode::FUSE_STATFS,
+        unique: 2,
+        nodeid: FUSE_ROOT_ID,
+        uid: 0,
+        gid: 0,
+        pid: 1,
+        total_extlen: 0,
+        padding: 0,
+    };
+    let mut recording = b"FUSEREC1".to_vec();
+    recording.push(0);
+    recording.extend_from_slice(&42u64.to_le_bytes());
+    recording.extend_from_slice(&header.len.to_le_bytes());
+    recording.extend_from_slice(header.as_bytes());
+    let records = read(&recording);
+    assert_eq!(records.len(), 1);
+    assert_eq!((records[0].direction, records[0].time.as_nanos()), (Direction::Request, 42));
+    assert_eq!(records[0].data, header.as_bytes());
+
+    // A truncated message, then a bad direction
+    let mut records = Recording::new(&recording[..recording.len() - 1]).unwrap();
+    assert_eq!(records.next().unwrap().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    recording[8] = 2;
+    let mut records = Recording::new(&recording[..]).unwrap();
+    assert_eq!(records.next().unwrap().unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "passthrough")]
+#[tokio::test]
+async fn recordings_are_replayed_by_the_command() {
+    use std::process::Command;
+
+    let (live, recording) = record().await;
+    let path = std::env::temp_dir()
+        .join(format!("fuse-async-trace-{}.rec", std::process::id()));
+    std::fs::write(&path, recording).unwrap();
+
+    let trace = || Command::new(env!("CARGO_BIN_EXE_fuse-async-trace"));
+    let output = trace().arg("--replay").arg(&path).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), live);
+
+    let output = trace().args(["--opcode", "lookup", "--replay"]).arg(&path).output().unwrap();
+    let lookups: Vec<_> = live.lines().filter(|line| line.contains(" FUSE_LOOKUP ")).collect();
+    assert_eq!(String::from_utf8(output.stderr).unwrap().lines().collect::<Vec<_>>(), lookups);
+
+    let output = trace().args(["--opcode", "nothing", "--replay"]).arg(&path).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown opcode nothing"));
+    std::fs::remove_file(&path).unwrap();
+
+    let output = trace().arg("--replay").arg(&path).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Failed to open"));
+}
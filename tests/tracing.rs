@@ -0,0 +1,173 @@
+#![cfg(feature = "tracing")]
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use fuse_async::protocol::*;
+use fuse_async::testing::MockKernel;
+use fuse_async::{Errno, MemFs};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+type Fields = BTreeMap<&'static str, String>;
+
+#[derive(Debug)]
+struct CapturedSpan {
+    metadata: &'static Metadata<'static>,
+    fields: Fields,
+}
+
+#[derive(Debug)]
+struct CapturedEvent {
+    /// Index of the span of the event.
+    span: Option<usize>,
+    fields: Fields,
+}
+
+impl CapturedEvent {
+    fn message(&self) -> &str {
+        self.fields.get("message").map_or("", String::as_str)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Captured {
+    spans: Vec<CapturedSpan>,
+    events: Vec<CapturedEvent>,
+    /// Spans entered on the thread of the test, the session runs there too.
+    entered: Vec<usize>,
+}
+
+impl Captured {
+    /// The requests with `opcode`, with the events logged in their span.
+    fn requests(&self, opcode: &str) -> Vec<(&Fields, Vec<&CapturedEvent>)> {
+        self.spans.iter()
+            .enumerate()
+            .filter(|(_, span)| span.metadata.name() == "request")
+            .filter(|(_, span)| span.fields["opcode"] == opcode)
+            .map(|(index, span)| {
+                let events = self.events.iter().filter(|event| event.span == Some(index));
+                (&span.fields, events.collect())
+            })
+            .collect()
+    }
+}
+
+/// Records the spans and the events, with the debug format of their fields.
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Captured>>);
+
+struct Visitor<'a>(&'a mut Fields);
+
+impl Visit for Visitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name(), format!("{value:?}"));
+    }
+}
+
+impl Subscriber for Capture {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut fields = Fields::new();
+        span.record(&mut Visitor(&mut fields));
+        let mut captured = self.0.lock().unwrap();
+        captured.spans.push(CapturedSpan { metadata: span.metadata(), fields });
+        Id::from_u64(captured.spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut captured = self.0.lock().unwrap();
+        let span = &mut captured.spans[span.into_u64() as usize - 1];
+        values.record(&mut Visitor(&mut span.fields));
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::new();
+        event.record(&mut Visitor(&mut fields));
+        let mut captured = self.0.lock().unwrap();
+        let span = match event.parent() {
+            Some(parent) => Some(parent.into_u64() as usize - 1),
+            None => captured.entered.last().copied(),
+        };
+        captured.events.push(CapturedEvent { span, fields });
+    }
+
+    fn enter(&self, span: &Id) {
+        self.0.lock().unwrap().entered.push(span.into_u64() as usize - 1);
+    }
+
+    fn exit(&self, _span: &Id) {
+        self.0.lock().unwrap().entered.pop();
+    }
+}
+
+#[tokio::test]
+async fn requests_are_traced_in_spans() {
+    let capture = Capture::default();
+    let _guard = tracing::subscriber::set_default(capture.clone());
+
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    kernel.getattr(FUSE_ROOT_ID).await.unwrap();
+    assert_eq!(kernel.lookup(FUSE_ROOT_ID, "missing").await.unwrap_err(), Errno::ENOENT);
+    kernel.shutdown().await.unwrap();
+
+    let captured = capture.0.lock().unwrap();
+    let uid = unsafe { libc::getuid() }.to_string();
+    let pid = std::process::id().to_string();
+
+    let init = captured.requests("FUSE_INIT");
+    let [(fields, events)] = &init[..] else { panic!("{captured:#?}") };
+    assert_eq!(fields["unique"], "2");
+    assert!(events.iter().any(|event| event.message() == "request"
+        && event.fields["args"].starts_with("Init")), "{events:#?}");
+    assert!(events.iter().any(|event| event.message() == "reply"
+        && event.fields["out"].contains("max_write")), "{events:#?}");
+
+    let getattr = captured.requests("FUSE_GETATTR");
+    let [(fields, events)] = &getattr[..] else { panic!("{captured:#?}") };
+    assert_eq!(fields["nodeid"], FUSE_ROOT_ID.to_string());
+    assert_eq!((&fields["uid"], &fields["pid"]), (&uid, &pid));
+    assert!(fields.contains_key("latency") && !fields.contains_key("errno"), "{fields:#?}");
+    let messages: Vec<_> = events.iter().map(|event| event.message()).collect();
+    assert_eq!(messages, ["request", "reply"]);
+    assert!(events[0].fields["args"].starts_with("Getattr"), "{events:#?}");
+    assert!(events[1].fields["len"].parse::<usize>().unwrap() > 0);
+
+    let lookup = captured.requests("FUSE_LOOKUP");
+    let [(fields, events)] = &lookup[..] else { panic!("{captured:#?}") };
+    assert_eq!(fields["errno"], "ENOENT");
+    assert!(fields.contains_key("latency"), "{fields:#?}");
+    assert!(events[0].fields["args"].contains("missing"), "{events:#?}");
+
+    let destroy = captured.requests("FUSE_DESTROY");
+    let [(_, events)] = &destroy[..] else { panic!("{captured:#?}") };
+    assert_eq!(events[0].message(), "request");
+}
+
+#[tokio::test]
+async fn malformed_requests_are_dumped() {
+    let capture = Capture::default();
+    let _guard = tracing::subscriber::set_default(capture.clone());
+
+    let kernel = MockKernel::start(MemFs::new()).await.unwrap();
+    let reply = kernel.request_raw(u32::MAX, FUSE_ROOT_ID, &[b"whatever"]).await;
+    assert_eq!(reply.unwrap_err(), Errno::ENOSYS);
+    kernel.shutdown().await.unwrap();
+
+    let captured = capture.0.lock().unwrap();
+    let event = captured.events.iter()
+        .find(|event| event.message().starts_with("malformed request, unknown opcode"))
+        .unwrap_or_else(|| panic!("{captured:#?}"));
+    let len = size_of::<fuse_in_header>() + b"whatever".len();
+    assert_eq!(event.fields["len"], len.to_string());
+    // The hexdump ends with the arguments
+    assert!(event.message().contains("whatever|"), "{}", event.message());
+    assert_eq!(event.span, None);
+}
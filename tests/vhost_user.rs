@@ -0,0 +1,425 @@
+#![cfg(feature = "vhost-user")]
+
+use std::io::{Read, Write};
+use std::mem::{offset_of, size_of};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::sync::atomic::{Ordering, fence};
+use std::time::Duration;
+
+use fuse_async::protocol::*;
+use fuse_async::testing::MockKernel;
+use fuse_async::transport::VhostUserFs;
+use fuse_async::{Errno, Filesystem, MemFs, Request, Session, SessionConfig};
+use zerocopy::IntoBytes;
+
+// Messages of the frontend
+const GET_FEATURES: u32 = 1;
+const SET_FEATURES: u32 = 2;
+const SET_OWNER: u32 = 3;
+const SET_MEM_TABLE: u32 = 5;
+const SET_VRING_NUM: u32 = 8;
+const SET_VRING_ADDR: u32 = 9;
+const SET_VRING_BASE: u32 = 10;
+const SET_VRING_KICK: u32 = 12;
+const SET_VRING_CALL: u32 = 13;
+const GET_PROTOCOL_FEATURES: u32 = 15;
+const SET_PROTOCOL_FEATURES: u32 = 16;
+const SET_VRING_ENABLE: u32 = 18;
+const SET_BACKEND_REQ_FD: u32 = 21;
+// Messages of the backend
+const BACKEND_FS_MAP: u32 = 6;
+
+const VERSION: u32 = 0x1;
+const REPLY: u32 = 1 << 2;
+const NEED_REPLY: u32 = 1 << 3;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const F_PROTOCOL_FEATURES: u64 = 1 << 30;
+const PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+const PROTOCOL_F_BACKEND_REQ: u64 = 1 << 5;
+
+/// The request queue, queue 0 is the high priority one.
+const QUEUE: u32 = 1;
+const QUEUE_SIZE: u16 = 8;
+const VRING_DESC_F_NEXT: u16 = 1 << 0;
+const VRING_DESC_F_WRITE: u16 = 1 << 1;
+
+// Layout of the guest memory, by guest physical address
+const DESC: u64 = 0x0;
+const AVAIL: u64 = 0x1000;
+const USED: u64 = 0x2000;
+/// Buffers of the requests, one page per pair of descriptors.
+const REQUESTS: u64 = 0x4000;
+const REPLIES: u64 = 0x8000;
+const MEMORY_SIZE: u64 = 0x10000;
+/// Where the frontend pretends to have mapped the guest memory, the rings are
+/// given at these addresses.
+const USER_BASE: u64 = 0x7f00_0000_0000;
+
+/// Plays the part of QEMU and of the guest driver.
+struct Frontend {
+    socket: UnixStream,
+    memfd: OwnedFd,
+    memory: *mut u8,
+    kick: OwnedFd,
+    call: OwnedFd,
+    next_avail: u16,
+    next_used: u16,
+    unique: u64,
+}
+
+// SAFETY: the guest memory is only accessed by the thread owning the frontend
+unsafe impl Send for Frontend {}
+
+impl Frontend {
+    /// Connects to a backend, returns its end of the socket.
+    fn new() -> (Self, UnixStream) {
+        let (socket, backend) = UnixStream::pair().unwrap();
+        let memfd = unsafe { libc::memfd_create(c"guest".as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(memfd >= 0, "{}", std::io::Error::last_os_error());
+        let memfd = unsafe { OwnedFd::from_raw_fd(memfd) };
+        assert_eq!(unsafe { libc::ftruncate(memfd.as_raw_fd(), MEMORY_SIZE as _) }, 0);
+        let memory = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                MEMORY_SIZE as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                memfd.as_raw_fd(),
+                0
+            )
+        };
+        assert_ne!(memory, libc::MAP_FAILED);
+
+        let frontend = Self {
+            socket,
+            memfd,
+            memory: memory.cast(),
+            kick: eventfd(),
+            call: eventfd(),
+            next_avail: 0,
+            next_used: 0,
+            unique: 0,
+        };
+        (frontend, backend)
+    }
+
+    /// Negotiates the features and starts the request queue, with the
+    /// protocol features if `protocol` is set.
+    fn start(&mut self, protocol: bool) {
+        let reply = self.call_message(GET_FEATURES, &[]);
+        let offered = u64::from_ne_bytes(reply.try_into().unwrap());
+        assert_ne!(offered & VIRTIO_F_VERSION_1, 0);
+        self.message(SET_OWNER, 0, &[], &[]);
+
+        let mut features = VIRTIO_F_VERSION_1;
+        if protocol {
+            assert_ne!(offered & F_PROTOCOL_FEATURES, 0);
+            let reply = self.call_message(GET_PROTOCOL_FEATURES, &[]);
+            let offered = u64::from_ne_bytes(reply.try_into().unwrap());
+            let wanted = PROTOCOL_F_REPLY_ACK | PROTOCOL_F_BACKEND_REQ;
+            assert_eq!(offered & wanted, wanted);
+            features |= F_PROTOCOL_FEATURES;
+            self.message(SET_FEATURES, 0, features.as_bytes(), &[]);
+            self.message(SET_PROTOCOL_FEATURES, 0, wanted.as_bytes(), &[]);
+        } else {
+            self.message(SET_FEATURES, 0, features.as_bytes(), &[]);
+        }
+
+        // One region backed by the memfd: guest address, size, frontend
+        // address and offset in the memfd
+        let mut table = [1u32, 0].as_bytes().to_vec();
+        for value in [0, MEMORY_SIZE, USER_BASE, 0] {
+            table.extend_from_slice(value.as_bytes());
+        }
+        self.acked_message(protocol, SET_MEM_TABLE, &table, &[self.memfd.as_raw_fd()]);
+
+        let state = |num: u32| [QUEUE, num].as_bytes().to_vec();
+        self.acked_message(protocol, SET_VRING_NUM, &state(QUEUE_SIZE as u32), &[]);
+        let mut addr = [QUEUE, 0].as_bytes().to_vec();
+        for value in [USER_BASE + DESC, USER_BASE + USED, USER_BASE + AVAIL, 0] {
+            addr.extend_from_slice(value.as_bytes());
+        }
+        self.acked_message(protocol, SET_VRING_ADDR, &addr, &[]);
+        self.acked_message(protocol, SET_VRING_BASE, &state(0), &[]);
+        let kick = self.kick.as_raw_fd();
+        self.acked_message(protocol, SET_VRING_KICK, (QUEUE as u64).as_bytes(), &[kick]);
+        let call = self.call.as_raw_fd();
+        self.acked_message(protocol, SET_VRING_CALL, (QUEUE as u64).as_bytes(), &[call]);
+        if protocol {
+            self.acked_message(protocol, SET_VRING_ENABLE, &state(1), &[]);
+        } else {
+            // The messages are applied in order, the queue is ready once this
+            // one is answered
+            self.call_message(GET_FEATURES, &[]);
+        }
+    }
+
+    /// Sends a message to the backend.
+    fn message(&mut self, request: u32, flags: u32, payload: &[u8], fds: &[RawFd]) {
+        let mut message = [request, VERSION | flags, payload.len() as u32].as_bytes().to_vec();
+        message.extend_from_slice(payload);
+        send_with_fds(&self.socket, &message, fds);
+    }
+
+    /// Sends a message, and waits for the acknowledgement of the backend if
+    /// `ack` is set.
+    fn acked_message(&mut self, ack: bool, request: u32, payload: &[u8], fds: &[RawFd]) {
+        if !ack {
+            return self.message(request, 0, payload, fds);
+        }
+        self.message(request, NEED_REPLY, payload, fds);
+        let reply = read_reply(&mut self.socket, request);
+        assert_eq!(reply, 0u64.as_bytes(), "request {request} failed");
+    }
+
+    /// Sends a message that has a reply, returns its payload.
+    fn call_message(&mut self, request: u32, payload: &[u8]) -> Vec<u8> {
+        self.message(request, 0, payload, &[]);
+        read_reply(&mut self.socket, request)
+    }
+
+    /// Puts a FUSE request in the queue and notifies the backend, returns
+    /// its unique id.
+    fn submit(&mut self, opcode: fuse_opcode, nodeid: u64, arg: &[u8]) -> u64 {
+        self.unique += 1;
+        let slot = self.next_avail % (QUEUE_SIZE / 2);
+        let len = size_of::<fuse_in_header>() + arg.len();
+        let header = fuse_in_header {
+            len: len as u32,
+            opcode,
+            unique: self.unique,
+            nodeid,
+            uid: 0,
+            gid: 0,
+            pid: std::process::id(),
+            total_extlen: 0,
+            padding: 0,
+        };
+        let request = REQUESTS + slot as u64 * 0x1000;
+        let reply = REPLIES + slot as u64 * 0x1000;
+        self.write(request, header.as_bytes());
+        self.write(request + size_of::<fuse_in_header>() as u64, arg);
+
+        // The request, then the buffer of the reply
+        let head = slot * 2;
+        let desc = |addr: u64, len: u32, flags: u16, next: u16| {
+            let mut desc = addr.as_bytes().to_vec();
+            desc.extend_from_slice(len.as_bytes());
+            desc.extend_from_slice([flags, next].as_bytes());
+            desc
+        };
+        let next = head + 1;
+        self.write(DESC + head as u64 * 16, &desc(request, len as u32, VRING_DESC_F_NEXT, next));
+        self.write(DESC + next as u64 * 16, &desc(reply, 0x1000, VRING_DESC_F_WRITE, 0));
+
+        let entry = AVAIL + 4 + 2 * (self.next_avail % QUEUE_SIZE) as u64;
+        self.write(entry, head.as_bytes());
+        self.next_avail = self.next_avail.wrapping_add(1);
+        fence(Ordering::SeqCst);
+        self.write(AVAIL + 2, self.next_avail.as_bytes());
+        fence(Ordering::SeqCst);
+        write_eventfd(&self.kick);
+        self.unique
+    }
+
+    /// Waits for the next reply in the used ring, returns its unique id and
+    /// its result.
+    fn complete(&mut self) -> (u64, Result<Vec<u8>, Errno>) {
+        loop {
+            fence(Ordering::SeqCst);
+            let used = u16::from_ne_bytes(self.read(USED + 2, 2).try_into().unwrap());
+            if used != self.next_used {
+                break;
+            }
+            read_eventfd(&self.call);
+        }
+        let elem = self.read(USED + 4 + 8 * (self.next_used % QUEUE_SIZE) as u64, 8);
+        self.next_used = self.next_used.wrapping_add(1);
+        let head = u32::from_ne_bytes(elem[..4].try_into().unwrap()) as u64;
+        let len = u32::from_ne_bytes(elem[4..].try_into().unwrap()) as u64;
+
+        let reply = self.read(REPLIES + head / 2 * 0x1000, len);
+        assert_eq!(u32::from_ne_bytes(reply[..4].try_into().unwrap()) as u64, len);
+        let unique = u64::from_ne_bytes(reply[8..16].try_into().unwrap());
+        let result = match i32::from_ne_bytes(reply[4..8].try_into().unwrap()) {
+            0 => Ok(reply[size_of::<fuse_out_header>()..].to_vec()),
+            error => Err(Errno::new(-error).unwrap()),
+        };
+        (unique, result)
+    }
+
+    /// Sends a request and waits for its reply.
+    fn request(&mut self, opcode: fuse_opcode, nodeid: u64, arg: &[u8]) -> Result<Vec<u8>, Errno> {
+        let unique = self.submit(opcode, nodeid, arg);
+        let (replied, result) = self.complete();
+        assert_eq!(replied, unique);
+        result
+    }
+
+    fn init(&mut self) {
+        let reply = self.request(fuse_opcode::FUSE_INIT, 0, MockKernel::default_init().as_bytes());
+        let major = u32::from_ne_bytes(reply.unwrap()[..4].try_into().unwrap());
+        assert_eq!(major, FUSE_KERNEL_VERSION);
+    }
+
+    fn write(&self, addr: u64, data: &[u8]) {
+        assert!(addr + data.len() as u64 <= MEMORY_SIZE);
+        let dst = unsafe { self.memory.add(addr as usize) };
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+    }
+
+    fn read(&self, addr: u64, len: u64) -> Vec<u8> {
+        assert!(addr + len <= MEMORY_SIZE);
+        let mut data = vec![0; len as usize];
+        let src = unsafe { self.memory.add(addr as usize) };
+        unsafe { ptr::copy_nonoverlapping(src, data.as_mut_ptr(), data.len()) };
+        data
+    }
+}
+
+impl Drop for Frontend {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.memory.cast(), MEMORY_SIZE as usize) };
+    }
+}
+
+fn eventfd() -> OwnedFd {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    assert!(fd >= 0, "{}", std::io::Error::last_os_error());
+    unsafe { OwnedFd::from_raw_fd(fd) }
+}
+
+fn write_eventfd(fd: &OwnedFd) {
+    let ret = unsafe { libc::write(fd.as_raw_fd(), 1u64.as_bytes().as_ptr().cast(), 8) };
+    assert_eq!(ret, 8);
+}
+
+fn read_eventfd(fd: &OwnedFd) {
+    let mut value = 0u64;
+    let ret = unsafe { libc::read(fd.as_raw_fd(), (&raw mut value).cast(), 8) };
+    assert_eq!(ret, 8);
+}
+
+fn send_with_fds(socket: &UnixStream, data: &[u8], fds: &[RawFd]) {
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut _, iov_len: data.len() };
+    let space = unsafe { libc::CMSG_SPACE(size_of_val(fds) as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &raw mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = space as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of_val(fds) as u32) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+        }
+    }
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+    assert_eq!(sent, data.len() as isize, "{}", std::io::Error::last_os_error());
+}
+
+/// Reads a reply to `request`, returns its payload.
+fn read_reply(socket: &mut UnixStream, request: u32) -> Vec<u8> {
+    let mut header = [0u32; 3];
+    socket.read_exact(header.as_mut_bytes()).unwrap();
+    assert_eq!(header[0], request);
+    assert_eq!(header[1], VERSION | REPLY);
+    let mut payload = vec![0; header[2] as usize];
+    socket.read_exact(&mut payload).unwrap();
+    payload
+}
+
+#[tokio::test]
+async fn memfs_is_served_over_a_virtqueue() {
+    let (mut frontend, backend) = Frontend::new();
+    let transport = VhostUserFs::new(backend, 1).unwrap();
+    let session = Session::with_transport(transport, MemFs::new(), SessionConfig::new()).unwrap();
+    let shutdown = session.shutdown_handle();
+    let session = tokio::spawn(session.run());
+
+    let attr = tokio::task::spawn_blocking(move || {
+        frontend.start(false);
+        frontend.init();
+        let arg = fuse_getattr_in { getattr_flags: GetattrFlags::empty(), dummy: 0, fh: 0 };
+        frontend.request(fuse_opcode::FUSE_GETATTR, FUSE_ROOT_ID, arg.as_bytes())
+    }).await.unwrap().unwrap();
+
+    let at = offset_of!(fuse_attr_out, attr) + offset_of!(fuse_attr, mode);
+    let mode = u32::from_ne_bytes(attr[at..at + 4].try_into().unwrap());
+    assert_eq!(mode & libc::S_IFMT, libc::S_IFDIR);
+
+    shutdown.shutdown(Duration::from_secs(5)).await;
+    session.await.unwrap().unwrap();
+}
+
+/// Maps its file in the DAX window for every request.
+struct Dax(OwnedFd);
+
+impl Filesystem for Dax {
+    async fn setupmapping(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        arg: &fuse_setupmapping_in,
+        window: &dyn fuse_async::transport::DaxWindow
+    ) -> Result<(), Errno> {
+        window.map(self.0.as_fd(), arg).await.map_err(Errno::from)
+    }
+}
+
+#[tokio::test]
+async fn dax_mapping_waits_for_the_frontend_without_blocking() {
+    let (mut frontend, backend) = Frontend::new();
+    let file = frontend.memfd.try_clone().unwrap();
+    let transport = VhostUserFs::new(backend, 1).unwrap();
+    let session = Session::with_transport(transport, Dax(file), SessionConfig::new()).unwrap();
+    let shutdown = session.shutdown_handle();
+    let session = tokio::spawn(session.run());
+
+    tokio::task::spawn_blocking(move || {
+        frontend.start(true);
+        let (mut channel, theirs) = UnixStream::pair().unwrap();
+        frontend.acked_message(true, SET_BACKEND_REQ_FD, &[], &[theirs.as_raw_fd()]);
+        drop(theirs);
+        frontend.init();
+
+        let flags = SetupMappingFlags::FUSE_SETUPMAPPING_FLAG_READ
+            | SetupMappingFlags::FUSE_SETUPMAPPING_FLAG_WRITE;
+        let arg = fuse_setupmapping_in {
+            fh: 0,
+            foffset: 0x1000,
+            len: 0x2000,
+            flags: flags.bits(),
+            moffset: 0x4000,
+        };
+        let mapping = frontend.submit(fuse_opcode::FUSE_SETUPMAPPING, FUSE_ROOT_ID, arg.as_bytes());
+
+        // The fd_offset, cache_offset, len and flags arrays of 8 entries
+        let mut header = [0u32; 3];
+        channel.read_exact(header.as_mut_bytes()).unwrap();
+        assert_eq!(header, [BACKEND_FS_MAP, VERSION | NEED_REPLY, 4 * 8 * 8]);
+        let mut msg = [0u64; 4 * 8];
+        channel.read_exact(msg.as_mut_bytes()).unwrap();
+        assert_eq!([msg[0], msg[8], msg[16], msg[24]], [0x1000, 0x4000, 0x2000, 0b11]);
+
+        // The session still answers while the mapping waits for the frontend
+        let arg = fuse_getattr_in { getattr_flags: GetattrFlags::empty(), dummy: 0, fh: 0 };
+        let getattr = frontend.request(fuse_opcode::FUSE_GETATTR, FUSE_ROOT_ID, arg.as_bytes());
+        assert_eq!(getattr.unwrap_err(), Errno::ENOSYS);
+
+        let mut ack = [BACKEND_FS_MAP, VERSION | REPLY, 8].as_bytes().to_vec();
+        ack.extend_from_slice(0u64.as_bytes());
+        channel.write_all(&ack).unwrap();
+        assert_eq!(frontend.complete(), (mapping, Ok(Vec::new())));
+    }).await.unwrap();
+
+    shutdown.shutdown(Duration::from_secs(5)).await;
+    session.await.unwrap().unwrap();
+}